@@ -0,0 +1,132 @@
+//! Instants measured by the monotonic clock of an [`Environment`].
+//!
+//! [`Environment::now`] returns a [`MonotonicInstant`], which never moves backwards and is the
+//! clock which timers are driven by. It is distinct from [`Environment::node_now`], which
+//! returns the time according to the clock of the current node and may be skewed from the
+//! monotonic clock. Keeping the two as separate types makes it explicit which clock a caller is
+//! using.
+//!
+//! [`Environment`]:`crate::Environment`
+//! [`Environment::now`]:`crate::Environment::now`
+//! [`Environment::node_now`]:`crate::Environment::node_now`
+use std::{cmp, ops, time};
+
+/// An instant measured by the monotonic clock of an [`Environment`].
+///
+/// [`Environment`]:`crate::Environment`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MonotonicInstant(time::Instant);
+
+impl MonotonicInstant {
+    /// Wrap an instant which was measured by a monotonic clock.
+    pub fn from_std(instant: time::Instant) -> Self {
+        MonotonicInstant(instant)
+    }
+
+    /// Returns the underlying `std::time::Instant`.
+    pub fn into_std(self) -> time::Instant {
+        self.0
+    }
+
+    /// Returns the amount of time elapsed from `earlier` to this instant. Panics if `earlier`
+    /// is later than this instant.
+    pub fn duration_since(&self, earlier: MonotonicInstant) -> time::Duration {
+        self.0.duration_since(earlier.0)
+    }
+
+    /// Returns the amount of time elapsed from `earlier` to this instant, or zero if `earlier`
+    /// is later than this instant.
+    pub fn saturating_duration_since(&self, earlier: MonotonicInstant) -> time::Duration {
+        self.0
+            .checked_duration_since(earlier.0)
+            .unwrap_or_else(|| time::Duration::from_secs(0))
+    }
+
+    pub fn checked_add(&self, duration: time::Duration) -> Option<MonotonicInstant> {
+        self.0.checked_add(duration).map(MonotonicInstant)
+    }
+
+    pub fn checked_sub(&self, duration: time::Duration) -> Option<MonotonicInstant> {
+        self.0.checked_sub(duration).map(MonotonicInstant)
+    }
+}
+
+impl From<time::Instant> for MonotonicInstant {
+    fn from(instant: time::Instant) -> Self {
+        MonotonicInstant(instant)
+    }
+}
+
+impl From<MonotonicInstant> for time::Instant {
+    fn from(instant: MonotonicInstant) -> Self {
+        instant.0
+    }
+}
+
+impl PartialEq<time::Instant> for MonotonicInstant {
+    fn eq(&self, other: &time::Instant) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialOrd<time::Instant> for MonotonicInstant {
+    fn partial_cmp(&self, other: &time::Instant) -> Option<cmp::Ordering> {
+        self.0.partial_cmp(other)
+    }
+}
+
+impl ops::Add<time::Duration> for MonotonicInstant {
+    type Output = MonotonicInstant;
+    fn add(self, duration: time::Duration) -> MonotonicInstant {
+        MonotonicInstant(self.0 + duration)
+    }
+}
+
+impl ops::AddAssign<time::Duration> for MonotonicInstant {
+    fn add_assign(&mut self, duration: time::Duration) {
+        self.0 += duration;
+    }
+}
+
+impl ops::Sub<time::Duration> for MonotonicInstant {
+    type Output = MonotonicInstant;
+    fn sub(self, duration: time::Duration) -> MonotonicInstant {
+        MonotonicInstant(self.0 - duration)
+    }
+}
+
+impl ops::SubAssign<time::Duration> for MonotonicInstant {
+    fn sub_assign(&mut self, duration: time::Duration) {
+        self.0 -= duration;
+    }
+}
+
+impl ops::Sub<MonotonicInstant> for MonotonicInstant {
+    type Output = time::Duration;
+    fn sub(self, earlier: MonotonicInstant) -> time::Duration {
+        self.0 - earlier.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that arithmetic and comparisons agree with the underlying instant.
+    fn arithmetic() {
+        let std_now = time::Instant::now();
+        let now = MonotonicInstant::from(std_now);
+        let later = now + time::Duration::from_secs(5);
+        assert_eq!(later - now, time::Duration::from_secs(5));
+        assert_eq!(
+            now.saturating_duration_since(later),
+            time::Duration::from_secs(0)
+        );
+        assert!(later > std_now);
+        assert_eq!(
+            time::Instant::from(later),
+            std_now + time::Duration::from_secs(5)
+        );
+    }
+}
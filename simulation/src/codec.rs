@@ -0,0 +1,213 @@
+//! Fault injection at serialization boundaries.
+//!
+//! [`FaultyCodec`] wraps a Tokio codec, injecting faults into the frames it encodes and decodes
+//! with a probability sampled from the [`Environment`] source of randomness. Wrapping the codec
+//! of a `Framed` transport exercises the error handling paths of protocol handlers without
+//! requiring a custom corruptor for each protocol.
+//!
+//! - Decode errors are injected in place of successfully decoded frames.
+//! - Truncated frames are injected by writing only a prefix of an encoded frame.
+//! - Oversized frames are injected by appending filler bytes to an encoded frame.
+//! - Duplicate frames are injected by writing an encoded frame twice, so that the peer decodes
+//!   the same message again, exercising its deduplication and idempotency handling.
+use crate::Environment;
+use bytes::BytesMut;
+use std::io;
+use tokio::codec::{Decoder, Encoder};
+use tracing::trace;
+
+/// Byte used to extend oversized frames.
+const OVERSIZE_FILLER: u8 = 0xAA;
+
+/// A codec wrapper which injects faults into encoded and decoded frames.
+#[derive(Debug, Clone)]
+pub struct FaultyCodec<C, E> {
+    inner: C,
+    env: E,
+    decode_error_probability: f64,
+    truncate_probability: f64,
+    oversize_probability: f64,
+    oversize_len: usize,
+    duplicate_probability: f64,
+}
+
+impl<C, E> FaultyCodec<C, E>
+where
+    E: Environment,
+{
+    /// Wrap `inner`. No faults are injected until a fault probability is configured.
+    pub fn new(inner: C, env: E) -> Self {
+        Self {
+            inner,
+            env,
+            decode_error_probability: 0.0,
+            truncate_probability: 0.0,
+            oversize_probability: 0.0,
+            oversize_len: 64 * 1024,
+            duplicate_probability: 0.0,
+        }
+    }
+
+    /// Probability that a successfully decoded frame is replaced with an `InvalidData` error.
+    pub fn decode_error_probability(mut self, probability: f64) -> Self {
+        self.decode_error_probability = probability;
+        self
+    }
+
+    /// Probability that an encoded frame is truncated to a random prefix of itself.
+    pub fn truncate_probability(mut self, probability: f64) -> Self {
+        self.truncate_probability = probability;
+        self
+    }
+
+    /// Probability that an encoded frame is extended with filler bytes.
+    pub fn oversize_probability(mut self, probability: f64) -> Self {
+        self.oversize_probability = probability;
+        self
+    }
+
+    /// Number of filler bytes appended to oversized frames. Defaults to 64KiB.
+    pub fn oversize_len(mut self, len: usize) -> Self {
+        self.oversize_len = len;
+        self
+    }
+
+    /// Probability that an encoded frame is written twice.
+    pub fn duplicate_probability(mut self, probability: f64) -> Self {
+        self.duplicate_probability = probability;
+        self
+    }
+
+    /// Returns a reference to the wrapped codec.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Consumes this wrapper, returning the wrapped codec.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn decode_fault<T, Err>(&self, frame: Option<T>) -> Result<Option<T>, Err>
+    where
+        Err: From<io::Error>,
+    {
+        if frame.is_some()
+            && self.decode_error_probability > 0.0
+            && self.env.gen_bool(self.decode_error_probability)
+        {
+            trace!("injecting decode error");
+            let err = io::Error::new(io::ErrorKind::InvalidData, "injected decode fault");
+            return Err(err.into());
+        }
+        Ok(frame)
+    }
+}
+
+impl<C, E> Decoder for FaultyCodec<C, E>
+where
+    C: Decoder,
+    E: Environment,
+{
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let frame = self.inner.decode(src)?;
+        self.decode_fault(frame)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let frame = self.inner.decode_eof(src)?;
+        self.decode_fault(frame)
+    }
+}
+
+impl<C, E> Encoder for FaultyCodec<C, E>
+where
+    C: Encoder,
+    E: Environment,
+{
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut frame = BytesMut::new();
+        self.inner.encode(item, &mut frame)?;
+        if !frame.is_empty()
+            && self.truncate_probability > 0.0
+            && self.env.gen_bool(self.truncate_probability)
+        {
+            let len = self.env.gen_range(0..frame.len());
+            trace!(
+                "truncating encoded frame from {} to {} bytes",
+                frame.len(),
+                len
+            );
+            frame.truncate(len);
+        } else if self.oversize_probability > 0.0 && self.env.gen_bool(self.oversize_probability) {
+            trace!("extending encoded frame by {} bytes", self.oversize_len);
+            frame.extend_from_slice(&vec![OVERSIZE_FILLER; self.oversize_len]);
+        } else if self.duplicate_probability > 0.0 && self.env.gen_bool(self.duplicate_probability)
+        {
+            trace!("duplicating encoded frame of {} bytes", frame.len());
+            dst.extend_from_slice(&frame);
+        }
+        dst.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use tokio::codec::LinesCodec;
+
+    #[test]
+    /// Test that decoded frames are replaced with errors.
+    fn decode_errors() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        let mut codec = FaultyCodec::new(LinesCodec::new(), runtime.localhost_handle())
+            .decode_error_probability(1.0);
+        let mut src = BytesMut::from(&b"ping\n"[..]);
+        assert!(codec.decode(&mut src).is_err());
+        assert!(src.is_empty(), "expected faulty frame to be consumed");
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    /// Test that encoded frames are truncated or extended.
+    fn encode_faults() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+
+        let mut codec =
+            FaultyCodec::new(LinesCodec::new(), handle.clone()).truncate_probability(1.0);
+        let mut dst = BytesMut::new();
+        codec.encode(String::from("ping"), &mut dst).unwrap();
+        assert!(dst.len() < 5);
+
+        let mut codec = FaultyCodec::new(LinesCodec::new(), handle)
+            .oversize_probability(1.0)
+            .oversize_len(10);
+        let mut dst = BytesMut::new();
+        codec.encode(String::from("ping"), &mut dst).unwrap();
+        assert_eq!(dst.len(), 15);
+        assert_eq!(&dst[..5], b"ping\n");
+    }
+
+    #[test]
+    /// Test that duplicated frames are decoded twice.
+    fn duplicate_frames() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        let mut codec = FaultyCodec::new(LinesCodec::new(), runtime.localhost_handle())
+            .duplicate_probability(1.0);
+        let mut buf = BytesMut::new();
+        codec.encode(String::from("ping"), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"ping\nping\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(String::from("ping")));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(String::from("ping")));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+}
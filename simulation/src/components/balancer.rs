@@ -0,0 +1,425 @@
+//! A layer 4 load balancer.
+//!
+//! [`SimulatedLoadBalancer`] binds a virtual address and forwards each connection accepted on it
+//! to one of a set of backends, copying bytes in both directions until each side shuts down its
+//! writes. Backends are chosen by a [`Policy`] from those currently considered healthy.
+//!
+//! Backends are health checked by connecting to them at seeded intervals. A backend which fails
+//! the configured number of consecutive checks, or to which forwarding a connection fails, stops
+//! receiving new connections until it passes a check again. Connections already forwarded to a
+//! backend are not affected by its health, so tests can exercise how clients handle a backend
+//! failing underneath an established connection, as well as how quickly the balancer routes
+//! around it.
+use crate::{Environment, TcpListener, TcpStream};
+use std::{io, net, sync, time};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::trace;
+
+/// Policy by which a [`SimulatedLoadBalancer`] chooses the backend of each connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Choose each healthy backend in turn.
+    RoundRobin,
+    /// Choose the healthy backend with the fewest active connections, in turn among ties.
+    LeastConnections,
+}
+
+#[derive(Debug)]
+struct Backend {
+    addr: net::SocketAddr,
+    healthy: bool,
+    failures: u32,
+    active: usize,
+    /// Distinguishes this backend from earlier backends with the same address which were
+    /// removed, so that their connections completing does not affect its count.
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    backends: Vec<Backend>,
+    /// Index of the backend after the one most recently chosen.
+    next: usize,
+    /// Generation of the next backend added.
+    next_generation: u64,
+}
+
+impl State {
+    fn backend(&mut self, addr: net::SocketAddr) -> Option<&mut Backend> {
+        self.backends.iter_mut().find(|b| b.addr == addr)
+    }
+
+    /// Choose the backend of a new connection, skipping those in `tried`.
+    fn choose(&mut self, policy: Policy, tried: &[net::SocketAddr]) -> Option<net::SocketAddr> {
+        let len = self.backends.len();
+        let candidates = (0..len)
+            .map(|offset| (self.next + offset) % len)
+            .filter(|i| self.backends[*i].healthy && !tried.contains(&self.backends[*i].addr));
+        let chosen = match policy {
+            Policy::RoundRobin => candidates.take(1).next(),
+            Policy::LeastConnections => {
+                let backends = &self.backends;
+                candidates.min_by_key(|i| backends[*i].active)
+            }
+        }?;
+        self.next = (chosen + 1) % len;
+        Some(self.backends[chosen].addr)
+    }
+
+    /// Record the result of a health check or forwarded connection to `addr`.
+    fn record(&mut self, addr: net::SocketAddr, success: bool, threshold: u32) {
+        if let Some(backend) = self.backend(addr) {
+            if success {
+                backend.failures = 0;
+                if !backend.healthy {
+                    trace!("backend {} is healthy", addr);
+                    backend.healthy = true;
+                }
+            } else {
+                backend.failures += 1;
+                if backend.healthy && backend.failures >= threshold {
+                    trace!("backend {} is unhealthy", addr);
+                    backend.healthy = false;
+                }
+            }
+        }
+    }
+}
+
+/// Decrements the active connections of a backend when a forwarded connection completes,
+/// unless the backend has since been removed.
+struct Active {
+    state: sync::Arc<sync::Mutex<State>>,
+    addr: net::SocketAddr,
+    generation: u64,
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        if let Some(backend) = self.state.lock().unwrap().backend(self.addr) {
+            if backend.generation == self.generation {
+                backend.active -= 1;
+            }
+        }
+    }
+}
+
+/// A load balancer which forwards connections to a set of backends.
+#[derive(Debug, Clone)]
+pub struct SimulatedLoadBalancer<E> {
+    env: E,
+    policy: Policy,
+    interval: Option<time::Duration>,
+    timeout: time::Duration,
+    failure_threshold: u32,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl<E> SimulatedLoadBalancer<E>
+where
+    E: Environment,
+{
+    /// Create a round robin load balancer without backends or health checks, which fails
+    /// connects to backends that do not complete within a second and considers a backend
+    /// unhealthy after 3 consecutive failures.
+    pub fn new(env: E) -> Self {
+        let state = State {
+            backends: vec![],
+            next: 0,
+            next_generation: 0,
+        };
+        Self {
+            env,
+            policy: Policy::RoundRobin,
+            interval: None,
+            timeout: time::Duration::from_secs(1),
+            failure_threshold: 3,
+            state: sync::Arc::new(sync::Mutex::new(state)),
+        }
+    }
+
+    /// Forward connections to `addr`.
+    pub fn backend(self, addr: net::SocketAddr) -> Self {
+        self.add_backend(addr);
+        self
+    }
+
+    /// Policy by which the backend of each connection is chosen. Defaults to round robin.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Health check each backend at this mean interval. Each interval is jittered by up to 50%.
+    pub fn health_check(mut self, interval: time::Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Time after which a health check or a connect to a backend is considered failed.
+    pub fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of consecutive failures after which a backend is considered unhealthy.
+    pub fn failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures;
+        self
+    }
+
+    /// Begin forwarding new connections to `addr`. Backends are initially considered healthy.
+    pub fn add_backend(&self, addr: net::SocketAddr) {
+        let mut lock = self.state.lock().unwrap();
+        if lock.backend(addr).is_none() {
+            let generation = lock.next_generation;
+            lock.next_generation += 1;
+            lock.backends.push(Backend {
+                addr,
+                healthy: true,
+                failures: 0,
+                active: 0,
+                generation,
+            });
+        }
+    }
+
+    /// Stop forwarding new connections to `addr`. Connections already forwarded to it are not
+    /// affected.
+    pub fn remove_backend(&self, addr: net::SocketAddr) {
+        let mut lock = self.state.lock().unwrap();
+        lock.backends.retain(|b| b.addr != addr);
+        lock.next = 0;
+    }
+
+    /// Returns the backends which are currently considered healthy.
+    pub fn healthy_backends(&self) -> Vec<net::SocketAddr> {
+        let lock = self.state.lock().unwrap();
+        lock.backends
+            .iter()
+            .filter(|b| b.healthy)
+            .map(|b| b.addr)
+            .collect()
+    }
+
+    /// Returns the number of connections forwarded to `addr` which are still active.
+    pub fn active_connections(&self, addr: net::SocketAddr) -> usize {
+        let mut lock = self.state.lock().unwrap();
+        lock.backend(addr).map(|b| b.active).unwrap_or(0)
+    }
+
+    /// Forward connections accepted on `addr`, health checking backends if configured.
+    pub async fn serve(self, addr: net::SocketAddr) -> io::Result<()> {
+        let mut listener = self.env.bind(addr).await?;
+        if let Some(interval) = self.interval {
+            self.env.spawn(self.clone().check_health(interval));
+        }
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let balancer = self.clone();
+            self.env.spawn(async move {
+                match balancer.connect_backend().await {
+                    Some((addr, backend, active)) => {
+                        trace!("forwarding {} to {}", peer, addr);
+                        balancer.forward(socket, backend).await;
+                        drop(active);
+                    }
+                    None => trace!("no healthy backend for {}, dropping connection", peer),
+                }
+            });
+        }
+    }
+
+    /// Connect to a backend chosen by the policy, trying each healthy backend once.
+    async fn connect_backend(&self) -> Option<(net::SocketAddr, E::TcpStream, Active)> {
+        let mut tried = vec![];
+        loop {
+            let (addr, generation) = {
+                let mut lock = self.state.lock().unwrap();
+                let addr = lock.choose(self.policy, &tried)?;
+                // Count the connection as active while connecting, so that concurrent
+                // connections are balanced by least connections.
+                let backend = lock.backend(addr).expect("chosen backend exists");
+                backend.active += 1;
+                (addr, backend.generation)
+            };
+            tried.push(addr);
+            let active = Active {
+                state: sync::Arc::clone(&self.state),
+                addr,
+                generation,
+            };
+            let connected = self.connect(addr).await;
+            let threshold = self.failure_threshold;
+            let mut lock = self.state.lock().unwrap();
+            match connected {
+                Ok(backend) => {
+                    lock.record(addr, true, threshold);
+                    return Some((addr, backend, active));
+                }
+                Err(e) => {
+                    trace!("failed to connect to backend {}: {}", addr, e);
+                    lock.record(addr, false, threshold);
+                }
+            }
+        }
+    }
+
+    async fn connect(&self, addr: net::SocketAddr) -> io::Result<E::TcpStream> {
+        match self.env.timeout(self.env.connect(addr), self.timeout).await {
+            Ok(result) => result,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// Copy bytes between `client` and `backend` until both have shut down their writes.
+    async fn forward(&self, client: E::TcpStream, backend: E::TcpStream) {
+        let (client_read, client_write) = client.into_split();
+        let (backend_read, backend_write) = backend.into_split();
+        let upstream = crate::spawn_with_result(&self.env, pipe(client_read, backend_write));
+        let _ = pipe(backend_read, client_write).await;
+        let _ = upstream.await;
+    }
+
+    /// Connect to each backend at jittered intervals, recording the result.
+    async fn check_health(self, interval: time::Duration) {
+        loop {
+            let jitter = self.env.gen_range(0.5..1.5);
+            self.env.delay_from(interval.mul_f64(jitter)).await;
+            let backends: Vec<_> = {
+                let lock = self.state.lock().unwrap();
+                lock.backends.iter().map(|b| b.addr).collect()
+            };
+            for addr in backends {
+                let success = self.connect(addr).await.is_ok();
+                let mut lock = self.state.lock().unwrap();
+                lock.record(addr, success, self.failure_threshold);
+            }
+        }
+    }
+}
+
+/// Copy bytes from `reader` to `writer`, shutting down `writer` once `reader` reaches EOF.
+async fn pipe<R, W>(mut reader: R, mut writer: W) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0; 4096];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..read]).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use futures::FutureExt;
+
+    /// Serve connections on `addr` by writing `name` and closing the connection.
+    async fn serve_name<E: Environment>(env: E, addr: net::SocketAddr, name: &'static str) {
+        let mut listener = env.bind(addr).await.unwrap();
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            env.spawn(async move {
+                let _ = socket.write_all(name.as_bytes()).await;
+            });
+        }
+    }
+
+    #[test]
+    /// Test that connections are balanced across backends, and routed around a backend which
+    /// fails its health checks until it recovers.
+    fn balance_and_health_check() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let lb_addr: net::SocketAddr = "10.0.0.100:80".parse().unwrap();
+        let backends: Vec<(net::SocketAddr, &'static str)> = vec![
+            ("10.0.0.1:8080".parse().unwrap(), "a"),
+            ("10.0.0.2:8080".parse().unwrap(), "b"),
+        ];
+        let mut balancer = SimulatedLoadBalancer::new(runtime.handle(lb_addr.ip()))
+            .health_check(time::Duration::from_secs(1))
+            .failure_threshold(1);
+        for (addr, name) in backends.iter() {
+            let backend = runtime.handle(addr.ip());
+            runtime.spawn(serve_name(backend, *addr, *name));
+            balancer = balancer.backend(*addr);
+        }
+        let client = runtime.handle("10.0.0.3".parse().unwrap());
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            handle.spawn(balancer.clone().serve(lb_addr).map(|_| ()));
+            handle.delay_from(time::Duration::from_millis(1)).await;
+            let client = &client;
+            let request = || async move {
+                let mut socket = client.connect(lb_addr).await.unwrap();
+                let mut name = String::new();
+                socket.read_to_string(&mut name).await.unwrap();
+                name
+            };
+            assert_eq!(request().await, "a");
+            assert_eq!(request().await, "b");
+            assert_eq!(request().await, "a");
+
+            handle
+                .network_handle()
+                .partition(&[lb_addr.ip()], &[backends[0].0.ip()]);
+            handle.delay_from(time::Duration::from_secs(5)).await;
+            assert_eq!(balancer.healthy_backends(), vec![backends[1].0]);
+            assert_eq!(request().await, "b");
+            assert_eq!(request().await, "b");
+
+            handle.network_handle().heal();
+            handle.delay_from(time::Duration::from_secs(5)).await;
+            assert_eq!(balancer.healthy_backends().len(), 2);
+        });
+    }
+
+    #[test]
+    /// Test that least connections chooses the backend with the fewest active connections.
+    fn least_connections() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let lb_addr: net::SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let first: net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let second: net::SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let balancer = SimulatedLoadBalancer::new(handle.clone())
+            .policy(Policy::LeastConnections)
+            .backend(first)
+            .backend(second);
+        runtime.block_on(async {
+            let mut first_listener = handle.bind(first).await.unwrap();
+            let mut second_listener = handle.bind(second).await.unwrap();
+            handle.spawn(balancer.clone().serve(lb_addr).map(|_| ()));
+
+            let _first_client = handle.connect(lb_addr).await.unwrap();
+            let (_first_backend, _) = first_listener.accept().await.unwrap();
+            let second_client = handle.connect(lb_addr).await.unwrap();
+            let (second_backend, _) = second_listener.accept().await.unwrap();
+            assert_eq!(balancer.active_connections(first), 1);
+            assert_eq!(balancer.active_connections(second), 1);
+
+            drop(second_client);
+            drop(second_backend);
+            handle.delay_from(time::Duration::from_millis(10)).await;
+            assert_eq!(balancer.active_connections(second), 0);
+            // round robin would choose the first backend.
+            let third_client = handle.connect(lb_addr).await.unwrap();
+            let (third_backend, _) = second_listener.accept().await.unwrap();
+            assert_eq!(balancer.active_connections(second), 1);
+
+            // connections to a removed backend completing do not affect it once re-added.
+            balancer.remove_backend(second);
+            balancer.add_backend(second);
+            drop(third_client);
+            drop(third_backend);
+            handle.delay_from(time::Duration::from_millis(10)).await;
+            assert_eq!(balancer.active_connections(second), 0);
+        });
+    }
+}
@@ -0,0 +1,246 @@
+//! Eventually consistent object storage.
+//!
+//! [`BlobStore`] models an S3-like object store. Writes and deletes become visible to readers
+//! after a seeded delay of up to the configured staleness, so clients can observe stale reads
+//! and listings. Requests can be configured to incur latency and to fail at a given rate, and
+//! the whole store can be failed and recovered.
+use crate::{Environment, MonotonicInstant};
+use bytes::Bytes;
+use std::{collections, io, ops, sync, time};
+use tracing::trace;
+
+#[derive(Debug)]
+struct Version {
+    visible_at: MonotonicInstant,
+    /// Contents of the object, or `None` if this version is a deletion.
+    data: Option<Bytes>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    available: bool,
+    staleness: time::Duration,
+    error_rate: f64,
+    latency: Option<ops::Range<time::Duration>>,
+    objects: collections::BTreeMap<String, Vec<Version>>,
+}
+
+impl Inner {
+    /// Returns the contents of `key` visible at `now`.
+    fn visible(&self, key: &str, now: MonotonicInstant) -> Option<&Bytes> {
+        self.objects
+            .get(key)?
+            .iter()
+            .rev()
+            .find(|version| version.visible_at <= now)
+            .and_then(|version| version.data.as_ref())
+    }
+
+    /// Record a new version of `key`, visible from `visible_at`. Versions of a key always become
+    /// visible in the order they were written.
+    fn write(
+        &mut self,
+        key: &str,
+        visible_at: MonotonicInstant,
+        data: Option<Bytes>,
+        now: MonotonicInstant,
+    ) {
+        let versions = self.objects.entry(key.to_string()).or_default();
+        let visible_at = versions
+            .last()
+            .map_or(visible_at, |last| visible_at.max(last.visible_at));
+        // Versions superseded by a version which is already visible can never be read again.
+        if let Some(current) = versions
+            .iter()
+            .rposition(|version| version.visible_at <= now)
+        {
+            versions.drain(..current);
+        }
+        versions.push(Version { visible_at, data });
+    }
+}
+
+/// An eventually consistent object store.
+#[derive(Debug, Clone)]
+pub struct BlobStore<E> {
+    env: E,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl<E> BlobStore<E>
+where
+    E: Environment,
+{
+    /// Create a strongly consistent store which never fails and responds immediately.
+    pub fn new(env: E) -> Self {
+        let inner = Inner {
+            available: true,
+            staleness: time::Duration::from_secs(0),
+            error_rate: 0.0,
+            latency: None,
+            objects: collections::BTreeMap::new(),
+        };
+        Self {
+            env,
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        }
+    }
+
+    /// Maximum delay before a write or delete becomes visible to readers.
+    pub fn staleness(self, staleness: time::Duration) -> Self {
+        self.inner.lock().unwrap().staleness = staleness;
+        self
+    }
+
+    /// Probability that a request fails. Panics if `probability` is not in `0.0..=1.0`.
+    pub fn error_rate(self, probability: f64) -> Self {
+        assert!(
+            probability >= 0.0 && probability <= 1.0,
+            "illegal error rate: {}",
+            probability
+        );
+        self.inner.lock().unwrap().error_rate = probability;
+        self
+    }
+
+    /// Range from which the latency of each request is sampled.
+    pub fn latency(self, latency: ops::Range<time::Duration>) -> Self {
+        self.inner.lock().unwrap().latency = Some(latency);
+        self
+    }
+
+    /// Store `data` under `key`, replacing any existing object.
+    pub async fn put(&self, key: &str, data: Bytes) -> io::Result<()> {
+        self.request().await?;
+        self.write(key, Some(data));
+        Ok(())
+    }
+
+    /// Returns the object stored under `key`. Returns `NotFound` if no object is visible.
+    pub async fn get(&self, key: &str) -> io::Result<Bytes> {
+        self.request().await?;
+        let now = self.env.now();
+        let lock = self.inner.lock().unwrap();
+        lock.visible(key, now)
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    /// Returns the keys of all visible objects starting with `prefix`, in lexicographic order.
+    pub async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.request().await?;
+        let now = self.env.now();
+        let lock = self.inner.lock().unwrap();
+        Ok(lock
+            .objects
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(key, _)| lock.visible(key, now).is_some())
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    /// Delete the object stored under `key`. Deleting a missing object succeeds.
+    pub async fn delete(&self, key: &str) -> io::Result<()> {
+        self.request().await?;
+        self.write(key, None);
+        Ok(())
+    }
+
+    /// Make the store unavailable. All requests fail with `NotConnected` until
+    /// [`BlobStore::recover`] is called.
+    pub fn fail(&self) {
+        trace!("blob store failed");
+        self.inner.lock().unwrap().available = false;
+    }
+
+    /// Make a failed store available again.
+    pub fn recover(&self) {
+        trace!("blob store recovered");
+        self.inner.lock().unwrap().available = true;
+    }
+
+    /// Wait for the request latency, then fail the request if the store is unavailable or an
+    /// error is injected.
+    async fn request(&self) -> io::Result<()> {
+        let (latency, error_rate) = {
+            let lock = self.inner.lock().unwrap();
+            (lock.latency.clone(), lock.error_rate)
+        };
+        if let Some(latency) = latency {
+            let delay = self.env.gen_range(latency);
+            self.env.delay_from(delay).await;
+        }
+        if !self.inner.lock().unwrap().available {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        if error_rate > 0.0 && self.env.gen_bool(error_rate) {
+            trace!("injecting blob store error");
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "injected blob store error",
+            ));
+        }
+        Ok(())
+    }
+
+    fn write(&self, key: &str, data: Option<Bytes>) {
+        let now = self.env.now();
+        let staleness = self.inner.lock().unwrap().staleness;
+        let lag = if staleness > time::Duration::from_secs(0) {
+            self.env.gen_range(time::Duration::from_secs(0)..staleness)
+        } else {
+            staleness
+        };
+        trace!("writing {}, visible in {:?}", key, lag);
+        self.inner.lock().unwrap().write(key, now + lag, data, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that writes become visible to readers once the staleness has elapsed.
+    fn eventual_consistency() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let store = BlobStore::new(handle.clone()).staleness(time::Duration::from_secs(10));
+            store.put("logs/1", Bytes::from("first")).await.unwrap();
+            store.put("logs/2", Bytes::from("second")).await.unwrap();
+            store.put("other", Bytes::from("other")).await.unwrap();
+            handle.delay_from(time::Duration::from_secs(10)).await;
+            assert_eq!(store.get("logs/1").await.unwrap(), Bytes::from("first"));
+            assert_eq!(store.list("logs/").await.unwrap(), vec!["logs/1", "logs/2"]);
+
+            store.delete("logs/1").await.unwrap();
+            handle.delay_from(time::Duration::from_secs(10)).await;
+            assert_eq!(
+                store.get("logs/1").await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+            assert_eq!(store.list("logs/").await.unwrap(), vec!["logs/2"]);
+        });
+    }
+
+    #[test]
+    /// Test that a failed store rejects requests until it recovers.
+    fn fail_and_recover() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let store = BlobStore::new(handle.clone());
+            store.put("key", Bytes::from("value")).await.unwrap();
+            store.fail();
+            assert_eq!(
+                store.get("key").await.unwrap_err().kind(),
+                io::ErrorKind::NotConnected
+            );
+            store.recover();
+            assert_eq!(store.get("key").await.unwrap(), Bytes::from("value"));
+        });
+    }
+}
@@ -0,0 +1,186 @@
+//! Liveness probing of simulated nodes.
+//!
+//! Nodes serve a line based health protocol with [`serve_health`]: each `healthz` request line is
+//! answered with `ok` if the node reports itself as healthy, or `unhealthy` otherwise. A
+//! [`HealthProber`] polls a node at seeded intervals and reports transitions between healthy and
+//! unhealthy, modeling orchestrator liveness probes which restart unhealthy nodes.
+use crate::{Environment, TcpListener};
+use futures::{SinkExt, StreamExt};
+use std::{io, net, sync, time};
+use tokio::codec::{Framed, LinesCodec};
+use tracing::trace;
+
+/// Request line sent by probers.
+pub const HEALTH_REQUEST: &str = "healthz";
+/// Response line sent by healthy nodes.
+pub const HEALTHY_RESPONSE: &str = "ok";
+/// Response line sent by unhealthy nodes.
+pub const UNHEALTHY_RESPONSE: &str = "unhealthy";
+
+/// Serve health requests on `addr`, answering each request by calling `check`.
+pub async fn serve_health<E, F>(env: E, addr: net::SocketAddr, check: F) -> io::Result<()>
+where
+    E: Environment,
+    F: Fn() -> bool + Send + Sync + 'static,
+{
+    let check = sync::Arc::new(check);
+    let mut listener = env.bind(addr).await?;
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let check = sync::Arc::clone(&check);
+        env.spawn(async move {
+            let mut transport = Framed::new(socket, LinesCodec::new());
+            while let Some(Ok(request)) = transport.next().await {
+                let response = if request == HEALTH_REQUEST && (*check)() {
+                    HEALTHY_RESPONSE
+                } else {
+                    UNHEALTHY_RESPONSE
+                };
+                if transport.send(response.to_string()).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Health transitions reported by a [`HealthProber`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthEvent {
+    /// A previously unhealthy node answered a probe successfully.
+    Healthy,
+    /// A node failed the configured number of consecutive probes.
+    Unhealthy,
+}
+
+/// Periodically probes a node served by [`serve_health`].
+#[derive(Debug, Clone)]
+pub struct HealthProber<E> {
+    env: E,
+    target: net::SocketAddr,
+    interval: time::Duration,
+    timeout: time::Duration,
+    failure_threshold: u32,
+}
+
+impl<E> HealthProber<E>
+where
+    E: Environment,
+{
+    /// Create a prober for `target` which probes every second, failing probes which do not
+    /// complete within a second, and reporting a node unhealthy after 3 consecutive failures.
+    pub fn new(env: E, target: net::SocketAddr) -> Self {
+        Self {
+            env,
+            target,
+            interval: time::Duration::from_secs(1),
+            timeout: time::Duration::from_secs(1),
+            failure_threshold: 3,
+        }
+    }
+
+    /// Mean interval between probes. Each interval is jittered by up to 50%.
+    pub fn interval(mut self, interval: time::Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Time after which an unanswered probe is considered failed.
+    pub fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of consecutive failed probes after which a node is reported unhealthy.
+    pub fn failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures;
+        self
+    }
+
+    /// Issue a single probe, returning true if the node reported itself healthy.
+    pub async fn probe(&self) -> bool {
+        let env = self.env.clone();
+        let target = self.target;
+        let probe = async move {
+            let socket = env.connect(target).await?;
+            let mut transport = Framed::new(socket, LinesCodec::new());
+            transport
+                .send(HEALTH_REQUEST.to_string())
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            match transport.next().await {
+                Some(Ok(response)) => Ok(response == HEALTHY_RESPONSE),
+                _ => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            }
+        };
+        match self.env.timeout(probe, self.timeout).await {
+            Ok(Ok(healthy)) => healthy,
+            _ => false,
+        }
+    }
+
+    /// Consumes this prober and begins probing, calling `on_event` whenever the node transitions
+    /// between healthy and unhealthy. Nodes are assumed healthy to begin with.
+    pub async fn run<F>(self, mut on_event: F)
+    where
+        F: FnMut(HealthEvent) + Send,
+    {
+        let mut healthy = true;
+        let mut failures = 0;
+        loop {
+            let jitter = self.env.gen_range(0.5..1.5);
+            self.env.delay_from(self.interval.mul_f64(jitter)).await;
+            if self.probe().await {
+                failures = 0;
+                if !healthy {
+                    trace!("node {} is healthy", self.target);
+                    healthy = true;
+                    on_event(HealthEvent::Healthy);
+                }
+            } else {
+                failures += 1;
+                if healthy && failures >= self.failure_threshold {
+                    trace!("node {} is unhealthy", self.target);
+                    healthy = false;
+                    on_event(HealthEvent::Unhealthy);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use futures::{channel::mpsc, FutureExt};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    /// Test that the prober reports transitions between healthy and unhealthy.
+    fn probe_transitions() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+            let healthy = sync::Arc::new(AtomicBool::new(true));
+            let check = sync::Arc::clone(&healthy);
+            handle.spawn(
+                serve_health(handle.clone(), addr, move || check.load(Ordering::SeqCst))
+                    .map(|_| ()),
+            );
+
+            let prober = HealthProber::new(handle.clone(), addr).failure_threshold(2);
+            assert!(prober.probe().await);
+            let (tx, mut events) = mpsc::unbounded();
+            handle.spawn(prober.run(move |event| {
+                let _ = tx.unbounded_send(event);
+            }));
+
+            healthy.store(false, Ordering::SeqCst);
+            assert_eq!(events.next().await, Some(HealthEvent::Unhealthy));
+            healthy.store(true, Ordering::SeqCst);
+            assert_eq!(events.next().await, Some(HealthEvent::Healthy));
+        });
+    }
+}
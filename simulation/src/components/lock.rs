@@ -0,0 +1,341 @@
+//! A networked distributed lock service.
+//!
+//! [`LockService`] serves locks backed by a [`LeaseService`] over a line based protocol, so that
+//! clients reach it through the simulated network and are subject to its faults. Partitioning a
+//! [`LockClient`] away from the service lets tests exercise what happens when a lock holder can
+//! no longer renew its lock, and whether the fencing token issued with each lock protects the
+//! resources it guards. The service itself can additionally be configured to respond slowly, to
+//! fail requests, or to become unavailable.
+//!
+//! The protocol consists of the following requests, one per line:
+//!
+//! - `acquire <lock> <holder> <ttl ms>`
+//! - `renew <lock> <holder> <token> <ttl ms>`
+//! - `release <lock> <holder> <token>`
+//!
+//! Each is answered with a single line: `granted <token>`, `released`, `busy`, `lost`,
+//! `unavailable` or `error <message>`.
+use super::{Lease, LeaseService};
+use crate::{Environment, TcpListener};
+use futures::{SinkExt, StreamExt};
+use std::{io, net, ops, sync, time};
+use tokio::codec::{Framed, LinesCodec};
+use tracing::trace;
+
+#[derive(Debug)]
+struct Faults {
+    available: bool,
+    error_rate: f64,
+    latency: Option<ops::Range<time::Duration>>,
+}
+
+/// A lock service which grants locks with fencing tokens over the network.
+#[derive(Debug, Clone)]
+pub struct LockService<E> {
+    env: E,
+    leases: LeaseService<E>,
+    faults: sync::Arc<sync::Mutex<Faults>>,
+}
+
+impl<E> LockService<E>
+where
+    E: Environment,
+{
+    /// Create a lock service which never fails and responds immediately.
+    pub fn new(env: E) -> Self {
+        let faults = Faults {
+            available: true,
+            error_rate: 0.0,
+            latency: None,
+        };
+        Self {
+            leases: LeaseService::new(env.clone()),
+            env,
+            faults: sync::Arc::new(sync::Mutex::new(faults)),
+        }
+    }
+
+    /// Probability that a request fails.
+    pub fn error_rate(self, probability: f64) -> Self {
+        self.faults.lock().unwrap().error_rate = probability;
+        self
+    }
+
+    /// Range from which the time taken to process each request is sampled.
+    pub fn latency(self, latency: ops::Range<time::Duration>) -> Self {
+        self.faults.lock().unwrap().latency = Some(latency);
+        self
+    }
+
+    /// Returns the leases backing the locks granted by this service, which can be used to
+    /// inspect lock holders and to fence requests to protected resources.
+    pub fn leases(&self) -> &LeaseService<E> {
+        &self.leases
+    }
+
+    /// Make the service unavailable. All requests fail with `NotConnected` until
+    /// [`LockService::recover`] is called. Locks continue to expire.
+    pub fn fail(&self) {
+        trace!("lock service failed");
+        self.faults.lock().unwrap().available = false;
+    }
+
+    /// Make a failed service available again.
+    pub fn recover(&self) {
+        trace!("lock service recovered");
+        self.faults.lock().unwrap().available = true;
+    }
+
+    /// Serve lock requests on `addr`.
+    pub async fn serve(self, addr: net::SocketAddr) -> io::Result<()> {
+        let mut listener = self.env.bind(addr).await?;
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let service = self.clone();
+            self.env.spawn(async move {
+                let mut transport = Framed::new(socket, LinesCodec::new());
+                while let Some(Ok(request)) = transport.next().await {
+                    let response = service.respond(&request).await;
+                    if transport.send(response).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    async fn respond(&self, request: &str) -> String {
+        let (latency, error_rate) = {
+            let lock = self.faults.lock().unwrap();
+            (lock.latency.clone(), lock.error_rate)
+        };
+        if let Some(latency) = latency {
+            let delay = self.env.gen_range(latency);
+            self.env.delay_from(delay).await;
+        }
+        if !self.faults.lock().unwrap().available {
+            return "unavailable".to_string();
+        }
+        if error_rate > 0.0 && self.env.gen_bool(error_rate) {
+            trace!("injecting lock service error");
+            return "error injected lock service error".to_string();
+        }
+        match self.handle(request) {
+            Ok(Some(token)) => format!("granted {}", token),
+            Ok(None) => "released".to_string(),
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock => "busy".to_string(),
+                io::ErrorKind::PermissionDenied => "lost".to_string(),
+                _ => format!("error {}", e),
+            },
+        }
+    }
+
+    /// Handle a request, returning the fencing token of granted locks.
+    fn handle(&self, request: &str) -> io::Result<Option<u64>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed request");
+        let parse = |field: Option<&str>| -> io::Result<u64> {
+            field.and_then(|f| f.parse().ok()).ok_or_else(invalid)
+        };
+        let mut fields = request.split_whitespace();
+        let command = fields.next().ok_or_else(invalid)?;
+        let resource = fields.next().ok_or_else(invalid)?.to_string();
+        let holder = fields.next().ok_or_else(invalid)?.to_string();
+        match command {
+            "acquire" => {
+                let ttl = time::Duration::from_millis(parse(fields.next())?);
+                let lease = self.leases.grant(&resource, &holder, ttl)?;
+                Ok(Some(lease.token))
+            }
+            "renew" | "release" => {
+                let lease = Lease {
+                    resource,
+                    holder,
+                    token: parse(fields.next())?,
+                    expires: self.env.now(),
+                };
+                if command == "release" {
+                    self.leases.release(&lease);
+                    return Ok(None);
+                }
+                let ttl = time::Duration::from_millis(parse(fields.next())?);
+                let lease = self.leases.renew(&lease, ttl)?;
+                Ok(Some(lease.token))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A client of a [`LockService`], acquiring locks on behalf of a single holder.
+#[derive(Debug, Clone)]
+pub struct LockClient<E> {
+    env: E,
+    addr: net::SocketAddr,
+    holder: String,
+    timeout: time::Duration,
+}
+
+impl<E> LockClient<E>
+where
+    E: Environment,
+{
+    /// Create a client of the lock service at `addr`, which fails requests that do not complete
+    /// within a second. `holder` must not contain whitespace.
+    pub fn new(env: E, addr: net::SocketAddr, holder: &str) -> Self {
+        Self {
+            env,
+            addr,
+            holder: holder.to_string(),
+            timeout: time::Duration::from_secs(1),
+        }
+    }
+
+    /// Time after which an unanswered request fails with `TimedOut`.
+    pub fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Acquire `lock` for `ttl`. Returns `WouldBlock` if the lock is held by another holder.
+    ///
+    /// The returned lease expires `ttl` after the request was sent, which is no later than the
+    /// service considers it to expire.
+    pub async fn acquire(&self, lock: &str, ttl: time::Duration) -> io::Result<Lease> {
+        let request = format!("acquire {} {} {}", lock, self.holder, ttl.as_millis());
+        self.grant(lock, request, ttl).await
+    }
+
+    /// Extend `lease` by `ttl`. Returns `PermissionDenied` if the lock was lost.
+    pub async fn renew(&self, lease: &Lease, ttl: time::Duration) -> io::Result<Lease> {
+        let request = format!(
+            "renew {} {} {} {}",
+            lease.resource,
+            self.holder,
+            lease.token,
+            ttl.as_millis()
+        );
+        self.grant(&lease.resource, request, ttl).await
+    }
+
+    /// Release `lease`, allowing the lock to be acquired immediately by another holder.
+    pub async fn release(&self, lease: &Lease) -> io::Result<()> {
+        let request = format!("release {} {} {}", lease.resource, self.holder, lease.token);
+        match self.request(request).await?.as_str() {
+            "released" => Ok(()),
+            response => Err(response_error(response)),
+        }
+    }
+
+    async fn grant(&self, lock: &str, request: String, ttl: time::Duration) -> io::Result<Lease> {
+        let sent = self.env.now();
+        let response = self.request(request).await?;
+        let token = match response.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["granted", token] => token
+                .parse()
+                .map_err(|_| response_error(response.as_str()))?,
+            _ => return Err(response_error(response.as_str())),
+        };
+        trace!("{} acquired {} with token {}", self.holder, lock, token);
+        Ok(Lease {
+            resource: lock.to_string(),
+            holder: self.holder.clone(),
+            token,
+            expires: sent + ttl,
+        })
+    }
+
+    /// Send `request` over a new connection and return the response.
+    async fn request(&self, request: String) -> io::Result<String> {
+        let env = self.env.clone();
+        let addr = self.addr;
+        let exchange = async move {
+            let socket = env.connect(addr).await?;
+            let mut transport = Framed::new(socket, LinesCodec::new());
+            transport
+                .send(request)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            match transport.next().await {
+                Some(Ok(response)) => Ok(response),
+                _ => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            }
+        };
+        match self.env.timeout(exchange, self.timeout).await {
+            Ok(result) => result,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+/// Converts an unsuccessful response into an error.
+fn response_error(response: &str) -> io::Error {
+    match response {
+        "busy" => io::ErrorKind::WouldBlock.into(),
+        "lost" => io::ErrorKind::PermissionDenied.into(),
+        "unavailable" => io::ErrorKind::NotConnected.into(),
+        response if response.starts_with("error ") => {
+            io::Error::new(io::ErrorKind::Other, &response["error ".len()..])
+        }
+        response => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected response: {}", response),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use futures::FutureExt;
+
+    #[test]
+    /// Test that a holder partitioned away from the lock service loses its lock, and is fenced
+    /// out once the lock is acquired by another holder.
+    fn partitioned_holder() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let addr: net::SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        let first_ip: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let service = LockService::new(runtime.handle(addr.ip()));
+        let first = LockClient::new(runtime.handle(first_ip), addr, "first");
+        let second = LockClient::new(runtime.handle("10.0.0.3".parse().unwrap()), addr, "second");
+        let handle = runtime.localhost_handle();
+        let ttl = time::Duration::from_secs(5);
+        runtime.block_on(async {
+            handle.spawn(service.clone().serve(addr).map(|_| ()));
+            handle.delay_from(time::Duration::from_millis(1)).await;
+
+            let stale = first.acquire("log", ttl).await.unwrap();
+            assert_eq!(
+                second.acquire("log", ttl).await.unwrap_err().kind(),
+                io::ErrorKind::WouldBlock
+            );
+
+            handle.network_handle().partition(&[first_ip], &[addr.ip()]);
+            assert_eq!(
+                first.renew(&stale, ttl).await.unwrap_err().kind(),
+                io::ErrorKind::TimedOut
+            );
+            handle.delay_from(ttl).await;
+            let current = second.acquire("log", ttl).await.unwrap();
+            assert!(current.token > stale.token);
+            service.leases().fence("log", current.token).unwrap();
+            assert!(service.leases().fence("log", stale.token).is_err());
+
+            handle.network_handle().heal();
+            assert_eq!(
+                first.renew(&stale, ttl).await.unwrap_err().kind(),
+                io::ErrorKind::PermissionDenied
+            );
+            second.release(&current).await.unwrap();
+            first.acquire("log", ttl).await.unwrap();
+
+            service.fail();
+            assert_eq!(
+                first.acquire("log", ttl).await.unwrap_err().kind(),
+                io::ErrorKind::NotConnected
+            );
+        });
+    }
+}
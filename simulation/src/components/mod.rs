@@ -0,0 +1,22 @@
+//! Reusable components which run inside a simulation.
+//!
+//! Components are deterministic stand-ins for infrastructure that systems under test commonly
+//! depend on. They are generic over [`Environment`], driven by its clock, and expose controls for
+//! failing and recovering them so that tests can exercise their clients' failure handling. The
+//! exception is [`TimeSync`], which corrects the host clocks of the deterministic runtime.
+//!
+//! [`Environment`]:`crate::Environment`
+pub mod balancer;
+pub mod blob;
+pub mod health;
+pub mod lease;
+pub mod lock;
+pub mod ntp;
+pub mod registry;
+pub use balancer::{Policy, SimulatedLoadBalancer};
+pub use blob::BlobStore;
+pub use health::{serve_health, HealthEvent, HealthProber};
+pub use lease::{Lease, LeaseService};
+pub use lock::{LockClient, LockService};
+pub use ntp::{Skew, TimeSync};
+pub use registry::ServiceRegistry;
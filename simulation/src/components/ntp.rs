@@ -0,0 +1,189 @@
+//! Simulated clock synchronization.
+//!
+//! [`TimeSync`] models an NTP-like service which keeps the clocks of a set of simulated hosts
+//! close to simulated time. It drives the host clocks of the [`DeterministicRuntime`], which hosts
+//! read through [`Environment::node_now`] and [`Environment::system_time`], so skew and drift set
+//! on the runtime and corrections made by the service apply to the same clocks. While the service
+//! is available, each host periodically measures its offset with a seeded error of up to the
+//! configured accuracy, and slews its clock toward the measured time at a bounded rate rather
+//! than stepping it. Failing the service lets clocks drift apart, so that protocols which assume
+//! clocks are within some bound of each other can have that bound violated and then restored.
+//!
+//! [`DeterministicRuntime`]:`crate::deterministic::DeterministicRuntime`
+//! [`Environment::node_now`]:`crate::Environment::node_now`
+//! [`Environment::system_time`]:`crate::Environment::system_time`
+use crate::{deterministic::DeterministicRuntimeHandle, Environment};
+use std::{collections, net, sync, time};
+use tracing::trace;
+
+pub use crate::deterministic::Skew;
+
+#[derive(Debug)]
+struct Inner {
+    available: bool,
+    accuracy: time::Duration,
+    slew_rate: f64,
+    poll_interval: time::Duration,
+    hosts: collections::BTreeSet<net::IpAddr>,
+}
+
+/// A clock synchronization service for simulated hosts.
+#[derive(Debug, Clone)]
+pub struct TimeSync {
+    handle: DeterministicRuntimeHandle,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl TimeSync {
+    /// Create a service with an accuracy of 1ms, which polls every 16 seconds and slews clocks
+    /// by at most 500 parts per million.
+    pub fn new(handle: DeterministicRuntimeHandle) -> Self {
+        let inner = Inner {
+            available: true,
+            accuracy: time::Duration::from_millis(1),
+            slew_rate: 500.0,
+            poll_interval: time::Duration::from_secs(16),
+            hosts: collections::BTreeSet::new(),
+        };
+        Self {
+            handle,
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        }
+    }
+
+    /// Maximum error of each offset measurement. Synchronized clocks stay within this bound of
+    /// simulated time, plus any drift accumulated within a poll interval.
+    pub fn accuracy(self, accuracy: time::Duration) -> Self {
+        self.inner.lock().unwrap().accuracy = accuracy;
+        self
+    }
+
+    /// Maximum rate, in parts per million, at which a clock is corrected.
+    pub fn slew_rate(self, ppm: f64) -> Self {
+        self.inner.lock().unwrap().slew_rate = ppm;
+        self
+    }
+
+    /// Time between offset measurements.
+    pub fn poll_interval(self, interval: time::Duration) -> Self {
+        self.inner.lock().unwrap().poll_interval = interval;
+        self
+    }
+
+    /// Synchronize the clock of `host`.
+    pub fn add_host(&self, host: net::IpAddr) {
+        self.inner.lock().unwrap().hosts.insert(host);
+    }
+
+    /// Set the offset of the clock of `host`, adding the host if it is not yet synchronized.
+    pub fn set_skew(&self, host: net::IpAddr, skew: Skew) {
+        self.add_host(host);
+        self.handle.set_clock_skew(host, skew);
+    }
+
+    /// Set the rate, in parts per million, at which the clock of `host` drifts ahead of
+    /// simulated time, adding the host if it is not yet synchronized. Negative rates drift
+    /// behind.
+    pub fn set_drift(&self, host: net::IpAddr, ppm: f64) {
+        self.add_host(host);
+        self.handle.set_clock_drift(host, ppm);
+    }
+
+    /// Returns the offset of the clock of `host`. Hosts are not skewed by default.
+    pub fn skew(&self, host: net::IpAddr) -> Skew {
+        self.handle.clock_skew(host)
+    }
+
+    /// Returns the largest offset of any synchronized host clock.
+    pub fn max_skew(&self) -> time::Duration {
+        let lock = self.inner.lock().unwrap();
+        lock.hosts
+            .iter()
+            .map(|host| self.skew(*host).magnitude())
+            .max()
+            .unwrap_or_else(|| time::Duration::from_secs(0))
+    }
+
+    /// Make the service unavailable. Clocks are no longer corrected and drift freely until
+    /// [`TimeSync::recover`] is called.
+    pub fn fail(&self) {
+        trace!("time sync failed");
+        self.inner.lock().unwrap().available = false;
+    }
+
+    /// Make a failed service available again.
+    pub fn recover(&self) {
+        trace!("time sync recovered");
+        self.inner.lock().unwrap().available = true;
+    }
+
+    /// Consumes this handle and begins synchronizing host clocks.
+    pub async fn run(self) {
+        loop {
+            let interval = self.inner.lock().unwrap().poll_interval;
+            self.handle.delay_from(interval).await;
+            self.poll();
+        }
+    }
+
+    /// Measure and correct the offset of each host if the service is available. Drift is
+    /// accumulated by the host clocks themselves.
+    fn poll(&self) {
+        let lock = self.inner.lock().unwrap();
+        if !lock.available {
+            return;
+        }
+        let interval = lock.poll_interval.as_nanos() as f64;
+        let accuracy = lock.accuracy.as_nanos() as i64;
+        let max_correction = (interval * lock.slew_rate / 1_000_000.0) as i64;
+        for host in lock.hosts.iter() {
+            let offset = self.skew(*host).as_nanos();
+            let error = if accuracy > 0 {
+                self.handle.gen_range(-accuracy..accuracy)
+            } else {
+                0
+            };
+            let measured = offset + error;
+            let correction = measured.max(-max_correction).min(max_correction);
+            trace!("slewing {} by {}ns", host, -correction);
+            self.handle
+                .set_clock_skew(*host, Skew::from_nanos(offset - correction));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that skewed clocks converge, drift apart during an outage, then converge again.
+    fn slew_and_outage() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let a_ip: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b_ip: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let b = runtime.handle(b_ip);
+        runtime.block_on(async {
+            let sync = TimeSync::new(handle.clone());
+            sync.set_skew(a_ip, Skew::Ahead(time::Duration::from_secs(1)));
+            sync.set_skew(b_ip, Skew::Behind(time::Duration::from_millis(500)));
+            sync.set_drift(b_ip, -100.0);
+            handle.spawn(sync.clone().run());
+
+            let hour = time::Duration::from_secs(60 * 60);
+            handle.delay_from(hour).await;
+            assert!(sync.max_skew() < time::Duration::from_millis(5));
+
+            sync.fail();
+            handle.delay_from(hour).await;
+            assert!(sync.max_skew() > time::Duration::from_millis(300));
+            assert!(b.node_now() < handle.now().into_std());
+
+            sync.recover();
+            handle.delay_from(hour).await;
+            assert!(sync.max_skew() < time::Duration::from_millis(5));
+        });
+    }
+}
@@ -0,0 +1,303 @@
+//! Health checked service registry.
+//!
+//! Instances register under a service name and must heartbeat before their TTL expires to remain
+//! discoverable. Clients can look up the live instances of a service, or watch a service to be
+//! notified whenever its membership changes.
+use crate::{Environment, MonotonicInstant};
+use futures::{channel::mpsc, task::Waker, Poll};
+use std::{collections, io, net, sync, time};
+use tracing::trace;
+
+#[derive(Debug)]
+struct Inner {
+    ttl: time::Duration,
+    available: bool,
+    /// Mapping from service name to registered instances and their expiry.
+    services:
+        collections::BTreeMap<String, collections::BTreeMap<net::SocketAddr, MonotonicInstant>>,
+    watchers: Vec<(String, mpsc::UnboundedSender<Vec<net::SocketAddr>>)>,
+    /// Waker of [`ServiceRegistry::run`] while it waits for an instance to be registered.
+    run_waker: Option<Waker>,
+}
+
+impl Inner {
+    fn check_available(&self) -> io::Result<()> {
+        if self.available {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::NotConnected.into())
+        }
+    }
+
+    fn members(&self, service: &str) -> Vec<net::SocketAddr> {
+        self.services
+            .get(service)
+            .map(|instances| instances.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Notify all watchers of `service` of its current membership.
+    fn notify(&mut self, service: &str) {
+        let members = self.members(service);
+        self.watchers
+            .retain(|(name, tx)| name != service || tx.unbounded_send(members.clone()).is_ok());
+    }
+
+    /// Remove all instances which have expired by `now`, notifying watchers of affected services.
+    fn reap(&mut self, now: MonotonicInstant) {
+        let mut changed = vec![];
+        for (service, instances) in self.services.iter_mut() {
+            let before = instances.len();
+            instances.retain(|_, expires| *expires > now);
+            if instances.len() != before {
+                trace!(
+                    "expired {} instances of {}",
+                    before - instances.len(),
+                    service
+                );
+                changed.push(service.clone());
+            }
+        }
+        for service in changed {
+            self.notify(&service);
+        }
+    }
+
+    fn next_expiry(&self) -> Option<MonotonicInstant> {
+        self.services
+            .values()
+            .flat_map(|instances| instances.values())
+            .min()
+            .cloned()
+    }
+}
+
+/// A service registry which expires instances that fail to heartbeat within the configured TTL.
+///
+/// [`ServiceRegistry::run`] must be spawned for expired instances to be removed and watchers
+/// notified.
+#[derive(Debug, Clone)]
+pub struct ServiceRegistry<E> {
+    env: E,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl<E> ServiceRegistry<E>
+where
+    E: Environment,
+{
+    pub fn new(env: E, ttl: time::Duration) -> Self {
+        let inner = Inner {
+            ttl,
+            available: true,
+            services: collections::BTreeMap::new(),
+            watchers: vec![],
+            run_waker: None,
+        };
+        Self {
+            env,
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        }
+    }
+
+    /// Register an instance of `service`, or refresh the TTL of an existing registration.
+    pub fn register(&self, service: &str, addr: net::SocketAddr) -> io::Result<()> {
+        let now = self.env.now();
+        let mut lock = self.inner.lock().unwrap();
+        lock.check_available()?;
+        lock.reap(now);
+        let expires = now + lock.ttl;
+        let previous = lock
+            .services
+            .entry(service.to_string())
+            .or_default()
+            .insert(addr, expires);
+        if previous.is_none() {
+            trace!("registered {} for {}", addr, service);
+            lock.notify(service);
+            if let Some(waker) = lock.run_waker.take() {
+                waker.wake();
+            }
+        }
+        Ok(())
+    }
+
+    /// Refresh the TTL of a registered instance. Returns `NotFound` if the instance has expired or
+    /// was never registered, in which case it must register again.
+    pub fn heartbeat(&self, service: &str, addr: net::SocketAddr) -> io::Result<()> {
+        let now = self.env.now();
+        let mut lock = self.inner.lock().unwrap();
+        lock.check_available()?;
+        lock.reap(now);
+        let expires = now + lock.ttl;
+        match lock
+            .services
+            .get_mut(service)
+            .and_then(|instances| instances.get_mut(&addr))
+        {
+            Some(expiry) => {
+                *expiry = expires;
+                Ok(())
+            }
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Remove a registered instance.
+    pub fn deregister(&self, service: &str, addr: net::SocketAddr) -> io::Result<()> {
+        let now = self.env.now();
+        let mut lock = self.inner.lock().unwrap();
+        lock.check_available()?;
+        lock.reap(now);
+        let removed = lock
+            .services
+            .get_mut(service)
+            .and_then(|instances| instances.remove(&addr));
+        if removed.is_some() {
+            trace!("deregistered {} for {}", addr, service);
+            lock.notify(service);
+        }
+        Ok(())
+    }
+
+    /// Returns the live instances of `service`.
+    pub fn lookup(&self, service: &str) -> io::Result<Vec<net::SocketAddr>> {
+        let now = self.env.now();
+        let mut lock = self.inner.lock().unwrap();
+        lock.check_available()?;
+        lock.reap(now);
+        Ok(lock.members(service))
+    }
+
+    /// Returns a stream which yields the live instances of `service`, first immediately and then
+    /// whenever its membership changes.
+    pub fn watch(
+        &self,
+        service: &str,
+    ) -> io::Result<mpsc::UnboundedReceiver<Vec<net::SocketAddr>>> {
+        let now = self.env.now();
+        let mut lock = self.inner.lock().unwrap();
+        lock.check_available()?;
+        lock.reap(now);
+        let (tx, rx) = mpsc::unbounded();
+        let _ = tx.unbounded_send(lock.members(service));
+        lock.watchers.push((service.to_string(), tx));
+        Ok(rx)
+    }
+
+    /// Make the registry unavailable. All operations fail with `NotConnected` and watchers are
+    /// disconnected until [`ServiceRegistry::recover`] is called. Registrations continue to expire.
+    pub fn fail(&self) {
+        trace!("service registry failed");
+        let mut lock = self.inner.lock().unwrap();
+        lock.available = false;
+        lock.watchers.clear();
+    }
+
+    /// Make a failed registry available again.
+    pub fn recover(&self) {
+        trace!("service registry recovered");
+        self.inner.lock().unwrap().available = true;
+    }
+
+    /// Consumes this handle and begins expiring instances which have failed to heartbeat. Sleeps
+    /// until the next instance is due to expire, or until an instance is registered if there are
+    /// none.
+    pub async fn run(self) {
+        loop {
+            let next_expiry = futures::future::poll_fn(|cx| {
+                let mut lock = self.inner.lock().unwrap();
+                match lock.next_expiry() {
+                    Some(expiry) => Poll::Ready(expiry),
+                    None => {
+                        lock.run_waker.replace(cx.waker().clone());
+                        Poll::Pending
+                    }
+                }
+            })
+            .await;
+            self.env.delay(next_expiry).await;
+            let now = self.env.now();
+            self.inner.lock().unwrap().reap(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use futures::StreamExt;
+
+    #[test]
+    /// Test that instances which fail to heartbeat are expired, and watchers are notified.
+    fn expiry() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let registry = ServiceRegistry::new(handle.clone(), time::Duration::from_secs(10));
+            handle.spawn(registry.clone().run());
+            let addr1: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+            let addr2: net::SocketAddr = "10.0.0.2:9092".parse().unwrap();
+            let mut watch = registry.watch("storage").unwrap();
+            assert_eq!(watch.next().await.unwrap(), Vec::<net::SocketAddr>::new());
+
+            registry.register("storage", addr1).unwrap();
+            registry.register("storage", addr2).unwrap();
+            assert_eq!(watch.next().await.unwrap(), vec![addr1]);
+            assert_eq!(watch.next().await.unwrap(), vec![addr1, addr2]);
+
+            handle.delay_from(time::Duration::from_secs(5)).await;
+            registry.heartbeat("storage", addr1).unwrap();
+            handle.delay_from(time::Duration::from_secs(6)).await;
+            assert_eq!(watch.next().await.unwrap(), vec![addr1]);
+            assert_eq!(registry.lookup("storage").unwrap(), vec![addr1]);
+            assert_eq!(
+                registry.heartbeat("storage", addr2).unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+        });
+    }
+
+    #[test]
+    /// Test that an idle registry expires an instance registered later exactly when its TTL
+    /// elapses.
+    fn expiry_after_idle() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let ttl = time::Duration::from_secs(10);
+            let registry = ServiceRegistry::new(handle.clone(), ttl);
+            handle.spawn(registry.clone().run());
+            let addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+            let mut watch = registry.watch("storage").unwrap();
+            assert_eq!(watch.next().await.unwrap(), Vec::<net::SocketAddr>::new());
+
+            handle.delay_from(time::Duration::from_secs(105)).await;
+            let registered_at = handle.now();
+            registry.register("storage", addr).unwrap();
+            assert_eq!(watch.next().await.unwrap(), vec![addr]);
+            assert_eq!(watch.next().await.unwrap(), Vec::<net::SocketAddr>::new());
+            assert_eq!(handle.now() - registered_at, ttl);
+        });
+    }
+
+    #[test]
+    /// Test that a failed registry rejects operations until it recovers.
+    fn fail_and_recover() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let registry = ServiceRegistry::new(handle.clone(), time::Duration::from_secs(10));
+            let addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+            registry.register("storage", addr).unwrap();
+            registry.fail();
+            assert_eq!(
+                registry.lookup("storage").unwrap_err().kind(),
+                io::ErrorKind::NotConnected
+            );
+            registry.recover();
+            assert_eq!(registry.lookup("storage").unwrap(), vec![addr]);
+        });
+    }
+}
@@ -0,0 +1,7 @@
+//! Client connection utilities built on [`Environment::connect`].
+//!
+//! [`Environment::connect`]:`crate::Environment::connect`
+mod pool;
+mod reconnect;
+pub use pool::{ConnectionPool, PoolMetrics, Pooled};
+pub use reconnect::ReconnectingStream;
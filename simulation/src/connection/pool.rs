@@ -0,0 +1,427 @@
+//! A pool of reusable client connections.
+use crate::{Environment, MonotonicInstant};
+use futures::{task::Waker, Future, Poll};
+use std::{collections, fmt, io, net, ops, pin::Pin, sync, task::Context, time};
+use tracing::trace;
+
+type HealthCheck<S> =
+    sync::Arc<dyn Fn(S) -> Pin<Box<dyn Future<Output = io::Result<S>> + Send>> + Send + Sync>;
+
+/// Counters describing the behavior of a [`ConnectionPool`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Number of connections established.
+    pub created: u64,
+    /// Number of times an idle connection was checked out.
+    pub reused: u64,
+    /// Number of idle connections closed after exceeding the idle timeout.
+    pub evicted_idle: u64,
+    /// Number of idle connections closed after failing a health check.
+    pub evicted_unhealthy: u64,
+    /// Number of connections discarded by their user.
+    pub discarded: u64,
+}
+
+struct Inner<S> {
+    max_size: usize,
+    idle_timeout: time::Duration,
+    /// Idle connections ordered from least to most recently returned.
+    idle: collections::VecDeque<(MonotonicInstant, S)>,
+    /// Number of connections which are checked out or being established.
+    active: usize,
+    waiters: Vec<Waker>,
+    metrics: PoolMetrics,
+}
+
+impl<S> Inner<S> {
+    /// Release the slot held by an active connection, waking tasks waiting for a connection.
+    fn release(&mut self) {
+        self.active -= 1;
+        for waiter in self.waiters.drain(..) {
+            waiter.wake();
+        }
+    }
+}
+
+enum Checkout<S> {
+    Idle(S),
+    Connect,
+}
+
+/// A slot reserved by a checkout, released if the checkout fails or is cancelled before the slot
+/// is handed to a [`Pooled`] connection.
+struct Reservation<S> {
+    inner: Option<sync::Arc<sync::Mutex<Inner<S>>>>,
+}
+
+impl<S> Reservation<S> {
+    /// Hand the slot to a checked out connection, which releases it once returned.
+    fn keep(mut self) {
+        self.inner.take();
+    }
+}
+
+impl<S> Drop for Reservation<S> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            inner.lock().unwrap().release();
+        }
+    }
+}
+
+/// A pool of connections to a single address.
+///
+/// Connections are established on demand up to a maximum size, after which callers wait for a
+/// connection to be returned. Returned connections are reused most recently returned first. Idle
+/// connections are closed once they have been idle for longer than the idle timeout, measured
+/// using the [`Environment`] clock, and an optional health check is run on idle connections
+/// before they are reused.
+pub struct ConnectionPool<E>
+where
+    E: Environment,
+{
+    env: E,
+    addr: net::SocketAddr,
+    health_check: Option<HealthCheck<E::TcpStream>>,
+    inner: sync::Arc<sync::Mutex<Inner<E::TcpStream>>>,
+}
+
+impl<E> Clone for ConnectionPool<E>
+where
+    E: Environment,
+{
+    fn clone(&self) -> Self {
+        Self {
+            env: self.env.clone(),
+            addr: self.addr,
+            health_check: self.health_check.clone(),
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<E> fmt::Debug for ConnectionPool<E>
+where
+    E: Environment,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = self.inner.lock().unwrap();
+        f.debug_struct("ConnectionPool")
+            .field("addr", &self.addr)
+            .field("max_size", &lock.max_size)
+            .field("idle", &lock.idle.len())
+            .field("active", &lock.active)
+            .field("metrics", &lock.metrics)
+            .finish()
+    }
+}
+
+impl<E> ConnectionPool<E>
+where
+    E: Environment,
+{
+    /// Create a pool of at most 8 connections to `addr`, closing connections which have been
+    /// idle for 60 seconds.
+    pub fn new(env: E, addr: net::SocketAddr) -> Self {
+        let inner = Inner {
+            max_size: 8,
+            idle_timeout: time::Duration::from_secs(60),
+            idle: collections::VecDeque::new(),
+            active: 0,
+            waiters: vec![],
+            metrics: PoolMetrics::default(),
+        };
+        Self {
+            env,
+            addr,
+            health_check: None,
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        }
+    }
+
+    /// Maximum number of connections, both idle and checked out.
+    pub fn max_size(self, max_size: usize) -> Self {
+        assert!(max_size > 0, "illegal connection pool size: {}", max_size);
+        self.inner.lock().unwrap().max_size = max_size;
+        self
+    }
+
+    /// Time after which an idle connection is closed rather than reused.
+    pub fn idle_timeout(self, idle_timeout: time::Duration) -> Self {
+        self.inner.lock().unwrap().idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Check idle connections using `check` before they are reused. The check is passed the
+    /// connection and returns it if healthy. Connections for which the check fails are closed.
+    pub fn health_check<F, Fut>(mut self, check: F) -> Self
+    where
+        F: Fn(E::TcpStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<E::TcpStream>> + Send + 'static,
+    {
+        let check: HealthCheck<E::TcpStream> = sync::Arc::new(move |stream| {
+            let future: Pin<Box<dyn Future<Output = io::Result<E::TcpStream>> + Send>> =
+                Box::pin(check(stream));
+            future
+        });
+        self.health_check = Some(check);
+        self
+    }
+
+    pub fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// Returns the number of idle connections.
+    pub fn idle(&self) -> usize {
+        self.inner.lock().unwrap().idle.len()
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        self.inner.lock().unwrap().metrics
+    }
+
+    /// Check out a connection, reusing an idle connection if possible. Waits for a connection to
+    /// be returned if the pool is at its maximum size. Dropping the returned future before it
+    /// completes releases any slot it reserved.
+    pub async fn get(&self) -> io::Result<Pooled<E>> {
+        loop {
+            let checkout = futures::future::poll_fn(|cx| self.poll_checkout(cx)).await;
+            let reservation = Reservation {
+                inner: Some(sync::Arc::clone(&self.inner)),
+            };
+            let idle = match checkout {
+                Checkout::Idle(stream) => stream,
+                Checkout::Connect => return self.connect(reservation).await,
+            };
+            let health_check = match self.health_check.as_ref() {
+                Some(health_check) => health_check,
+                None => return Ok(self.pooled(idle, reservation)),
+            };
+            match health_check(idle).await {
+                Ok(stream) => return Ok(self.pooled(stream, reservation)),
+                Err(e) => {
+                    trace!("evicting unhealthy connection to {}: {}", self.addr, e);
+                    self.inner.lock().unwrap().metrics.evicted_unhealthy += 1;
+                }
+            }
+        }
+    }
+
+    /// Reserve a slot for a connection, taking the most recently returned idle connection if any.
+    fn poll_checkout(&self, cx: &mut Context<'_>) -> Poll<Checkout<E::TcpStream>> {
+        let now = self.env.now();
+        let mut lock = self.inner.lock().unwrap();
+        let idle_timeout = lock.idle_timeout;
+        while let Some((returned_at, _)) = lock.idle.front() {
+            if now - *returned_at < idle_timeout {
+                break;
+            }
+            trace!(
+                "evicting connection to {} idle since {:?}",
+                self.addr,
+                returned_at
+            );
+            lock.idle.pop_front();
+            lock.metrics.evicted_idle += 1;
+        }
+        if let Some((_, stream)) = lock.idle.pop_back() {
+            trace!("reusing idle connection to {}", self.addr);
+            lock.active += 1;
+            lock.metrics.reused += 1;
+            return Poll::Ready(Checkout::Idle(stream));
+        }
+        if lock.active < lock.max_size {
+            lock.active += 1;
+            return Poll::Ready(Checkout::Connect);
+        }
+        trace!("connection pool for {} exhausted, waiting", self.addr);
+        lock.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    async fn connect(&self, reservation: Reservation<E::TcpStream>) -> io::Result<Pooled<E>> {
+        trace!("establishing pooled connection to {}", self.addr);
+        match self.env.connect(self.addr).await {
+            Ok(stream) => {
+                self.inner.lock().unwrap().metrics.created += 1;
+                Ok(self.pooled(stream, reservation))
+            }
+            Err(e) => {
+                trace!("pooled connection to {} failed: {}", self.addr, e);
+                Err(e)
+            }
+        }
+    }
+
+    fn pooled(&self, stream: E::TcpStream, reservation: Reservation<E::TcpStream>) -> Pooled<E> {
+        reservation.keep();
+        Pooled {
+            env: self.env.clone(),
+            stream: Some(stream),
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// A connection checked out from a [`ConnectionPool`], which is returned to the pool when
+/// dropped.
+pub struct Pooled<E>
+where
+    E: Environment,
+{
+    env: E,
+    stream: Option<E::TcpStream>,
+    inner: sync::Arc<sync::Mutex<Inner<E::TcpStream>>>,
+}
+
+impl<E> Pooled<E>
+where
+    E: Environment,
+{
+    /// Close this connection rather than returning it to the pool, such as after an IO error.
+    pub fn discard(mut self) {
+        trace!("discarding pooled connection");
+        self.stream.take();
+        let mut lock = self.inner.lock().unwrap();
+        lock.metrics.discarded += 1;
+        lock.release();
+    }
+}
+
+impl<E> ops::Deref for Pooled<E>
+where
+    E: Environment,
+{
+    type Target = E::TcpStream;
+    fn deref(&self) -> &Self::Target {
+        self.stream.as_ref().expect("pooled connection taken")
+    }
+}
+
+impl<E> ops::DerefMut for Pooled<E>
+where
+    E: Environment,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.stream.as_mut().expect("pooled connection taken")
+    }
+}
+
+impl<E> Drop for Pooled<E>
+where
+    E: Environment,
+{
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let now = self.env.now();
+            let mut lock = self.inner.lock().unwrap();
+            lock.idle.push_back((now, stream));
+            lock.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Echo reads back to each client until it closes the connection.
+    async fn echo<E: Environment>(env: E, addr: net::SocketAddr) {
+        let mut listener = env.bind(addr).await.unwrap();
+        while let Ok((mut socket, _)) = listener.accept().await {
+            env.spawn(async move {
+                let mut buf = [0; 64];
+                while let Ok(read) = socket.read(&mut buf).await {
+                    if read == 0 || socket.write_all(&buf[..read]).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    #[test]
+    /// Test that connections are reused until they exceed the idle timeout.
+    fn reuse_and_idle_timeout() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            handle.spawn(echo(handle.clone(), addr));
+            let pool = ConnectionPool::new(handle.clone(), addr)
+                .max_size(1)
+                .idle_timeout(time::Duration::from_secs(30));
+
+            let mut conn = pool.get().await.unwrap();
+            let mut buf = [0; 4];
+            conn.write_all(b"ping").await.unwrap();
+            conn.read_exact(&mut buf).await.unwrap();
+
+            // The pool is exhausted, so a second caller waits for the connection to be returned.
+            let waiter_pool = pool.clone();
+            let waiter =
+                crate::spawn_with_result(&handle, async move { waiter_pool.get().await.map(drop) });
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            drop(conn);
+            waiter.await.unwrap();
+            assert_eq!(pool.idle(), 1);
+
+            handle.delay_from(time::Duration::from_secs(31)).await;
+            let _conn = pool.get().await.unwrap();
+            let metrics = pool.metrics();
+            assert_eq!(metrics.created, 2);
+            assert_eq!(metrics.reused, 1);
+            assert_eq!(metrics.evicted_idle, 1);
+        });
+    }
+
+    #[test]
+    /// Test that idle connections failing the health check are replaced.
+    fn health_check_eviction() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            handle.spawn(echo(handle.clone(), addr));
+            let pool = ConnectionPool::new(handle.clone(), addr)
+                .health_check(|_| async { Err(io::Error::new(io::ErrorKind::Other, "unhealthy")) });
+            drop(pool.get().await.unwrap());
+            drop(pool.get().await.unwrap());
+            let metrics = pool.metrics();
+            assert_eq!(metrics.created, 2);
+            assert_eq!(metrics.evicted_unhealthy, 1);
+            assert_eq!(pool.idle(), 1);
+        });
+    }
+
+    #[test]
+    /// Test that cancelling a checkout during its health check releases the reserved slot.
+    fn cancelled_checkout() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            handle.spawn(echo(handle.clone(), addr));
+            let checks = sync::Arc::new(sync::atomic::AtomicUsize::new(0));
+            let pool_checks = sync::Arc::clone(&checks);
+            let pool = ConnectionPool::new(handle.clone(), addr)
+                .max_size(1)
+                .health_check(move |stream| {
+                    let hang = pool_checks.fetch_add(1, sync::atomic::Ordering::SeqCst) == 0;
+                    async move {
+                        if hang {
+                            futures::future::pending::<()>().await;
+                        }
+                        Ok(stream)
+                    }
+                });
+            drop(pool.get().await.unwrap());
+            let timeout = time::Duration::from_secs(1);
+            assert!(handle.timeout(pool.get(), timeout).await.is_err());
+            handle.timeout(pool.get(), timeout).await.unwrap().unwrap();
+        });
+    }
+}
@@ -0,0 +1,352 @@
+//! A stream which transparently re-establishes its connection.
+use crate::{Environment, TcpStream};
+use futures::{Future, Poll};
+use std::{fmt, io, net, pin::Pin, task::Context, time};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::trace;
+
+type ConnectFuture<S> = Pin<Box<dyn Future<Output = io::Result<S>> + Send>>;
+
+enum State<S> {
+    Disconnected,
+    Connecting(ConnectFuture<S>),
+    Connected(S),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    initial: time::Duration,
+    max: time::Duration,
+    max_attempts: Option<u32>,
+}
+
+impl Backoff {
+    /// Returns the delay before connection attempt `attempt`, doubling for each failed attempt
+    /// and jittered by up to 50%.
+    fn delay<E: Environment>(&self, env: &E, attempt: u32) -> time::Duration {
+        let exponential = self.initial * 2u32.pow(attempt.min(16));
+        let jitter = env.gen_range(0.5..1.5);
+        exponential.min(self.max).mul_f64(jitter)
+    }
+
+    fn exhausted(&self, attempts: u32) -> bool {
+        self.max_attempts.map_or(false, |max| attempts >= max)
+    }
+}
+
+/// A stream to a fixed address which reconnects whenever its connection fails.
+///
+/// The connection is established lazily on first use. When a read or write fails, or the peer
+/// closes the connection, the result is returned to the caller and the next operation
+/// re-establishes the connection. Connection attempts are retried with exponential backoff,
+/// jittered using the [`Environment`] source of randomness so that reconnecting clients spread
+/// out deterministically for a given seed.
+pub struct ReconnectingStream<E>
+where
+    E: Environment,
+{
+    env: E,
+    addr: net::SocketAddr,
+    backoff: Backoff,
+    state: State<E::TcpStream>,
+    connects: u64,
+}
+
+impl<E> ReconnectingStream<E>
+where
+    E: Environment,
+{
+    /// Create a stream to `addr` which retries failed connection attempts indefinitely, backing
+    /// off from 100ms up to 10s between attempts.
+    pub fn new(env: E, addr: net::SocketAddr) -> Self {
+        Self {
+            env,
+            addr,
+            backoff: Backoff {
+                initial: time::Duration::from_millis(100),
+                max: time::Duration::from_secs(10),
+                max_attempts: None,
+            },
+            state: State::Disconnected,
+            connects: 0,
+        }
+    }
+
+    /// Initial and maximum delay between connection attempts.
+    pub fn backoff(mut self, initial: time::Duration, max: time::Duration) -> Self {
+        self.backoff.initial = initial;
+        self.backoff.max = max;
+        self
+    }
+
+    /// Maximum number of consecutive failed connection attempts before the connection error is
+    /// returned to the caller. The next operation begins a new series of attempts.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.backoff.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Returns the address this stream connects to.
+    pub fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// Returns true if this stream currently has an established connection.
+    pub fn is_connected(&self) -> bool {
+        match self.state {
+            State::Connected(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the number of times a connection has been established.
+    pub fn connects(&self) -> u64 {
+        self.connects
+    }
+
+    /// Drop the current connection, if any. The next operation reconnects.
+    pub fn disconnect(&mut self) {
+        if self.is_connected() {
+            trace!("disconnected from {}", self.addr);
+        }
+        self.state = State::Disconnected;
+    }
+
+    /// Returns the current connection, or `NotConnected` if there is none.
+    fn connected(&self) -> io::Result<&E::TcpStream> {
+        match self.state {
+            State::Connected(ref stream) => Ok(stream),
+            _ => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn poll_connect(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&mut E::TcpStream>> {
+        loop {
+            match self.state {
+                State::Connected(ref mut stream) => return Poll::Ready(Ok(stream)),
+                State::Disconnected => {
+                    let reconnect = self.connects > 0;
+                    let future = connect(self.env.clone(), self.addr, self.backoff, reconnect);
+                    self.state = State::Connecting(Box::pin(future));
+                }
+                State::Connecting(ref mut future) => {
+                    match futures::ready!(future.as_mut().poll(cx)) {
+                        Ok(stream) => {
+                            self.connects += 1;
+                            if self.connects > 1 {
+                                trace!("reconnected to {}", self.addr);
+                            }
+                            self.state = State::Connected(stream);
+                        }
+                        Err(e) => {
+                            self.state = State::Disconnected;
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn connect<E>(
+    env: E,
+    addr: net::SocketAddr,
+    backoff: Backoff,
+    reconnect: bool,
+) -> io::Result<E::TcpStream>
+where
+    E: Environment,
+{
+    let mut attempts = 0;
+    loop {
+        if reconnect || attempts > 0 {
+            let delay = backoff.delay(&env, attempts);
+            trace!("reconnecting to {} in {:?}", addr, delay);
+            env.delay_from(delay).await;
+        }
+        let attempt = env.connect(addr);
+        match attempt.await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                attempts += 1;
+                trace!("connection attempt {} to {} failed: {}", attempts, addr, e);
+                if backoff.exhausted(attempts) {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl<E> fmt::Debug for ReconnectingStream<E>
+where
+    E: Environment + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingStream")
+            .field("env", &self.env)
+            .field("addr", &self.addr)
+            .field("backoff", &self.backoff)
+            .field("connected", &self.is_connected())
+            .field("connects", &self.connects)
+            .finish()
+    }
+}
+
+impl<E> AsyncRead for ReconnectingStream<E>
+where
+    E: Environment,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = futures::ready!(self.poll_connect(cx))?;
+        let result = futures::ready!(Pin::new(stream).poll_read(cx, buf));
+        match result {
+            Ok(0) if !buf.is_empty() => self.disconnect(),
+            Err(_) => self.disconnect(),
+            _ => (),
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<E> AsyncWrite for ReconnectingStream<E>
+where
+    E: Environment,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let stream = futures::ready!(self.poll_connect(cx))?;
+        let result = futures::ready!(Pin::new(stream).poll_write(cx, buf));
+        if result.is_err() {
+            self.disconnect();
+        }
+        Poll::Ready(result)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let result = match self.state {
+            State::Connected(ref mut stream) => futures::ready!(Pin::new(stream).poll_flush(cx)),
+            _ => return Poll::Ready(Ok(())),
+        };
+        if result.is_err() {
+            self.disconnect();
+        }
+        Poll::Ready(result)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        match self.state {
+            State::Connected(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl<E> TcpStream for ReconnectingStream<E>
+where
+    E: Environment,
+{
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        match self.state {
+            State::Connected(ref stream) => stream.local_addr(),
+            _ => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.addr)
+    }
+    fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        self.connected()?.shutdown(how)
+    }
+    fn nodelay(&self) -> io::Result<bool> {
+        self.connected()?.nodelay()
+    }
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.connected()?.set_nodelay(nodelay)
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        self.connected()?.ttl()
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.connected()?.set_ttl(ttl)
+    }
+    fn keepalive(&self) -> io::Result<Option<time::Duration>> {
+        self.connected()?.keepalive()
+    }
+    fn set_keepalive(&self, keepalive: Option<time::Duration>) -> io::Result<()> {
+        self.connected()?.set_keepalive(keepalive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Echo a single read back to each client before closing the connection.
+    async fn echo_once<E: Environment>(env: E, addr: net::SocketAddr) {
+        let mut listener = env.bind(addr).await.unwrap();
+        while let Ok((mut socket, _)) = listener.accept().await {
+            env.spawn(async move {
+                let mut buf = [0; 64];
+                if let Ok(read) = socket.read(&mut buf).await {
+                    let _ = socket.write_all(&buf[..read]).await;
+                }
+            });
+        }
+    }
+
+    #[test]
+    /// Test that the stream reconnects after the peer closes the connection.
+    fn reconnects() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            handle.spawn(echo_once(handle.clone(), addr));
+            let mut stream = ReconnectingStream::new(handle.clone(), addr);
+            let mut buf = [0; 4];
+
+            stream.write_all(b"ping").await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+            assert!(!stream.is_connected());
+
+            stream.write_all(b"pong").await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+            assert_eq!(stream.connects(), 2);
+        });
+    }
+
+    #[test]
+    /// Test that connection errors are returned once attempts are exhausted.
+    fn attempts_exhausted() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            // Connections to a closed listener are refused.
+            drop(handle.bind(addr).await.unwrap());
+            let start = handle.now();
+            let mut stream = ReconnectingStream::new(handle.clone(), addr)
+                .backoff(time::Duration::from_secs(1), time::Duration::from_secs(1))
+                .max_attempts(3);
+            let err = stream.write_all(b"ping").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(handle.now() - start >= time::Duration::from_secs(1));
+            assert_eq!(stream.connects(), 0);
+        });
+    }
+}
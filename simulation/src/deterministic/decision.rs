@@ -0,0 +1,213 @@
+//! Recording and replay of random decisions.
+//!
+//! A seed only reproduces a run when paired with the exact algorithms used to turn raw random bits
+//! into values, which differ across versions of the `rand` crates. A `DecisionLog` instead records
+//! the value of every decision made by `DeterministicRandom`, so that a run can be replayed
+//! bit-exactly regardless of how those values were originally generated.
+//!
+//! Logs are encoded compactly: consecutive identical decisions are run-length encoded, and all
+//! integers are written as variable length integers.
+use std::{collections, io, time};
+
+/// A value which can be recorded in a `DecisionLog`.
+pub trait Recordable: Copy {
+    /// Tag identifying the kind of value, used to detect replays which have diverged.
+    const TAG: u8;
+    fn to_bits(self) -> u64;
+    fn from_bits(bits: u64) -> Self;
+}
+
+macro_rules! recordable_int {
+    ($tag:expr, $($t:ty),*) => {
+        $(
+            impl Recordable for $t {
+                const TAG: u8 = $tag;
+                fn to_bits(self) -> u64 {
+                    self as u64
+                }
+                fn from_bits(bits: u64) -> Self {
+                    bits as $t
+                }
+            }
+        )*
+    };
+}
+
+recordable_int!(1, u8, u16, u32, u64, usize);
+recordable_int!(2, i8, i16, i32, i64, isize);
+
+impl Recordable for bool {
+    const TAG: u8 = 0;
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+    fn from_bits(bits: u64) -> Self {
+        bits != 0
+    }
+}
+
+impl Recordable for f32 {
+    const TAG: u8 = 3;
+    fn to_bits(self) -> u64 {
+        u64::from(f32::to_bits(self))
+    }
+    fn from_bits(bits: u64) -> Self {
+        f32::from_bits(bits as u32)
+    }
+}
+
+impl Recordable for f64 {
+    const TAG: u8 = 4;
+    fn to_bits(self) -> u64 {
+        f64::to_bits(self)
+    }
+    fn from_bits(bits: u64) -> Self {
+        f64::from_bits(bits)
+    }
+}
+
+impl Recordable for time::Duration {
+    const TAG: u8 = 5;
+    fn to_bits(self) -> u64 {
+        self.as_nanos() as u64
+    }
+    fn from_bits(bits: u64) -> Self {
+        time::Duration::from_nanos(bits)
+    }
+}
+
+/// An ordered log of random decisions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecisionLog {
+    decisions: collections::VecDeque<(u8, u64)>,
+}
+
+impl DecisionLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of decisions in this log.
+    pub fn len(&self) -> usize {
+        self.decisions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decisions.is_empty()
+    }
+
+    pub(crate) fn push<T: Recordable>(&mut self, value: T) {
+        self.decisions.push_back((T::TAG, value.to_bits()));
+    }
+
+    /// Pop the next decision from the log. Panics if the log is exhausted or the next decision is
+    /// of a different kind, both of which indicate that the replay has diverged from the
+    /// recording.
+    pub(crate) fn pop<T: Recordable>(&mut self) -> T {
+        match self.decisions.pop_front() {
+            Some((tag, bits)) if tag == T::TAG => T::from_bits(bits),
+            Some((tag, _)) => panic!(
+                "replay diverged, expected decision of kind {} but found {}",
+                T::TAG,
+                tag
+            ),
+            None => panic!("replay diverged, decision log exhausted"),
+        }
+    }
+
+    /// Encode this log into its compact binary form.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = vec![];
+        let mut decisions = self.decisions.iter().peekable();
+        while let Some(decision) = decisions.next() {
+            let mut run: u64 = 1;
+            while decisions.peek() == Some(&decision) {
+                decisions.next();
+                run += 1;
+            }
+            encoded.push(decision.0);
+            write_varint(&mut encoded, decision.1);
+            write_varint(&mut encoded, run);
+        }
+        encoded
+    }
+
+    /// Decode a log from the binary form produced by [`DecisionLog::encode`].
+    pub fn decode(mut encoded: &[u8]) -> io::Result<Self> {
+        let mut decisions = collections::VecDeque::new();
+        while let Some((&tag, rest)) = encoded.split_first() {
+            encoded = rest;
+            let bits = read_varint(&mut encoded)?;
+            let run = read_varint(&mut encoded)?;
+            for _ in 0..run {
+                decisions.push_back((tag, bits));
+            }
+        }
+        Ok(Self { decisions })
+    }
+}
+
+fn write_varint(dst: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            dst.push(byte);
+            return;
+        }
+        dst.push(byte | 0x80);
+    }
+}
+
+fn read_varint(src: &mut &[u8]) -> io::Result<u64> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = src
+            .split_first()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        *src = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::ErrorKind::InvalidData.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that logs survive an encoding round trip.
+    fn round_trip() {
+        let mut log = DecisionLog::new();
+        log.push(true);
+        log.push(3.5f64);
+        log.push(time::Duration::from_millis(1500));
+        log.push(u64::max_value());
+        let decoded = DecisionLog::decode(&log.encode()).unwrap();
+        assert_eq!(log, decoded);
+    }
+
+    #[test]
+    /// Test that runs of identical decisions are compacted.
+    fn runs_are_compact() {
+        let mut log = DecisionLog::new();
+        for _ in 0..1000 {
+            log.push(false);
+        }
+        let encoded = log.encode();
+        assert!(encoded.len() < 8, "expected run to be encoded compactly");
+        assert_eq!(DecisionLog::decode(&encoded).unwrap().len(), 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "replay diverged")]
+    /// Test that replaying a decision of a different kind panics.
+    fn divergence() {
+        let mut log = DecisionLog::new();
+        log.push(true);
+        let _: f64 = log.pop();
+    }
+}
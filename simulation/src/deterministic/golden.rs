@@ -0,0 +1,193 @@
+//! Golden trace digests, which catch unintended changes in the behavior of a simulation.
+//!
+//! A simulation run with the same seed makes the same random decisions and reaches the same
+//! simulated time, so a digest of those fingerprints the behavior of the code under test. Storing
+//! the digest of each seed of a test, and comparing it on every run, catches code changes which
+//! alter the behavior of a protocol even when every assertion still passes. Intended changes are
+//! accepted by re-running the tests with the `SIMULATION_BLESS` environment variable set, which
+//! overwrites the stored digests rather than comparing them.
+use std::{collections, env, fs, hash, io, path};
+use tracing::trace;
+
+/// Environment variable which, when set, causes [`GoldenTraces::check`] to store digests rather
+/// than compare them.
+pub const BLESS_VAR: &str = "SIMULATION_BLESS";
+
+/// A 64-bit FNV-1a hasher. Unlike the hasher of the standard library, its output is stable
+/// across Rust releases and platforms, so digests produced by it can be stored.
+#[derive(Debug, Clone)]
+pub struct TraceDigest {
+    state: u64,
+}
+
+impl TraceDigest {
+    pub fn new() -> Self {
+        Self {
+            state: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+}
+
+impl Default for TraceDigest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl hash::Hasher for TraceDigest {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= u64::from(*byte);
+            self.state = self.state.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // integers are hashed in little endian byte order, and sizes as 64 bits, so that digests
+    // do not depend on the platform.
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+/// Digests of the seeds of a test, stored in a file with a `seed digest` line per seed.
+#[derive(Debug, Clone)]
+pub struct GoldenTraces {
+    path: path::PathBuf,
+    bless: bool,
+}
+
+impl GoldenTraces {
+    /// Digests of `test`, stored in `<dir>/<test>.golden`. Digests are stored rather than
+    /// compared if the `SIMULATION_BLESS` environment variable is set.
+    pub fn new<P: AsRef<path::Path>>(dir: P, test: &str) -> Self {
+        Self {
+            path: dir.as_ref().join(format!("{}.golden", test)),
+            bless: env::var_os(BLESS_VAR).is_some(),
+        }
+    }
+
+    /// Store digests rather than comparing them, overriding the `SIMULATION_BLESS` environment
+    /// variable.
+    pub fn bless(mut self, bless: bool) -> Self {
+        self.bless = bless;
+        self
+    }
+
+    fn load(&self) -> io::Result<collections::BTreeMap<u64, u64>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let invalid = |line: &str| {
+            let message = format!("invalid line in {}: {:?}", self.path.display(), line);
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        };
+        let mut digests = collections::BTreeMap::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let seed = fields.next().and_then(|seed| seed.parse().ok());
+            let digest = fields
+                .next()
+                .and_then(|digest| u64::from_str_radix(digest, 16).ok());
+            match (seed, digest) {
+                (Some(seed), Some(digest)) => digests.insert(seed, digest),
+                _ => return Err(invalid(line)),
+            };
+        }
+        Ok(digests)
+    }
+
+    fn store(&self, digests: &collections::BTreeMap<u64, u64>) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents: String = digests
+            .iter()
+            .map(|(seed, digest)| format!("{} {:016x}\n", seed, digest))
+            .collect();
+        fs::write(&self.path, contents)
+    }
+
+    /// Compare `digest` against the digest stored for `seed`, failing with `InvalidData` if
+    /// they differ. The digest is stored instead if none was stored for `seed`, or if blessing.
+    pub fn check(&self, seed: u64, digest: u64) -> io::Result<()> {
+        let mut digests = self.load()?;
+        match digests.get(&seed) {
+            Some(&golden) if golden == digest => Ok(()),
+            Some(&golden) if !self.bless => {
+                let message = format!(
+                    "trace digest of seed {} changed from {:016x} to {:016x} ({}), set {}=1 to \
+                     accept the change",
+                    seed,
+                    golden,
+                    digest,
+                    self.path.display(),
+                    BLESS_VAR
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidData, message))
+            }
+            _ => {
+                trace!("storing trace digest {:016x} of seed {}", digest, seed);
+                digests.insert(seed, digest);
+                self.store(&digests)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::time;
+
+    /// Run a simulation which sleeps for random durations, returning its trace digest.
+    fn digest(seed: u64, sleeps: usize) -> u64 {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        runtime.record_decisions();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            for _ in 0..sleeps {
+                let millis = handle.gen_range(0..1000);
+                handle.delay_from(time::Duration::from_millis(millis)).await;
+            }
+        });
+        runtime.trace_digest().unwrap()
+    }
+
+    #[test]
+    /// Test that digests are stable for a seed, and that changes to them are detected until
+    /// they are blessed.
+    fn golden_traces() {
+        assert_eq!(digest(1, 10), digest(1, 10));
+        assert_ne!(digest(1, 10), digest(2, 10));
+        assert_ne!(digest(1, 10), digest(1, 11));
+
+        let dir = env::temp_dir().join(format!("simulation-golden-{}", std::process::id()));
+        let golden = GoldenTraces::new(&dir, "golden_traces").bless(false);
+        golden.check(1, digest(1, 10)).unwrap();
+        golden.check(1, digest(1, 10)).unwrap();
+        let changed = golden.check(1, digest(1, 11)).unwrap_err();
+        assert_eq!(changed.kind(), io::ErrorKind::InvalidData);
+        golden.clone().bless(true).check(1, digest(1, 11)).unwrap();
+        golden.check(1, digest(1, 11)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+//! Online checking of invariants spanning multiple nodes.
+//!
+//! Invariants such as "at most one leader per term" relate the state of several nodes, which
+//! cannot be observed by any single node without exchanging messages over the network. An
+//! [`InvariantChecker`] is a side channel outside of the simulated network: nodes publish digests
+//! of their state to it, and it periodically checks registered invariants against the latest
+//! digest of every node. Publishing never blocks and is unaffected by network faults, so it does
+//! not perturb the system under test.
+//!
+//! Checkers can follow the [`CurrentPhase`] of a [`PhaseSchedule`], restricting invariants to
+//! the phases in which they are expected to hold and recording the phase of each violation.
+//!
+//! [`PhaseSchedule`]:`super::PhaseSchedule`
+use super::CurrentPhase;
+use crate::{Environment, MonotonicInstant};
+use std::{collections, fmt, net, sync, time};
+use tracing::trace;
+
+type Invariant<D> =
+    Box<dyn Fn(&collections::BTreeMap<net::IpAddr, D>) -> Result<(), String> + Send + Sync>;
+
+/// An invariant which did not hold when checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub invariant: String,
+    /// Time at which the violation was detected.
+    pub at: MonotonicInstant,
+    /// Phase the simulation was in when the violation was detected, if phases are followed.
+    pub phase: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invariant {} violated at {:?}", self.invariant, self.at)?;
+        if let Some(phase) = self.phase.as_ref() {
+            write!(f, " during {}", phase)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+struct Registered<D> {
+    name: String,
+    /// Phases in which the invariant is checked, or `None` to check it in every phase.
+    phases: Option<Vec<String>>,
+    invariant: Invariant<D>,
+}
+
+struct Inner<D> {
+    digests: collections::BTreeMap<net::IpAddr, D>,
+    invariants: Vec<Registered<D>>,
+    violations: Vec<Violation>,
+    phase: Option<CurrentPhase>,
+}
+
+/// Collects state digests published by nodes and checks invariants across them.
+pub struct InvariantChecker<D> {
+    inner: sync::Arc<sync::Mutex<Inner<D>>>,
+}
+
+impl<D> Clone for InvariantChecker<D> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<D> fmt::Debug for InvariantChecker<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = self.inner.lock().unwrap();
+        f.debug_struct("InvariantChecker")
+            .field("nodes", &lock.digests.len())
+            .field("invariants", &lock.invariants.len())
+            .field("violations", &lock.violations.len())
+            .finish()
+    }
+}
+
+impl<D> Default for InvariantChecker<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> InvariantChecker<D> {
+    pub fn new() -> Self {
+        let inner = Inner {
+            digests: collections::BTreeMap::new(),
+            invariants: vec![],
+            violations: vec![],
+            phase: None,
+        };
+        Self {
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        }
+    }
+
+    /// Register an invariant named `name`. The invariant is passed the latest digest published
+    /// by each node, and returns an error describing the violation if it does not hold.
+    pub fn invariant<F>(self, name: &str, invariant: F) -> Self
+    where
+        F: Fn(&collections::BTreeMap<net::IpAddr, D>) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.register(name, None, Box::new(invariant))
+    }
+
+    /// Register an invariant named `name` which is only checked while the followed phase is one
+    /// of `phases`, such as an invariant which is only expected to hold once faults stop.
+    pub fn invariant_during<F>(self, name: &str, phases: &[&str], invariant: F) -> Self
+    where
+        F: Fn(&collections::BTreeMap<net::IpAddr, D>) -> Result<(), String> + Send + Sync + 'static,
+    {
+        let phases = phases.iter().map(|phase| phase.to_string()).collect();
+        self.register(name, Some(phases), Box::new(invariant))
+    }
+
+    /// Follow the phase of a [`PhaseSchedule`], see [`InvariantChecker::invariant_during`].
+    ///
+    /// [`PhaseSchedule`]:`super::PhaseSchedule`
+    pub fn phases(self, current: CurrentPhase) -> Self {
+        self.inner.lock().unwrap().phase.replace(current);
+        self
+    }
+
+    fn register(self, name: &str, phases: Option<Vec<String>>, invariant: Invariant<D>) -> Self {
+        let mut lock = self.inner.lock().unwrap();
+        lock.invariants.push(Registered {
+            name: name.to_string(),
+            phases,
+            invariant,
+        });
+        drop(lock);
+        self
+    }
+
+    /// Publish a digest of the state of `node`, replacing any digest it previously published.
+    pub fn publish(&self, node: net::IpAddr, digest: D) {
+        self.inner.lock().unwrap().digests.insert(node, digest);
+    }
+
+    /// Remove the digest of `node`, such as after it crashes.
+    pub fn retract(&self, node: net::IpAddr) {
+        self.inner.lock().unwrap().digests.remove(&node);
+    }
+
+    /// Check every invariant against the latest digests, recording and returning any
+    /// violations.
+    pub fn check(&self, now: MonotonicInstant) -> Vec<Violation> {
+        let mut lock = self.inner.lock().unwrap();
+        let phase = lock.phase.as_ref().and_then(CurrentPhase::name);
+        let violations: Vec<_> = lock
+            .invariants
+            .iter()
+            .filter(
+                |registered| match (registered.phases.as_ref(), phase.as_ref()) {
+                    (None, _) => true,
+                    (Some(phases), Some(phase)) => phases.contains(phase),
+                    (Some(_), None) => false,
+                },
+            )
+            .filter_map(|registered| {
+                (registered.invariant)(&lock.digests)
+                    .err()
+                    .map(|message| Violation {
+                        invariant: registered.name.clone(),
+                        at: now,
+                        phase: phase.clone(),
+                        message,
+                    })
+            })
+            .collect();
+        for violation in violations.iter() {
+            trace!("{}", violation);
+        }
+        lock.violations.extend(violations.iter().cloned());
+        violations
+    }
+
+    /// Returns every violation detected so far, in the order they were detected.
+    pub fn violations(&self) -> Vec<Violation> {
+        self.inner.lock().unwrap().violations.clone()
+    }
+
+    /// Panic if any violation has been detected.
+    pub fn assert_upheld(&self) {
+        let violations = self.violations();
+        if let Some(first) = violations.first() {
+            panic!("{} ({} violations total)", first, violations.len());
+        }
+    }
+
+    /// Check invariants every `interval` of simulated time, forever.
+    pub async fn run<E>(self, env: E, interval: time::Duration)
+    where
+        E: Environment,
+    {
+        loop {
+            env.delay_from(interval).await;
+            self.check(env.now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[derive(Debug, Clone, Copy)]
+    struct RaftDigest {
+        term: u64,
+        leader: bool,
+    }
+
+    fn single_leader(
+        digests: &collections::BTreeMap<net::IpAddr, RaftDigest>,
+    ) -> Result<(), String> {
+        let mut leaders = collections::BTreeMap::new();
+        for (node, digest) in digests.iter().filter(|(_, digest)| digest.leader) {
+            if let Some(other) = leaders.insert(digest.term, *node) {
+                return Err(format!(
+                    "{} and {} are both leader for term {}",
+                    other, node, digest.term
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Test that invariants are checked periodically against the latest digests.
+    fn single_leader_per_term() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let checker = InvariantChecker::new().invariant("single leader per term", single_leader);
+        let a: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b: net::IpAddr = "10.0.0.2".parse().unwrap();
+        runtime.block_on(async {
+            handle.spawn(
+                checker
+                    .clone()
+                    .run(handle.clone(), time::Duration::from_secs(1)),
+            );
+            checker.publish(
+                a,
+                RaftDigest {
+                    term: 1,
+                    leader: true,
+                },
+            );
+            checker.publish(
+                b,
+                RaftDigest {
+                    term: 1,
+                    leader: false,
+                },
+            );
+            handle.delay_from(time::Duration::from_millis(1500)).await;
+            assert!(checker.violations().is_empty());
+
+            // A stale leader in an old term does not violate the invariant.
+            checker.publish(
+                b,
+                RaftDigest {
+                    term: 2,
+                    leader: true,
+                },
+            );
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            assert!(checker.violations().is_empty());
+
+            checker.publish(
+                a,
+                RaftDigest {
+                    term: 2,
+                    leader: true,
+                },
+            );
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            let violations = checker.violations();
+            assert_eq!(violations.len(), 1);
+            assert_eq!(violations[0].invariant, "single leader per term");
+            assert_eq!(
+                violations[0].message,
+                "10.0.0.1 and 10.0.0.2 are both leader for term 2"
+            );
+
+            checker.retract(a);
+            assert!(checker.check(handle.now()).is_empty());
+        });
+    }
+
+    #[test]
+    /// Test that phase restricted invariants are only checked during their phases.
+    fn phase_restricted() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let schedule = crate::deterministic::PhaseSchedule::new()
+            .phase("chaos", time::Duration::from_secs(10), 1.0)
+            .phase("verify", time::Duration::from_secs(10), 0.0);
+        let checker = InvariantChecker::<bool>::new()
+            .phases(schedule.current())
+            .invariant_during("converged", &["verify"], |digests| {
+                if digests.values().all(|converged| *converged) {
+                    Ok(())
+                } else {
+                    Err("not converged".to_string())
+                }
+            });
+        let node: net::IpAddr = "10.0.0.1".parse().unwrap();
+        runtime.block_on(async {
+            // Offset checks from phase boundaries, so each check falls clearly within a phase.
+            let offset = handle.clone();
+            let run = checker
+                .clone()
+                .run(handle.clone(), time::Duration::from_secs(1));
+            handle.spawn(async move {
+                offset.delay_from(time::Duration::from_millis(500)).await;
+                run.await
+            });
+            checker.publish(node, false);
+            schedule.run(handle.clone(), |_| ()).await;
+            let violations = checker.violations();
+            assert_eq!(violations.len(), 10);
+            let verify = Some("verify".to_string());
+            assert!(violations.iter().all(|violation| violation.phase == verify));
+        });
+    }
+}
@@ -10,34 +10,115 @@
 //! - `DeterministicNetwork` provides a process wide networking in memory networking implementation.
 //!
 //! `DeterministicRuntime` uses these to support deterministic task scheduling and fault injection.
-use crate::Error;
+use crate::{state::StateRegistry, Error, MonotonicInstant};
 use async_trait::async_trait;
 use futures::Future;
-use std::{
-    io, net,
-    time::{Duration, Instant},
-};
+use rand::distributions::uniform::SampleUniform;
+use std::{cell::Cell, io, net, ops, sync, time::Duration};
 
+mod decision;
+mod golden;
+mod invariant;
 mod network;
+mod phase;
+mod profile;
 mod random;
+mod search;
+mod sweep;
+mod task;
 mod time;
-pub(crate) use network::{DeterministicNetwork, DeterministicNetworkHandle};
-pub use network::{Listener, Socket};
-pub(crate) use random::{DeterministicRandom, DeterministicRandomHandle};
+pub use decision::{DecisionLog, Recordable};
+pub use golden::{GoldenTraces, TraceDigest, BLESS_VAR};
+pub use invariant::{InvariantChecker, Violation};
+pub(crate) use network::DeterministicNetwork;
+pub use network::{
+    AddrExhausted, ByteLedger, ChaosProfile, ConnectionLedger, DeterministicNetworkHandle,
+    FaultTarget, FlushMode, Handshake, LatencyModel, LinkProfile, Listener, Nat, NatHandle,
+    Partition, PartitionShape, PortUsage, Priority, Resolver, Socket, Topology, TopologyBuilder,
+    UdpSocket, UnixListener, UnixStream,
+};
+pub use phase::{CurrentPhase, Phase, PhaseSchedule};
+use profile::PollProfiler;
+pub use profile::TaskPollProfile;
+pub(crate) use random::{DeterministicRandom, DeterministicRandomHandle, Zipf};
+pub use search::{Coverage, CoverageSearch, SearchReport, SearchRun};
+pub use sweep::{
+    Failure, FailureClass, Observations, RunObservations, Summary, Sweep, SweepReport,
+};
+use task::{SpawnLimit, TaskTracker};
+pub use task::{TaskNode, TaskTree};
 pub(crate) use time::{DeterministicTime, DeterministicTimeHandle};
+pub use time::{FarTimer, Skew};
 use tokio_net::driver;
+use tracing::trace;
+
+thread_local! {
+    /// Set while a `DeterministicRuntime` is executing on the current thread.
+    static IN_SIMULATION: Cell<bool> = Cell::new(false);
+}
+
+/// Returns true if the current thread is executing a `DeterministicRuntime`.
+pub(crate) fn in_simulation() -> bool {
+    IN_SIMULATION.with(|in_simulation| in_simulation.get())
+}
+
+/// Marks the current thread as executing a `DeterministicRuntime` until dropped.
+struct SimulationGuard {
+    previous: bool,
+}
+
+impl SimulationGuard {
+    fn enter() -> Self {
+        let previous = IN_SIMULATION.with(|in_simulation| in_simulation.replace(true));
+        SimulationGuard { previous }
+    }
+}
+
+impl Drop for SimulationGuard {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        IN_SIMULATION.with(|in_simulation| in_simulation.set(previous));
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DeterministicRuntimeHandle {
+    /// Host whose clock is read by `node_now` and `system_time`.
+    host: net::IpAddr,
     time_handle: time::DeterministicTimeHandle,
     network_handle: DeterministicNetworkHandle,
     executor_handle: tokio_executor::current_thread::Handle,
     random_handle: DeterministicRandomHandle,
+    profiler: PollProfiler,
+    tasks: TaskTracker,
+    spawn_limit: SpawnLimit,
+    state: StateRegistry,
 }
 
 impl DeterministicRuntimeHandle {
-    pub fn now(&self) -> Instant {
-        self.time_handle.now()
+    fn spawn_named<F>(&self, name: &'static str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let result = match (self.profiler.is_enabled(), self.tasks.is_enabled()) {
+            (false, false) => self.executor_handle.spawn(future),
+            (true, false) => self.executor_handle.spawn(self.profiler.wrap(name, future)),
+            (false, true) => self.executor_handle.spawn(self.tasks.wrap(name, future)),
+            (true, true) => {
+                let future = self.profiler.wrap(name, future);
+                self.executor_handle.spawn(self.tasks.wrap(name, future))
+            }
+        };
+        result.expect("failed to spawn");
+    }
+
+    /// Returns the number of tasks spawned since the spawn limit was set which are waiting in
+    /// the ready queue to be polled.
+    pub fn ready_tasks(&self) -> usize {
+        self.spawn_limit.ready()
+    }
+    pub fn now(&self) -> MonotonicInstant {
+        self.time_handle.now().into()
     }
     pub fn time_handle(&self) -> time::DeterministicTimeHandle {
         self.time_handle.clone()
@@ -45,27 +126,128 @@ impl DeterministicRuntimeHandle {
     pub fn random_handle(&self) -> DeterministicRandomHandle {
         self.random_handle.clone()
     }
+    pub fn network_handle(&self) -> DeterministicNetworkHandle {
+        self.network_handle.clone()
+    }
+    /// Offset the clock of `host` from simulated time, see
+    /// [`DeterministicRuntime::set_clock_skew`].
+    pub fn set_clock_skew(&self, host: net::IpAddr, skew: Skew) {
+        self.time_handle.set_host_skew(host, skew);
+    }
+    /// Set the rate, in parts per million, at which the clock of `host` drifts, see
+    /// [`DeterministicRuntime::set_clock_drift`].
+    pub fn set_clock_drift(&self, host: net::IpAddr, ppm: f64) {
+        self.time_handle.set_host_drift(host, ppm);
+    }
+    /// Returns the current offset of the clock of `host` from simulated time.
+    pub fn clock_skew(&self, host: net::IpAddr) -> Skew {
+        self.time_handle.host_skew(host)
+    }
+    /// Wait for `grace` to elapse, then panic if any bytes are written to the network during a
+    /// further `grace`. Intended for use once a workload completes, to catch protocols which keep
+    /// exchanging bytes after they should have gone idle, such as retry storms or leaked
+    /// heartbeats.
+    pub async fn assert_network_quiescent(&self, grace: Duration) {
+        self.time_handle.delay_from(grace).await;
+        let before = self.network_handle.bytes_sent();
+        self.time_handle.delay_from(grace).await;
+        let chatty: Vec<_> = self
+            .network_handle
+            .bytes_sent()
+            .into_iter()
+            .filter_map(|(host, sent)| {
+                let sent = sent - before.get(&host).cloned().unwrap_or(0);
+                if sent > 0 {
+                    Some(format!("{} sent {} bytes", host, sent))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        assert!(
+            chatty.is_empty(),
+            "network not quiescent after {:?}: {}",
+            grace,
+            chatty.join(", ")
+        );
+    }
 }
 
 #[async_trait]
 impl crate::Environment for DeterministicRuntimeHandle {
     type TcpStream = network::Socket;
     type TcpListener = network::Listener;
+    type UdpSocket = network::UdpSocket;
+    type Resolver = network::Resolver;
+    #[cfg(unix)]
+    type UnixStream = network::UnixStream;
+    #[cfg(unix)]
+    type UnixListener = network::UnixListener;
     fn spawn<F>(&self, future: F)
     where
         F: Future<Output = ()> + Send + 'static,
     {
-        self.executor_handle.spawn(future).expect("failed to spawn");
+        let name = std::any::type_name::<F>();
+        if self.spawn_limit.is_enabled() {
+            self.spawn_named(name, self.spawn_limit.wrap(future));
+        } else {
+            self.spawn_named(name, future);
+        }
+    }
+    fn try_spawn<F>(&self, future: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if self.spawn_limit.at_capacity() {
+            trace!("rejecting spawn, task limit reached");
+            let source = tokio_executor::SpawnError::at_capacity();
+            return Err(Error::Spawn { source });
+        }
+        self.spawn(future);
+        Ok(())
+    }
+    fn now(&self) -> MonotonicInstant {
+        self.time_handle.now().into()
+    }
+    fn node_now(&self) -> std::time::Instant {
+        self.time_handle.host_now(self.host)
     }
-    fn now(&self) -> Instant {
-        self.time_handle.now()
+    fn system_time(&self) -> std::time::SystemTime {
+        self.time_handle.host_system_time(self.host)
     }
-    fn delay(&self, deadline: Instant) -> tokio_timer::Delay {
-        self.time_handle.delay(deadline)
+    fn delay(&self, deadline: MonotonicInstant) -> tokio_timer::Delay {
+        self.time_handle.delay(deadline.into_std())
     }
     fn timeout<T>(&self, value: T, timeout: Duration) -> tokio_timer::Timeout<T> {
         self.time_handle.timeout(value, timeout)
     }
+    fn exponential(&self, rate: f64) -> f64 {
+        self.random_handle.exponential(rate)
+    }
+    fn poisson(&self, lambda: f64) -> u64 {
+        self.random_handle.poisson(lambda)
+    }
+    fn zipf(&self, n: u64, s: f64) -> u64 {
+        self.random_handle.zipf(n, s)
+    }
+    fn gen_bool(&self, probability: f64) -> bool {
+        self.random_handle.should_fault(probability)
+    }
+    fn gen_range<T>(&self, range: ops::Range<T>) -> T
+    where
+        T: SampleUniform + Recordable,
+    {
+        self.random_handle.gen_range(range)
+    }
+    fn state<T>(&self) -> sync::Arc<T>
+    where
+        T: Default + Send + Sync + 'static,
+    {
+        self.state.get()
+    }
+    fn resolver(&self) -> Self::Resolver {
+        self.network_handle.resolver()
+    }
     async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
     where
         A: Into<net::SocketAddr> + Send + Sync,
@@ -78,6 +260,26 @@ impl crate::Environment for DeterministicRuntimeHandle {
     {
         self.network_handle.connect(addr.into()).await
     }
+    async fn bind_udp<A>(&self, addr: A) -> io::Result<Self::UdpSocket>
+    where
+        A: Into<net::SocketAddr> + Send + Sync,
+    {
+        self.network_handle.bind_udp(addr.into()).await
+    }
+    #[cfg(unix)]
+    async fn bind_unix<P>(&self, path: P) -> io::Result<Self::UnixListener>
+    where
+        P: AsRef<std::path::Path> + Send + Sync,
+    {
+        self.network_handle.bind_unix(path.as_ref()).await
+    }
+    #[cfg(unix)]
+    async fn connect_unix<P>(&self, path: P) -> io::Result<Self::UnixStream>
+    where
+        P: AsRef<std::path::Path> + Send + Sync,
+    {
+        self.network_handle.connect_unix(path.as_ref()).await
+    }
 }
 
 type Executor = tokio_executor::current_thread::CurrentThread<DeterministicTime<driver::Reactor>>;
@@ -87,6 +289,10 @@ pub struct DeterministicRuntime {
     time_handle: DeterministicTimeHandle,
     network: DeterministicNetwork,
     random: DeterministicRandom,
+    profiler: PollProfiler,
+    tasks: TaskTracker,
+    spawn_limit: SpawnLimit,
+    state: StateRegistry,
 }
 
 impl DeterministicRuntime {
@@ -94,30 +300,204 @@ impl DeterministicRuntime {
         DeterministicRuntime::new_with_seed(0)
     }
     pub fn new_with_seed(seed: u64) -> Result<Self, Error> {
+        DeterministicRuntime::new_with_random(DeterministicRandom::new_with_seed(seed))
+    }
+    /// Create a runtime which replays the random decisions recorded in `log`, see
+    /// [`DeterministicRuntime::record_decisions`]. Panics if the simulation diverges from the
+    /// recording.
+    pub fn new_with_decision_log(log: DecisionLog) -> Result<Self, Error> {
+        DeterministicRuntime::new_with_random(DeterministicRandom::new_with_log(log))
+    }
+    fn new_with_random(random: DeterministicRandom) -> Result<Self, Error> {
         let reactor = driver::Reactor::new().map_err(|source| Error::RuntimeBuild { source })?;
 
         let time = DeterministicTime::new_with_park(reactor);
         let time_handle = time.handle();
         let network = DeterministicNetwork::new(time_handle.clone());
+        network.set_random_handle(random.handle());
         let executor = tokio_executor::current_thread::CurrentThread::new_with_park(time);
-        let random = DeterministicRandom::new_with_seed(seed);
         Ok(DeterministicRuntime {
             executor,
             time_handle,
             network,
             random,
+            profiler: PollProfiler::default(),
+            tasks: TaskTracker::default(),
+            spawn_limit: SpawnLimit::default(),
+            state: StateRegistry::default(),
         })
     }
 
     pub fn handle(&self, addr: net::IpAddr) -> DeterministicRuntimeHandle {
+        self.handle_with_network(addr, self.network.scoped(addr))
+    }
+
+    /// Returns a handle for a dual-stack host with both an IPv4 and an IPv6 address. Binding the
+    /// unspecified IPv6 address accepts connections to either address.
+    pub fn dual_stack_handle(
+        &self,
+        v4: net::Ipv4Addr,
+        v6: net::Ipv6Addr,
+    ) -> DeterministicRuntimeHandle {
+        self.handle_with_network(v4.into(), self.network.dual_stack(v4, v6))
+    }
+
+    fn handle_with_network(
+        &self,
+        host: net::IpAddr,
+        network_handle: DeterministicNetworkHandle,
+    ) -> DeterministicRuntimeHandle {
         DeterministicRuntimeHandle {
+            host,
             time_handle: self.time_handle.clone(),
-            network_handle: self.network.scoped(addr),
+            network_handle,
             executor_handle: self.executor.handle(),
             random_handle: self.random.handle(),
+            profiler: self.profiler.clone(),
+            tasks: self.tasks.clone(),
+            spawn_limit: self.spawn_limit.clone(),
+            state: self.state.clone(),
+        }
+    }
+
+    /// Begin recording the wall-clock time spent polling tasks spawned from handles of this
+    /// runtime. Only tasks spawned after profiling is enabled are recorded.
+    pub fn enable_poll_profiling(&self) {
+        self.profiler.enable();
+    }
+
+    /// Returns the wall-clock time spent polling spawned tasks, grouped by the type of the spawned
+    /// future and ordered from the most to the least time spent.
+    pub fn poll_profile(&self) -> Vec<TaskPollProfile> {
+        self.profiler.report()
+    }
+
+    /// Remove every singleton returned by [`Environment::state`], so that the next access
+    /// creates a fresh value.
+    ///
+    /// [`Environment::state`]:`crate::Environment::state`
+    pub fn reset_state(&self) {
+        self.state.clear();
+    }
+
+    /// Limit the ready queue of the executor. Once `limit` tasks spawned from handles of this
+    /// runtime after the limit was set are waiting to be polled, having been spawned or woken,
+    /// `Environment::try_spawn` returns an at capacity error instead of spawning, allowing load
+    /// shedding around task creation to be simulated. Tasks which are waiting on I/O or timers
+    /// do not count against the limit. `Environment::spawn` is unaffected. Passing `None`
+    /// removes the limit.
+    pub fn set_spawn_limit(&self, limit: Option<usize>) {
+        self.spawn_limit.set(limit);
+    }
+
+    /// Set the wall clock time returned by [`Environment::system_time`] when the simulation
+    /// started, which then advances with simulated time. Defaults to 2019-01-01T00:00:00Z.
+    ///
+    /// [`Environment::system_time`]:`crate::Environment::system_time`
+    pub fn set_epoch(&self, epoch: std::time::SystemTime) {
+        self.time_handle.set_epoch(epoch);
+    }
+
+    /// Offset the clock of `host`, as read by [`Environment::node_now`] and
+    /// [`Environment::system_time`], from simulated time. Timers of the host still fire
+    /// according to simulated time, so code which mixes the two clocks observes the skew.
+    ///
+    /// [`Environment::node_now`]:`crate::Environment::node_now`
+    /// [`Environment::system_time`]:`crate::Environment::system_time`
+    pub fn set_clock_skew(&self, host: net::IpAddr, skew: Skew) {
+        self.time_handle.set_host_skew(host, skew);
+    }
+
+    /// Set the rate, in parts per million, at which the clock of `host` drifts ahead of
+    /// simulated time from now on. Negative rates drift behind.
+    pub fn set_clock_drift(&self, host: net::IpAddr, ppm: f64) {
+        self.time_handle.set_host_drift(host, ppm);
+    }
+
+    /// Returns the current offset of the clock of `host` from simulated time, including any
+    /// drift. Hosts are not skewed by default.
+    pub fn clock_skew(&self, host: net::IpAddr) -> Skew {
+        self.time_handle.host_skew(host)
+    }
+
+    /// Skew the clock of each of `hosts` by a seeded offset of up to `max_skew` in either
+    /// direction, drifting at a seeded rate of up to `max_drift` parts per million in either
+    /// direction.
+    pub fn randomize_clocks(&self, hosts: &[net::IpAddr], max_skew: Duration, max_drift: f64) {
+        let random = self.random.handle();
+        for host in hosts {
+            let mut offset = 0;
+            if max_skew > Duration::from_millis(0) {
+                let sample = random.gen_range(Duration::from_millis(0)..max_skew * 2);
+                offset = sample.as_nanos() as i64 - max_skew.as_nanos() as i64;
+            }
+            let mut drift = 0.0;
+            if max_drift > 0.0 {
+                drift = random.gen_range(-max_drift..max_drift);
+            }
+            trace!(
+                "skewing clock of {} by {}ns, drift {}ppm",
+                host,
+                offset,
+                drift
+            );
+            self.time_handle
+                .set_host_skew(*host, Skew::from_nanos(offset));
+            self.time_handle.set_host_drift(*host, drift);
         }
     }
 
+    /// Set the threshold above which timers are reported as scheduled implausibly far in the
+    /// future, which usually indicates a unit bug such as milliseconds passed as seconds. Each
+    /// such timer is logged at warn level and returned by `far_timers`. The threshold defaults
+    /// to 30 days, passing `None` disables auditing.
+    pub fn set_timer_audit_threshold(&self, threshold: Option<Duration>) {
+        self.time_handle.set_audit_threshold(threshold);
+    }
+
+    /// Returns the timers which were scheduled further ahead than the timer audit threshold.
+    /// Only timers scheduled through a `DeterministicRuntimeHandle` are audited.
+    pub fn far_timers(&self) -> Vec<FarTimer> {
+        self.time_handle.far_timers()
+    }
+
+    /// Begin tracking the tasks spawned from handles of this runtime, recording which task spawned
+    /// each of them. Tracked tasks are polled within a `task` tracing span.
+    pub fn enable_task_tracking(&self) {
+        self.tasks.enable();
+    }
+
+    /// Returns the tree of tasks spawned since task tracking was enabled.
+    pub fn task_tree(&self) -> TaskTree {
+        self.tasks.tree()
+    }
+
+    /// Begin recording every random decision made by this runtime. The recorded log can be
+    /// encoded and used to replay the run bit-exactly, independent of the version of the `rand`
+    /// crates used to generate it.
+    pub fn record_decisions(&self) {
+        self.random.record();
+    }
+
+    /// Returns the random decisions recorded since [`DeterministicRuntime::record_decisions`] was
+    /// called, or `None` if decisions are not being recorded or replayed.
+    pub fn decision_log(&self) -> Option<DecisionLog> {
+        self.random.log()
+    }
+
+    /// Returns a digest of the random decisions recorded since
+    /// [`DeterministicRuntime::record_decisions`] was called and of the simulated time elapsed,
+    /// or `None` if decisions are not being recorded. Runs with the same seed have the same
+    /// digest unless the behavior of the simulated code changes, see [`GoldenTraces`].
+    pub fn trace_digest(&self) -> Option<u64> {
+        use std::hash::{Hash, Hasher};
+        let log = self.random.log()?;
+        let mut digest = TraceDigest::new();
+        digest.write(&log.encode());
+        self.time_handle.elapsed().hash(&mut digest);
+        Some(digest.finish())
+    }
+
     pub fn latency_fault(&self) -> network::fault::LatencyFaultInjector {
         let network_inner = self.network.clone_inner();
         network::fault::LatencyFaultInjector::new(
@@ -127,6 +507,153 @@ impl DeterministicRuntime {
         )
     }
 
+    /// Returns a fault injector which periodically partitions the provided hosts.
+    pub fn partition_fault(
+        &self,
+        hosts: Vec<net::IpAddr>,
+    ) -> network::fault::PartitionFaultInjector {
+        network::fault::PartitionFaultInjector::new(
+            self.network.clone_inner(),
+            self.random.handle(),
+            self.time_handle.clone(),
+            hosts,
+        )
+    }
+
+    /// Returns a fault injector which injects the latency, connection drops and partitions
+    /// described by `profile`, partitioning the provided hosts.
+    pub fn chaos_fault(
+        &self,
+        profile: ChaosProfile,
+        hosts: Vec<net::IpAddr>,
+    ) -> network::fault::ChaosFaultInjector {
+        network::fault::ChaosFaultInjector::new(
+            profile,
+            self.network.clone_inner(),
+            self.random.handle(),
+            self.time_handle.clone(),
+            hosts,
+        )
+    }
+
+    /// Returns a fault injector which drops datagrams with a seeded probability.
+    pub fn packet_loss_fault(&self) -> network::fault::PacketLossFaultInjector {
+        network::fault::PacketLossFaultInjector::new(
+            self.network.clone_inner(),
+            self.random.handle(),
+        )
+    }
+
+    /// Returns a fault injector which flips bits in and truncates reads with a seeded
+    /// probability.
+    pub fn corruption_fault(&self) -> network::fault::CorruptionFaultInjector {
+        network::fault::CorruptionFaultInjector::new(
+            self.network.clone_inner(),
+            self.random.handle(),
+        )
+    }
+
+    /// Returns a fault injector which delivers datagrams twice with a seeded probability.
+    pub fn duplication_fault(&self) -> network::fault::DuplicationFaultInjector {
+        network::fault::DuplicationFaultInjector::new(
+            self.network.clone_inner(),
+            self.random.handle(),
+        )
+    }
+
+    /// Returns a fault injector which shortens reads to a seeded random length.
+    pub fn fragmentation_fault(&self) -> network::fault::FragmentationFaultInjector {
+        network::fault::FragmentationFaultInjector::new(
+            self.network.clone_inner(),
+            self.random.handle(),
+        )
+    }
+
+    /// Returns a fault injector which resets established connections.
+    pub fn reset_fault(&self) -> network::fault::ResetFaultInjector {
+        network::fault::ResetFaultInjector::new(
+            self.network.clone_inner(),
+            self.random.handle(),
+            self.time_handle.clone(),
+        )
+    }
+
+    /// Returns a builder for a NAT which places hosts behind the public address `public`.
+    pub fn nat(&self, public: net::IpAddr) -> network::Nat {
+        network::Nat::new(self.network.clone_inner(), public)
+    }
+
+    /// Returns a builder for a topology which derives the latency and loss of links between
+    /// hosts from the racks and regions they are placed in.
+    pub fn topology(&self) -> network::TopologyBuilder {
+        network::TopologyBuilder::new(self.network.clone_inner(), self.random.handle())
+    }
+
+    /// Returns a fault injector which holds connections open from `source` to `target` while
+    /// sending bytes slowly or not at all.
+    pub fn slowloris_fault(
+        &self,
+        source: net::IpAddr,
+        target: net::SocketAddr,
+    ) -> network::fault::SlowlorisFaultInjector {
+        network::fault::SlowlorisFaultInjector::new(
+            self.network.clone_inner(),
+            self.time_handle.clone(),
+            source,
+            target,
+        )
+    }
+
+    /// Partition the hosts in `a` from the hosts in `b`. Connects between the two groups wait for
+    /// the partition to be healed, failing with `TimedOut` after the SYN timeout, and existing
+    /// connections stall until the partition is healed.
+    pub fn partition(&self, a: &[net::IpAddr], b: &[net::IpAddr]) {
+        self.network.partition(a, b);
+    }
+
+    /// Drop traffic sent from hosts in `from` to hosts in `to`, while traffic in the opposite
+    /// direction continues to flow.
+    pub fn partition_one_way(&self, from: &[net::IpAddr], to: &[net::IpAddr]) {
+        self.network.partition_one_way(from, to);
+    }
+
+    /// Delay traffic sent from `from` to `to` by `latency`, leaving traffic in the opposite
+    /// direction unchanged. Passing `None` removes the delay.
+    pub fn set_one_way_latency(
+        &self,
+        from: net::IpAddr,
+        to: net::IpAddr,
+        latency: Option<Duration>,
+    ) {
+        self.network.set_one_way_latency(from, to, latency);
+    }
+
+    /// Set when flushes of connection streams complete. By default a flush waits until every
+    /// byte written has been delivered under the current latency, while [`FlushMode::Relaxed`]
+    /// completes flushes immediately.
+    pub fn set_flush_mode(&self, mode: FlushMode) {
+        self.network.set_flush_mode(mode);
+    }
+
+    /// Hold the local address of each connection which a host closes before its peer in
+    /// TIME_WAIT for `time_wait`, or never if `None`. Defaults to `None`. Hosts can override the
+    /// period with [`DeterministicNetworkHandle::set_time_wait`].
+    pub fn set_time_wait(&self, time_wait: Option<Duration>) {
+        self.network.set_time_wait(time_wait);
+    }
+
+    /// Reset connections once no bytes have been exchanged in either direction for `timeout`,
+    /// or never if `None`. Applies to both new and existing connections.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.network.set_idle_timeout(timeout);
+    }
+
+    /// Heal every partition created by [`DeterministicRuntime::partition`] or
+    /// [`DeterministicRuntime::partition_one_way`].
+    pub fn heal(&self) {
+        self.network.heal();
+    }
+
     pub fn localhost_handle(&self) -> DeterministicRuntimeHandle {
         self.handle(net::IpAddr::V4(net::Ipv4Addr::LOCALHOST))
     }
@@ -144,6 +671,95 @@ impl DeterministicRuntime {
             .map_err(|source| Error::CurrentThreadRun { source })
     }
 
+    /// Run spawned tasks until `condition` returns true or `limit` of simulated time has
+    /// elapsed, returning whether the condition was met. The condition is checked before each
+    /// turn of the executor, so it can count events recorded by tasks. Tasks which have not
+    /// completed are left in place, and continue the next time the runtime is run. While time
+    /// is paused, returns once no task is ready to run.
+    pub fn run_until<F>(&mut self, limit: Duration, mut condition: F) -> Result<bool, Error>
+    where
+        F: FnMut() -> bool,
+    {
+        let time_handle = self.time_handle.clone();
+        let deadline = time_handle.now() + limit;
+        self.enter(|executor| loop {
+            if condition() {
+                return Ok(true);
+            }
+            let now = time_handle.now();
+            if now >= deadline {
+                trace!("stop condition not met within {:?}", limit);
+                return Ok(false);
+            }
+            let turn = executor
+                .turn(Some(deadline - now))
+                .map_err(|source| Error::CurrentThreadTurn { source })?;
+            if !turn.has_polled() && time_handle.is_paused() && time_handle.now() == now {
+                trace!("no task is ready to run while time is paused");
+                return Ok(condition());
+            }
+        })
+    }
+
+    /// Run spawned tasks for `duration` of simulated time, leaving any which have not completed
+    /// in place.
+    pub fn run_for(&mut self, duration: Duration) -> Result<(), Error> {
+        self.run_until(duration, || false).map(|_| ())
+    }
+
+    /// Stop advancing simulated time automatically when every task is waiting on a timer, until
+    /// [`DeterministicRuntime::resume_time`] is called. While paused, time only moves with
+    /// [`DeterministicRuntime::advance`] and [`DeterministicRuntime::advance_to`], so running
+    /// ready tasks with `advance(Duration::from_secs(0))` shows whether a future is pending on
+    /// something other than a timer. [`DeterministicRuntime::run_until`] returns once no task
+    /// is ready to run, while [`DeterministicRuntime::block_on`] and
+    /// [`DeterministicRuntime::run`] panic once every task is waiting on a timer which paused
+    /// time does not reach.
+    pub fn pause_time(&self) {
+        trace!("pausing automatic time advancement");
+        self.time_handle.pause();
+    }
+
+    /// Resume advancing simulated time automatically when every task is waiting on a timer.
+    pub fn resume_time(&self) {
+        trace!("resuming automatic time advancement");
+        self.time_handle.resume();
+    }
+
+    /// Returns true if automatic time advancement is paused.
+    pub fn is_time_paused(&self) -> bool {
+        self.time_handle.is_paused()
+    }
+
+    /// Advance simulated time by `duration`, see [`DeterministicRuntime::advance_to`].
+    pub fn advance(&mut self, duration: Duration) -> Result<(), Error> {
+        let deadline = self.time_handle.now() + duration;
+        self.advance_to(deadline.into())
+    }
+
+    /// Advance simulated time to `deadline`, firing the timers which become due along the way
+    /// in order and running the tasks they wake. Returns once simulated time has reached
+    /// `deadline` and no task is ready to run, so the state of timer-driven tasks can be
+    /// inspected at precise instants. If `deadline` has already passed, only the tasks which are
+    /// ready are run. Time may be advanced while automatic advancement is paused.
+    pub fn advance_to(&mut self, deadline: MonotonicInstant) -> Result<(), Error> {
+        let time_handle = self.time_handle.clone();
+        let deadline = deadline.into_std();
+        trace!("advancing time to {:?}", deadline);
+        time_handle.allow_advance_to(deadline);
+        self.enter(|executor| loop {
+            let remaining = deadline
+                .checked_duration_since(time_handle.now())
+                .unwrap_or_else(|| Duration::from_millis(0));
+            let turn = executor
+                .turn(Some(remaining))
+                .map_err(|source| Error::CurrentThreadTurn { source })?;
+            if remaining == Duration::from_millis(0) && !turn.has_polled() {
+                return Ok(());
+            }
+        })
+    }
+
     pub fn block_on<F>(&mut self, f: F) -> F::Output
     where
         F: Future,
@@ -160,6 +776,7 @@ impl DeterministicRuntime {
             ref mut executor,
             ..
         } = *self;
+        let _simulation = SimulationGuard::enter();
         // Setup mock clock globals
         let clock = tokio_timer::clock::Clock::new_with_now(time_handle.clone_now());
         let timer_handle = time_handle.clone_timer_handle();
@@ -174,7 +791,7 @@ impl DeterministicRuntime {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Environment;
+    use crate::{Environment, TcpListener};
 
     #[test]
     /// Test that delays accurately advance the clock.
@@ -217,6 +834,382 @@ mod tests {
         });
     }
 
+    #[test]
+    /// Test that tasks spawned with a delay begin executing once the delay has elapsed.
+    fn spawn_after() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let start_time = handle.now();
+            let (tx, rx) = futures::channel::oneshot::channel();
+            let task_handle = handle.clone();
+            handle.spawn_after(Duration::from_secs(10), async move {
+                tx.send(task_handle.now()).unwrap();
+            });
+            let started_at = rx.await.unwrap();
+            assert_eq!(started_at - start_time, Duration::from_secs(10));
+        });
+    }
+
+    #[test]
+    /// Test that polls of spawned tasks are recorded once profiling is enabled.
+    fn poll_profiling() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.enable_poll_profiling();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let delay_handle = handle.clone();
+            crate::spawn_with_result(&handle, async move {
+                delay_handle.delay_from(Duration::from_secs(1)).await;
+            })
+            .await;
+        });
+        let profile = runtime.poll_profile();
+        assert_eq!(profile.len(), 1);
+        assert_eq!(profile[0].tasks, 1);
+        assert!(
+            profile[0].polls >= 2,
+            "expected task to be polled before and after the delay"
+        );
+    }
+
+    #[test]
+    /// Test that spawned tasks are recorded as children of the task which spawned them.
+    fn task_tree() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.enable_task_tracking();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let child_handle = handle.clone();
+            crate::spawn_with_result(&handle, async move {
+                child_handle.spawn(async {});
+            })
+            .await;
+        });
+        let tree = runtime.task_tree();
+        let roots: Vec<_> = tree.children(None).collect();
+        assert_eq!(roots.len(), 1);
+        let children: Vec<_> = tree.children(Some(roots[0].id)).collect();
+        assert_eq!(children.len(), 1);
+        assert!(roots[0].completed);
+    }
+
+    #[test]
+    /// Test that the wall clock starts at the configured epoch and advances with simulated time.
+    fn system_time() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let default_epoch = std::time::UNIX_EPOCH + Duration::from_secs(1_546_300_800);
+        assert_eq!(handle.system_time(), default_epoch);
+        let epoch = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        runtime.set_epoch(epoch);
+        runtime.block_on(async {
+            handle.delay_from(Duration::from_secs(90)).await;
+            assert_eq!(handle.system_time(), epoch + Duration::from_secs(90));
+        });
+    }
+
+    #[test]
+    /// Test that skewed and drifting host clocks are offset from simulated time, while their
+    /// timers still fire according to simulated time.
+    fn clock_skew() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let a_ip: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b_ip: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let a = runtime.handle(a_ip);
+        let b = runtime.handle(b_ip);
+        runtime.set_clock_skew(a_ip, Skew::Behind(Duration::from_secs(2)));
+        runtime.set_clock_drift(b_ip, 1000.0);
+        let start = a.now();
+        let wall_start = b.system_time();
+        runtime.block_on(async {
+            assert_eq!(a.node_now(), start.into_std() - Duration::from_secs(2));
+            b.delay_from(Duration::from_secs(100)).await;
+            assert_eq!(b.now() - start, Duration::from_secs(100));
+            let elapsed = b.system_time().duration_since(wall_start).unwrap();
+            assert_eq!(elapsed, Duration::from_millis(100_100));
+        });
+        assert_eq!(
+            runtime.clock_skew(b_ip),
+            Skew::Ahead(Duration::from_millis(100))
+        );
+        assert_eq!(
+            runtime.clock_skew(a_ip),
+            Skew::Behind(Duration::from_secs(2))
+        );
+
+        runtime.randomize_clocks(&[a_ip, b_ip], Duration::from_secs(1), 100.0);
+        for host in [a_ip, b_ip].iter() {
+            assert!(runtime.clock_skew(*host).magnitude() <= Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    /// Test that advancing time manually fires timers at precise instants.
+    fn advance() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let start = handle.now();
+        let fired = sync::Arc::new(sync::Mutex::new(vec![]));
+        for delay in [10, 20].iter() {
+            let delay = Duration::from_secs(*delay);
+            let (handle, fired) = (handle.clone(), sync::Arc::clone(&fired));
+            runtime.spawn(async move {
+                handle.delay_from(delay).await;
+                fired.lock().unwrap().push(handle.now() - start);
+            });
+        }
+        runtime.advance(Duration::from_secs(5)).unwrap();
+        assert_eq!(handle.now() - start, Duration::from_secs(5));
+        assert!(fired.lock().unwrap().is_empty());
+        runtime.advance(Duration::from_secs(5)).unwrap();
+        assert_eq!(*fired.lock().unwrap(), vec![Duration::from_secs(10)]);
+        runtime.advance_to(start + Duration::from_secs(25)).unwrap();
+        assert_eq!(handle.now() - start, Duration::from_secs(25));
+        assert_eq!(fired.lock().unwrap().len(), 2);
+
+        // advancing to an instant which has passed leaves the clock unchanged.
+        runtime.advance_to(start).unwrap();
+        assert_eq!(handle.now() - start, Duration::from_secs(25));
+    }
+
+    #[test]
+    /// Test that paused time only moves when advanced explicitly.
+    fn pause_time() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let start = handle.now();
+        let (tx, rx) = futures::channel::oneshot::channel::<()>();
+        let completed = sync::Arc::new(sync::atomic::AtomicUsize::new(0));
+        let (timer_handle, timer_completed) = (handle.clone(), sync::Arc::clone(&completed));
+        runtime.spawn(async move {
+            timer_handle.delay_from(Duration::from_secs(10)).await;
+            timer_completed.fetch_add(1, sync::atomic::Ordering::SeqCst);
+        });
+        let channel_completed = sync::Arc::clone(&completed);
+        runtime.spawn(async move {
+            rx.await.unwrap();
+            channel_completed.fetch_add(1, sync::atomic::Ordering::SeqCst);
+        });
+        let count = || completed.load(sync::atomic::Ordering::SeqCst);
+
+        runtime.pause_time();
+        assert!(runtime.is_time_paused());
+        runtime.advance(Duration::from_secs(0)).unwrap();
+        assert_eq!((count(), handle.now()), (0, start));
+        runtime.advance(Duration::from_secs(10)).unwrap();
+        assert_eq!(count(), 1);
+        assert!(runtime.is_time_paused());
+
+        // the remaining task is pending on the channel rather than a timer.
+        runtime.advance(Duration::from_secs(60)).unwrap();
+        assert_eq!(count(), 1);
+        tx.send(()).unwrap();
+        runtime.resume_time();
+        runtime.run().unwrap();
+        assert_eq!(count(), 2);
+        assert_eq!(handle.now() - start, Duration::from_secs(70));
+    }
+
+    #[test]
+    /// Test that running while time is paused returns once every task is waiting on a timer,
+    /// without advancing time.
+    fn paused_timer() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let start = handle.now();
+        let timer_handle = handle.clone();
+        runtime.spawn(async move {
+            timer_handle.delay_from(Duration::from_secs(1)).await;
+        });
+        runtime.pause_time();
+        let finished = runtime
+            .run_until(Duration::from_secs(10), || false)
+            .unwrap();
+        assert!(!finished);
+        assert_eq!(handle.now(), start);
+    }
+
+    #[test]
+    #[should_panic(expected = "every task is waiting on a timer while time is paused")]
+    /// Test that blocking on a timer which paused time does not reach panics rather than
+    /// hanging.
+    fn paused_block_on() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.pause_time();
+        runtime.block_on(handle.delay_from(Duration::from_secs(1)));
+    }
+
+    #[test]
+    /// Test that open-ended tasks can be bounded by a stop condition or a simulated duration.
+    fn run_until() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let ticks = sync::Arc::new(sync::atomic::AtomicUsize::new(0));
+        let task_ticks = sync::Arc::clone(&ticks);
+        let task_handle = handle.clone();
+        runtime.spawn(async move {
+            loop {
+                task_handle.delay_from(Duration::from_secs(1)).await;
+                task_ticks.fetch_add(1, sync::atomic::Ordering::SeqCst);
+            }
+        });
+        let start = handle.now();
+        let count = || ticks.load(sync::atomic::Ordering::SeqCst);
+        assert!(runtime
+            .run_until(Duration::from_secs(60), || count() >= 5)
+            .unwrap());
+        assert_eq!(handle.now() - start, Duration::from_secs(5));
+
+        runtime.run_for(Duration::from_secs(10)).unwrap();
+        assert_eq!(count(), 15);
+        assert_eq!(handle.now() - start, Duration::from_secs(15));
+        assert!(!runtime
+            .run_until(Duration::from_millis(500), || count() > 15)
+            .unwrap());
+    }
+
+    #[test]
+    /// Test that connecting with a timeout fails once the timeout elapses, rather than waiting
+    /// on a handshake which completes much later.
+    fn connect_timeout() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let server_addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+        let server = runtime.handle(server_addr.ip());
+        let client = runtime.handle("10.0.0.2".parse().unwrap());
+        let handshake = Handshake::new(0, Duration::from_secs(60));
+        runtime.block_on(async {
+            let _listener = server.bind(server_addr).await.unwrap();
+            server
+                .network_handle()
+                .set_handshake(server_addr, Some(handshake));
+            let start = client.now();
+            let error = client
+                .connect_timeout(server_addr, Duration::from_secs(5))
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(client.now() - start, Duration::from_secs(5));
+
+            server.network_handle().set_handshake(server_addr, None);
+            client
+                .connect_timeout(server_addr, Duration::from_secs(5))
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that names are resolved when connecting, failing over to later addresses and
+    /// honoring resolution failures injected for the name.
+    fn connect_name() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let server_addr: net::SocketAddr = "10.0.0.1:9090".parse().unwrap();
+        let client_ip: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let down_ip: net::IpAddr = "10.0.0.3".parse().unwrap();
+        let down = runtime.handle(down_ip);
+        let server = runtime.handle(server_addr.ip());
+        let client = runtime.handle(client_ip);
+        runtime.block_on(async {
+            let mut listener = server.bind(server_addr).await.unwrap();
+            client.network_handle().partition(&[client_ip], &[down_ip]);
+            down.network_handle().register_name("storage-3");
+            server.network_handle().register_name("storage-3");
+            client.connect_name("storage-3:9090").await.unwrap();
+            listener.accept().await.unwrap();
+            client.connect_name("10.0.0.1:9090").await.unwrap();
+            listener.accept().await.unwrap();
+
+            client
+                .network_handle()
+                .set_resolve_error("storage-3", Some(io::ErrorKind::TimedOut));
+            let error = client.connect_name("storage-3:9090").await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+            let error = client.connect_name("storage-4:9090").await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+            let error = client.connect_name("storage-3").await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    /// Test that try_spawn is rejected while the ready queue is at the spawn limit, and that
+    /// tasks waiting to be woken do not count against it.
+    fn spawn_limit() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_spawn_limit(Some(1));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let assert_rejected = || match handle.try_spawn(async {}) {
+                Err(Error::Spawn { source }) => assert!(source.is_at_capacity()),
+                result => panic!("expected spawn to be rejected, got {:?}", result),
+            };
+            let (tx, rx) = futures::channel::oneshot::channel::<()>();
+            handle
+                .try_spawn(async move {
+                    let _ = rx.await;
+                })
+                .unwrap();
+            assert_eq!(handle.ready_tasks(), 1);
+            assert_rejected();
+
+            handle.delay_from(Duration::from_secs(1)).await;
+            assert_eq!(handle.ready_tasks(), 0);
+            tx.send(()).unwrap();
+            assert_eq!(handle.ready_tasks(), 1);
+            assert_rejected();
+
+            handle.delay_from(Duration::from_secs(1)).await;
+            assert_eq!(handle.ready_tasks(), 0);
+            assert!(handle.try_spawn(async {}).is_ok());
+        });
+    }
+
+    #[test]
+    /// Test that timers scheduled beyond the audit threshold are reported.
+    fn far_timers() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_timer_audit_threshold(Some(Duration::from_secs(60 * 60)));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            handle.delay_from(Duration::from_secs(60)).await;
+            // A timeout in milliseconds, mistakenly passed as seconds.
+            handle.delay_from(Duration::from_secs(5000)).await;
+        });
+        let far_timers = runtime.far_timers();
+        assert_eq!(far_timers.len(), 1);
+        assert_eq!(far_timers[0].ahead(), Duration::from_secs(5000));
+    }
+
+    #[test]
+    #[should_panic(expected = "network not quiescent")]
+    /// Test that traffic continuing after the grace period fails the quiescence assertion.
+    fn network_quiescence() {
+        use tokio::io::AsyncWriteExt;
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut conn = handle.connect(addr).await.unwrap();
+            conn.write_all(b"request").await.unwrap();
+            handle
+                .assert_network_quiescent(Duration::from_secs(5))
+                .await;
+
+            let heartbeat_handle = handle.clone();
+            handle.spawn(async move {
+                loop {
+                    heartbeat_handle.delay_from(Duration::from_secs(1)).await;
+                    conn.write_all(b"heartbeat").await.unwrap();
+                }
+            });
+            handle
+                .assert_network_quiescent(Duration::from_secs(5))
+                .await;
+        });
+    }
+
     #[test]
     /// Test that the Tokio global timer and clock are both set correctly.
     fn globals() {
@@ -0,0 +1,161 @@
+//! Propagation of host crashes into pending network operations.
+//!
+//! Each host has an `AbortSignal` which is captured by connects and listeners started on the
+//! host. Crashing the host fires the signal, failing any operation still waiting on it with
+//! `ConnectionAborted`, and replaces it so that operations started after the host restarts are
+//! unaffected.
+//!
+//! Each listener address similarly has a signal captured by connects to the address, which is
+//! fired when the listener is closed, failing connects still waiting on a handshake with
+//! `ConnectionRefused`.
+use futures::{task::Waker, Poll};
+use std::{collections, future::Future, io, pin::Pin, sync, task::Context};
+
+#[derive(Debug, Default)]
+struct State {
+    aborted: bool,
+    /// Wakers of the operations waiting on this signal, keyed by the `Aborted` future which
+    /// registered each, so that each is removed once its operation completes.
+    wakers: collections::HashMap<usize, Waker>,
+    next_key: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AbortSignal {
+    state: sync::Arc<sync::Mutex<State>>,
+    kind: io::ErrorKind,
+    reason: &'static str,
+}
+
+impl Default for AbortSignal {
+    fn default() -> Self {
+        Self::new(io::ErrorKind::ConnectionAborted, "host crashed")
+    }
+}
+
+impl AbortSignal {
+    /// Returns a signal which fails operations waiting on it with an error of `kind`.
+    pub(crate) fn new(kind: io::ErrorKind, reason: &'static str) -> Self {
+        Self {
+            state: sync::Arc::default(),
+            kind,
+            reason,
+        }
+    }
+
+    /// Fire this signal, waking every operation waiting on it.
+    pub(crate) fn abort(&self) {
+        let mut lock = self.state.lock().unwrap();
+        lock.aborted = true;
+        for (_, waker) in lock.wakers.drain() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.state.lock().unwrap().aborted
+    }
+
+    /// Returns a future which fails once this signal is fired. The waker registered by the
+    /// future is removed when it is dropped.
+    pub(crate) fn aborted(&self) -> Aborted {
+        Aborted {
+            signal: self.clone(),
+            key: None,
+        }
+    }
+
+    fn error(&self) -> io::Error {
+        io::Error::new(self.kind, self.reason)
+    }
+}
+
+/// Future returned by [`AbortSignal::aborted`].
+#[derive(Debug)]
+pub(crate) struct Aborted {
+    signal: AbortSignal,
+    /// Key of the waker this future registered with the signal, if any.
+    key: Option<usize>,
+}
+
+impl Aborted {
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.signal.is_aborted()
+    }
+
+    /// Fails with the error of the signal if it has fired.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_aborted() {
+            Err(self.signal.error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Future for Aborted {
+    type Output = io::Error;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let signal = self.signal.clone();
+        let mut lock = signal.state.lock().unwrap();
+        if lock.aborted {
+            self.key = None;
+            return Poll::Ready(signal.error());
+        }
+        match self.key {
+            Some(key) => {
+                let waker = lock.wakers.get_mut(&key).expect("waker was removed");
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let key = lock.next_key;
+                lock.next_key += 1;
+                lock.wakers.insert(key, cx.waker().clone());
+                self.key = Some(key);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Aborted {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if let Ok(mut lock) = self.signal.state.lock() {
+                lock.wakers.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    /// Test that operations which complete without the signal firing remove their wakers.
+    fn completed_waits_removed() {
+        let signal = AbortSignal::default();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..3 {
+            let mut aborted = signal.aborted();
+            assert!(aborted.poll_unpin(&mut cx).is_pending());
+            assert!(aborted.poll_unpin(&mut cx).is_pending());
+            assert_eq!(signal.state.lock().unwrap().wakers.len(), 1);
+        }
+        assert!(signal.state.lock().unwrap().wakers.is_empty());
+
+        let mut aborted = signal.aborted();
+        assert!(aborted.poll_unpin(&mut cx).is_pending());
+        signal.abort();
+        assert_eq!(
+            aborted.poll_unpin(&mut cx).map(|error| error.kind()),
+            Poll::Ready(io::ErrorKind::ConnectionAborted)
+        );
+        assert!(aborted.check().is_err());
+    }
+}
@@ -0,0 +1,49 @@
+//! Simulated name resolution.
+//!
+//! Hosts register names for their address into a registry shared by the whole network, which
+//! [`Resolver`] answers lookups from. Lookups complete immediately and are unaffected by network
+//! faults, but registrations can be changed at any time to exercise clients which re-resolve
+//! names when reconnecting. Lookups of individual names can also be made to fail, modeling an
+//! unavailable or misconfigured service discovery system.
+//!
+//! Like a real resolver, each host can cache answers for a TTL, including negative answers for
+//! names which are not registered, so that a host keeps using an address after it has been
+//! unregistered until its cached answer expires. A resolver can also be made to serve stale
+//! answers past their TTL.
+use super::Inner;
+use async_trait::async_trait;
+use std::{fmt, io, net, sync, time};
+
+/// Answer to a lookup cached by the resolver of a host.
+#[derive(Debug)]
+pub(crate) struct CachedName {
+    pub(crate) answer: Result<Vec<net::IpAddr>, io::ErrorKind>,
+    pub(crate) expires: time::Instant,
+}
+
+/// Resolves names registered with the in-memory network, on behalf of a single host.
+#[derive(Clone)]
+pub struct Resolver {
+    host: net::IpAddr,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Resolver {{ host: {} }}", self.host)
+    }
+}
+
+impl Resolver {
+    pub(crate) fn new(host: net::IpAddr, inner: sync::Arc<sync::Mutex<Inner>>) -> Self {
+        Self { host, inner }
+    }
+}
+
+#[async_trait]
+impl crate::Resolver for Resolver {
+    async fn resolve(&self, name: &str) -> io::Result<Vec<net::IpAddr>> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.resolve(self.host, name)
+    }
+}
@@ -0,0 +1,249 @@
+//! Named presets which configure several fault injectors at once.
+//!
+//! Choosing latency ranges, drop rates and partition frequencies from scratch is a barrier for
+//! new users, and a wall of fault configuration obscures what a test is trying to exercise. A
+//! [`ChaosProfile`] names a set of sensible starting values for a class of network, and a
+//! [`ChaosFaultInjector`] runs the latency, connection drop and partition faults it describes.
+use super::{FaultTarget, Inner, LatencyFaultInjector, PartitionFaultInjector, PartitionShape};
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use std::{fmt, io, net, str, sync, time};
+use tracing::trace;
+
+/// Preset fault configurations, ordered from the most to the least forgiving network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChaosProfile {
+    /// A local network with sub-millisecond latency, no dropped connections and no partitions.
+    Lan,
+    /// A wide area network with tens of milliseconds of latency, rare connection drops and
+    /// infrequent partitions, mostly of single hosts or flapping links.
+    Wan,
+    /// A cloud environment with highly variable latency, occasional connection drops and
+    /// partitions every few minutes.
+    FlakyCloud,
+    /// A hostile network with multi-second latency, frequent connection drops and near
+    /// continuous partitions.
+    Adversarial,
+}
+
+impl ChaosProfile {
+    /// Returns the name of this profile, as accepted by `str::parse`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChaosProfile::Lan => "lan",
+            ChaosProfile::Wan => "wan",
+            ChaosProfile::FlakyCloud => "flaky-cloud",
+            ChaosProfile::Adversarial => "adversarial",
+        }
+    }
+}
+
+impl fmt::Display for ChaosProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl str::FromStr for ChaosProfile {
+    type Err = io::Error;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "lan" => Ok(ChaosProfile::Lan),
+            "wan" => Ok(ChaosProfile::Wan),
+            "flaky-cloud" => Ok(ChaosProfile::FlakyCloud),
+            "adversarial" => Ok(ChaosProfile::Adversarial),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown chaos profile: {}", name),
+            )),
+        }
+    }
+}
+
+/// Disconnects random connections.
+struct ConnectionDropper {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    random_handle: DeterministicRandomHandle,
+    time_handle: DeterministicTimeHandle,
+    probability: f64,
+    target: FaultTarget,
+}
+
+impl ConnectionDropper {
+    async fn run(self) {
+        if self.probability <= 0.0 {
+            return;
+        }
+        loop {
+            self.time_handle
+                .delay_from(time::Duration::from_secs(1))
+                .await;
+            self.drop_connections();
+        }
+    }
+
+    fn drop_connections(&self) {
+        let lock = self.inner.lock().unwrap();
+        for connection in lock
+            .connections
+            .iter()
+            .filter(|connection| self.target.includes(connection.priority()))
+        {
+            if self.random_handle.should_fault(self.probability) {
+                trace!(
+                    "dropping connection {} -> {}",
+                    connection.source(),
+                    connection.dest()
+                );
+                connection.disconnect();
+            }
+        }
+    }
+}
+
+/// Runs the latency, connection drop and partition faults described by a [`ChaosProfile`].
+pub struct ChaosFaultInjector {
+    profile: ChaosProfile,
+    latency: LatencyFaultInjector,
+    partition: PartitionFaultInjector,
+    dropper: ConnectionDropper,
+}
+
+impl ChaosFaultInjector {
+    pub(crate) fn new(
+        profile: ChaosProfile,
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+        time_handle: DeterministicTimeHandle,
+        hosts: Vec<net::IpAddr>,
+    ) -> Self {
+        let latency = LatencyFaultInjector::new(
+            sync::Arc::clone(&inner),
+            random_handle.clone(),
+            time_handle.clone(),
+        );
+        let partition = PartitionFaultInjector::new(
+            sync::Arc::clone(&inner),
+            random_handle.clone(),
+            time_handle.clone(),
+            hosts,
+        );
+        let ms = time::Duration::from_millis;
+        let secs = time::Duration::from_secs;
+        let (latency, partition, drop_probability) = match profile {
+            ChaosProfile::Lan => (
+                latency.latency(ms(0)..ms(1)),
+                partition
+                    .weight(PartitionShape::IsolateNode, 0.0)
+                    .weight(PartitionShape::MinorityGroup, 0.0)
+                    .weight(PartitionShape::BridgeNode, 0.0)
+                    .weight(PartitionShape::FlappingLink, 0.0),
+                0.0,
+            ),
+            ChaosProfile::Wan => (
+                latency.latency(ms(20)..ms(150)),
+                partition
+                    .weight(PartitionShape::BridgeNode, 0.0)
+                    .weight(PartitionShape::FlappingLink, 2.0)
+                    .idle(secs(10 * 60)..secs(30 * 60))
+                    .duration(secs(5)..secs(60)),
+                0.0001,
+            ),
+            ChaosProfile::FlakyCloud => (
+                latency.latency(ms(1)..ms(500)).probability(0.2),
+                partition
+                    .idle(secs(60)..secs(5 * 60))
+                    .duration(secs(10)..secs(60)),
+                0.001,
+            ),
+            ChaosProfile::Adversarial => (
+                latency.latency(ms(0)..secs(10)).probability(0.5),
+                partition
+                    .weight(PartitionShape::BridgeNode, 2.0)
+                    .idle(secs(0)..secs(30))
+                    .duration(secs(1)..secs(30)),
+                0.01,
+            ),
+        };
+        let dropper = ConnectionDropper {
+            inner,
+            random_handle,
+            time_handle,
+            probability: drop_probability,
+            target: FaultTarget::All,
+        };
+        Self {
+            profile,
+            latency,
+            partition,
+            dropper,
+        }
+    }
+
+    pub fn profile(&self) -> ChaosProfile {
+        self.profile
+    }
+
+    /// Probability that each connection is dropped every second.
+    pub fn drop_probability(&self) -> f64 {
+        self.dropper.probability
+    }
+
+    /// Restrict the connections which latency is injected into and which are dropped based on
+    /// their priority. Partitions apply to every connection on a link regardless.
+    pub fn target(mut self, target: FaultTarget) -> Self {
+        self.latency = self.latency.target(target);
+        self.dropper.target = target;
+        self
+    }
+
+    /// Consumes this fault injector and begins injecting the faults described by its profile.
+    pub async fn run(self) {
+        trace!("running {} chaos profile", self.profile);
+        futures::future::join3(self.latency.run(), self.partition.run(), self.dropper.run()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    /// Test that profiles can be looked up by name.
+    fn profile_names() {
+        for profile in [
+            ChaosProfile::Lan,
+            ChaosProfile::Wan,
+            ChaosProfile::FlakyCloud,
+            ChaosProfile::Adversarial,
+        ]
+        .iter()
+        {
+            assert_eq!(profile.name().parse::<ChaosProfile>().unwrap(), *profile);
+        }
+        assert_eq!(
+            "wireless".parse::<ChaosProfile>().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    /// Test that the adversarial profile drops established connections.
+    fn adversarial_drops() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let injector = runtime.chaos_fault(ChaosProfile::Adversarial, vec![]);
+        assert!(injector.drop_probability() > 0.0);
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut conn = handle.connect(addr).await.unwrap();
+            handle.spawn(injector.run());
+            handle.delay_from(time::Duration::from_secs(60 * 60)).await;
+            assert_eq!(
+                conn.write_all(b"ping").await.unwrap_err().kind(),
+                io::ErrorKind::BrokenPipe
+            );
+        });
+    }
+}
@@ -0,0 +1,208 @@
+//! Fault injector which corrupts the bytes delivered to reads.
+//!
+//! TCP checksums catch most corruption on the wire, but corruption introduced by faulty
+//! hardware, middleboxes or bugs above the transport still reaches applications, which must
+//! detect it with checksums and framing validation of their own. The
+//! [`CorruptionFaultInjector`] flips a seeded random bit in reads, and truncates reads by
+//! discarding a seeded random suffix of the bytes they would have returned, so that those
+//! validation paths are exercised.
+use super::{Connection, FaultTarget, Inner};
+use crate::deterministic::DeterministicRandomHandle;
+use std::{net, sync};
+use tracing::trace;
+
+/// Read corruption installed into the network by a [`CorruptionFaultInjector`].
+#[derive(Debug, Clone)]
+pub(crate) struct Corruption {
+    random_handle: DeterministicRandomHandle,
+    flip_probability: f64,
+    truncate_probability: f64,
+    link: Option<(net::IpAddr, net::IpAddr)>,
+    target: FaultTarget,
+}
+
+impl Corruption {
+    /// Returns true if reads on `connection` are corrupted.
+    pub(crate) fn applies(&self, connection: &Connection) -> bool {
+        let on_link = match self.link {
+            Some((a, b)) => connection.is_between(a, b),
+            None => true,
+        };
+        on_link && self.target.includes(connection.priority())
+    }
+
+    /// Corrupt the bytes returned by a read, returning the number of bytes which remain. At
+    /// least one byte of a non-empty read remains, so that truncation is never mistaken for EOF.
+    pub(crate) fn corrupt(&self, read: &mut [u8]) -> usize {
+        let mut len = read.len();
+        if len > 1
+            && self.truncate_probability > 0.0
+            && self.random_handle.should_fault(self.truncate_probability)
+        {
+            len = self.random_handle.gen_range(1..len);
+            trace!("truncating read from {} to {} bytes", read.len(), len);
+        }
+        if len > 0
+            && self.flip_probability > 0.0
+            && self.random_handle.should_fault(self.flip_probability)
+        {
+            let index = self.random_handle.gen_range(0..len);
+            let bit = self.random_handle.gen_range(0..8u8);
+            trace!("flipping bit {} of byte {} of read", bit, index);
+            read[index] ^= 1 << bit;
+        }
+        len
+    }
+}
+
+pub struct CorruptionFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    corruption: Corruption,
+}
+
+impl CorruptionFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+    ) -> Self {
+        Self {
+            inner,
+            corruption: Corruption {
+                random_handle,
+                flip_probability: 0.01,
+                truncate_probability: 0.0,
+                link: None,
+                target: FaultTarget::All,
+            },
+        }
+    }
+
+    /// Probability that a bit of each read is flipped. Defaults to 1%.
+    pub fn flip_probability(mut self, probability: f64) -> Self {
+        self.corruption.flip_probability = probability;
+        self
+    }
+
+    /// Probability that each read is truncated, discarding the remainder of the bytes it would
+    /// have returned. Defaults to 0%.
+    pub fn truncate_probability(mut self, probability: f64) -> Self {
+        self.corruption.truncate_probability = probability;
+        self
+    }
+
+    /// Only corrupt connections between `a` and `b`, initiated by either.
+    pub fn link(mut self, a: net::IpAddr, b: net::IpAddr) -> Self {
+        self.corruption.link = Some((a, b));
+        self
+    }
+
+    /// Restrict the connections which are corrupted based on their priority.
+    pub fn target(mut self, target: FaultTarget) -> Self {
+        self.corruption.target = target;
+        self
+    }
+
+    /// Consumes this fault injector and begins corrupting reads on new and existing
+    /// connections, replacing any previously installed corruption.
+    pub fn install(self) {
+        trace!(
+            "installing read corruption, flip probability {}, truncate probability {}, link {:?}",
+            self.corruption.flip_probability,
+            self.corruption.truncate_probability,
+            self.corruption.link
+        );
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_corruption(Some(self.corruption));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        deterministic::{DeterministicRuntime, FaultTarget, Priority},
+        Environment, TcpListener,
+    };
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const MESSAGE: &[u8] = b"fails its checksum";
+
+    /// Send `MESSAGE` over a connection and return the bytes the server reads before EOF.
+    fn exchange(runtime: &mut DeterministicRuntime) -> Vec<u8> {
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(server_addr).await.unwrap();
+            let mut client_conn = handle.connect(server_addr).await.unwrap();
+            let (mut server_conn, _) = listener.accept().await.unwrap();
+            client_conn.write_all(MESSAGE).await.unwrap();
+            drop(client_conn);
+            let mut received = vec![];
+            server_conn.read_to_end(&mut received).await.unwrap();
+            received
+        })
+    }
+
+    #[test]
+    /// Test that corrupted reads flip a single bit.
+    fn flipped_bits() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.corruption_fault().flip_probability(1.0).install();
+        let received = exchange(&mut runtime);
+        assert_eq!(received.len(), MESSAGE.len());
+        let flipped: u32 = received
+            .iter()
+            .zip(MESSAGE.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(flipped, 1);
+    }
+
+    #[test]
+    /// Test that truncated reads discard the remainder of the bytes they would have returned.
+    fn truncated_reads() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime
+            .corruption_fault()
+            .flip_probability(0.0)
+            .truncate_probability(1.0)
+            .install();
+        let received = exchange(&mut runtime);
+        assert!(!received.is_empty() && received.len() < MESSAGE.len());
+        assert!(MESSAGE.starts_with(&received));
+    }
+
+    #[test]
+    /// Test that connections raised to high priority after corruption is installed are spared
+    /// by a target which spares them.
+    fn spare_high_priority() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime
+            .corruption_fault()
+            .flip_probability(1.0)
+            .target(FaultTarget::SpareHighPriority)
+            .install();
+        runtime.block_on(async {
+            let control: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let data: net::SocketAddr = "127.0.0.1:9093".parse().unwrap();
+            let mut control_listener = handle.bind(control).await.unwrap();
+            let mut data_listener = handle.bind(data).await.unwrap();
+            let mut control_conn = handle.connect(control).await.unwrap();
+            let mut data_conn = handle.connect(data).await.unwrap();
+            handle
+                .network_handle()
+                .set_priority(control, Priority::High);
+            let (mut control_server, _) = control_listener.accept().await.unwrap();
+            let (mut data_server, _) = data_listener.accept().await.unwrap();
+
+            let mut buf = [0; MESSAGE.len()];
+            control_conn.write_all(MESSAGE).await.unwrap();
+            control_server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], MESSAGE);
+            data_conn.write_all(MESSAGE).await.unwrap();
+            data_server.read_exact(&mut buf).await.unwrap();
+            assert_ne!(&buf[..], MESSAGE);
+        });
+    }
+}
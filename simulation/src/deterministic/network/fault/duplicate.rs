@@ -0,0 +1,156 @@
+//! Fault injector which delivers datagrams more than once.
+//!
+//! Datagram transports make no guarantee that a datagram is delivered only once, and a
+//! retransmission at a lower layer or a misbehaving switch can deliver a datagram again after the
+//! original. The [`DuplicationFaultInjector`] delivers a second copy of each datagram with a
+//! seeded probability, after a seeded additional delay, so that protocols which must process
+//! each message exactly once can exercise their deduplication and idempotency handling.
+use super::Inner;
+use crate::deterministic::DeterministicRandomHandle;
+use std::{collections, net, ops, sync, time};
+use tracing::trace;
+
+/// Datagram duplication installed into the network by a [`DuplicationFaultInjector`].
+#[derive(Debug)]
+pub(crate) struct Duplication {
+    random_handle: DeterministicRandomHandle,
+    probability: f64,
+    delay: ops::Range<time::Duration>,
+    links: collections::HashMap<(net::IpAddr, net::IpAddr), f64>,
+}
+
+impl Duplication {
+    /// Returns the additional delay after which a duplicate of a datagram sent from `source` to
+    /// `dest` is delivered, or `None` if it should not be duplicated.
+    pub(crate) fn duplicate_after(
+        &self,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+    ) -> Option<time::Duration> {
+        let probability = self
+            .links
+            .get(&(source, dest))
+            .cloned()
+            .unwrap_or(self.probability);
+        if probability > 0.0 && self.random_handle.should_fault(probability) {
+            if self.delay.start < self.delay.end {
+                Some(self.random_handle.gen_range(self.delay.clone()))
+            } else {
+                Some(self.delay.start)
+            }
+        } else {
+            None
+        }
+    }
+}
+
+pub struct DuplicationFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    duplication: Duplication,
+}
+
+impl DuplicationFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+    ) -> Self {
+        Self {
+            inner,
+            duplication: Duplication {
+                random_handle,
+                probability: 0.01,
+                delay: time::Duration::from_millis(0)..time::Duration::from_millis(100),
+                links: collections::HashMap::new(),
+            },
+        }
+    }
+
+    /// Probability that each datagram is delivered twice. Defaults to 1%.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.duplication.probability = probability;
+        self
+    }
+
+    /// Range from which the time between the delivery of a datagram and its duplicate is
+    /// sampled. Defaults to 0-100ms.
+    pub fn delay(mut self, delay: ops::Range<time::Duration>) -> Self {
+        self.duplication.delay = delay;
+        self
+    }
+
+    /// Probability that each datagram sent from `source` to `dest` is delivered twice,
+    /// overriding the probability for all datagrams. Datagrams sent in the opposite direction
+    /// are unaffected.
+    pub fn link(mut self, source: net::IpAddr, dest: net::IpAddr, probability: f64) -> Self {
+        self.duplication.links.insert((source, dest), probability);
+        self
+    }
+
+    /// Consumes this fault injector and begins duplicating datagrams, replacing the
+    /// configuration of any previously installed duplication fault injector. Installing a fault
+    /// injector with a probability of zero stops datagrams from being duplicated.
+    pub fn install(self) {
+        trace!(
+            "installing datagram duplication, probability {}, {} link overrides",
+            self.duplication.probability,
+            self.duplication.links.len()
+        );
+        self.inner
+            .lock()
+            .unwrap()
+            .set_duplication(Some(self.duplication));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, Environment, UdpSocket};
+    use std::{net, time};
+
+    #[test]
+    /// Test that duplicated datagrams are delivered again after the original.
+    fn duplicated_datagrams() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let a_ip: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b_ip: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let a = runtime.handle(a_ip);
+        let b = runtime.handle(b_ip);
+        let delay = time::Duration::from_millis(10);
+        runtime
+            .duplication_fault()
+            .probability(0.0)
+            .link(a_ip, b_ip, 1.0)
+            .delay(delay..delay)
+            .install();
+        runtime.block_on(async {
+            let mut a = a.bind_udp(net::SocketAddr::new(a_ip, 53)).await.unwrap();
+            let mut b = b.bind_udp(net::SocketAddr::new(b_ip, 53)).await.unwrap();
+            a.send_to(b"first", b.local_addr().unwrap()).await.unwrap();
+            a.send_to(b"second", b.local_addr().unwrap()).await.unwrap();
+            let start = handle.now();
+            let mut received = vec![];
+            let mut buf = [0; 8];
+            let timeout = time::Duration::from_millis(100);
+            while let Ok(Ok((len, _))) = handle.timeout(b.recv_from(&mut buf), timeout).await {
+                received.push((buf[..len].to_vec(), handle.now() - start));
+            }
+            let zero = time::Duration::from_millis(0);
+            assert_eq!(
+                received,
+                vec![
+                    (b"first".to_vec(), zero),
+                    (b"second".to_vec(), zero),
+                    (b"first".to_vec(), delay),
+                    (b"second".to_vec(), delay),
+                ]
+            );
+
+            // datagrams in the opposite direction are not duplicated.
+            b.send_to(b"reply", a.local_addr().unwrap()).await.unwrap();
+            a.recv_from(&mut buf).await.unwrap();
+            let duplicate = handle.timeout(a.recv_from(&mut buf), timeout).await;
+            assert!(duplicate.is_err(), "expected a single reply");
+        });
+    }
+}
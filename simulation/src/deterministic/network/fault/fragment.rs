@@ -0,0 +1,127 @@
+//! Fault injector which fragments the bytes delivered to reads.
+//!
+//! Bytes written to an in memory stream are delivered to the peer in the same chunks they were
+//! written in, so a reader which assumes each read returns a whole message rarely fails. Over a
+//! real network, segments are split and coalesced along the way, and reads regularly return a
+//! fraction of a message. The [`FragmentationFaultInjector`] shortens reads to a seeded random
+//! length, exercising framing and codec logic which must reassemble messages across reads.
+use super::{Connection, FaultTarget, Inner};
+use crate::deterministic::DeterministicRandomHandle;
+use std::{net, sync};
+use tracing::trace;
+
+/// Read fragmentation installed into the network by a [`FragmentationFaultInjector`].
+#[derive(Debug, Clone)]
+pub(crate) struct Fragmentation {
+    random_handle: DeterministicRandomHandle,
+    probability: f64,
+    link: Option<(net::IpAddr, net::IpAddr)>,
+    target: FaultTarget,
+}
+
+impl Fragmentation {
+    /// Returns true if reads on `connection` are fragmented.
+    pub(crate) fn applies(&self, connection: &Connection) -> bool {
+        let on_link = match self.link {
+            Some((a, b)) => connection.is_between(a, b),
+            None => true,
+        };
+        on_link && self.target.includes(connection.priority())
+    }
+
+    /// Returns the number of bytes out of `len` which a read should return.
+    pub(crate) fn read_len(&self, len: usize) -> usize {
+        if len > 1 && self.random_handle.should_fault(self.probability) {
+            self.random_handle.gen_range(1..len)
+        } else {
+            len
+        }
+    }
+}
+
+pub struct FragmentationFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    fragmentation: Fragmentation,
+}
+
+impl FragmentationFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+    ) -> Self {
+        Self {
+            inner,
+            fragmentation: Fragmentation {
+                random_handle,
+                probability: 0.5,
+                link: None,
+                target: FaultTarget::All,
+            },
+        }
+    }
+
+    /// Probability that each read is shortened to a random length. Defaults to 50%.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.fragmentation.probability = probability;
+        self
+    }
+
+    /// Only fragment connections between `a` and `b`, initiated by either.
+    pub fn link(mut self, a: net::IpAddr, b: net::IpAddr) -> Self {
+        self.fragmentation.link = Some((a, b));
+        self
+    }
+
+    /// Restrict the connections which are fragmented based on their priority.
+    pub fn target(mut self, target: FaultTarget) -> Self {
+        self.fragmentation.target = target;
+        self
+    }
+
+    /// Consumes this fault injector and begins fragmenting reads on new and existing
+    /// connections, replacing any previously installed fragmentation.
+    pub fn install(self) {
+        trace!(
+            "installing read fragmentation, probability {}, link {:?}",
+            self.fragmentation.probability,
+            self.fragmentation.link
+        );
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_fragmentation(Some(self.fragmentation));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, Environment, TcpListener};
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that fragmented reads deliver every byte in order, in shorter reads than written.
+    fn fragmented_reads() {
+        let mut runtime = DeterministicRuntime::new_with_seed(7).unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.fragmentation_fault().probability(1.0).install();
+        runtime.block_on(async {
+            let server_addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(server_addr).await.unwrap();
+            let mut client_conn = handle.connect(server_addr).await.unwrap();
+            let (mut server_conn, _) = listener.accept().await.unwrap();
+
+            let message = b"a message split across several reads";
+            client_conn.write_all(message).await.unwrap();
+            // reads into a buffer of the message length are always shortened.
+            let mut buf = vec![0; message.len()];
+            let mut received = vec![];
+            let mut reads = 0;
+            while received.len() < message.len() {
+                let read = server_conn.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..read]);
+                reads += 1;
+            }
+            assert_eq!(&received[..], &message[..]);
+            assert!(reads > 1, "expected message to be fragmented");
+        });
+    }
+}
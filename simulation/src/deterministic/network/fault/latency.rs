@@ -1,11 +1,58 @@
 //! Fault injector which periodically adjusts socket latency.
-use super::Inner;
+//!
+//! Latencies are sampled from a [`LatencyModel`], which can be configured for all connections
+//! and overridden for connections between particular hosts, allowing a simulation to mix fast
+//! local links with slow, long tailed wide area links.
+use super::{FaultTarget, Inner};
 use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
-use std::{ops, sync, time};
+use std::{collections, net, ops, sync, time};
+
+/// Upper bound on sampled latencies, preventing long tailed distributions from producing
+/// latencies which stall a simulation indefinitely.
+const MAX_LATENCY: time::Duration = time::Duration::from_secs(60 * 60);
+
+/// Distribution from which latencies are sampled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LatencyModel {
+    /// Every sample has the same latency.
+    Constant(time::Duration),
+    /// Latencies are sampled uniformly from a range.
+    Uniform(ops::Range<time::Duration>),
+    /// Latencies are normally distributed, with negative samples clamped to zero.
+    Normal {
+        mean: time::Duration,
+        std_dev: time::Duration,
+    },
+    /// Latencies follow a long tailed Pareto distribution, with a minimum latency of `scale`.
+    /// Smaller values of `shape` produce longer tails.
+    Pareto { scale: time::Duration, shape: f64 },
+}
+
+impl LatencyModel {
+    /// Sample a latency using the provided source of randomness.
+    pub fn sample(&self, random_handle: &DeterministicRandomHandle) -> time::Duration {
+        let seconds = match self {
+            LatencyModel::Constant(latency) => return *latency,
+            LatencyModel::Uniform(range) => return random_handle.gen_range(range.clone()),
+            LatencyModel::Normal { mean, std_dev } => {
+                random_handle.normal_dist(mean.as_secs_f64(), std_dev.as_secs_f64())
+            }
+            LatencyModel::Pareto { scale, shape } => {
+                random_handle.pareto(scale.as_secs_f64(), *shape)
+            }
+        };
+        time::Duration::from_secs_f64(seconds.max(0.0).min(MAX_LATENCY.as_secs_f64()))
+    }
+}
 
 pub struct LatencyFaultInjectorConfig {
-    client_latency_range: ops::Range<time::Duration>,
-    server_latency_range: ops::Range<time::Duration>,
+    client_model: LatencyModel,
+    server_model: LatencyModel,
+    /// Models overriding the client and server models for connections from a source host to a
+    /// destination host.
+    links: collections::HashMap<(net::IpAddr, net::IpAddr), LatencyModel>,
+    target: FaultTarget,
+    probability: f64,
 }
 
 pub struct LatencyFaultInjector {
@@ -35,17 +82,57 @@ impl LatencyFaultInjector {
         random_handle: DeterministicRandomHandle,
         time_handle: DeterministicTimeHandle,
     ) -> Self {
+        let default_range = time::Duration::from_secs(0)..time::Duration::from_secs(100);
         Self {
             inner,
             random_handle,
             time_handle,
             config: LatencyFaultInjectorConfig {
-                client_latency_range: time::Duration::from_secs(0)..time::Duration::from_secs(100),
-                server_latency_range: time::Duration::from_secs(0)..time::Duration::from_secs(100),
+                client_model: LatencyModel::Uniform(default_range.clone()),
+                server_model: LatencyModel::Uniform(default_range),
+                links: collections::HashMap::new(),
+                target: FaultTarget::All,
+                probability: 0.1,
             },
         }
     }
 
+    /// Restrict the connections latency is injected into based on their priority.
+    pub fn target(mut self, target: FaultTarget) -> Self {
+        self.config.target = target;
+        self
+    }
+
+    /// Range from which the latency of both client and server connections is sampled.
+    pub fn latency(self, range: ops::Range<time::Duration>) -> Self {
+        self.model(LatencyModel::Uniform(range))
+    }
+
+    /// Model from which the latency of both client and server connections is sampled.
+    pub fn model(mut self, model: LatencyModel) -> Self {
+        self.config.client_model = model.clone();
+        self.config.server_model = model;
+        self
+    }
+
+    /// Model from which the latency of connections from `source` to `dest` is sampled,
+    /// overriding the model for all connections.
+    pub fn link_model(
+        mut self,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+        model: LatencyModel,
+    ) -> Self {
+        self.config.links.insert((source, dest), model);
+        self
+    }
+
+    /// Probability that latencies are adjusted each second.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.config.probability = probability;
+        self
+    }
+
     /// Consumes this fault injector and begins injecting randomized latency into both client and server connections..
     pub async fn run(self) {
         loop {
@@ -53,40 +140,138 @@ impl LatencyFaultInjector {
             self.time_handle
                 .delay_from(time::Duration::from_secs(1))
                 .await;
-            if self.random_handle.should_fault(0.1) {
+            if self.random_handle.should_fault(self.config.probability) {
                 self.inject_latency();
             }
         }
     }
 
-    /// Generate a new client latency value for the provided config.
-    fn client_latency(&self) -> time::Duration {
-        self.random_handle
-            .gen_range(self.config.client_latency_range.clone())
-    }
-
-    /// Generate a new server latency value for the provided config.
-    fn server_latency(&self) -> time::Duration {
-        self.random_handle
-            .gen_range(self.config.server_latency_range.clone())
+    /// Returns the models used for the client and server sides of connections from `source` to
+    /// `dest`.
+    fn models(&self, source: net::IpAddr, dest: net::IpAddr) -> (&LatencyModel, &LatencyModel) {
+        match self.config.links.get(&(source, dest)) {
+            Some(model) => (model, model),
+            None => (&self.config.client_model, &self.config.server_model),
+        }
     }
 
     /// Iterate through all connections, setting a random latency value for both server and client send/receive calls.
     fn inject_latency(&self) {
         let mut lock = self.inner.lock().unwrap();
-        for connection in lock.connections.iter_mut() {
+        let target = self.config.target;
+        for connection in lock
+            .connections
+            .iter_mut()
+            .filter(|connection| target.includes(connection.priority()))
+        {
+            let (client_model, server_model) =
+                self.models(connection.source().ip(), connection.dest().ip());
+            let random = &self.random_handle;
             connection
                 .client_fault_handle
-                .set_receive_latency(self.client_latency());
+                .set_receive_latency(client_model.sample(random));
             connection
                 .client_fault_handle
-                .set_send_latency(self.client_latency());
+                .set_send_latency(client_model.sample(random));
             connection
                 .server_fault_handle
-                .set_receive_latency(self.server_latency());
+                .set_receive_latency(server_model.sample(random));
             connection
                 .server_fault_handle
-                .set_send_latency(self.server_latency());
+                .set_send_latency(server_model.sample(random));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, Priority},
+        Environment,
+    };
+    use std::net;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    /// Test that latency is only injected into connections selected by the fault target.
+    fn priority_target() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let injector = runtime
+            .latency_fault()
+            .target(FaultTarget::OnlyHighPriority);
+        runtime.block_on(async {
+            let control: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let data: net::SocketAddr = "127.0.0.1:9093".parse().unwrap();
+            handle
+                .network_handle()
+                .set_priority(control, Priority::High);
+            let mut control_conn = handle.connect(control).await.unwrap();
+            let mut data_conn = handle.connect(data).await.unwrap();
+            injector.inject_latency();
+
+            let start_time = handle.now();
+            data_conn.write_all(b"ping").await.unwrap();
+            assert_eq!(handle.now(), start_time, "expected data link to be spared");
+            control_conn.write_all(b"ping").await.unwrap();
+            assert!(
+                handle.now() > start_time,
+                "expected control link to be delayed"
+            );
+        });
+    }
+
+    #[test]
+    /// Test that link models override the model for all connections.
+    fn link_model() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let wan = time::Duration::from_millis(80);
+        let local: net::IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let injector = runtime
+            .latency_fault()
+            .model(LatencyModel::Constant(time::Duration::from_secs(0)))
+            .link_model(local, remote, LatencyModel::Constant(wan));
+        runtime.block_on(async {
+            let mut lan_conn = handle
+                .connect("127.0.0.1:9092".parse::<net::SocketAddr>().unwrap())
+                .await
+                .unwrap();
+            let mut wan_conn = handle
+                .connect(net::SocketAddr::new(remote, 9092))
+                .await
+                .unwrap();
+            injector.inject_latency();
+
+            let start_time = handle.now();
+            lan_conn.write_all(b"ping").await.unwrap();
+            assert_eq!(handle.now(), start_time);
+            wan_conn.write_all(b"ping").await.unwrap();
+            assert_eq!(handle.now() - start_time, wan);
+        });
+    }
+
+    #[test]
+    /// Test that samples respect the bounds of each model.
+    fn model_bounds() {
+        let random = crate::deterministic::DeterministicRandom::new_with_seed(3).handle();
+        let ms = time::Duration::from_millis;
+        let normal = LatencyModel::Normal {
+            mean: ms(10),
+            std_dev: ms(50),
+        };
+        let pareto = LatencyModel::Pareto {
+            scale: ms(5),
+            shape: 1.5,
+        };
+        for _ in 0..1000 {
+            assert_eq!(LatencyModel::Constant(ms(3)).sample(&random), ms(3));
+            let uniform = LatencyModel::Uniform(ms(1)..ms(2)).sample(&random);
+            assert!(uniform >= ms(1) && uniform < ms(2));
+            normal.sample(&random);
+            assert!(pareto.sample(&random) >= ms(5));
         }
     }
 }
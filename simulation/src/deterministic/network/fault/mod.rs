@@ -1,20 +1,81 @@
 use super::socket;
 use super::Inner;
 use std::net;
+mod chaos;
+mod corrupt;
+mod duplicate;
+mod fragment;
 mod latency;
+mod packet_loss;
+mod partition;
+mod reset;
+mod slowloris;
 mod swizzle;
-pub use latency::{LatencyFaultInjector, LatencyFaultInjectorConfig};
+pub use chaos::{ChaosFaultInjector, ChaosProfile};
+pub(crate) use corrupt::Corruption;
+pub use corrupt::CorruptionFaultInjector;
+pub(crate) use duplicate::Duplication;
+pub use duplicate::DuplicationFaultInjector;
+pub(crate) use fragment::Fragmentation;
+pub use fragment::FragmentationFaultInjector;
+pub use latency::{LatencyFaultInjector, LatencyFaultInjectorConfig, LatencyModel};
+pub use packet_loss::PacketLossFaultInjector;
+pub(crate) use packet_loss::{PacketLoss, ProbeLoss};
+pub use partition::{Partition, PartitionFaultInjector, PartitionShape};
+pub use reset::ResetFaultInjector;
+pub use slowloris::{SlowlorisFaultInjector, SlowlorisStats};
 pub(crate) use swizzle::CloggedConnection;
 
 const SWIZZLE_START_PROBABILITY: f64 = 0.01;
 const SWIZZLE_SELECTION_PROBABILITY: f64 = 0.30;
 
+/// Priority of a connection, used to model separate control-plane and data-plane links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Priority {
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// Selects the connections a fault injector applies faults to, based on their [`Priority`].
+///
+/// Priority belongs to individual connections, so only injectors which fault individual
+/// connections accept a target: latency, reset, corruption, fragmentation and the connection
+/// drops of chaos profiles. Partitions cut every connection on a link, and datagrams have no
+/// priority, so those faults apply regardless of priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultTarget {
+    /// Fault all connections.
+    All,
+    /// Fault only normal priority connections, sparing high priority connections.
+    SpareHighPriority,
+    /// Fault only high priority connections.
+    OnlyHighPriority,
+}
+
+impl FaultTarget {
+    /// Returns true if connections of the provided priority should be faulted.
+    pub fn includes(self, priority: Priority) -> bool {
+        match self {
+            FaultTarget::All => true,
+            FaultTarget::SpareHighPriority => priority != Priority::High,
+            FaultTarget::OnlyHighPriority => priority == Priority::High,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Connection {
     source: net::SocketAddr,
     dest: net::SocketAddr,
     client_fault_handle: socket::FaultyTcpStreamHandle,
     server_fault_handle: socket::FaultyTcpStreamHandle,
+    priority: Priority,
 }
 
 impl Connection {
@@ -29,6 +90,7 @@ impl Connection {
             dest,
             client_fault_handle,
             server_fault_handle,
+            priority: Priority::default(),
         }
     }
 
@@ -40,25 +102,177 @@ impl Connection {
         self.dest
     }
 
+    pub(crate) fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub(crate) fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Returns true if `stream` is the server side of this connection.
+    pub(crate) fn is_server<T>(&self, stream: &socket::FaultyTcpStream<T>) -> bool {
+        self.server_fault_handle.is_handle_of(stream)
+    }
+
+    /// Returns the local address of the side which closed the connection first, leaving that
+    /// address in TIME_WAIT, and the time at which it closed the connection.
+    pub(crate) fn active_close(&self) -> Option<(net::SocketAddr, std::time::Instant)> {
+        let client = self.client_fault_handle.dropped_at();
+        let server = self.server_fault_handle.dropped_at();
+        match (client, server) {
+            (Some(client), Some(server)) if server < client => Some((self.dest(), server)),
+            (Some(client), _) => Some((self.source(), client)),
+            (None, Some(server)) => Some((self.dest(), server)),
+            (None, None) => None,
+        }
+    }
+
     pub(crate) fn is_dropped(&self) -> bool {
         self.client_fault_handle.is_dropped() || self.server_fault_handle.is_dropped()
     }
 
+    pub(crate) fn disconnect(&self) {
+        self.client_fault_handle.disconnect();
+        self.server_fault_handle.disconnect();
+    }
+
+    /// Leave this connection half open, with `survivor` believing it is still alive while the
+    /// peer has dropped it.
+    pub(crate) fn half_open(&self, survivor: net::IpAddr) {
+        let (survivor, peer) = self.direction(survivor);
+        survivor.black_hole();
+        peer.disconnect();
+    }
+
+    pub(crate) fn reset(&self) {
+        self.client_fault_handle.reset();
+        self.server_fault_handle.reset();
+    }
+
     pub(crate) fn is_clogged(&self) -> bool {
         self.client_fault_handle.is_fully_clogged() && self.server_fault_handle.is_fully_clogged()
     }
 
-    pub(crate) fn clog(&mut self) {
-        self.client_fault_handle.clog_sends();
-        self.client_fault_handle.clog_receives();
-        self.server_fault_handle.clog_sends();
-        self.server_fault_handle.clog_receives();
+    /// Clog or unclog each direction of this connection, waking any reads or writes blocked on
+    /// a direction which changed.
+    pub(crate) fn set_clogged(&self, client_to_server: bool, server_to_client: bool) {
+        let set = |sender: &socket::FaultyTcpStreamHandle,
+                   receiver: &socket::FaultyTcpStreamHandle,
+                   clogged: bool| {
+            if clogged {
+                sender.clog_sends();
+                receiver.clog_receives();
+            } else {
+                sender.unclog_sends();
+                receiver.unclog_receives();
+            }
+        };
+        set(
+            &self.client_fault_handle,
+            &self.server_fault_handle,
+            client_to_server,
+        );
+        set(
+            &self.server_fault_handle,
+            &self.client_fault_handle,
+            server_to_client,
+        );
+    }
+
+    /// Returns true if this connection is between `a` and `b`, initiated by either.
+    pub(crate) fn is_between(&self, a: net::IpAddr, b: net::IpAddr) -> bool {
+        let (source, dest) = (self.source.ip(), self.dest.ip());
+        (source, dest) == (a, b) || (source, dest) == (b, a)
+    }
+
+    /// Returns the fault handles of the sending and receiving sides for traffic sent by `from`.
+    fn direction(
+        &self,
+        from: net::IpAddr,
+    ) -> (
+        &socket::FaultyTcpStreamHandle,
+        &socket::FaultyTcpStreamHandle,
+    ) {
+        if from == self.source.ip() {
+            (&self.client_fault_handle, &self.server_fault_handle)
+        } else {
+            (&self.server_fault_handle, &self.client_fault_handle)
+        }
+    }
+
+    /// Set the latency of traffic sent by `from`, leaving the opposite direction unchanged.
+    /// Passing `None` restores the latency the connection had without it.
+    pub(crate) fn set_latency_from(&self, from: net::IpAddr, latency: Option<std::time::Duration>) {
+        let (sender, _) = self.direction(from);
+        sender.set_link_latency(latency);
+    }
+
+    /// Limit the bandwidth of each direction of this connection.
+    pub(crate) fn set_bandwidth(&self, bytes_per_second: Option<u64>) {
+        self.client_fault_handle.set_bandwidth(bytes_per_second);
+        self.server_fault_handle.set_bandwidth(bytes_per_second);
+    }
+
+    /// Fragment reads on this connection if it is selected by `fragmentation`, and stop
+    /// fragmenting them otherwise.
+    pub(crate) fn set_fragmentation(&self, fragmentation: Option<&Fragmentation>) {
+        let applied = fragmentation.filter(|fragmentation| fragmentation.applies(self));
+        self.client_fault_handle.set_fragmentation(applied.cloned());
+        self.server_fault_handle.set_fragmentation(applied.cloned());
+    }
+
+    /// Corrupt reads on this connection if it is selected by `corruption`, and stop corrupting
+    /// them otherwise.
+    pub(crate) fn set_corruption(&self, corruption: Option<&Corruption>) {
+        let applied = corruption.filter(|corruption| corruption.applies(self));
+        self.client_fault_handle.set_corruption(applied.cloned());
+        self.server_fault_handle.set_corruption(applied.cloned());
+    }
+
+    /// Lose keepalive probes sent on this connection according to `loss`, or never if `None`.
+    pub(crate) fn set_probe_loss(&self, loss: Option<&PacketLoss>) {
+        let (source, dest) = (self.source.ip(), self.dest.ip());
+        let probe_loss = |local, peer| loss.map(|loss| ProbeLoss::new(loss.clone(), local, peer));
+        self.client_fault_handle
+            .set_probe_loss(probe_loss(source, dest));
+        self.server_fault_handle
+            .set_probe_loss(probe_loss(dest, source));
+    }
+
+    pub(crate) fn set_mtu(&self, mtu: Option<usize>) {
+        self.client_fault_handle.set_mtu(mtu);
+        self.server_fault_handle.set_mtu(mtu);
+    }
+
+    /// Reset the connection once no bytes have been exchanged in either direction for
+    /// `timeout`, measured from `now`, or never if `None`.
+    pub(crate) fn set_idle_timeout(
+        &self,
+        timeout: Option<std::time::Duration>,
+        now: std::time::Instant,
+    ) {
+        let idle_timeout = timeout.map(|timeout| {
+            let idle_timeout = socket::IdleTimeout::new(timeout, now);
+            std::sync::Arc::new(std::sync::Mutex::new(idle_timeout))
+        });
+        self.client_fault_handle
+            .set_idle_timeout(idle_timeout.clone());
+        self.server_fault_handle.set_idle_timeout(idle_timeout);
+    }
+
+    pub(crate) fn set_flush_mode(&self, mode: socket::FlushMode) {
+        self.client_fault_handle.set_flush_mode(mode);
+        self.server_fault_handle.set_flush_mode(mode);
+    }
+
+    /// Record all bytes subsequently sent by the client to the provided log.
+    pub(crate) fn capture_client(&self, log: std::sync::Arc<std::sync::Mutex<Vec<u8>>>) {
+        self.client_fault_handle.capture(log);
     }
 
-    pub(crate) fn unclog(&mut self) {
-        self.client_fault_handle.unclog_sends();
-        self.client_fault_handle.unclog_receives();
-        self.server_fault_handle.unclog_sends();
-        self.server_fault_handle.unclog_receives();
+    /// Inject bytes into the server side of the connection, as if sent by the client.
+    pub(crate) fn inject_server(&self, bytes: &[u8]) {
+        self.server_fault_handle.inject(bytes);
     }
 }
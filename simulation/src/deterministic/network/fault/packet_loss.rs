@@ -0,0 +1,163 @@
+//! Fault injector which drops datagrams.
+//!
+//! Unlike stream connections, datagram transports make no delivery guarantees, and protocols
+//! built on them must retry lost requests themselves. The [`PacketLossFaultInjector`] drops each
+//! datagram with a seeded probability, which can be configured for all traffic and overridden
+//! for traffic from one host to another, so that retry logic can be exercised against both
+//! uniformly lossy networks and individual bad links. The keepalive probes of stream
+//! connections are datagrams too, and are lost along with other datagrams.
+use super::Inner;
+use crate::deterministic::DeterministicRandomHandle;
+use std::{collections, net, sync};
+use tracing::trace;
+
+/// Datagram loss probabilities installed into the network by a [`PacketLossFaultInjector`].
+#[derive(Debug, Clone)]
+pub(crate) struct PacketLoss {
+    random_handle: DeterministicRandomHandle,
+    probability: f64,
+    links: collections::HashMap<(net::IpAddr, net::IpAddr), f64>,
+}
+
+impl PacketLoss {
+    pub(crate) fn new(random_handle: DeterministicRandomHandle, probability: f64) -> Self {
+        Self {
+            random_handle,
+            probability,
+            links: collections::HashMap::new(),
+        }
+    }
+
+    /// Override the probability that datagrams sent from `source` to `dest` are dropped.
+    pub(crate) fn set_link(&mut self, source: net::IpAddr, dest: net::IpAddr, probability: f64) {
+        self.links.insert((source, dest), probability);
+    }
+
+    /// Returns true if a datagram sent from `source` to `dest` should be dropped.
+    pub(crate) fn should_drop(&self, source: net::IpAddr, dest: net::IpAddr) -> bool {
+        let probability = self
+            .links
+            .get(&(source, dest))
+            .cloned()
+            .unwrap_or(self.probability);
+        probability > 0.0 && self.random_handle.should_fault(probability)
+    }
+}
+
+/// Loss of the keepalive probes sent by one side of a connection, and of the acknowledgements
+/// of those probes.
+#[derive(Debug, Clone)]
+pub(crate) struct ProbeLoss {
+    loss: PacketLoss,
+    local: net::IpAddr,
+    peer: net::IpAddr,
+}
+
+impl ProbeLoss {
+    pub(crate) fn new(loss: PacketLoss, local: net::IpAddr, peer: net::IpAddr) -> Self {
+        Self { loss, local, peer }
+    }
+
+    /// Returns true if a probe or its acknowledgement is dropped.
+    pub(crate) fn is_lost(&self) -> bool {
+        self.loss.should_drop(self.local, self.peer) || self.loss.should_drop(self.peer, self.local)
+    }
+}
+
+pub struct PacketLossFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    loss: PacketLoss,
+}
+
+impl PacketLossFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+    ) -> Self {
+        Self {
+            inner,
+            loss: PacketLoss::new(random_handle, 0.01),
+        }
+    }
+
+    /// Probability that each datagram is dropped. Defaults to 1%.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.loss.probability = probability;
+        self
+    }
+
+    /// Probability that each datagram sent from `source` to `dest` is dropped, overriding the
+    /// probability for all datagrams. Datagrams sent in the opposite direction are unaffected.
+    pub fn link(mut self, source: net::IpAddr, dest: net::IpAddr, probability: f64) -> Self {
+        self.loss.set_link(source, dest, probability);
+        self
+    }
+
+    /// Consumes this fault injector and begins dropping datagrams, replacing the configuration
+    /// of any previously installed packet loss fault injector. Installing a fault injector with
+    /// a probability of zero stops datagrams from being dropped.
+    pub fn install(self) {
+        trace!(
+            "installing packet loss, probability {}, {} link overrides",
+            self.loss.probability,
+            self.loss.links.len()
+        );
+        self.inner.lock().unwrap().set_packet_loss(Some(self.loss));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        deterministic::{DeterministicRuntime, DeterministicRuntimeHandle},
+        Environment, UdpSocket,
+    };
+    use std::{net, time};
+
+    /// Send `count` datagrams from `from` to `to`, returning the number received.
+    async fn delivered(
+        handle: &DeterministicRuntimeHandle,
+        from: &mut impl UdpSocket,
+        to: &mut impl UdpSocket,
+        count: usize,
+    ) -> usize {
+        let dest = to.local_addr().unwrap();
+        for _ in 0..count {
+            from.send_to(b"ping", dest).await.unwrap();
+        }
+        let mut buf = [0; 4];
+        let mut received = 0;
+        let timeout = time::Duration::from_millis(1);
+        while let Ok(Ok(_)) = handle.timeout(to.recv_from(&mut buf), timeout).await {
+            received += 1;
+        }
+        received
+    }
+
+    #[test]
+    /// Test that link probabilities override the probability for all datagrams in one direction.
+    fn link_loss() {
+        let mut runtime = DeterministicRuntime::new_with_seed(5).unwrap();
+        let handle = runtime.localhost_handle();
+        let a_ip: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b_ip: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let a = runtime.handle(a_ip);
+        let b = runtime.handle(b_ip);
+        runtime
+            .packet_loss_fault()
+            .probability(0.5)
+            .link(a_ip, b_ip, 1.0)
+            .install();
+        runtime.block_on(async {
+            let mut a = a.bind_udp(net::SocketAddr::new(a_ip, 53)).await.unwrap();
+            let mut b = b.bind_udp(net::SocketAddr::new(b_ip, 53)).await.unwrap();
+            assert_eq!(delivered(&handle, &mut a, &mut b, 100).await, 0);
+            let received = delivered(&handle, &mut b, &mut a, 100).await;
+            assert!(
+                received > 25 && received < 75,
+                "expected about half of datagrams to be dropped, received {}",
+                received
+            );
+        });
+    }
+}
@@ -0,0 +1,331 @@
+//! Fault injector which partitions hosts into realistic shapes.
+//!
+//! Uniformly random link cuts rarely produce the partitions which exercise consensus edge
+//! cases. Instead, each partition is drawn from one of a few shapes observed in production,
+//! selected using seed controlled weights:
+//!
+//! - A single host isolated from all others.
+//! - A minority group of hosts isolated from the majority.
+//! - Two groups which can only reach each other through a single bridge host.
+//! - A single link which repeatedly fails and recovers.
+use super::Inner;
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use std::{net, ops, sync, time};
+use tracing::trace;
+
+/// Shapes of partition produced by a [`PartitionFaultInjector`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionShape {
+    /// A single host is isolated from all other hosts.
+    IsolateNode,
+    /// A minority of hosts are isolated from the majority.
+    MinorityGroup,
+    /// Hosts are split into two groups, with a single bridge host able to reach both.
+    BridgeNode,
+    /// The link between two hosts repeatedly fails and recovers.
+    FlappingLink,
+}
+
+/// A partition generated by a [`PartitionFaultInjector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    shape: PartitionShape,
+    cut: Vec<(net::IpAddr, net::IpAddr)>,
+}
+
+impl Partition {
+    pub fn shape(&self) -> PartitionShape {
+        self.shape
+    }
+
+    /// Returns the links which are cut by this partition. Links are cut in both directions.
+    pub fn cut(&self) -> &[(net::IpAddr, net::IpAddr)] {
+        &self.cut
+    }
+
+    /// Returns true if this partition prevents `a` from communicating directly with `b`.
+    pub fn separates(&self, a: net::IpAddr, b: net::IpAddr) -> bool {
+        self.cut
+            .iter()
+            .any(|link| *link == (a, b) || *link == (b, a))
+    }
+}
+
+pub struct PartitionFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    random_handle: DeterministicRandomHandle,
+    time_handle: DeterministicTimeHandle,
+    hosts: Vec<net::IpAddr>,
+    weights: [(PartitionShape, f64); 4],
+    idle: ops::Range<time::Duration>,
+    duration: ops::Range<time::Duration>,
+}
+
+impl PartitionFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+        time_handle: DeterministicTimeHandle,
+        hosts: Vec<net::IpAddr>,
+    ) -> Self {
+        Self {
+            inner,
+            random_handle,
+            time_handle,
+            hosts,
+            weights: [
+                (PartitionShape::IsolateNode, 1.0),
+                (PartitionShape::MinorityGroup, 1.0),
+                (PartitionShape::BridgeNode, 1.0),
+                (PartitionShape::FlappingLink, 1.0),
+            ],
+            idle: time::Duration::from_secs(0)..time::Duration::from_secs(120),
+            duration: time::Duration::from_secs(10)..time::Duration::from_secs(60),
+        }
+    }
+
+    /// Set the relative weight with which partitions of `shape` are generated. All shapes have a
+    /// weight of 1 by default, a weight of 0 disables a shape.
+    pub fn weight(mut self, shape: PartitionShape, weight: f64) -> Self {
+        for (s, w) in self.weights.iter_mut() {
+            if *s == shape {
+                *w = weight;
+            }
+        }
+        self
+    }
+
+    /// Range from which the time between partitions is sampled.
+    pub fn idle(mut self, idle: ops::Range<time::Duration>) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// Range from which the time a partition lasts before healing is sampled.
+    pub fn duration(mut self, duration: ops::Range<time::Duration>) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Generate the next partition. Shapes which need more hosts than are available are skipped.
+    pub fn next_partition(&self) -> Option<Partition> {
+        let available: Vec<_> = self
+            .weights
+            .iter()
+            .filter(|(shape, weight)| *weight > 0.0 && self.hosts.len() >= min_hosts(*shape))
+            .cloned()
+            .collect();
+        let total: f64 = available.iter().map(|(_, weight)| weight).sum();
+        if available.is_empty() {
+            return None;
+        }
+        let mut roll = self.random_handle.gen_range(0.0..total);
+        let mut shape = available[available.len() - 1].0;
+        for (candidate, weight) in available.iter() {
+            if roll < *weight {
+                shape = *candidate;
+                break;
+            }
+            roll -= weight;
+        }
+
+        let mut hosts = self.hosts.clone();
+        self.shuffle(&mut hosts);
+        let cut = match shape {
+            PartitionShape::IsolateNode => between(&hosts[..1], &hosts[1..]),
+            PartitionShape::MinorityGroup => {
+                let minority = self.random_handle.gen_range(1..(hosts.len() + 1) / 2);
+                between(&hosts[..minority], &hosts[minority..])
+            }
+            PartitionShape::BridgeNode => {
+                // hosts[0] is the bridge, the remaining hosts are split into two groups.
+                let split = self.random_handle.gen_range(2..hosts.len());
+                between(&hosts[1..split], &hosts[split..])
+            }
+            PartitionShape::FlappingLink => between(&hosts[..1], &hosts[1..2]),
+        };
+        Some(Partition { shape, cut })
+    }
+
+    /// Consumes this fault injector and begins partitioning hosts.
+    pub async fn run(self) {
+        loop {
+            let idle = self.random_handle.gen_range(self.idle.clone());
+            self.time_handle.delay_from(idle).await;
+            let partition = match self.next_partition() {
+                Some(partition) => partition,
+                None => return,
+            };
+            trace!("partitioning {:?}", partition);
+            let duration = self.random_handle.gen_range(self.duration.clone());
+            if partition.shape == PartitionShape::FlappingLink {
+                let end = self.time_handle.now() + duration;
+                while self.time_handle.now() < end {
+                    self.apply(&partition, true);
+                    let flap = self
+                        .random_handle
+                        .gen_range(time::Duration::from_millis(100)..time::Duration::from_secs(5));
+                    self.time_handle.delay_from(flap).await;
+                    self.apply(&partition, false);
+                    let flap = self
+                        .random_handle
+                        .gen_range(time::Duration::from_millis(100)..time::Duration::from_secs(5));
+                    self.time_handle.delay_from(flap).await;
+                }
+            } else {
+                self.apply(&partition, true);
+                self.time_handle.delay_from(duration).await;
+                self.apply(&partition, false);
+            }
+            trace!("healed {:?}", partition);
+        }
+    }
+
+    /// Cut or restore the links of `partition`. Links are cut as partitions rather than
+    /// clogged, so that restoring them leaves clogs and other partitions of the link in place.
+    fn apply(&self, partition: &Partition, cut: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        for (a, b) in partition.cut.iter() {
+            for (source, dest) in [(*a, *b), (*b, *a)].iter() {
+                if cut {
+                    lock.cut_link(*source, *dest);
+                } else {
+                    lock.restore_link(*source, *dest);
+                }
+            }
+        }
+    }
+
+    fn shuffle(&self, hosts: &mut [net::IpAddr]) {
+        for i in (1..hosts.len()).rev() {
+            let j = self.random_handle.gen_range(0..i + 1);
+            hosts.swap(i, j);
+        }
+    }
+}
+
+/// Returns the minimum number of hosts required to generate a partition of `shape`.
+fn min_hosts(shape: PartitionShape) -> usize {
+    match shape {
+        PartitionShape::IsolateNode | PartitionShape::FlappingLink => 2,
+        PartitionShape::MinorityGroup => 3,
+        PartitionShape::BridgeNode => 3,
+    }
+}
+
+/// Returns every link between a host in `a` and a host in `b`.
+fn between(a: &[net::IpAddr], b: &[net::IpAddr]) -> Vec<(net::IpAddr, net::IpAddr)> {
+    a.iter()
+        .flat_map(|a| b.iter().map(move |b| (*a, *b)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{network::fault::CloggedConnection, DeterministicRuntime},
+        Environment, TcpListener,
+    };
+    use futures::future;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn hosts(count: u8) -> Vec<net::IpAddr> {
+        (1..=count)
+            .map(|i| net::Ipv4Addr::new(10, 0, 0, i).into())
+            .collect()
+    }
+
+    #[test]
+    /// Test that bridge partitions leave a single host able to reach both groups.
+    fn bridge_shape() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        let injector = runtime
+            .partition_fault(hosts(5))
+            .weight(PartitionShape::IsolateNode, 0.0)
+            .weight(PartitionShape::MinorityGroup, 0.0)
+            .weight(PartitionShape::FlappingLink, 0.0);
+        for _ in 0..10 {
+            let partition = injector.next_partition().unwrap();
+            assert_eq!(partition.shape(), PartitionShape::BridgeNode);
+            let bridges: Vec<_> = hosts(5)
+                .into_iter()
+                .filter(|host| !partition.cut().iter().any(|(a, b)| a == host || b == host))
+                .collect();
+            assert_eq!(bridges.len(), 1);
+        }
+    }
+
+    #[test]
+    /// Test that minority partitions never isolate a majority of hosts.
+    fn minority_shape() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        let injector = runtime
+            .partition_fault(hosts(5))
+            .weight(PartitionShape::IsolateNode, 0.0)
+            .weight(PartitionShape::BridgeNode, 0.0)
+            .weight(PartitionShape::FlappingLink, 0.0);
+        for _ in 0..10 {
+            let partition = injector.next_partition().unwrap();
+            let isolated = partition.cut().first().unwrap().0;
+            let minority = partition
+                .cut()
+                .iter()
+                .filter(|(_, b)| *b == partition.cut()[0].1)
+                .count();
+            assert!(minority <= 2, "expected minority, got {} hosts", minority);
+            assert!(partition.separates(isolated, partition.cut()[0].1));
+        }
+    }
+
+    #[test]
+    /// Test that restoring a generated partition leaves clogs and other partitions of its
+    /// links in place, so that existing connections across them still stall.
+    fn restore_keeps_other_faults() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let (a, b, c) = (hosts(3)[0], hosts(3)[1], hosts(3)[2]);
+        let (a_addr, c_addr) = (net::SocketAddr::new(a, 9092), net::SocketAddr::new(c, 9092));
+        let (a_handle, b_handle, c_handle) =
+            (runtime.handle(a), runtime.handle(b), runtime.handle(c));
+        let injector = runtime.partition_fault(hosts(3));
+        let partition = Partition {
+            shape: PartitionShape::IsolateNode,
+            cut: vec![(a, b), (a, c)],
+        };
+        runtime.block_on(async {
+            let mut a_listener = a_handle.bind(a_addr).await.unwrap();
+            let mut c_listener = c_handle.bind(c_addr).await.unwrap();
+            let mut b_to_a = b_handle.connect(a_addr).await.unwrap();
+            let (mut a_from_b, _) = a_listener.accept().await.unwrap();
+            let mut a_to_c = a_handle.connect(c_addr).await.unwrap();
+            let (mut c_from_a, _) = c_listener.accept().await.unwrap();
+
+            a_handle.network_handle().partition(&[a], &[b]);
+            injector
+                .inner
+                .lock()
+                .unwrap()
+                .clog_connection(CloggedConnection::new(a, c));
+            injector.apply(&partition, true);
+            injector.apply(&partition, false);
+
+            let timeout = time::Duration::from_secs(10);
+            let mut buf = [0; 4];
+            let exchange = future::join(b_to_a.write_all(b"ping"), a_from_b.read_exact(&mut buf));
+            let exchanged = a_handle.timeout(exchange, timeout).await;
+            assert!(exchanged.is_err(), "expected traffic from b to a to stall");
+            let exchange = future::join(a_from_b.write_all(b"ping"), b_to_a.read_exact(&mut buf));
+            let exchanged = a_handle.timeout(exchange, timeout).await;
+            assert!(exchanged.is_err(), "expected traffic from a to b to stall");
+
+            let exchange = future::join(a_to_c.write_all(b"ping"), c_from_a.read_exact(&mut buf));
+            let exchanged = a_handle.timeout(exchange, timeout).await;
+            assert!(exchanged.is_err(), "expected traffic from a to c to stall");
+            let exchange = future::join(c_from_a.write_all(b"pong"), a_to_c.read_exact(&mut buf));
+            let (written, read) = a_handle.timeout(exchange, timeout).await.unwrap();
+            written.unwrap();
+            read.unwrap();
+            assert_eq!(&buf, b"pong");
+        });
+    }
+}
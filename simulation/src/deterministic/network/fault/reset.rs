@@ -0,0 +1,146 @@
+//! Fault injector which resets established connections.
+//!
+//! Latency and partitions leave connections intact, so they rarely exercise the paths which
+//! handle a connection failing mid-stream. The [`ResetFaultInjector`] periodically severs
+//! established connections with a seeded probability. Reads from a reset connection fail with
+//! `ConnectionReset` and writes fail with `BrokenPipe`, exercising reconnect and request
+//! idempotency logic.
+use super::{FaultTarget, Inner};
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use std::{net, sync, time};
+use tracing::trace;
+
+pub struct ResetFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    random_handle: DeterministicRandomHandle,
+    time_handle: DeterministicTimeHandle,
+    interval: time::Duration,
+    probability: f64,
+    target: FaultTarget,
+    link: Option<(net::IpAddr, net::IpAddr)>,
+}
+
+impl ResetFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+        time_handle: DeterministicTimeHandle,
+    ) -> Self {
+        Self {
+            inner,
+            random_handle,
+            time_handle,
+            interval: time::Duration::from_secs(1),
+            probability: 0.05,
+            target: FaultTarget::All,
+            link: None,
+        }
+    }
+
+    /// Interval between each round of resets. Defaults to one second.
+    pub fn interval(mut self, interval: time::Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Probability that each connection is reset in a round. Defaults to 5%.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    /// Restrict the connections which are reset based on their priority.
+    pub fn target(mut self, target: FaultTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Only reset connections between `a` and `b`, initiated by either.
+    pub fn link(mut self, a: net::IpAddr, b: net::IpAddr) -> Self {
+        self.link = Some((a, b));
+        self
+    }
+
+    /// Consumes this fault injector and begins resetting connections.
+    pub async fn run(self) {
+        loop {
+            self.time_handle.delay_from(self.interval).await;
+            self.reset_connections();
+        }
+    }
+
+    /// Reset each selected connection with the configured probability, returning the number of
+    /// connections reset.
+    fn reset_connections(&self) -> usize {
+        let lock = self.inner.lock().unwrap();
+        let mut reset = 0;
+        for connection in lock
+            .connections
+            .iter()
+            .filter(|connection| self.target.includes(connection.priority()))
+            .filter(|connection| match self.link {
+                Some((a, b)) => connection.is_between(a, b),
+                None => true,
+            })
+        {
+            if self.random_handle.should_fault(self.probability) {
+                trace!(
+                    "resetting connection {} -> {}",
+                    connection.source(),
+                    connection.dest()
+                );
+                connection.reset();
+                reset += 1;
+            }
+        }
+        reset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, Environment, TcpListener};
+    use std::{io, net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that reset connections fail pending and subsequent reads and writes, and that
+    /// connections outside the link are spared.
+    fn reset_link() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let client_ip: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let server_addr: net::SocketAddr = "10.0.0.2:9092".parse().unwrap();
+        let other_addr: net::SocketAddr = "10.0.0.3:9092".parse().unwrap();
+        let client = runtime.handle(client_ip);
+        let server = runtime.handle(server_addr.ip());
+        let other = runtime.handle(other_addr.ip());
+        let injector = runtime
+            .reset_fault()
+            .probability(1.0)
+            .link(client_ip, server_addr.ip());
+        runtime.block_on(async {
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let _other_listener = other.bind(other_addr).await.unwrap();
+            let mut client_conn = client.connect(server_addr).await.unwrap();
+            let mut other_conn = client.connect(other_addr).await.unwrap();
+            let (mut server_conn, _) = listener.accept().await.unwrap();
+            let pending_read = crate::spawn_with_result(&server, async move {
+                let mut buf = [0; 4];
+                server_conn.read(&mut buf).await
+            });
+
+            // Allow the read to begin waiting before resetting the connection.
+            client.delay_from(time::Duration::from_millis(1)).await;
+            assert_eq!(injector.reset_connections(), 1);
+            assert_eq!(
+                pending_read.await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionReset
+            );
+            assert_eq!(
+                client_conn.write_all(b"ping").await.unwrap_err().kind(),
+                io::ErrorKind::BrokenPipe
+            );
+            other_conn.write_all(b"ping").await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,193 @@
+//! Fault injector which holds connections open while sending little or nothing.
+//!
+//! Slow clients tie up server resources such as connection slots, buffers and tasks for as long
+//! as the server lets them. The [`SlowlorisFaultInjector`] opens connections from a host to a
+//! server and trickles bytes at a fixed interval, or never sends anything, so that a server's
+//! idle connection reaping and connection limits are exercised. Connections closed by the server
+//! are counted and replaced.
+use super::Inner;
+use crate::deterministic::{network::DeterministicNetworkHandle, DeterministicTimeHandle};
+use std::{
+    net,
+    sync::{self, atomic},
+    time,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::trace;
+
+/// Time waited after a connection is closed by the server before it is reopened.
+const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(1);
+
+/// Counters describing the connections opened by a [`SlowlorisFaultInjector`].
+#[derive(Debug, Clone, Default)]
+pub struct SlowlorisStats {
+    opened: sync::Arc<atomic::AtomicU64>,
+    reaped: sync::Arc<atomic::AtomicU64>,
+}
+
+impl SlowlorisStats {
+    /// Returns the number of connections opened.
+    pub fn opened(&self) -> u64 {
+        self.opened.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Returns the number of connections closed by the server.
+    pub fn reaped(&self) -> u64 {
+        self.reaped.load(atomic::Ordering::SeqCst)
+    }
+}
+
+pub struct SlowlorisFaultInjector {
+    network: DeterministicNetworkHandle,
+    time_handle: DeterministicTimeHandle,
+    target: net::SocketAddr,
+    connections: usize,
+    trickle: Option<time::Duration>,
+    payload: Vec<u8>,
+    stats: SlowlorisStats,
+}
+
+impl SlowlorisFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        time_handle: DeterministicTimeHandle,
+        source: net::IpAddr,
+        target: net::SocketAddr,
+    ) -> Self {
+        Self {
+            network: DeterministicNetworkHandle::new(source, inner),
+            time_handle,
+            target,
+            connections: 16,
+            trickle: Some(time::Duration::from_secs(10)),
+            payload: b"GET / HTTP/1.1\r\n".to_vec(),
+            stats: SlowlorisStats::default(),
+        }
+    }
+
+    /// Number of connections to hold open at once. Defaults to 16.
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Interval between each byte sent, or `None` to never send anything after connecting.
+    /// Defaults to one byte every 10 seconds.
+    pub fn trickle(mut self, interval: Option<time::Duration>) -> Self {
+        self.trickle = interval;
+        self
+    }
+
+    /// Bytes to trickle, repeated once exhausted. Defaults to the start of an HTTP request
+    /// which is never completed.
+    pub fn payload(mut self, payload: &[u8]) -> Self {
+        assert!(!payload.is_empty(), "slowloris payload must not be empty");
+        self.payload = payload.to_vec();
+        self
+    }
+
+    pub fn stats(&self) -> SlowlorisStats {
+        self.stats.clone()
+    }
+
+    /// Consumes this fault injector and begins holding connections open to the target, reopening
+    /// each connection a second after it is closed by the server. Returns if a connection cannot
+    /// be established.
+    pub async fn run(self) {
+        let connections = (0..self.connections).map(|_| self.hold_connections());
+        futures::future::join_all(connections).await;
+    }
+
+    async fn hold_connections(&self) {
+        loop {
+            let mut stream = match self.network.connect(self.target).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    trace!("slowloris failed to connect to {}: {}", self.target, e);
+                    return;
+                }
+            };
+            self.stats.opened.fetch_add(1, atomic::Ordering::SeqCst);
+            let mut buf = [0; 1024];
+            let mut payload = self.payload.iter().cycle();
+            loop {
+                let read = stream.read(&mut buf);
+                let read = match self.trickle {
+                    Some(interval) => self.time_handle.timeout(read, interval).await.ok(),
+                    None => Some(read.await),
+                };
+                match read {
+                    Some(Ok(0)) | Some(Err(_)) => break,
+                    // Responses are read and discarded so the server is never blocked writing.
+                    Some(Ok(_)) => continue,
+                    None => {
+                        let byte = *payload.next().expect("payload is not empty");
+                        if stream.write_all(&[byte]).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            trace!("slowloris connection to {} reaped", self.target);
+            self.stats.reaped.fetch_add(1, atomic::Ordering::SeqCst);
+            self.time_handle.delay_from(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, DeterministicRuntimeHandle},
+        Environment, TcpListener,
+    };
+
+    /// Accept connections, closing each once no bytes are read from it for `idle`.
+    async fn reaping_server(handle: DeterministicRuntimeHandle, addr: net::SocketAddr) {
+        let idle = time::Duration::from_secs(5);
+        let mut listener = handle.bind(addr).await.unwrap();
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let handle = handle.clone();
+            handle.clone().spawn(async move {
+                let mut buf = [0; 64];
+                while let Ok(Ok(read)) = handle.timeout(socket.read(&mut buf), idle).await {
+                    if read == 0 {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    #[test]
+    /// Test that silent connections are reaped and reopened, while trickling connections are not.
+    fn reaped_connections() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let server_addr: net::SocketAddr = "10.0.0.1:80".parse().unwrap();
+        let attacker: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let server = runtime.handle(server_addr.ip());
+        let handle = runtime.localhost_handle();
+        let silent = runtime
+            .slowloris_fault(attacker, server_addr)
+            .connections(2)
+            .trickle(None);
+        let silent_stats = silent.stats();
+        let trickling = runtime
+            .slowloris_fault(attacker, server_addr)
+            .connections(2)
+            .trickle(Some(time::Duration::from_secs(1)));
+        let trickling_stats = trickling.stats();
+        runtime.block_on(async {
+            handle.spawn(reaping_server(server.clone(), server_addr));
+            handle.delay_from(time::Duration::from_millis(1)).await;
+            handle.spawn(silent.run());
+            handle.spawn(trickling.run());
+            handle.delay_from(time::Duration::from_secs(8)).await;
+            assert_eq!(silent_stats.opened(), 4);
+            assert_eq!(silent_stats.reaped(), 2);
+            assert_eq!(trickling_stats.opened(), 2);
+            assert_eq!(trickling_stats.reaped(), 0);
+        });
+    }
+}
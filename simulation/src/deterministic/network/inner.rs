@@ -1,18 +1,117 @@
-use super::fault::{CloggedConnection, Connection};
-use super::{socket, FaultyTcpStream, Listener, ListenerState, SocketHalf};
-use futures::{channel::mpsc, Future, SinkExt};
+use super::abort::AbortSignal;
+use super::dns::CachedName;
+use super::fault::{
+    CloggedConnection, Connection, Corruption, Duplication, Fragmentation, PacketLoss, Priority,
+};
+use super::ledger::{ConnectionLedger, ConnectionLedgers};
+use super::nat::NatState;
+use super::nic::Nic;
+use super::udp::Datagram;
+use super::unix::UnixStream;
+use super::{
+    socket, AddrExhausted, Backlog, FaultyTcpStream, FlushMode, Handshake, Listener, ListenerState,
+    PortUsage, SocketHalf,
+};
+use crate::deterministic::DeterministicRandomHandle;
+use futures::{channel::mpsc, future, task::Waker, Future, FutureExt, Poll};
 use std::{
     collections::{self, hash_map::Entry},
-    io, net,
+    io, net, ops, path, sync, time,
 };
 use tracing::trace;
 
+/// Number of connection ledgers retained for each link, beyond which the ledgers of the oldest
+/// connections are discarded.
+pub(crate) const MAX_LEDGERS_PER_LINK: usize = 64;
+
 #[derive(Debug)]
 pub(crate) struct Inner {
     handle: crate::deterministic::DeterministicTimeHandle,
     pub(crate) connections: Vec<Connection>,
     clogged: collections::HashSet<CloggedConnection>,
+    /// Links cut by partitions, from the first host to the second, with the number of
+    /// partitions cutting each. Tracked separately from `clogged`, so that healing a partition
+    /// leaves clogs in place and unclogging leaves partitions in place.
+    partitioned: collections::HashMap<(net::IpAddr, net::IpAddr), usize>,
+    /// Links over which traffic is dropped in a single direction, from the first host to the
+    /// second.
+    one_way_cuts: collections::HashSet<(net::IpAddr, net::IpAddr)>,
+    /// Time for which a connect retransmits its SYN across a cut link before failing.
+    syn_timeout: time::Duration,
+    /// Wakers of the connects waiting on a cut link, woken when links are restored.
+    link_waiters: Vec<Waker>,
+    /// Latency of traffic sent in a single direction, from the first host to the second.
+    one_way_latencies: collections::HashMap<(net::IpAddr, net::IpAddr), time::Duration>,
     endpoints: collections::HashMap<net::SocketAddr, ListenerState>,
+    /// TIME_WAIT period of hosts without their own, if TIME_WAIT is enabled.
+    time_wait: Option<time::Duration>,
+    /// TIME_WAIT period of hosts which override the default.
+    host_time_waits: collections::HashMap<net::IpAddr, Option<time::Duration>>,
+    /// Time at which each local address held in TIME_WAIT by a closed connection is released.
+    time_waits: collections::HashMap<net::SocketAddr, time::Instant>,
+    /// Hosts which bind with SO_REUSEADDR, ignoring TIME_WAIT.
+    reuseaddr: collections::HashSet<net::IpAddr>,
+    nics: collections::HashMap<net::IpAddr, sync::Arc<sync::Mutex<Nic>>>,
+    mtus: collections::HashMap<(net::IpAddr, net::IpAddr), usize>,
+    bandwidths: collections::HashMap<(net::IpAddr, net::IpAddr), u64>,
+    /// Priority of connections from a host to a destination, if not normal.
+    priorities: collections::HashMap<(net::IpAddr, net::SocketAddr), Priority>,
+    /// Number of connections established from a host to a destination.
+    established: collections::HashMap<(net::IpAddr, net::SocketAddr), usize>,
+    /// Datagram sockets bound to each address.
+    udp_sockets: collections::HashMap<net::SocketAddr, mpsc::UnboundedSender<Datagram>>,
+    /// Datagram sockets which may send to the broadcast address.
+    udp_broadcast: collections::HashSet<net::SocketAddr>,
+    /// Datagram sockets which have joined each multicast group.
+    multicast_groups: collections::HashMap<net::IpAddr, collections::HashSet<net::SocketAddr>>,
+    /// Unix domain socket listeners, keyed by host and path.
+    unix_listeners:
+        collections::HashMap<(net::IpAddr, path::PathBuf), mpsc::UnboundedSender<UnixStream>>,
+    /// Addresses registered for each name, in registration order.
+    names: collections::HashMap<String, Vec<net::IpAddr>>,
+    /// Errors returned when resolving each name, overriding its registered addresses.
+    resolve_errors: collections::HashMap<String, io::ErrorKind>,
+    /// Answers cached by the resolver of each host, keyed by host and then by name.
+    name_caches: collections::HashMap<net::IpAddr, collections::HashMap<String, CachedName>>,
+    /// Time for which resolved and unregistered names are cached. Zero disables caching.
+    name_ttl: time::Duration,
+    negative_name_ttl: time::Duration,
+    /// Whether cached answers are served past their TTL rather than resolved again.
+    serve_stale: bool,
+    /// Ephemeral port range of each host, if not the full port range.
+    ephemeral_ports: collections::HashMap<net::IpAddr, ops::RangeInclusive<u16>>,
+    /// Handshakes performed when connecting to listeners, if enabled.
+    handshakes: collections::HashMap<net::SocketAddr, Handshake>,
+    /// Connections waiting to be accepted by the listener bound to each address.
+    backlogs: collections::HashMap<net::SocketAddr, Backlog>,
+    /// Captured client traffic for each connection from a host to a destination, in order of
+    /// establishment. Only present for destinations with capture enabled.
+    captures:
+        collections::HashMap<(net::IpAddr, net::SocketAddr), Vec<sync::Arc<sync::Mutex<Vec<u8>>>>>,
+    /// Probabilities with which datagrams are dropped, if packet loss is installed.
+    packet_loss: Option<PacketLoss>,
+    /// Probabilities with which datagrams are delivered twice, if duplication is installed.
+    duplication: Option<Duplication>,
+    /// Shortening of reads on connections, if fragmentation is installed.
+    fragmentation: Option<Fragmentation>,
+    /// Corruption of reads on connections, if corruption is installed.
+    corruption: Option<Corruption>,
+    /// Ledgers of the most recent connections from a host to a destination, in order of
+    /// establishment. At most `MAX_LEDGERS_PER_LINK` are retained for each.
+    ledgers: collections::HashMap<(net::IpAddr, net::SocketAddr), Vec<ConnectionLedgers>>,
+    /// Signal fired when each host crashes, failing its pending connects and accepts.
+    aborts: collections::HashMap<net::IpAddr, AbortSignal>,
+    /// Signal fired when the listener bound to each address is closed, refusing connects which
+    /// are still waiting on a handshake.
+    closes: collections::HashMap<net::SocketAddr, AbortSignal>,
+    /// Source of the seeded offsets at which ephemeral listener ports are allocated.
+    random_handle: Option<DeterministicRandomHandle>,
+    /// When flushes of connection streams complete.
+    flush_mode: FlushMode,
+    /// Time after which idle connections are reset, if enabled.
+    idle_timeout: Option<time::Duration>,
+    /// Installed NATs, each translating connections from the hosts behind it.
+    nats: Vec<NatState>,
 }
 
 impl Inner {
@@ -21,9 +120,64 @@ impl Inner {
             handle,
             connections: vec![],
             clogged: collections::HashSet::new(),
+            partitioned: collections::HashMap::new(),
+            one_way_cuts: collections::HashSet::new(),
+            // Linux gives up after its sixth SYN retransmission, 127 seconds after the first.
+            syn_timeout: time::Duration::from_secs(127),
+            link_waiters: vec![],
+            one_way_latencies: collections::HashMap::new(),
             endpoints: collections::HashMap::new(),
+            time_wait: None,
+            host_time_waits: collections::HashMap::new(),
+            time_waits: collections::HashMap::new(),
+            reuseaddr: collections::HashSet::new(),
+            nics: collections::HashMap::new(),
+            mtus: collections::HashMap::new(),
+            bandwidths: collections::HashMap::new(),
+            priorities: collections::HashMap::new(),
+            established: collections::HashMap::new(),
+            udp_sockets: collections::HashMap::new(),
+            udp_broadcast: collections::HashSet::new(),
+            multicast_groups: collections::HashMap::new(),
+            unix_listeners: collections::HashMap::new(),
+            names: collections::HashMap::new(),
+            resolve_errors: collections::HashMap::new(),
+            name_caches: collections::HashMap::new(),
+            name_ttl: time::Duration::from_secs(0),
+            negative_name_ttl: time::Duration::from_secs(0),
+            serve_stale: false,
+            ephemeral_ports: collections::HashMap::new(),
+            handshakes: collections::HashMap::new(),
+            backlogs: collections::HashMap::new(),
+            captures: collections::HashMap::new(),
+            ledgers: collections::HashMap::new(),
+            packet_loss: None,
+            duplication: None,
+            fragmentation: None,
+            corruption: None,
+            aborts: collections::HashMap::new(),
+            closes: collections::HashMap::new(),
+            random_handle: None,
+            flush_mode: FlushMode::default(),
+            idle_timeout: None,
+            nats: vec![],
         }
     }
+
+    /// Returns the network interface for the provided host, creating it if it does not exist.
+    pub(crate) fn nic(&mut self, addr: net::IpAddr) -> sync::Arc<sync::Mutex<Nic>> {
+        let nic = self.nics.entry(addr).or_insert_with(Nic::new);
+        sync::Arc::clone(nic)
+    }
+
+    /// Returns the total number of bytes written by connections on each host.
+    pub(crate) fn bytes_sent(&self) -> collections::BTreeMap<net::IpAddr, u64> {
+        self.nics
+            .iter()
+            .map(|(addr, nic)| (*addr, nic.lock().unwrap().bytes_sent()))
+            .collect()
+    }
+
     fn register_new_connection_pair(
         &mut self,
         source: net::SocketAddr,
@@ -38,45 +192,266 @@ impl Inner {
             return Err(io::ErrorKind::AddrInUse.into());
         }
 
-        let (client, server) = socket::new_socket_pair(source, dest);
+        let (client, mut server) = socket::new_socket_pair(source, dest);
+        if let Some(translated) = self.translate(source, dest.ip()) {
+            server.set_peer_addr(translated?);
+        }
         let (client, client_fault_handle) =
             socket::FaultyTcpStream::wrap(self.handle.clone(), client);
         let (server, server_fault_handle) =
             socket::FaultyTcpStream::wrap(self.handle.clone(), server);
+        client_fault_handle.set_peer(&server_fault_handle);
+        server_fault_handle.set_peer(&client_fault_handle);
+        client_fault_handle.attach_nic(self.nic(source.ip()));
+        server_fault_handle.attach_nic(self.nic(dest.ip()));
+        let ledgers = ConnectionLedgers::new(source, dest);
+        client_fault_handle.attach_ledgers(
+            sync::Arc::clone(&ledgers.client_to_server),
+            sync::Arc::clone(&ledgers.server_to_client),
+        );
+        server_fault_handle.attach_ledgers(
+            sync::Arc::clone(&ledgers.server_to_client),
+            sync::Arc::clone(&ledgers.client_to_server),
+        );
+        let link_ledgers = self.ledgers.entry((source.ip(), dest)).or_default();
+        if link_ledgers.len() == MAX_LEDGERS_PER_LINK {
+            link_ledgers.remove(0);
+        }
+        link_ledgers.push(ledgers);
         let mut connection =
             Connection::new(source, dest, client_fault_handle, server_fault_handle);
-        if self.should_clog(source, dest) {
-            connection.clog();
+        connection.set_flush_mode(self.flush_mode);
+        if self.idle_timeout.is_some() {
+            connection.set_idle_timeout(self.idle_timeout, self.handle.now());
+        }
+        let link = (source.ip(), dest);
+        if let Some(priority) = self.priorities.get(&link) {
+            connection.set_priority(*priority);
+        }
+        connection.set_fragmentation(self.fragmentation.as_ref());
+        connection.set_corruption(self.corruption.as_ref());
+        connection.set_probe_loss(self.packet_loss.as_ref());
+        connection.set_mtu(self.mtus.get(&(source.ip(), dest.ip())).cloned());
+        connection.set_bandwidth(self.bandwidths.get(&(source.ip(), dest.ip())).cloned());
+        *self.established.entry(link).or_insert(0) += 1;
+        if let Some(captures) = self.captures.get_mut(&link) {
+            let log = sync::Arc::new(sync::Mutex::new(vec![]));
+            connection.capture_client(sync::Arc::clone(&log));
+            captures.push(log);
+        }
+        self.apply_clogs(&connection);
+        for (from, to) in [(source.ip(), dest.ip()), (dest.ip(), source.ip())].iter() {
+            if let Some(latency) = self.one_way_latencies.get(&(*from, *to)) {
+                connection.set_latency_from(*from, Some(*latency));
+            }
         }
         self.connections.push(connection);
         Ok((client, server))
     }
-    // find an unused socket port for the provided ipaddr.
-    fn unused_socket_port(&self, addr: net::IpAddr) -> u16 {
-        let mut start = 65535;
+    fn ephemeral_ports(&self, addr: net::IpAddr) -> ops::RangeInclusive<u16> {
+        self.ephemeral_ports
+            .get(&addr)
+            .cloned()
+            .unwrap_or(1..=u16::max_value())
+    }
+
+    // find an unused socket port for the provided ipaddr, starting from the top of the
+    // ephemeral port range. Ports held by listeners, such as those bound to port 0, are skipped.
+    fn unused_socket_port(&self, addr: net::IpAddr) -> Option<u16> {
+        let listening = self
+            .endpoints
+            .keys()
+            .filter(|bind_addr| self.is_bound(**bind_addr))
+            .cloned();
         let occupied: collections::HashSet<u16> = self
             .connections
             .iter()
-            .filter(|v| v.source().ip() == addr)
-            .map(|v| v.source().port())
+            .map(|v| v.source())
+            .chain(self.time_waits.keys().cloned())
+            .chain(listening)
+            .filter(|source| source.ip() == addr)
+            .map(|source| source.port())
             .collect();
-        loop {
-            if !occupied.contains(&start) {
-                return start;
-            }
-            if start == 0 {}
-            start -= 1;
+        self.ephemeral_ports(addr)
+            .rev()
+            .find(|port| !occupied.contains(port))
+    }
+
+    pub(crate) fn set_random_handle(&mut self, random_handle: DeterministicRandomHandle) {
+        self.random_handle.replace(random_handle);
+    }
+
+    /// Find an unused port in the ephemeral port range of `addr` for a listener bound to port
+    /// 0. As on Linux, the search starts from a random offset in the range, which is derived
+    /// from the seed if the network has a random handle and is otherwise the top of the range.
+    pub(crate) fn unused_listener_port(&mut self, addr: net::IpAddr) -> Result<u16, io::Error> {
+        self.gc_dropped();
+        let ports = self.ephemeral_ports(addr);
+        let (low, high) = (u32::from(*ports.start()), u32::from(*ports.end()));
+        let len = high - low + 1;
+        let offset = match self.random_handle.as_ref() {
+            Some(random_handle) => random_handle.gen_range(0..len),
+            None => 0,
+        };
+        let port = (0..len)
+            .map(|i| (high - (offset + i) % len) as u16)
+            .find(|port| {
+                let bind_addr = net::SocketAddr::new(addr, *port);
+                !self.endpoints.contains_key(&bind_addr)
+                    && !self.in_time_wait(bind_addr)
+                    && !self
+                        .connections
+                        .iter()
+                        .any(|connection| connection.source() == bind_addr)
+            });
+        port.ok_or_else(|| {
+            trace!("ephemeral ports exhausted on {}", addr);
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                AddrExhausted {
+                    host: addr,
+                    usage: self.port_usage(addr),
+                },
+            )
+        })
+    }
+
+    /// Restrict the ports used by connections from `addr` to `ports`.
+    pub(crate) fn set_ephemeral_ports(
+        &mut self,
+        addr: net::IpAddr,
+        ports: ops::RangeInclusive<u16>,
+    ) {
+        self.ephemeral_ports.insert(addr, ports);
+    }
+
+    /// Returns the ephemeral port usage of `addr`, excluding connections which have been dropped.
+    pub(crate) fn port_usage(&mut self, addr: net::IpAddr) -> PortUsage {
+        self.gc_dropped();
+        let ports = self.ephemeral_ports(addr);
+        let in_range =
+            |source: &net::SocketAddr| source.ip() == addr && ports.contains(&source.port());
+        let in_use = self
+            .connections
+            .iter()
+            .filter(|connection| in_range(&connection.source()))
+            .count();
+        let time_wait = self.time_waits.keys().filter(|port| in_range(port)).count();
+        let listening = self
+            .endpoints
+            .keys()
+            .filter(|bind_addr| in_range(bind_addr) && self.is_bound(**bind_addr))
+            .count();
+        PortUsage {
+            in_use,
+            time_wait,
+            listening,
+            capacity: usize::from(*ports.end() - *ports.start()) + 1,
+        }
+    }
+
+    /// Hold the local addresses of connections which hosts close before their peers in
+    /// TIME_WAIT for `time_wait`, or release them as soon as connections are dropped if `None`.
+    /// Hosts with their own TIME_WAIT period are unaffected.
+    pub(crate) fn set_time_wait(&mut self, time_wait: Option<time::Duration>) {
+        self.time_wait = time_wait;
+    }
+
+    /// Set the TIME_WAIT period of `addr`, overriding the period of the network.
+    pub(crate) fn set_host_time_wait(
+        &mut self,
+        addr: net::IpAddr,
+        time_wait: Option<time::Duration>,
+    ) {
+        self.host_time_waits.insert(addr, time_wait);
+    }
+
+    fn time_wait_of(&self, addr: net::IpAddr) -> Option<time::Duration> {
+        match self.host_time_waits.get(&addr) {
+            Some(time_wait) => *time_wait,
+            None => self.time_wait,
         }
     }
 
     fn gc_dropped(&mut self) {
+        let now = self.handle.now();
         let mut connections = vec![];
         for connection in self.connections.iter() {
             if !connection.is_dropped() {
                 connections.push(connection.clone());
+                continue;
+            }
+            if let Some((local, closed_at)) = connection.active_close() {
+                if let Some(time_wait) = self.time_wait_of(local.ip()) {
+                    trace!("holding {} in TIME_WAIT", local);
+                    self.time_waits.insert(local, closed_at + time_wait);
+                }
             }
         }
         self.connections = connections;
+        self.time_waits.retain(|_, released_at| *released_at > now);
+        if !self.nats.is_empty() {
+            let live: collections::HashSet<net::SocketAddr> =
+                self.connections.iter().map(|c| c.source()).collect();
+            for nat in self.nats.iter_mut() {
+                nat.retain(&live);
+            }
+        }
+    }
+
+    pub(crate) fn install_nat(&mut self, nat: NatState) {
+        self.nats
+            .retain(|installed| installed.public() != nat.public());
+        self.nats.push(nat);
+    }
+
+    /// Discard the mappings of the NAT with the public address `public`, resetting the
+    /// connections which were translated by it.
+    pub(crate) fn reset_nat(&mut self, public: net::IpAddr) {
+        trace!("resetting NAT {}", public);
+        let nat = match self.nats.iter_mut().find(|nat| nat.public() == public) {
+            Some(nat) => nat,
+            None => return,
+        };
+        for connection in self.connections.iter() {
+            if nat.is_mapped(connection.source()) {
+                connection.reset();
+            }
+        }
+        nat.reset();
+    }
+
+    /// Returns the public address which `source` is translated to when connecting to `dest`, if
+    /// `source` is behind a NAT which `dest` is outside of. Fails if the NAT has no free ports.
+    fn translate(
+        &mut self,
+        source: net::SocketAddr,
+        dest: net::IpAddr,
+    ) -> Option<Result<net::SocketAddr, io::Error>> {
+        let index = self
+            .nats
+            .iter()
+            .position(|nat| nat.contains(source.ip()) && !nat.contains(dest))?;
+        let ports = self.ephemeral_ports(self.nats[index].public());
+        let nat = &mut self.nats[index];
+        Some(nat.map(source, ports.clone()).ok_or_else(|| {
+            trace!("NAT ports exhausted on {}", nat.public());
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                AddrExhausted {
+                    host: nat.public(),
+                    usage: nat.port_usage(&ports),
+                },
+            )
+        }))
+    }
+
+    /// Returns true if a NAT drops connections from `source` to `dest`, because `dest` is
+    /// behind a NAT which `source` is outside of or is the public address of a NAT.
+    fn nat_drops(&self, source: net::IpAddr, dest: net::IpAddr) -> bool {
+        self.nats
+            .iter()
+            .any(|nat| nat.public() == dest || (nat.contains(dest) && !nat.contains(source)))
     }
 
     pub fn connect(
@@ -86,14 +461,51 @@ impl Inner {
     ) -> impl Future<Output = Result<socket::FaultyTcpStream<SocketHalf>, io::Error>> {
         trace!("establishing new connection {} -> {}", source, dest);
         self.gc_dropped();
-        let free_socket_port = self.unused_socket_port(source);
-        let source_addr = net::SocketAddr::new(source, free_socket_port);
-        let registration = self.register_new_connection_pair(source_addr, dest);
+        let registration = match self.unused_socket_port(source) {
+            // Establishing a connection requires traffic to flow in both directions.
+            _ if self.is_partitioned(source, dest.ip()) => {
+                trace!("{} is partitioned from {}", source, dest);
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "network partitioned",
+                ))
+            }
+            _ if self.nat_drops(source, dest.ip()) => {
+                trace!("NAT dropped unsolicited connection {} -> {}", source, dest);
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection dropped by NAT",
+                ))
+            }
+            Some(port) => {
+                self.register_new_connection_pair(net::SocketAddr::new(source, port), dest)
+            }
+            None => {
+                trace!("ephemeral ports exhausted on {}", source);
+                let usage = self.port_usage(source);
+                Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    AddrExhausted {
+                        host: source,
+                        usage,
+                    },
+                ))
+            }
+        };
+        let handshake = match (&registration, self.handshakes.get(&dest)) {
+            (Ok(_), Some(handshake)) => {
+                let round_trip_time = self.round_trip_time(source, dest.ip());
+                let duration = handshake.duration(round_trip_time);
+                trace!("handshake {} -> {} takes {:?}", source, dest, duration);
+                Some(self.handle.delay_from(duration))
+            }
+            _ => None,
+        };
 
-        let mut channel;
+        let channel;
         match self.endpoints.entry(dest) {
             Entry::Vacant(v) => {
-                let (tx, rx) = mpsc::channel(1);
+                let (tx, rx) = mpsc::unbounded();
                 let state = ListenerState::Unbound { tx: tx.clone(), rx };
                 channel = tx;
                 v.insert(state);
@@ -104,22 +516,158 @@ impl Inner {
             },
         }
 
-        async move {
+        let backlog = self.backlog(dest);
+        let mut establish = Box::pin(async move {
             let (client, server) = registration?;
-            match channel.send(server).await {
+            if let Some(handshake) = handshake {
+                handshake.await;
+            }
+            if !backlog.try_push() {
+                trace!("backlog of {} is full, refusing connection", dest);
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            match channel.unbounded_send(server) {
                 Ok(_) => Ok(client),
                 Err(_) => Err(io::ErrorKind::ConnectionRefused.into()),
             }
+        });
+        // A crash of either host, or the listener closing, fails the connect rather than leaving
+        // it waiting on a handshake which will never complete.
+        let mut aborts = [
+            self.abort_signal(source).aborted(),
+            self.abort_signal(dest.ip()).aborted(),
+            self.close_signal(dest).aborted(),
+        ];
+        future::poll_fn(move |cx| {
+            for abort in aborts.iter_mut() {
+                if let Poll::Ready(error) = abort.poll_unpin(cx) {
+                    trace!("aborted connection {} -> {}", source, dest);
+                    return Poll::Ready(Err(error));
+                }
+            }
+            establish.as_mut().poll(cx)
+        })
+    }
+
+    /// Returns the signal fired when `host` next crashes.
+    pub(crate) fn abort_signal(&mut self, host: net::IpAddr) -> AbortSignal {
+        self.aborts.entry(host).or_default().clone()
+    }
+
+    /// Returns the signal fired when the listener bound to `addr` is next closed.
+    fn close_signal(&mut self, addr: net::SocketAddr) -> AbortSignal {
+        let signal = self.closes.entry(addr).or_insert_with(|| {
+            AbortSignal::new(io::ErrorKind::ConnectionRefused, "listener closed")
+        });
+        // Aliases of a closed listener keep its fired signal until they are next used.
+        if signal.is_aborted() {
+            *signal = AbortSignal::new(io::ErrorKind::ConnectionRefused, "listener closed");
         }
+        signal.clone()
+    }
+
+    /// Reset the connection which `stream` is the server side of.
+    pub(crate) fn reset_connection(&mut self, stream: &FaultyTcpStream<SocketHalf>) {
+        if let Some(connection) = self.connections.iter().find(|c| c.is_server(stream)) {
+            trace!(
+                "resetting unaccepted connection {} -> {}",
+                connection.source(),
+                connection.dest()
+            );
+            connection.reset();
+        }
+    }
+
+    /// Crash `host`, failing its pending connects and accepts with `ConnectionAborted`,
+    /// resetting its established connections and closing its listeners and sockets. Connects to the host
+    /// which are still pending also fail. The host can be restarted by binding its listeners
+    /// again, which is not subject to TIME_WAIT.
+    pub(crate) fn crash(&mut self, host: net::IpAddr) {
+        trace!("crashing {}", host);
+        if let Some(abort) = self.aborts.remove(&host) {
+            abort.abort();
+        }
+        for connection in self.connections.iter() {
+            if connection.source().ip() == host || connection.dest().ip() == host {
+                connection.reset();
+            }
+        }
+        self.endpoints.retain(|addr, _| addr.ip() != host);
+        self.time_waits.retain(|addr, _| addr.ip() != host);
+        self.name_caches.remove(&host);
+        self.udp_sockets.retain(|addr, _| addr.ip() != host);
+        self.udp_broadcast.retain(|addr| addr.ip() != host);
+        for members in self.multicast_groups.values_mut() {
+            members.retain(|addr| addr.ip() != host);
+        }
+        self.unix_listeners.retain(|(ip, _), _| *ip != host);
+    }
+
+    /// Returns true if a listener which has not yet been closed is bound to `bind_addr`.
+    pub(crate) fn is_bound(&self, bind_addr: net::SocketAddr) -> bool {
+        match self.endpoints.get(&bind_addr) {
+            Some(ListenerState::Bound { tx }) => !tx.is_closed(),
+            _ => false,
+        }
+    }
+
+    /// Deliver connections to `alias` to the listener bound to `bind_addr`. Connections which
+    /// were waiting for a listener to be bound to `alias` are handed to the listener, or refused
+    /// if its backlog is full.
+    pub(crate) fn alias_listener(
+        &mut self,
+        alias: net::SocketAddr,
+        bind_addr: net::SocketAddr,
+    ) -> Result<(), io::Error> {
+        let tx = match self.endpoints.get(&bind_addr) {
+            Some(ListenerState::Bound { tx }) => tx.clone(),
+            _ => return Err(io::ErrorKind::AddrNotAvailable.into()),
+        };
+        trace!("aliasing listener {} as {}", bind_addr, alias);
+        let backlog = self.backlog(bind_addr);
+        let previous = self
+            .endpoints
+            .insert(alias, ListenerState::Bound { tx: tx.clone() });
+        if let Some(ListenerState::Unbound { mut rx, .. }) = previous {
+            rx.close();
+            while let Ok(Some(stream)) = rx.try_next() {
+                let refused = if backlog.try_push() {
+                    match tx.unbounded_send(stream) {
+                        Ok(()) => continue,
+                        Err(error) => error.into_inner(),
+                    }
+                } else {
+                    stream
+                };
+                trace!("backlog of {} is full, refusing connection", bind_addr);
+                self.reset_connection(&refused);
+            }
+        }
+        self.backlogs.insert(alias, backlog);
+        let close = self.close_signal(bind_addr);
+        self.closes.insert(alias, close);
+        Ok(())
     }
 
     pub fn listen(&mut self, bind_addr: net::SocketAddr) -> Result<Listener, io::Error> {
         trace!("registering listener for {}", bind_addr);
         self.gc_dropped();
+        if let Some(ListenerState::Bound { tx }) = self.endpoints.get(&bind_addr) {
+            if !tx.is_closed() {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+            self.endpoints.remove(&bind_addr);
+        }
+        if self.in_time_wait(bind_addr) {
+            trace!("{} is in TIME_WAIT", bind_addr);
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
         match self.endpoints.remove(&bind_addr) {
             Some(listener_state) => {
                 if let ListenerState::Unbound { tx, rx } = listener_state {
-                    let listener = Listener::new(bind_addr, rx);
+                    let mut listener = Listener::new(bind_addr, rx);
+                    listener.abort_on(self.abort_signal(bind_addr.ip()));
+                    listener.backlog_into(self.backlog(bind_addr));
                     let new_state = ListenerState::Bound { tx };
                     self.endpoints.insert(bind_addr, new_state);
                     Ok(listener)
@@ -129,56 +677,773 @@ impl Inner {
                 }
             }
             _ => {
-                let (tx, rx) = mpsc::channel(1);
+                let (tx, rx) = mpsc::unbounded();
                 let state = ListenerState::Bound { tx };
                 self.endpoints.insert(bind_addr, state);
-                let listener = Listener::new(bind_addr, rx);
+                let mut listener = Listener::new(bind_addr, rx);
+                listener.abort_on(self.abort_signal(bind_addr.ip()));
+                let backlog = self.backlog(bind_addr);
+                backlog.clear();
+                listener.backlog_into(backlog);
                 Ok(listener)
             }
         }
     }
 
-    /// Determines if a connection should be clogged based on the state of clogged connections.
+    /// Record that the listener bound to `bind_addr` was closed, refusing connections which are
+    /// still waiting to be accepted.
+    pub(crate) fn close_listener(&mut self, bind_addr: net::SocketAddr) {
+        trace!("closed listener for {}", bind_addr);
+        if let Some(close) = self.closes.remove(&bind_addr) {
+            close.abort();
+        }
+    }
+
+    /// Returns true if `bind_addr` cannot be bound yet because a connection it accepted was
+    /// closed by this side less than the TIME_WAIT period ago. Hosts which set SO_REUSEADDR may
+    /// bind addresses in TIME_WAIT.
+    fn in_time_wait(&self, bind_addr: net::SocketAddr) -> bool {
+        if self.reuseaddr.contains(&bind_addr.ip()) {
+            return false;
+        }
+        match self.time_waits.get(&bind_addr) {
+            Some(released_at) => self.handle.now() < *released_at,
+            None => false,
+        }
+    }
+
+    /// Set when flushes complete, applying to both new and existing connections.
+    pub(crate) fn set_flush_mode(&mut self, mode: FlushMode) {
+        self.flush_mode = mode;
+        for connection in self.connections.iter() {
+            connection.set_flush_mode(mode);
+        }
+    }
+
+    /// Reset new and existing connections once they have been idle for `timeout`, or stop
+    /// resetting idle connections if `None`. Existing connections are considered idle from now.
+    pub(crate) fn set_idle_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.idle_timeout = timeout;
+        let now = self.handle.now();
+        for connection in self.connections.iter() {
+            connection.set_idle_timeout(timeout, now);
+        }
+    }
+
+    pub(crate) fn set_reuseaddr(&mut self, host: net::IpAddr, reuseaddr: bool) {
+        if reuseaddr {
+            self.reuseaddr.insert(host);
+        } else {
+            self.reuseaddr.remove(&host);
+        }
+    }
+
+    /// Bind a datagram socket to `bind_addr`, choosing an unused ephemeral port if the port is
+    /// 0. Returns the bound address and the receiver for datagrams sent to it.
+    pub(crate) fn bind_udp(
+        &mut self,
+        mut bind_addr: net::SocketAddr,
+    ) -> Result<(net::SocketAddr, mpsc::UnboundedReceiver<Datagram>), io::Error> {
+        if bind_addr.port() == 0 {
+            let ip = bind_addr.ip();
+            let port = self
+                .ephemeral_ports(ip)
+                .rev()
+                .find(|port| {
+                    !self
+                        .udp_sockets
+                        .contains_key(&net::SocketAddr::new(ip, *port))
+                })
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+            bind_addr.set_port(port);
+        }
+        if self.udp_sockets.contains_key(&bind_addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        trace!("binding datagram socket to {}", bind_addr);
+        let (tx, rx) = mpsc::unbounded();
+        self.udp_sockets.insert(bind_addr, tx);
+        Ok((bind_addr, rx))
+    }
+
+    pub(crate) fn unbind_udp(&mut self, bind_addr: net::SocketAddr) {
+        self.udp_sockets.remove(&bind_addr);
+        self.udp_broadcast.remove(&bind_addr);
+        for members in self.multicast_groups.values_mut() {
+            members.remove(&bind_addr);
+        }
+    }
+
+    pub(crate) fn set_udp_broadcast(&mut self, bind_addr: net::SocketAddr, broadcast: bool) {
+        if broadcast {
+            self.udp_broadcast.insert(bind_addr);
+        } else {
+            self.udp_broadcast.remove(&bind_addr);
+        }
+    }
+
+    pub(crate) fn udp_broadcast(&self, bind_addr: net::SocketAddr) -> bool {
+        self.udp_broadcast.contains(&bind_addr)
+    }
+
+    /// Subscribe the socket bound to `bind_addr` to datagrams sent to `group`. Joining a group
+    /// the socket is already a member of has no effect.
+    pub(crate) fn join_multicast(
+        &mut self,
+        bind_addr: net::SocketAddr,
+        group: net::IpAddr,
+    ) -> io::Result<()> {
+        if !group.is_multicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a multicast address", group),
+            ));
+        }
+        if self
+            .multicast_groups
+            .entry(group)
+            .or_default()
+            .insert(bind_addr)
+        {
+            trace!("{} joined multicast group {}", bind_addr, group);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn leave_multicast(
+        &mut self,
+        bind_addr: net::SocketAddr,
+        group: net::IpAddr,
+    ) -> io::Result<()> {
+        match self.multicast_groups.get_mut(&group) {
+            Some(members) if members.remove(&bind_addr) => {
+                trace!("{} left multicast group {}", bind_addr, group);
+                Ok(())
+            }
+            _ => Err(io::ErrorKind::AddrNotAvailable.into()),
+        }
+    }
+
+    /// Send a datagram from `source` to `dest`. Datagrams sent to a multicast group are
+    /// delivered to every socket which joined the group and is bound to the destination port,
+    /// and datagrams sent to the broadcast address to every socket bound to the destination
+    /// port. Sending to the broadcast address fails with `PermissionDenied` unless broadcast was
+    /// enabled for the source socket.
+    pub(crate) fn send_datagram(
+        &mut self,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        datagram: bytes::Bytes,
+    ) -> io::Result<()> {
+        if let Some(nic) = self.nics.get(&source.ip()) {
+            nic.lock().unwrap().record_sent(datagram.len());
+        }
+        let mut receivers: Vec<net::SocketAddr> = match dest.ip() {
+            net::IpAddr::V4(ip) if ip.is_broadcast() => {
+                if !self.udp_broadcast.contains(&source) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "broadcast is not enabled",
+                    ));
+                }
+                self.udp_sockets
+                    .keys()
+                    .filter(|addr| addr.port() == dest.port())
+                    .cloned()
+                    .collect()
+            }
+            group if group.is_multicast() => self
+                .multicast_groups
+                .get(&group)
+                .into_iter()
+                .flatten()
+                .filter(|addr| addr.port() == dest.port())
+                .cloned()
+                .collect(),
+            _ => {
+                self.deliver_datagram(source, dest, datagram);
+                return Ok(());
+            }
+        };
+        // Deliver in a stable order so that loss is sampled deterministically.
+        receivers.sort_by_key(|addr| (addr.ip(), addr.port()));
+        for receiver in receivers {
+            self.deliver_datagram(source, receiver, datagram.clone());
+        }
+        Ok(())
+    }
+
+    /// Deliver a datagram from `source` to the socket bound to `dest` after the latency of the
+    /// link between the hosts. Datagrams are dropped if no socket is bound to `dest` or the link
+    /// is clogged.
+    fn deliver_datagram(
+        &mut self,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        datagram: bytes::Bytes,
+    ) {
+        if self.should_clog(source, dest) || self.one_way_cuts.contains(&(source.ip(), dest.ip())) {
+            trace!("dropping datagram {} -> {}, link clogged", source, dest);
+            return;
+        }
+        if let Some(loss) = self.packet_loss.as_ref() {
+            if loss.should_drop(source.ip(), dest.ip()) {
+                trace!("dropping datagram {} -> {}, packet lost", source, dest);
+                return;
+            }
+        }
+        let latency = self
+            .one_way_latencies
+            .get(&(source.ip(), dest.ip()))
+            .cloned()
+            .unwrap_or_default();
+        match self.udp_sockets.get(&dest) {
+            Some(tx) => {
+                let deliver_at = self.handle.now() + latency;
+                let duplicate = self
+                    .duplication
+                    .as_ref()
+                    .and_then(|d| d.duplicate_after(source.ip(), dest.ip()));
+                let _ = tx.unbounded_send((source, datagram.clone(), deliver_at));
+                if let Some(delay) = duplicate {
+                    trace!("duplicating datagram {} -> {}", source, dest);
+                    let _ = tx.unbounded_send((source, datagram, deliver_at + delay));
+                }
+            }
+            None => trace!("dropping datagram {} -> {}, no socket bound", source, dest),
+        }
+    }
+
+    pub(crate) fn time_handle(&self) -> crate::deterministic::DeterministicTimeHandle {
+        self.handle.clone()
+    }
+
+    /// Drop datagrams according to `loss`, including the keepalive probes of new and existing
+    /// connections, or stop dropping them if `None`.
+    pub(crate) fn set_packet_loss(&mut self, loss: Option<PacketLoss>) {
+        self.packet_loss = loss;
+        self.refresh_probe_loss();
+    }
+
+    fn refresh_probe_loss(&self) {
+        for connection in self.connections.iter() {
+            connection.set_probe_loss(self.packet_loss.as_ref());
+        }
+    }
+
+    pub(crate) fn set_duplication(&mut self, duplication: Option<Duplication>) {
+        self.duplication = duplication;
+    }
+
+    /// Drop datagrams sent from `source` to `dest` with `probability`, installing packet loss
+    /// which drops no other datagrams if none is installed.
+    pub(crate) fn set_link_loss(
+        &mut self,
+        random_handle: &DeterministicRandomHandle,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+        probability: f64,
+    ) {
+        self.packet_loss
+            .get_or_insert_with(|| PacketLoss::new(random_handle.clone(), 0.0))
+            .set_link(source, dest, probability);
+        self.refresh_probe_loss();
+    }
+
+    /// Fragment reads on new and existing connections selected by `fragmentation`, or stop
+    /// fragmenting reads if `None`.
+    pub(crate) fn set_fragmentation(&mut self, fragmentation: Option<Fragmentation>) {
+        for connection in self.connections.iter() {
+            connection.set_fragmentation(fragmentation.as_ref());
+        }
+        self.fragmentation = fragmentation;
+    }
+
+    /// Corrupt reads on new and existing connections selected by `corruption`, or stop
+    /// corrupting reads if `None`.
+    pub(crate) fn set_corruption(&mut self, corruption: Option<Corruption>) {
+        for connection in self.connections.iter() {
+            connection.set_corruption(corruption.as_ref());
+        }
+        self.corruption = corruption;
+    }
+
+    /// Bind a Unix domain socket listener to `path` on `host`, returning the receiver for
+    /// incoming streams.
+    pub(crate) fn bind_unix(
+        &mut self,
+        host: net::IpAddr,
+        path: path::PathBuf,
+    ) -> Result<mpsc::UnboundedReceiver<UnixStream>, io::Error> {
+        match self.unix_listeners.entry((host, path)) {
+            Entry::Occupied(_) => Err(io::ErrorKind::AddrInUse.into()),
+            Entry::Vacant(v) => {
+                trace!(
+                    "binding unix listener to {} on {}",
+                    v.key().1.display(),
+                    host
+                );
+                let (tx, rx) = mpsc::unbounded();
+                v.insert(tx);
+                Ok(rx)
+            }
+        }
+    }
+
+    pub(crate) fn unbind_unix(&mut self, host: net::IpAddr, path: &path::Path) {
+        self.unix_listeners.remove(&(host, path.to_path_buf()));
+    }
+
+    /// Connect to the Unix domain socket listener bound to `path` on `host`.
+    pub(crate) fn connect_unix(
+        &mut self,
+        host: net::IpAddr,
+        path: &path::Path,
+    ) -> Result<UnixStream, io::Error> {
+        let tx = self
+            .unix_listeners
+            .get(&(host, path.to_path_buf()))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let addr = net::SocketAddr::new(host, 0);
+        let (client, server) = socket::new_socket_pair(addr, addr);
+        tx.unbounded_send(server)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        trace!("connected to unix listener {} on {}", path.display(), host);
+        Ok(client)
+    }
+
+    /// Register `addr` as an address of `name`. Names are case insensitive.
+    pub(crate) fn register_name(&mut self, name: &str, addr: net::IpAddr) {
+        trace!("registering {} as {}", addr, name);
+        let addrs = self.names.entry(name.to_ascii_lowercase()).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    pub(crate) fn unregister_name(&mut self, name: &str, addr: net::IpAddr) {
+        trace!("unregistering {} as {}", addr, name);
+        let name = name.to_ascii_lowercase();
+        if let Some(addrs) = self.names.get_mut(&name) {
+            addrs.retain(|registered| *registered != addr);
+            if addrs.is_empty() {
+                self.names.remove(&name);
+            }
+        }
+    }
+
+    /// Returns the addresses registered for `name` as resolved by `host`, or a `NotFound` error
+    /// if there are none. Answers are cached by each host for the name TTL, and `NotFound`
+    /// answers for the negative name TTL. Injected resolution errors are never cached.
+    pub(crate) fn resolve(
+        &mut self,
+        host: net::IpAddr,
+        name: &str,
+    ) -> Result<Vec<net::IpAddr>, io::Error> {
+        let name_key = name.to_ascii_lowercase();
+        let now = self.handle.now();
+        if let Some(cached) = self
+            .name_caches
+            .get(&host)
+            .and_then(|cache| cache.get(&name_key))
+        {
+            if cached.expires > now || self.serve_stale {
+                trace!("{} resolved {} from its cache", host, name);
+                return cached
+                    .answer
+                    .clone()
+                    .map_err(|kind| io::Error::new(kind, format!("failed to resolve {}", name)));
+            }
+        }
+        if let Some(kind) = self.resolve_errors.get(&name_key) {
+            trace!("failing resolution of {} with {:?}", name, kind);
+            return Err(io::Error::new(*kind, format!("failed to resolve {}", name)));
+        }
+        let answer = self
+            .names
+            .get(&name_key)
+            .cloned()
+            .ok_or(io::ErrorKind::NotFound);
+        let ttl = if answer.is_ok() {
+            self.name_ttl
+        } else {
+            self.negative_name_ttl
+        };
+        if ttl > time::Duration::from_secs(0) {
+            let cached = CachedName {
+                answer: answer.clone(),
+                expires: now + ttl,
+            };
+            self.name_caches
+                .entry(host)
+                .or_default()
+                .insert(name_key, cached);
+        }
+        answer.map_err(|kind| io::Error::new(kind, format!("failed to resolve {}", name)))
+    }
+
+    pub(crate) fn set_name_ttl(&mut self, ttl: time::Duration, negative_ttl: time::Duration) {
+        self.name_ttl = ttl;
+        self.negative_name_ttl = negative_ttl;
+    }
+
+    pub(crate) fn set_serve_stale(&mut self, serve_stale: bool) {
+        self.serve_stale = serve_stale;
+    }
+
+    pub(crate) fn set_resolve_error(&mut self, name: &str, error: Option<io::ErrorKind>) {
+        let name = name.to_ascii_lowercase();
+        match error {
+            Some(kind) => self.resolve_errors.insert(name, kind),
+            None => self.resolve_errors.remove(&name),
+        };
+    }
+
+    /// Returns the backlog of the listener bound to `bind_addr`, creating it if it does not
+    /// exist.
+    fn backlog(&mut self, bind_addr: net::SocketAddr) -> Backlog {
+        self.backlogs.entry(bind_addr).or_default().clone()
+    }
+
+    /// Limit the number of established connections waiting to be accepted by the listener bound
+    /// to `bind_addr`. Once the limit is reached, new connections are refused.
+    pub(crate) fn set_backlog(&mut self, bind_addr: net::SocketAddr, backlog: usize) {
+        self.backlog(bind_addr).set_limit(backlog);
+    }
+
+    /// Require connections to `bind_addr` to complete the provided handshake before they are
+    /// established. Passing `None` disables the handshake.
+    pub(crate) fn set_handshake(
+        &mut self,
+        bind_addr: net::SocketAddr,
+        handshake: Option<Handshake>,
+    ) {
+        match handshake {
+            Some(handshake) => self.handshakes.insert(bind_addr, handshake),
+            None => self.handshakes.remove(&bind_addr),
+        };
+    }
+
+    /// Returns the number of connections which have been established from `source` to `dest`.
+    pub(crate) fn connections_established(
+        &self,
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+    ) -> usize {
+        self.established.get(&(source, dest)).cloned().unwrap_or(0)
+    }
+
+    /// Begin capturing bytes sent by new connections from `source` to `dest`.
+    pub(crate) fn capture_traffic(&mut self, source: net::IpAddr, dest: net::SocketAddr) {
+        self.captures.entry((source, dest)).or_default();
+    }
+
+    /// Returns the bytes sent by each captured connection from `source` to `dest`, in order of
+    /// establishment.
+    pub(crate) fn captured_traffic(
+        &self,
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+    ) -> Vec<Vec<u8>> {
+        self.captures
+            .get(&(source, dest))
+            .map(|logs| logs.iter().map(|log| log.lock().unwrap().clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the ledgers of each connection from `source` to `dest`, in order of
+    /// establishment.
+    pub(crate) fn ledgers(
+        &self,
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+    ) -> Vec<ConnectionLedger> {
+        self.ledgers
+            .get(&(source, dest))
+            .map(|ledgers| ledgers.iter().map(ConnectionLedgers::snapshot).collect())
+            .unwrap_or_default()
+    }
+
+    /// Inject bytes into the most recently established live connection from `source` to `dest`,
+    /// as if they were sent by the client.
+    pub(crate) fn inject(
+        &mut self,
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+        bytes: &[u8],
+    ) -> Result<(), io::Error> {
+        self.gc_dropped();
+        match self
+            .connections
+            .iter()
+            .rev()
+            .find(|c| c.source().ip() == source && c.dest() == dest)
+        {
+            Some(connection) => {
+                trace!("injecting {} bytes {} -> {}", bytes.len(), source, dest);
+                connection.inject_server(bytes);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// Set the MTU of the link between two hosts, applying to both new and existing connections.
+    pub(crate) fn set_link_mtu(&mut self, a: net::IpAddr, b: net::IpAddr, mtu: Option<usize>) {
+        trace!("setting mtu between {} and {} to {:?}", a, b, mtu);
+        for link in [(a, b), (b, a)].iter() {
+            match mtu {
+                Some(mtu) => self.mtus.insert(*link, mtu),
+                None => self.mtus.remove(link),
+            };
+        }
+        for connection in self.connections.iter() {
+            let (source, dest) = (connection.source().ip(), connection.dest().ip());
+            if (source, dest) == (a, b) || (source, dest) == (b, a) {
+                connection.set_mtu(mtu);
+            }
+        }
+    }
+
+    /// Limit the bandwidth of each connection between `a` and `b`, applying to both new and
+    /// existing connections.
+    pub(crate) fn set_link_bandwidth(
+        &mut self,
+        a: net::IpAddr,
+        b: net::IpAddr,
+        bytes_per_second: Option<u64>,
+    ) {
+        trace!(
+            "setting connection bandwidth between {} and {} to {:?}",
+            a,
+            b,
+            bytes_per_second
+        );
+        for link in [(a, b), (b, a)].iter() {
+            match bytes_per_second {
+                Some(bytes_per_second) => self.bandwidths.insert(*link, bytes_per_second),
+                None => self.bandwidths.remove(link),
+            };
+        }
+        for connection in self.connections.iter().filter(|c| c.is_between(a, b)) {
+            connection.set_bandwidth(bytes_per_second);
+        }
+    }
+
+    /// Set the priority of connections from `source` to `dest`, applying to both new and
+    /// existing connections.
+    pub(crate) fn set_priority(
+        &mut self,
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+        priority: Priority,
+    ) {
+        trace!(
+            "setting priority of {} -> {} to {:?}",
+            source,
+            dest,
+            priority
+        );
+        match priority {
+            Priority::Normal => self.priorities.remove(&(source, dest)),
+            priority => self.priorities.insert((source, dest), priority),
+        };
+        for connection in self.connections.iter_mut() {
+            if connection.source().ip() == source && connection.dest() == dest {
+                connection.set_priority(priority);
+                // corruption and fragmentation may target connections of the new priority.
+                connection.set_fragmentation(self.fragmentation.as_ref());
+                connection.set_corruption(self.corruption.as_ref());
+            }
+        }
+    }
+
+    /// Determines if a connection should be clogged based on the state of clogged connections
+    /// and partitions.
     fn should_clog(&self, source: net::SocketAddr, dest: net::SocketAddr) -> bool {
-        let source_ip = source.ip();
-        let dest_ip = dest.ip();
-        for connection in self.clogged.iter() {
-            if connection.source() == source_ip && connection.dest() == dest_ip {
-                return true;
+        self.is_clogged(source.ip(), dest.ip())
+    }
+
+    /// Returns true if traffic from `source` to `dest` is held back by a clog or a partition.
+    pub(crate) fn is_clogged(&self, source: net::IpAddr, dest: net::IpAddr) -> bool {
+        self.clogged.contains(&CloggedConnection::new(source, dest))
+            || self.partitioned.contains_key(&(source, dest))
+    }
+
+    /// Clog or unclog each direction of `connection` to match the clogs, partitions and one-way
+    /// cuts currently applied. A direction is clogged while the link the connection was made on
+    /// is clogged or partitioned, or while the direction is cut one way, so that removing one of
+    /// these faults leaves the others in place.
+    fn apply_clogs(&self, connection: &Connection) {
+        let (source, dest) = (connection.source().ip(), connection.dest().ip());
+        let clogged = self.is_clogged(source, dest);
+        connection.set_clogged(
+            clogged || self.one_way_cuts.contains(&(source, dest)),
+            clogged || self.one_way_cuts.contains(&(dest, source)),
+        );
+    }
+
+    /// Clog or unclog the existing connections between `a` and `b` to match the faults currently
+    /// applied to them, see [`Inner::apply_clogs`].
+    fn refresh_clogs(&self, a: net::IpAddr, b: net::IpAddr) {
+        for connection in self.connections.iter() {
+            if connection.is_between(a, b) {
+                self.apply_clogs(connection);
             }
         }
-        false
     }
 
     /// Clog all new connections from one IP to another. If there are any existing connections, they
     /// are also clogged.
-    fn clog_connection(&mut self, clog: CloggedConnection) {
+    pub(crate) fn clog_connection(&mut self, clog: CloggedConnection) {
         trace!("clogging connection {:?}", clog);
-        let clog_source = clog.source();
-        let clog_dest = clog.dest();
+        let (source, dest) = (clog.source(), clog.dest());
         self.clogged.insert(clog);
-        for connection in self.connections.iter_mut() {
-            let source_ip = connection.source().ip();
-            let dest_ip = connection.dest().ip();
-            if source_ip == clog_source && dest_ip == clog_dest {
-                connection.clog();
-            }
-        }
+        self.refresh_clogs(source, dest);
     }
 
     /// Unclog all new connection between two IP addresses. If there are any existing connections which
-    /// are clogged, they are unclogged.
-    fn unclog_connection(&mut self, unclog: CloggedConnection) {
+    /// are clogged, they are unclogged, unless the link is also partitioned.
+    pub(crate) fn unclog_connection(&mut self, unclog: CloggedConnection) {
         trace!("unclogging connection {:?}", unclog);
-        let clog_source = unclog.source();
-        let clog_dest = unclog.dest();
+        let (source, dest) = (unclog.source(), unclog.dest());
         self.clogged.remove(&unclog);
-        for connection in self.connections.iter_mut() {
-            let source_ip = connection.source().ip();
-            let dest_ip = connection.dest().ip();
-            if source_ip == clog_source && dest_ip == clog_dest {
-                connection.unclog();
+        self.refresh_clogs(source, dest);
+    }
+
+    /// Cut the link from `source` to `dest` for a partition. The link is restored once it is
+    /// healed, or every partition cutting it has been restored with [`Inner::restore_link`].
+    pub(crate) fn cut_link(&mut self, source: net::IpAddr, dest: net::IpAddr) {
+        *self.partitioned.entry((source, dest)).or_insert(0) += 1;
+        self.refresh_clogs(source, dest);
+    }
+
+    /// Restore the link from `source` to `dest` for one of the partitions cutting it.
+    pub(crate) fn restore_link(&mut self, source: net::IpAddr, dest: net::IpAddr) {
+        if let Entry::Occupied(mut cuts) = self.partitioned.entry((source, dest)) {
+            *cuts.get_mut() -= 1;
+            if *cuts.get() == 0 {
+                cuts.remove();
+            }
+        }
+        self.refresh_clogs(source, dest);
+        self.wake_link_waiters();
+    }
+
+    /// Returns true if a connection between `source` and `dest` cannot be established, because
+    /// traffic is cut in either direction. Establishing a connection requires traffic to flow
+    /// in both directions.
+    pub(crate) fn is_partitioned(&self, source: net::IpAddr, dest: net::IpAddr) -> bool {
+        self.partitioned.contains_key(&(source, dest))
+            || self.one_way_cuts.contains(&(source, dest))
+            || self.one_way_cuts.contains(&(dest, source))
+    }
+
+    /// Returns the time for which a connect waits on a cut link before failing.
+    pub(crate) fn syn_timeout(&self) -> time::Duration {
+        self.syn_timeout
+    }
+
+    /// Fail connects across a cut link once they have waited `timeout` for it to be restored.
+    pub(crate) fn set_syn_timeout(&mut self, timeout: time::Duration) {
+        self.syn_timeout = timeout;
+    }
+
+    /// Wake `waker` the next time a cut link is restored.
+    pub(crate) fn wait_for_link(&mut self, waker: Waker) {
+        if !self.link_waiters.iter().any(|w| w.will_wake(&waker)) {
+            self.link_waiters.push(waker);
+        }
+    }
+
+    fn wake_link_waiters(&mut self) {
+        for waker in self.link_waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Cut every link between a host in `a` and a host in `b`. Connecting across the partition
+    /// waits for it to be healed, failing after the SYN timeout, and existing connections
+    /// across it stall until it is healed.
+    pub(crate) fn partition(&mut self, a: &[net::IpAddr], b: &[net::IpAddr]) {
+        for source in a.iter() {
+            for dest in b.iter() {
+                self.cut_link(*source, *dest);
+                self.cut_link(*dest, *source);
             }
         }
     }
+
+    /// Drop traffic sent from hosts in `from` to hosts in `to`, see [`Inner::cut_one_way`].
+    pub(crate) fn partition_one_way(&mut self, from: &[net::IpAddr], to: &[net::IpAddr]) {
+        for from in from.iter() {
+            for to in to.iter() {
+                self.cut_one_way(*from, *to);
+            }
+        }
+    }
+
+    /// Leave every live connection between `survivor` and `peer` half open, as if `peer`
+    /// silently dropped them.
+    pub(crate) fn half_open(&mut self, survivor: net::IpAddr, peer: net::IpAddr) {
+        trace!(
+            "dropping connections from {} to {} silently",
+            peer,
+            survivor
+        );
+        for connection in self.connections.iter() {
+            if connection.is_between(survivor, peer) {
+                connection.half_open(survivor);
+            }
+        }
+    }
+
+    /// Drop traffic sent from `from` to `to`, while traffic from `to` to `from` continues to
+    /// flow. New connections between the hosts cannot be established until the cut is healed.
+    pub(crate) fn cut_one_way(&mut self, from: net::IpAddr, to: net::IpAddr) {
+        trace!("cutting link {} -> {}", from, to);
+        self.one_way_cuts.insert((from, to));
+        self.refresh_clogs(from, to);
+    }
+
+    /// Returns the time for `source` to send a message to `dest` and receive a reply, based on
+    /// the one-way latencies configured in each direction.
+    fn round_trip_time(&self, source: net::IpAddr, dest: net::IpAddr) -> time::Duration {
+        let one_way = |from, to| {
+            self.one_way_latencies
+                .get(&(from, to))
+                .cloned()
+                .unwrap_or_default()
+        };
+        one_way(source, dest) + one_way(dest, source)
+    }
+
+    /// Delay traffic sent from `from` to `to` by `latency`, or remove the delay if `None`.
+    pub(crate) fn set_one_way_latency(
+        &mut self,
+        from: net::IpAddr,
+        to: net::IpAddr,
+        latency: Option<time::Duration>,
+    ) {
+        match latency {
+            Some(latency) => self.one_way_latencies.insert((from, to), latency),
+            None => self.one_way_latencies.remove(&(from, to)),
+        };
+        for connection in self.connections.iter() {
+            if connection.is_between(from, to) {
+                connection.set_latency_from(from, latency);
+            }
+        }
+    }
+
+    /// Restore every link cut by [`Inner::partition`], [`Inner::cut_link`] or
+    /// [`Inner::cut_one_way`]. Clogged links remain clogged.
+    pub(crate) fn heal(&mut self) {
+        let mut links: Vec<_> = self.partitioned.drain().map(|(link, _)| link).collect();
+        links.extend(self.one_way_cuts.drain());
+        for (source, dest) in links {
+            self.refresh_clogs(source, dest);
+        }
+        self.wake_link_waiters();
+    }
 }
@@ -0,0 +1,188 @@
+//! Accounting of the bytes carried by simulated connections.
+//!
+//! Every connection keeps a ledger for each direction, recording bytes as they are written by
+//! the sender and as they are read by the receiver. Bytes are numbered by their offset in the
+//! stream, and each byte read is checked against the byte written at the same offset. Tests can
+//! then assert that a connection delivered every byte exactly once and in order, distinguishing
+//! reordering or duplication introduced by an application from data lost by the transport.
+//!
+//! Bytes injected into a connection, such as by replay, were never written by the sender and
+//! are excluded from the ledger.
+use std::{collections, fmt, net, sync};
+
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    /// Bytes which have been written but not yet read.
+    unread: collections::VecDeque<u8>,
+    sent: u64,
+    received: u64,
+    /// Offset of the first byte read which did not match the byte written at that offset.
+    first_divergence: Option<u64>,
+}
+
+impl Ledger {
+    pub(crate) fn new() -> sync::Arc<sync::Mutex<Ledger>> {
+        sync::Arc::new(sync::Mutex::new(Ledger::default()))
+    }
+
+    pub(crate) fn record_sent(&mut self, bytes: &[u8]) {
+        self.sent += bytes.len() as u64;
+        self.unread.extend(bytes);
+    }
+
+    pub(crate) fn record_received(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let offset = self.received;
+            self.received += 1;
+            if self.unread.pop_front() != Some(*byte) {
+                self.first_divergence.get_or_insert(offset);
+            }
+        }
+    }
+
+    fn snapshot(&self) -> ByteLedger {
+        ByteLedger {
+            sent: self.sent,
+            received: self.received,
+            in_flight: self.unread.len() as u64,
+            first_divergence: self.first_divergence,
+        }
+    }
+}
+
+/// Bytes carried in one direction of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteLedger {
+    /// Number of bytes written by the sender.
+    pub sent: u64,
+    /// Number of bytes read by the receiver.
+    pub received: u64,
+    /// Number of bytes written which have not been read.
+    pub in_flight: u64,
+    /// Offset of the first byte read which differs from the byte written at that offset, if
+    /// any. Bytes read beyond those written also diverge.
+    pub first_divergence: Option<u64>,
+}
+
+impl ByteLedger {
+    /// Returns true if every byte written has been read exactly once, in order.
+    pub fn delivered_exactly_once(&self) -> bool {
+        self.first_divergence.is_none() && self.in_flight == 0 && self.sent == self.received
+    }
+}
+
+/// Ledgers for both directions of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionLedger {
+    pub source: net::SocketAddr,
+    pub dest: net::SocketAddr,
+    /// Bytes written by the client and read by the server.
+    pub client_to_server: ByteLedger,
+    /// Bytes written by the server and read by the client.
+    pub server_to_client: ByteLedger,
+}
+
+impl ConnectionLedger {
+    /// Returns true if every byte written in either direction has been read exactly once, in
+    /// order.
+    pub fn delivered_exactly_once(&self) -> bool {
+        self.client_to_server.delivered_exactly_once()
+            && self.server_to_client.delivered_exactly_once()
+    }
+
+    /// Panic with a description of the ledger if any byte was lost, duplicated, reordered or
+    /// is still in flight.
+    pub fn assert_delivered_exactly_once(&self) {
+        assert!(
+            self.delivered_exactly_once(),
+            "connection did not deliver exactly once: {}",
+            self
+        );
+    }
+}
+
+impl fmt::Display for ConnectionLedger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (from, to, ledger) in [
+            (self.source, self.dest, &self.client_to_server),
+            (self.dest, self.source, &self.server_to_client),
+        ]
+        .iter()
+        {
+            write!(
+                f,
+                "{} -> {} sent {} received {} in flight {}",
+                from, to, ledger.sent, ledger.received, ledger.in_flight
+            )?;
+            if let Some(offset) = ledger.first_divergence {
+                write!(f, " diverged at offset {}", offset)?;
+            }
+            write!(f, "; ")?;
+        }
+        Ok(())
+    }
+}
+
+/// Shared ledgers of a connection, updated by both of its streams.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionLedgers {
+    source: net::SocketAddr,
+    dest: net::SocketAddr,
+    pub(crate) client_to_server: sync::Arc<sync::Mutex<Ledger>>,
+    pub(crate) server_to_client: sync::Arc<sync::Mutex<Ledger>>,
+}
+
+impl ConnectionLedgers {
+    pub(crate) fn new(source: net::SocketAddr, dest: net::SocketAddr) -> Self {
+        Self {
+            source,
+            dest,
+            client_to_server: Ledger::new(),
+            server_to_client: Ledger::new(),
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionLedger {
+        ConnectionLedger {
+            source: self.source,
+            dest: self.dest,
+            client_to_server: self.client_to_server.lock().unwrap().snapshot(),
+            server_to_client: self.server_to_client.lock().unwrap().snapshot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that reordered and duplicated bytes are detected at the offset they diverge.
+    fn divergence() {
+        let mut ledger = Ledger::default();
+        ledger.record_sent(b"abcd");
+        ledger.record_received(b"ab");
+        assert_eq!(
+            ledger.snapshot(),
+            ByteLedger {
+                sent: 4,
+                received: 2,
+                in_flight: 2,
+                first_divergence: None,
+            }
+        );
+        ledger.record_received(b"cd");
+        assert!(ledger.snapshot().delivered_exactly_once());
+
+        ledger.record_sent(b"ef");
+        ledger.record_received(b"fe");
+        assert_eq!(ledger.snapshot().first_divergence, Some(4));
+
+        let mut ledger = Ledger::default();
+        ledger.record_sent(b"a");
+        ledger.record_received(b"aa");
+        let snapshot = ledger.snapshot();
+        assert_eq!(snapshot.first_divergence, Some(1));
+        assert!(!snapshot.delivered_exactly_once());
+    }
+}
@@ -1,26 +1,124 @@
-use super::{FaultyTcpStream, SocketHalf};
+use super::{
+    abort::{AbortSignal, Aborted},
+    FaultyTcpStream, Inner, SocketHalf,
+};
 use crate::TcpStream;
 use async_trait::async_trait;
-use futures::{channel::mpsc, Poll, Stream, StreamExt};
-use std::{fmt, io, net, pin::Pin, task::Context};
+use futures::{channel::mpsc, FutureExt, Poll, Stream, StreamExt};
+use std::{fmt, io, net, pin::Pin, sync, task::Context, time};
 use tracing::trace;
 
+/// Cost of a connection setup handshake, such as TLS, performed before a connection to a
+/// listener is established. Models the timing of the handshake without performing any crypto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    /// Round trips between client and server required to complete the handshake, each taking
+    /// the current round trip time of the connection.
+    pub round_trips: u32,
+    /// Processing time spent on the handshake, such as for key exchange and certificate
+    /// verification.
+    pub cpu: time::Duration,
+}
+
+impl Handshake {
+    pub fn new(round_trips: u32, cpu: time::Duration) -> Self {
+        Self { round_trips, cpu }
+    }
+
+    /// A full TLS 1.2 handshake, taking 2 round trips.
+    pub fn tls12() -> Self {
+        Handshake::new(2, time::Duration::from_millis(2))
+    }
+
+    /// A full TLS 1.3 handshake, taking a single round trip.
+    pub fn tls13() -> Self {
+        Handshake::new(1, time::Duration::from_millis(1))
+    }
+
+    /// Returns the time taken to complete this handshake given the provided round trip time.
+    pub fn duration(&self, round_trip_time: time::Duration) -> time::Duration {
+        round_trip_time * self.round_trips + self.cpu
+    }
+}
+
+/// Default number of established connections which can wait to be accepted by a listener
+/// before new connections are refused, matching SOMAXCONN on Linux.
+const DEFAULT_BACKLOG: usize = 128;
+
+#[derive(Debug)]
+struct BacklogState {
+    limit: usize,
+    queued: usize,
+}
+
+/// Established connections which are waiting to be accepted by a listener.
+#[derive(Debug, Clone)]
+pub(crate) struct Backlog {
+    state: sync::Arc<sync::Mutex<BacklogState>>,
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        let state = BacklogState {
+            limit: DEFAULT_BACKLOG,
+            queued: 0,
+        };
+        Self {
+            state: sync::Arc::new(sync::Mutex::new(state)),
+        }
+    }
+}
+
+impl Backlog {
+    pub(crate) fn set_limit(&self, limit: usize) {
+        self.state.lock().unwrap().limit = limit;
+    }
+
+    /// Reserve a place in the backlog for a new connection, returning false if it is full.
+    pub(crate) fn try_push(&self) -> bool {
+        let mut lock = self.state.lock().unwrap();
+        if lock.queued >= lock.limit {
+            return false;
+        }
+        lock.queued += 1;
+        true
+    }
+
+    fn pop(&self) {
+        let mut lock = self.state.lock().unwrap();
+        lock.queued = lock.queued.saturating_sub(1);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.state.lock().unwrap().queued = 0;
+    }
+}
+
 #[derive(Debug)]
 /// ListenerState represents both the bound and unbound state of a Listener.
 /// This allows supporting late binding of Listeners to sockets.
 pub(crate) enum ListenerState {
     Unbound {
-        tx: mpsc::Sender<FaultyTcpStream<SocketHalf>>,
-        rx: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+        tx: mpsc::UnboundedSender<FaultyTcpStream<SocketHalf>>,
+        rx: mpsc::UnboundedReceiver<FaultyTcpStream<SocketHalf>>,
     },
     Bound {
-        tx: mpsc::Sender<FaultyTcpStream<SocketHalf>>,
+        tx: mpsc::UnboundedSender<FaultyTcpStream<SocketHalf>>,
     },
 }
 
 pub struct Listener {
     local_addr: net::SocketAddr,
-    incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+    incoming: mpsc::UnboundedReceiver<FaultyTcpStream<SocketHalf>>,
+    /// Network notified when this listener is closed, starting the TIME_WAIT period of its
+    /// address.
+    inner: Option<sync::Arc<sync::Mutex<Inner>>>,
+    /// Signal fired when the host of this listener crashes.
+    abort: Option<Aborted>,
+    /// Connections waiting to be accepted, released as they are accepted.
+    backlog: Option<Backlog>,
+    /// Other addresses this listener accepts connections to, closed along with it.
+    aliases: Vec<net::SocketAddr>,
 }
 
 impl fmt::Debug for Listener {
@@ -33,11 +131,86 @@ impl fmt::Debug for Listener {
 impl Listener {
     pub fn new(
         local_addr: net::SocketAddr,
-        incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+        incoming: mpsc::UnboundedReceiver<FaultyTcpStream<SocketHalf>>,
     ) -> Self {
         Self {
             local_addr,
             incoming,
+            inner: None,
+            abort: None,
+            backlog: None,
+            aliases: vec![],
+        }
+    }
+
+    /// Notify `inner` when this listener is closed.
+    pub(crate) fn close_into(&mut self, inner: sync::Arc<sync::Mutex<Inner>>) {
+        self.inner.replace(inner);
+    }
+
+    /// Fail pending and subsequent accepts once `abort` is fired.
+    pub(crate) fn abort_on(&mut self, abort: AbortSignal) {
+        self.abort.replace(abort.aborted());
+    }
+
+    /// Close `alias` along with this listener.
+    pub(crate) fn alias_into(&mut self, alias: net::SocketAddr) {
+        self.aliases.push(alias);
+    }
+
+    /// Release a place in `backlog` for each connection accepted.
+    pub(crate) fn backlog_into(&mut self, backlog: Backlog) {
+        self.backlog.replace(backlog);
+    }
+
+    /// Returns true if the host of this listener has crashed.
+    fn is_aborted(&self) -> bool {
+        self.abort.as_ref().map_or(false, Aborted::is_aborted)
+    }
+
+    fn poll_incoming(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<FaultyTcpStream<SocketHalf>>, io::Error>> {
+        if let Some(abort) = &mut self.abort {
+            if let Poll::Ready(error) = abort.poll_unpin(cx) {
+                return Poll::Ready(Err(error));
+            }
+        }
+        let next = futures::ready!(self.incoming.poll_next_unpin(cx));
+        if let (Some(_), Some(backlog)) = (&next, &self.backlog) {
+            backlog.pop();
+        }
+        Poll::Ready(Ok(next))
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Crashing a host closes its listeners without entering TIME_WAIT.
+        if self.is_aborted() {
+            return;
+        }
+        // Connections which were established but never accepted are reset, as when a listening
+        // socket with a non-empty accept queue is closed.
+        self.incoming.close();
+        let mut queued = vec![];
+        while let Ok(Some(stream)) = self.incoming.try_next() {
+            if let Some(backlog) = &self.backlog {
+                backlog.pop();
+            }
+            queued.push(stream);
+        }
+        if let Some(inner) = self.inner.take() {
+            if let Ok(mut lock) = inner.lock() {
+                for stream in queued.iter() {
+                    lock.reset_connection(stream);
+                }
+                lock.close_listener(self.local_addr);
+                for alias in self.aliases.iter() {
+                    lock.close_listener(*alias);
+                }
+            }
         }
     }
 }
@@ -48,7 +221,8 @@ impl Listener {
     async fn accept(
         &mut self,
     ) -> Result<(FaultyTcpStream<SocketHalf>, net::SocketAddr), io::Error> {
-        if let Some(next) = self.incoming.next().await {
+        let next = futures::future::poll_fn(|cx| self.poll_incoming(cx)).await?;
+        if let Some(next) = next {
             let addr = next.peer_addr()?;
             trace!("accepted new connection from {}", addr);
             Ok((next, addr))
@@ -60,15 +234,24 @@ impl Listener {
 }
 
 struct ListenerStream {
-    incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+    listener: Listener,
+    /// Set once the host of the listener has crashed, ending the stream.
+    aborted: bool,
 }
 
 impl Stream for ListenerStream {
     type Item = Result<FaultyTcpStream<SocketHalf>, io::Error>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match futures::ready!(self.incoming.poll_next_unpin(cx)) {
-            Some(item) => Poll::Ready(Some(Ok(item))),
-            None => Poll::Ready(None),
+        if self.aborted {
+            return Poll::Ready(None);
+        }
+        match futures::ready!(self.listener.poll_incoming(cx)) {
+            Ok(Some(item)) => Poll::Ready(Some(Ok(item))),
+            Ok(None) => Poll::Ready(None),
+            Err(error) => {
+                self.aborted = true;
+                Poll::Ready(Some(Err(error)))
+            }
         }
     }
 }
@@ -89,7 +272,9 @@ impl crate::TcpListener for Listener {
         Ok(())
     }
     fn into_stream(self) -> Pin<Box<dyn Stream<Item = Result<Self::Stream, io::Error>> + Send>> {
-        let Listener { incoming, .. } = self;
-        Box::pin(ListenerStream { incoming })
+        Box::pin(ListenerStream {
+            listener: self,
+            aborted: false,
+        })
     }
 }
@@ -5,17 +5,76 @@
 //!
 //! The network can inject partitions between machines.
 
-use std::{io, net, sync};
+use futures::{future, FutureExt, Poll};
+use std::{collections, error, fmt, io, net, ops, path, sync, time};
+use tracing::trace;
+mod abort;
+mod dns;
 pub(crate) mod fault;
 mod inner;
+mod ledger;
 mod listen;
+mod nat;
+mod nic;
 pub(crate) mod socket;
+mod topology;
+mod udp;
+mod unix;
+pub use dns::Resolver;
+pub use fault::{ChaosProfile, FaultTarget, LatencyModel, Partition, PartitionShape, Priority};
 pub(crate) use inner::Inner;
-pub use listen::Listener;
-use listen::ListenerState;
+pub use ledger::{ByteLedger, ConnectionLedger};
+use listen::{Backlog, ListenerState};
+pub use listen::{Handshake, Listener};
+pub use nat::{Nat, NatHandle};
+pub use socket::FlushMode;
 use socket::{FaultyTcpStream, SocketHalf};
+pub use topology::{LinkProfile, Topology, TopologyBuilder};
+pub use udp::UdpSocket;
+pub use unix::{UnixListener, UnixStream};
 
 pub type Socket = FaultyTcpStream<SocketHalf>;
+
+/// Ephemeral port usage of a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortUsage {
+    /// Number of ports used by live connections originating from the host.
+    pub in_use: usize,
+    /// Number of ports held in TIME_WAIT by connections which the host closed.
+    pub time_wait: usize,
+    /// Number of ports held by listeners bound on the host.
+    pub listening: usize,
+    /// Number of ports in the ephemeral port range of the host.
+    pub capacity: usize,
+}
+
+/// Error returned when every ephemeral port of a host is in use, by `connect` on the connecting
+/// host or the public address of a NAT it connects through, and by binding port 0. The error is
+/// returned as the inner error of an `io::Error` of kind `AddrNotAvailable`, and can be
+/// recovered with `io::Error::get_ref` and `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrExhausted {
+    /// Host which ran out of ports.
+    pub host: net::IpAddr,
+    /// Port usage of the host when the port was requested.
+    pub usage: PortUsage,
+}
+
+impl fmt::Display for AddrExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ephemeral ports exhausted on {}: {} of {} in use, {} in TIME_WAIT, {} listening",
+            self.host,
+            self.usage.in_use,
+            self.usage.capacity,
+            self.usage.time_wait,
+            self.usage.listening
+        )
+    }
+}
+
+impl error::Error for AddrExhausted {}
 pub struct DeterministicNetwork {
     inner: sync::Arc<sync::Mutex<Inner>>,
 }
@@ -34,6 +93,78 @@ impl DeterministicNetwork {
     {
         DeterministicNetworkHandle::new(local_addr.into(), sync::Arc::clone(&self.inner))
     }
+    /// Returns a handle for a dual-stack host with both an IPv4 and an IPv6 address.
+    pub fn dual_stack(&self, v4: net::Ipv4Addr, v6: net::Ipv6Addr) -> DeterministicNetworkHandle {
+        let mut handle = self.scoped(v4);
+        handle.local_v6 = Some(v6);
+        handle
+    }
+
+    /// Partition the hosts in `a` from the hosts in `b`. Connects between the two groups wait for
+    /// the partition to be healed, failing with `TimedOut` after the SYN timeout, and existing
+    /// connections stall until the partition is healed.
+    pub fn partition(&self, a: &[net::IpAddr], b: &[net::IpAddr]) {
+        self.inner.lock().unwrap().partition(a, b);
+    }
+
+    /// Drop traffic sent from hosts in `from` to hosts in `to`, while traffic in the opposite
+    /// direction continues to flow. Connects between the groups wait for the cut to be healed,
+    /// failing with `TimedOut` after the SYN timeout, and existing connections stall in one
+    /// direction until healed.
+    pub fn partition_one_way(&self, from: &[net::IpAddr], to: &[net::IpAddr]) {
+        self.inner.lock().unwrap().partition_one_way(from, to);
+    }
+
+    /// Delay traffic sent from `from` to `to` on new and existing connections by `latency`,
+    /// leaving traffic in the opposite direction unchanged. Passing `None` removes the delay.
+    pub fn set_one_way_latency(
+        &self,
+        from: net::IpAddr,
+        to: net::IpAddr,
+        latency: Option<time::Duration>,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_one_way_latency(from, to, latency);
+    }
+
+    /// Hold the local address of each connection which a host closes before its peer in
+    /// TIME_WAIT for `time_wait`, or never if `None`, for hosts without their own TIME_WAIT
+    /// period. Defaults to `None`.
+    pub fn set_time_wait(&self, time_wait: Option<time::Duration>) {
+        self.inner.lock().unwrap().set_time_wait(time_wait);
+    }
+
+    /// Set when flushes of connection streams complete, applying to both new and existing
+    /// connections. Defaults to [`FlushMode::Delivered`].
+    pub fn set_flush_mode(&self, mode: FlushMode) {
+        self.inner.lock().unwrap().set_flush_mode(mode);
+    }
+
+    /// Reset connections once no bytes have been exchanged in either direction for `timeout`,
+    /// as middleboxes and load balancers discard idle connections, or never if `None`. Applies
+    /// to both new and existing connections. Once reset, reads fail with `ConnectionReset` and
+    /// writes fail with `BrokenPipe`.
+    pub fn set_idle_timeout(&self, timeout: Option<time::Duration>) {
+        self.inner.lock().unwrap().set_idle_timeout(timeout);
+    }
+
+    /// Fail connects across a partition with `TimedOut` once they have waited `timeout` for it to
+    /// be healed, as a connect gives up retransmitting its SYN. Defaults to 127 seconds, as on
+    /// Linux. Callers which give up sooner can use `connect_timeout`.
+    pub fn set_syn_timeout(&self, timeout: time::Duration) {
+        self.inner.lock().unwrap().set_syn_timeout(timeout);
+    }
+
+    /// Heal every partition created by [`DeterministicNetwork::partition`] or
+    /// [`DeterministicNetwork::partition_one_way`].
+    pub fn heal(&self) {
+        self.inner.lock().unwrap().heal();
+    }
+
+    /// Allocate ephemeral listener ports from seeded offsets drawn from `random_handle`.
+    pub(crate) fn set_random_handle(&self, random_handle: super::DeterministicRandomHandle) {
+        self.inner.lock().unwrap().set_random_handle(random_handle);
+    }
 
     pub(crate) fn clone_inner(&self) -> sync::Arc<sync::Mutex<Inner>> {
         sync::Arc::clone(&self.inner)
@@ -43,35 +174,417 @@ impl DeterministicNetwork {
 /// NetworkHandle is a scoped handle for binding and creating new connections.
 /// Each NetworkHandle is scoped to a particular IP address, which is then used when
 /// injecting faults.
+///
+/// A dual-stack handle additionally has an IPv6 address, which is used in place of its IPv4
+/// address when binding IPv6 addresses and connecting to IPv6 peers.
 #[derive(Debug, Clone)]
 pub struct DeterministicNetworkHandle {
     local_addr: net::IpAddr,
+    local_v6: Option<net::Ipv6Addr>,
     inner: sync::Arc<sync::Mutex<Inner>>,
 }
 
 impl DeterministicNetworkHandle {
     fn new(local_addr: net::IpAddr, inner: sync::Arc<sync::Mutex<Inner>>) -> Self {
-        DeterministicNetworkHandle { local_addr, inner }
+        DeterministicNetworkHandle {
+            local_addr,
+            local_v6: None,
+            inner,
+        }
+    }
+
+    /// Returns the address of this host in the same family as `addr`, if it has one.
+    fn local_ip(&self, addr: net::IpAddr) -> net::IpAddr {
+        match (addr, self.local_v6) {
+            (net::IpAddr::V6(_), Some(v6)) => v6.into(),
+            _ => self.local_addr,
+        }
+    }
+
+    /// Returns the address of this host to bind in place of `addr`. Unspecified and loopback
+    /// addresses bind this host, while the addresses of other hosts cannot be bound.
+    fn bind_ip(&self, addr: net::IpAddr) -> io::Result<net::IpAddr> {
+        let own = addr == self.local_addr || self.local_v6.map(net::IpAddr::V6) == Some(addr);
+        if own || addr.is_unspecified() || addr.is_loopback() {
+            Ok(self.local_ip(addr))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} is not an address of host {}", addr, self.local_addr),
+            ))
+        }
+    }
+
+    /// Returns the address to connect to in place of `dest`. Loopback addresses never leave this
+    /// host, so connect to this host rather than to whichever host has the loopback address.
+    fn connect_addr(&self, mut dest: net::SocketAddr) -> net::SocketAddr {
+        if dest.ip().is_loopback() {
+            dest.set_ip(self.local_ip(dest.ip()));
+        }
+        dest
+    }
+
+    /// Register the address of this host under `name`, so that it is returned when `name` is
+    /// resolved. A name can be registered by several hosts.
+    pub fn register_name(&self, name: &str) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.register_name(name, self.local_addr);
+    }
+
+    /// Remove the address of this host from the addresses registered under `name`.
+    pub fn unregister_name(&self, name: &str) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.unregister_name(name, self.local_addr);
+    }
+
+    /// Fail resolution of `name` with `error` regardless of the addresses registered under it,
+    /// or resume resolving it normally if `None`.
+    pub fn set_resolve_error(&self, name: &str, error: Option<io::ErrorKind>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_resolve_error(name, error);
+    }
+
+    /// Cache the answers of every host's resolver for `ttl`, and the `NotFound` answers for names
+    /// which are not registered for `negative_ttl`. Until a cached answer expires, the host does
+    /// not observe changes to the registered addresses. Both TTLs default to zero, which disables
+    /// caching.
+    pub fn set_name_ttl(&self, ttl: time::Duration, negative_ttl: time::Duration) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_name_ttl(ttl, negative_ttl);
+    }
+
+    /// Serve cached answers past their TTL rather than resolving them again, as a resolver which
+    /// has lost contact with its upstream does. Only names which were already cached are affected.
+    pub fn set_serve_stale(&self, serve_stale: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_serve_stale(serve_stale);
+    }
+
+    /// Partition the hosts in `a` from the hosts in `b`. Connects between the two groups wait for
+    /// the partition to be healed, failing with `TimedOut` after the SYN timeout, and existing
+    /// connections stall until the partition is healed.
+    pub fn partition(&self, a: &[net::IpAddr], b: &[net::IpAddr]) {
+        self.inner.lock().unwrap().partition(a, b);
+    }
+
+    /// Drop traffic sent from hosts in `from` to hosts in `to`, while traffic in the opposite
+    /// direction continues to flow. Connects between the groups wait for the cut to be healed,
+    /// failing with `TimedOut` after the SYN timeout, and existing connections stall in one
+    /// direction until healed.
+    pub fn partition_one_way(&self, from: &[net::IpAddr], to: &[net::IpAddr]) {
+        self.inner.lock().unwrap().partition_one_way(from, to);
     }
 
+    /// Delay traffic sent from `from` to `to` on new and existing connections by `latency`,
+    /// leaving traffic in the opposite direction unchanged. Passing `None` removes the delay.
+    pub fn set_one_way_latency(
+        &self,
+        from: net::IpAddr,
+        to: net::IpAddr,
+        latency: Option<time::Duration>,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_one_way_latency(from, to, latency);
+    }
+
+    /// Heal every partition, restoring the links between partitioned hosts.
+    pub fn heal(&self) {
+        self.inner.lock().unwrap().heal();
+    }
+
+    pub fn resolver(&self) -> Resolver {
+        Resolver::new(self.local_addr, sync::Arc::clone(&self.inner))
+    }
+
+    /// Bind a listener to `bind_addr` on this host. On a dual-stack host, binding the
+    /// unspecified IPv6 address accepts connections to both the IPv4 and IPv6 addresses. Binding
+    /// port 0 allocates an unused port from the ephemeral port range of this host, which is
+    /// reported by `local_addr`, and fails with [`AddrExhausted`] once every port in the range is
+    /// in use. Binding the address of another host fails with `AddrNotAvailable`.
     pub async fn bind(&self, mut bind_addr: net::SocketAddr) -> Result<Listener, io::Error> {
-        bind_addr.set_ip(self.local_addr);
+        let dual_stack = bind_addr.ip() == net::IpAddr::V6(net::Ipv6Addr::UNSPECIFIED)
+            && self.local_v6.is_some();
+        bind_addr.set_ip(self.bind_ip(bind_addr.ip())?);
         let mut lock = self.inner.lock().unwrap();
-        lock.listen(bind_addr)
+        if bind_addr.port() == 0 {
+            bind_addr.set_port(lock.unused_listener_port(bind_addr.ip())?);
+        }
+        let v4_addr = net::SocketAddr::new(self.local_addr, bind_addr.port());
+        if dual_stack && lock.is_bound(v4_addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let mut listener = lock.listen(bind_addr)?;
+        if dual_stack {
+            lock.alias_listener(v4_addr, bind_addr)?;
+            listener.alias_into(v4_addr);
+        }
+        drop(lock);
+        listener.close_into(sync::Arc::clone(&self.inner));
+        Ok(listener)
     }
 
+    /// Bind a datagram socket to `bind_addr` on this host. If the port is 0, an unused ephemeral
+    /// port is chosen. Binding the address of another host fails with `AddrNotAvailable`.
+    pub async fn bind_udp(&self, mut bind_addr: net::SocketAddr) -> Result<UdpSocket, io::Error> {
+        bind_addr.set_ip(self.bind_ip(bind_addr.ip())?);
+        let mut lock = self.inner.lock().unwrap();
+        let (local_addr, incoming) = lock.bind_udp(bind_addr)?;
+        Ok(UdpSocket::new(
+            local_addr,
+            incoming,
+            lock.time_handle(),
+            sync::Arc::clone(&self.inner),
+            lock.abort_signal(local_addr.ip()),
+        ))
+    }
+
+    /// Bind a Unix domain socket listener to `path` on this host.
+    pub async fn bind_unix(&self, path: &path::Path) -> Result<UnixListener, io::Error> {
+        let mut lock = self.inner.lock().unwrap();
+        let incoming = lock.bind_unix(self.local_addr, path.to_path_buf())?;
+        Ok(UnixListener::new(
+            self.local_addr,
+            path.to_path_buf(),
+            incoming,
+            sync::Arc::clone(&self.inner),
+            lock.abort_signal(self.local_addr),
+        ))
+    }
+
+    /// Connect to the Unix domain socket listener bound to `path` on this host.
+    pub async fn connect_unix(&self, path: &path::Path) -> Result<UnixStream, io::Error> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.connect_unix(self.local_addr, path)
+    }
+
+    /// Connect to `dest` from this host. Connections originate from the address of this host,
+    /// and connections to a loopback address connect to this host. Connecting across a
+    /// partition waits for it to be healed, failing with `TimedOut` after the SYN timeout.
     pub async fn connect(
         &self,
         dest: net::SocketAddr,
     ) -> Result<FaultyTcpStream<SocketHalf>, io::Error> {
+        let dest = self.connect_addr(dest);
+        let source = self.local_ip(dest.ip());
+        self.wait_for_link(source, dest.ip()).await?;
         let connfut = {
             let mut lock = self.inner.lock().unwrap();
-            let ret = lock.connect(self.local_addr, dest);
+            let ret = lock.connect(source, dest);
             drop(lock);
             ret
         };
         connfut.await
     }
+
+    /// Wait for traffic to flow in both directions between `source` and `dest`, as a connect
+    /// retransmits its SYN across a partition. Fails with `TimedOut` once the SYN timeout
+    /// elapses, or if `source` crashes first.
+    async fn wait_for_link(&self, source: net::IpAddr, dest: net::IpAddr) -> Result<(), io::Error> {
+        let (mut timeout, mut abort) = {
+            let mut lock = self.inner.lock().unwrap();
+            if !lock.is_partitioned(source, dest) {
+                return Ok(());
+            }
+            trace!(
+                "{} is partitioned from {}, retransmitting SYN",
+                source,
+                dest
+            );
+            let timeout = lock.time_handle().delay_from(lock.syn_timeout());
+            (timeout, lock.abort_signal(source).aborted())
+        };
+        let inner = &self.inner;
+        future::poll_fn(move |cx| {
+            if let Poll::Ready(error) = abort.poll_unpin(cx) {
+                return Poll::Ready(Err(error));
+            }
+            let mut lock = inner.lock().unwrap();
+            if !lock.is_partitioned(source, dest) {
+                return Poll::Ready(Ok(()));
+            }
+            if timeout.poll_unpin(cx).is_ready() {
+                trace!("connect {} -> {} timed out", source, dest);
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "network partitioned",
+                )));
+            }
+            lock.wait_for_link(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Require connections to the listener bound to `bind_addr` on this host to complete the
+    /// provided handshake before they are established, delaying both the connecting client and
+    /// the accepting server. Passing `None` disables the handshake.
+    pub fn set_handshake(&self, mut bind_addr: net::SocketAddr, handshake: Option<Handshake>) {
+        bind_addr.set_ip(self.local_ip(bind_addr.ip()));
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_handshake(bind_addr, handshake);
+    }
+
+    /// Limit the number of established connections waiting to be accepted by the listener bound
+    /// to `bind_addr` on this host, which defaults to 128. Once the backlog is full, new
+    /// connections fail with `ConnectionRefused` rather than waiting to be accepted, as when an
+    /// accept loop falls behind.
+    pub fn set_backlog(&self, mut bind_addr: net::SocketAddr, backlog: usize) {
+        bind_addr.set_ip(self.local_ip(bind_addr.ip()));
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_backlog(bind_addr, backlog);
+    }
+
+    /// Restrict the local ports used by connections from this host, and by NATs using this host
+    /// as their public address, to `ports`. Once every port is in use, connecting fails with
+    /// [`AddrExhausted`].
+    pub fn set_ephemeral_ports(&self, ports: ops::RangeInclusive<u16>) {
+        assert!(
+            ports.start() <= ports.end(),
+            "illegal ephemeral port range: {:?}",
+            ports
+        );
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_ephemeral_ports(self.local_addr, ports);
+    }
+
+    /// Hold the local address of each connection which this host closes before its peer in
+    /// TIME_WAIT for `time_wait`, overriding the TIME_WAIT period of the network. New
+    /// connections cannot use a port in TIME_WAIT, so workloads which open and close connections
+    /// faster than ports leave TIME_WAIT eventually exhaust the ephemeral port range. A listener
+    /// address in TIME_WAIT cannot be bound again unless SO_REUSEADDR is set. `None` releases
+    /// addresses as soon as connections are dropped.
+    pub fn set_time_wait(&self, time_wait: Option<time::Duration>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_host_time_wait(self.local_addr, time_wait);
+        if let Some(v6) = self.local_v6 {
+            lock.set_host_time_wait(v6.into(), time_wait);
+        }
+    }
+
+    /// Returns the ephemeral port usage of this host. Ports used by connections which have been
+    /// dropped are not counted as in use, so churn tests can assert that connections are not
+    /// leaked.
+    pub fn port_usage(&self) -> PortUsage {
+        let mut lock = self.inner.lock().unwrap();
+        lock.port_usage(self.local_addr)
+    }
+
+    /// Returns the number of connections which have been established from this host to `dest`.
+    pub fn connections_established(&self, dest: net::SocketAddr) -> usize {
+        let lock = self.inner.lock().unwrap();
+        lock.connections_established(self.local_ip(dest.ip()), dest)
+    }
+
+    /// Begin capturing the bytes sent by new connections from this host to `dest`.
+    pub fn capture_traffic(&self, dest: net::SocketAddr) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.capture_traffic(self.local_ip(dest.ip()), dest);
+    }
+
+    /// Returns the bytes sent by each captured connection from this host to `dest`, in order of
+    /// establishment.
+    pub fn captured_traffic(&self, dest: net::SocketAddr) -> Vec<Vec<u8>> {
+        let lock = self.inner.lock().unwrap();
+        lock.captured_traffic(self.local_ip(dest.ip()), dest)
+    }
+
+    /// Returns the ledgers of each connection from this host to `dest`, in order of
+    /// establishment, including connections which have since closed. Ledgers record the bytes
+    /// carried in each direction, and can be used to assert that no bytes were lost, duplicated
+    /// or reordered by the connection. Only the ledgers of the 64 most recently established
+    /// connections are retained.
+    pub fn connection_ledgers(&self, dest: net::SocketAddr) -> Vec<ConnectionLedger> {
+        let lock = self.inner.lock().unwrap();
+        lock.ledgers(self.local_ip(dest.ip()), dest)
+    }
+
+    /// Replay bytes into the most recently established live connection from this host to `dest`.
+    /// The server reads the replayed bytes before any further bytes sent by the client.
+    pub fn replay(&self, dest: net::SocketAddr, bytes: &[u8]) -> Result<(), io::Error> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.inject(self.local_ip(dest.ip()), dest, bytes)
+    }
+
+    /// Set SO_REUSEADDR for listeners bound by this host, allowing an address to be bound again
+    /// while connections it accepted are in TIME_WAIT. Without it, binding fails with
+    /// `AddrInUse` until the TIME_WAIT period has elapsed, as on real systems.
+    pub fn set_reuseaddr(&self, reuseaddr: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_reuseaddr(self.local_addr, reuseaddr);
+        if let Some(v6) = self.local_v6 {
+            lock.set_reuseaddr(v6.into(), reuseaddr);
+        }
+    }
+
+    /// Leave every live connection between this host and `peer` half open, as if `peer` silently
+    /// dropped them, for example after crashing and restarting. Writes on this host are
+    /// discarded and reads never receive EOF, while `peer` can no longer use its side of the
+    /// connections. Only keepalive probes detect that the connections are dead.
+    pub fn half_open(&self, peer: net::IpAddr) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.half_open(self.local_ip(peer), peer);
+    }
+
+    /// Crash this host. Pending connects and accepts on the host, and connects to it, fail with
+    /// `ConnectionAborted` rather than waiting on a peer which will never respond. Established
+    /// connections are reset, and listeners, datagram sockets and Unix domain socket listeners
+    /// are closed, failing their pending and subsequent operations with `ConnectionAborted`. The host is restarted by binding its
+    /// listeners again, which is not subject to TIME_WAIT.
+    pub fn crash(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.crash(self.local_addr);
+        if let Some(v6) = self.local_v6 {
+            lock.crash(v6.into());
+        }
+    }
+
+    /// Set the priority of connections from this host to `dest`, applying to both new and
+    /// existing connections. Fault injectors can be configured to spare or target high
+    /// priority connections, see [`FaultTarget`].
+    pub fn set_priority(&self, dest: net::SocketAddr, priority: Priority) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_priority(self.local_ip(dest.ip()), dest, priority);
+    }
+
+    /// Limit the number of bytes delivered by a single write on connections between this host
+    /// and `peer`. Larger writes are split into multiple deliveries, each of which incurs latency.
+    /// Passing `None` removes the limit.
+    pub fn set_link_mtu(&self, peer: net::IpAddr, mtu: Option<usize>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_link_mtu(self.local_ip(peer), peer, mtu);
+    }
+
+    /// Limit the number of bytes per second which can be written in each direction of every
+    /// connection between this host and `peer`. Unlike the host bandwidth, the limit applies to
+    /// each connection independently. Large writes take proportionally longer to drain. Passing
+    /// `None` removes the limit.
+    pub fn set_link_bandwidth(&self, peer: net::IpAddr, bytes_per_second: Option<u64>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_link_bandwidth(self.local_ip(peer), peer, bytes_per_second);
+    }
+
+    /// Limit the aggregate number of bytes per second which can be written by connections
+    /// on this host. Writes queue behind each other once the limit is reached. Only traffic sent
+    /// by this host is limited, and datagrams are not limited. Passing `None` removes the limit.
+    pub fn set_host_bandwidth(&self, bytes_per_second: Option<u64>) {
+        let nic = self.inner.lock().unwrap().nic(self.local_addr);
+        nic.lock().unwrap().set_bandwidth(bytes_per_second);
+    }
+
+    /// Returns the total number of bytes written by connections on each host in the network,
+    /// ordered by host address.
+    pub fn bytes_sent(&self) -> collections::BTreeMap<net::IpAddr, u64> {
+        let lock = self.inner.lock().unwrap();
+        lock.bytes_sent()
+    }
+
+    /// Limit the aggregate number of writes per second which can be performed by connections
+    /// on this host. Only traffic sent by this host is limited, and datagrams are not limited.
+    /// Passing `None` removes the limit.
+    pub fn set_host_packet_rate(&self, packets_per_second: Option<u64>) {
+        let nic = self.inner.lock().unwrap().nic(self.local_addr);
+        nic.lock().unwrap().set_packet_rate(packets_per_second);
+    }
 }
 
 #[cfg(test)]
@@ -79,8 +592,9 @@ mod tests {
     use super::*;
     use crate::{Environment, TcpListener};
     use futures::{SinkExt, StreamExt};
-    use std::net;
+    use std::{net, time};
     use tokio::codec::{Framed, LinesCodec};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     /// Starts a server which will forward messages to the next server in the ring.
     async fn serve_message_ring(
@@ -169,4 +683,1293 @@ mod tests {
             )
         });
     }
+
+    #[test]
+    /// Test that the host bandwidth limit is shared by all connections from a host.
+    fn test_host_bandwidth() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            client.set_host_bandwidth(Some(1000));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            let _listener = server.bind(server_addr).await.unwrap();
+            let mut conn1 = client.connect(server_addr).await.unwrap();
+            let mut conn2 = client.connect(server_addr).await.unwrap();
+            let start_time = handle.now();
+            let (r1, r2) = futures::join!(conn1.write_all(&[0; 1000]), conn2.write_all(&[0; 1000]));
+            r1.unwrap();
+            r2.unwrap();
+            assert_eq!(
+                handle.now() - start_time,
+                time::Duration::from_secs(2),
+                "expected both writes to share the host bandwidth"
+            );
+        });
+    }
+
+    #[test]
+    /// Test that the link bandwidth limit applies to each connection independently, and only to
+    /// connections with the peer.
+    fn test_link_bandwidth() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let other = network.scoped(net::Ipv4Addr::new(10, 0, 0, 3));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            let other_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 3).into(), 9092);
+            client.set_link_bandwidth(server_addr.ip(), Some(1000));
+            let _listener = server.bind(server_addr).await.unwrap();
+            let _other_listener = other.bind(other_addr).await.unwrap();
+            let mut conn1 = client.connect(server_addr).await.unwrap();
+            let mut conn2 = client.connect(server_addr).await.unwrap();
+            let mut other_conn = client.connect(other_addr).await.unwrap();
+
+            let start_time = handle.now();
+            other_conn.write_all(&[0; 4000]).await.unwrap();
+            assert_eq!(
+                handle.now(),
+                start_time,
+                "expected other link to be unlimited"
+            );
+            let (r1, r2) = futures::join!(conn1.write_all(&[0; 2000]), conn2.write_all(&[0; 2000]));
+            r1.unwrap();
+            r2.unwrap();
+            assert_eq!(
+                handle.now() - start_time,
+                time::Duration::from_secs(2),
+                "expected each connection to drain at the link bandwidth"
+            );
+        });
+    }
+
+    #[test]
+    /// Test that the addresses of listeners which closed connections first cannot be bound again
+    /// until TIME_WAIT elapses, unless SO_REUSEADDR is set.
+    fn test_time_wait() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+
+            // TIME_WAIT is disabled by default, and idle listeners never enter it.
+            drop(server.bind(addr).await.unwrap());
+            let mut listener = server.bind(addr).await.unwrap();
+            let client_conn = client.connect(addr).await.unwrap();
+            let (server_conn, _) = listener.accept().await.unwrap();
+            drop((server_conn, client_conn, listener));
+            let mut listener = server.bind(addr).await.unwrap();
+
+            // a connection closed by the server first holds the listener address.
+            network.set_time_wait(Some(time::Duration::from_secs(30)));
+            let client_conn = client.connect(addr).await.unwrap();
+            let (server_conn, _) = listener.accept().await.unwrap();
+            drop((server_conn, listener));
+            handle.delay_from(time::Duration::from_millis(1)).await;
+            drop(client_conn);
+            assert_eq!(
+                server.bind(addr).await.unwrap_err().kind(),
+                io::ErrorKind::AddrInUse
+            );
+            server.set_reuseaddr(true);
+            let listener = server.bind(addr).await.unwrap();
+            assert_eq!(
+                server.bind(addr).await.unwrap_err().kind(),
+                io::ErrorKind::AddrInUse,
+                "expected SO_REUSEADDR to only ignore TIME_WAIT"
+            );
+            drop(listener);
+            server.set_reuseaddr(false);
+            handle.delay_from(time::Duration::from_secs(30)).await;
+            drop(server.bind(addr).await.unwrap());
+        });
+    }
+
+    #[test]
+    /// Test that crashing a host fails pending accepts and connects, and that the host can bind
+    /// again immediately.
+    fn test_crash_aborts_pending() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let handshake = Handshake::new(0, time::Duration::from_secs(10));
+            server.set_handshake(server_addr, Some(handshake));
+            let accept =
+                crate::spawn_with_result(
+                    &handle,
+                    async move { listener.accept().await.map(|_| ()) },
+                );
+            let connecting = client.clone();
+            let connect = crate::spawn_with_result(&handle, async move {
+                connecting.connect(server_addr).await.map(|_| ())
+            });
+
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            server.crash();
+            assert_eq!(
+                accept.await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionAborted
+            );
+            assert_eq!(
+                connect.await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionAborted
+            );
+
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let _client_conn = client.connect(server_addr).await.unwrap();
+            listener.accept().await.unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that crashing a host fails pending receives on its datagram sockets and accepts on
+    /// its Unix domain socket listeners, and that both can be bound again.
+    fn test_crash_aborts_sockets() {
+        use crate::{UdpSocket, UnixListener};
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let udp_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 5000);
+            let path = path::Path::new("/run/server.sock");
+            let mut socket = server.bind_udp(udp_addr).await.unwrap();
+            let mut listener = server.bind_unix(path).await.unwrap();
+            let recv = crate::spawn_with_result(&handle, async move {
+                let mut buf = [0; 16];
+                let result = socket.recv_from(&mut buf).await.map(|_| ());
+                (socket, result)
+            });
+            let accept = crate::spawn_with_result(&handle, async move {
+                let result = listener.accept().await.map(|_| ());
+                (listener, result)
+            });
+
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            server.crash();
+            let (mut socket, result) = recv.await;
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+            assert_eq!(
+                socket.send_to(b"ping", udp_addr).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionAborted
+            );
+            let (listener, result) = accept.await;
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+
+            let mut restarted = server.bind_udp(udp_addr).await.unwrap();
+            let _restarted_listener = server.bind_unix(path).await.unwrap();
+            // Dropping the crashed sockets leaves the new bindings in place.
+            drop(socket);
+            drop(listener);
+            restarted.send_to(b"ping", udp_addr).await.unwrap();
+            let mut buf = [0; 16];
+            assert_eq!(restarted.recv_from(&mut buf).await.unwrap().0, 4);
+            server.connect_unix(path).await.unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that closing a listener refuses connects waiting on a handshake and later connects,
+    /// and resets connections which were never accepted.
+    fn test_listener_close_refuses() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            let listener = server.bind(server_addr).await.unwrap();
+            let mut queued = client.connect(server_addr).await.unwrap();
+            let handshake = Handshake::new(0, time::Duration::from_secs(10));
+            server.set_handshake(server_addr, Some(handshake));
+            let connecting = client.clone();
+            let connect = crate::spawn_with_result(&handle, async move {
+                connecting.connect(server_addr).await.map(|_| ())
+            });
+
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            let closed_at = handle.now();
+            drop(listener);
+            assert_eq!(
+                connect.await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+            assert_eq!(
+                handle.now(),
+                closed_at,
+                "expected connect to fail immediately"
+            );
+            let mut buf = [0; 4];
+            assert_eq!(
+                queued.read(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionReset
+            );
+            assert_eq!(
+                client.connect(server_addr).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+        });
+    }
+
+    #[test]
+    /// Test that shutting down the write half of a connection delivers EOF to the peer, while
+    /// the peer can still respond, whether shut down through `TcpStream` or `AsyncWrite`.
+    fn test_shutdown_write() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let mut client_conn = client.connect(server_addr).await.unwrap();
+            let (mut server_conn, _) = listener.accept().await.unwrap();
+
+            client_conn.write_all(b"request").await.unwrap();
+            crate::TcpStream::shutdown(&client_conn, net::Shutdown::Write).unwrap();
+            assert_eq!(
+                client_conn.write_all(b"more").await.unwrap_err().kind(),
+                io::ErrorKind::BrokenPipe
+            );
+            let mut request = vec![];
+            server_conn.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+
+            // Shutting down through AsyncWrite shuts down the write half in the same way.
+            server_conn.write_all(b"response").await.unwrap();
+            tokio::io::AsyncWriteExt::shutdown(&mut server_conn)
+                .await
+                .unwrap();
+            assert_eq!(
+                server_conn.write_all(b"more").await.unwrap_err().kind(),
+                io::ErrorKind::BrokenPipe
+            );
+            let mut response = vec![];
+            client_conn.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"response");
+        });
+    }
+
+    #[test]
+    /// Test that the halves of a split stream can be used from separate tasks.
+    fn test_into_split() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let client_conn = client.connect(server_addr).await.unwrap();
+            let (server_conn, _) = listener.accept().await.unwrap();
+
+            // Echo bytes from the read half to the write half until EOF.
+            let (mut server_read, mut server_write) = crate::TcpStream::into_split(server_conn);
+            let (mut client_read, mut client_write) = crate::TcpStream::into_split(client_conn);
+            handle.spawn(async move {
+                let mut buf = [0; 64];
+                while let Ok(read) = server_read.read(&mut buf).await {
+                    if read == 0 {
+                        break;
+                    }
+                    server_write.write_all(&buf[..read]).await.unwrap();
+                }
+            });
+            handle.spawn(async move {
+                client_write.write_all(b"ping").await.unwrap();
+                client_write.shutdown().await.unwrap();
+            });
+            let mut echoed = vec![];
+            client_read.read_to_end(&mut echoed).await.unwrap();
+            assert_eq!(echoed, b"ping");
+        });
+    }
+
+    #[test]
+    /// Test that binding port 0 allocates unused ephemeral ports in a seeded order.
+    fn test_bind_ephemeral_port() {
+        let bind = |seed| {
+            let mut runtime =
+                crate::deterministic::DeterministicRuntime::new_with_seed(seed).unwrap();
+            let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+            let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+            server.network_handle().set_ephemeral_ports(40000..=40009);
+            runtime.block_on(async {
+                let unspecified = net::SocketAddr::from((net::Ipv4Addr::UNSPECIFIED, 0));
+                let mut ports = vec![];
+                let mut listeners = vec![];
+                for _ in 0..10 {
+                    let listener = server.bind(unspecified).await.unwrap();
+                    let addr = listener.local_addr().unwrap();
+                    assert_eq!(
+                        addr.ip(),
+                        net::IpAddr::from(net::Ipv4Addr::new(10, 0, 0, 1))
+                    );
+                    ports.push(addr.port());
+                    listeners.push(listener);
+                }
+                let err = server.bind(unspecified).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+                let exhausted = err
+                    .get_ref()
+                    .and_then(|err| err.downcast_ref::<AddrExhausted>())
+                    .unwrap();
+                let usage = PortUsage {
+                    in_use: 0,
+                    time_wait: 0,
+                    listening: 10,
+                    capacity: 10,
+                };
+                assert_eq!(
+                    exhausted,
+                    &AddrExhausted {
+                        host: net::Ipv4Addr::new(10, 0, 0, 1).into(),
+                        usage
+                    }
+                );
+                let addr = listeners[0].local_addr().unwrap();
+                // connections share the range, so the listeners leave no port to connect from.
+                assert_eq!(
+                    server.connect(addr).await.unwrap_err().kind(),
+                    io::ErrorKind::AddrNotAvailable
+                );
+                client.connect(addr).await.unwrap();
+                listeners[0].accept().await.unwrap();
+                ports
+            })
+        };
+        let ports = bind(1);
+        let mut sorted = ports.clone();
+        sorted.sort();
+        assert_eq!(sorted, (40000..=40009).collect::<Vec<_>>());
+        assert_eq!(
+            ports,
+            bind(1),
+            "expected the same seed to allocate the same ports"
+        );
+    }
+
+    #[test]
+    /// Test that connections are refused once the backlog of a listener is full, and accepted
+    /// again once the backlog drains.
+    fn test_backlog() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            server.set_backlog(server_addr, 2);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let _first = client.connect(server_addr).await.unwrap();
+            let _second = client.connect(server_addr).await.unwrap();
+            assert_eq!(
+                client.connect(server_addr).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+
+            listener.accept().await.unwrap();
+            let _third = client.connect(server_addr).await.unwrap();
+            listener.accept().await.unwrap();
+            listener.accept().await.unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that half open connections swallow writes and never deliver EOF, until keepalive
+    /// probes detect them.
+    fn test_half_open() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let mut client_conn = client.connect(server_addr).await.unwrap();
+            let (mut server_conn, _) = listener.accept().await.unwrap();
+
+            client.half_open(server_addr.ip());
+            client_conn.write_all(b"lost").await.unwrap();
+            let mut buf = [0; 4];
+            assert_eq!(
+                server_conn.read(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::BrokenPipe
+            );
+            drop(server_conn);
+            let timeout = time::Duration::from_secs(100);
+            let read = handle.timeout(client_conn.read(&mut buf), timeout).await;
+            assert!(read.is_err(), "expected half open read to never complete");
+            assert_eq!(
+                client.connection_ledgers(server_addr)[0]
+                    .client_to_server
+                    .in_flight,
+                4
+            );
+
+            client_conn.set_keepalive(Some(time::Duration::from_secs(10)));
+            client_conn.set_keepalive_probes(time::Duration::from_secs(1), 3);
+            assert_eq!(
+                client_conn.read(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::TimedOut
+            );
+        });
+    }
+
+    #[test]
+    /// Test that keepalive probes are lost on a lossy link, timing out an otherwise healthy
+    /// connection once the probe schedule elapses.
+    fn test_keepalive_probe_loss() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let server_ip: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let client_ip: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let server = runtime.handle(server_ip);
+        let client = runtime.handle(client_ip);
+        runtime.packet_loss_fault().probability(1.0).install();
+        runtime.block_on(async {
+            let server_addr = net::SocketAddr::new(server_ip, 9092);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let mut client_conn = client.connect(server_addr).await.unwrap();
+            let _server_conn = listener.accept().await.unwrap();
+
+            client_conn.set_keepalive(Some(time::Duration::from_secs(10)));
+            client_conn.set_keepalive_probes(time::Duration::from_secs(1), 3);
+            let start = handle.now();
+            let mut buf = [0; 4];
+            assert_eq!(
+                client_conn.read(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::TimedOut
+            );
+            assert_eq!(handle.now() - start, time::Duration::from_secs(13));
+        });
+    }
+
+    #[test]
+    /// Test that connection ledgers track bytes in flight in each direction, excluding
+    /// replayed bytes.
+    fn test_connection_ledgers() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let mut client_conn = client.connect(server_addr).await.unwrap();
+            let (mut server_conn, _) = listener.accept().await.unwrap();
+
+            client_conn.write_all(b"hello").await.unwrap();
+            let ledger = &client.connection_ledgers(server_addr)[0];
+            assert_eq!(ledger.client_to_server.in_flight, 5);
+            assert!(!ledger.delivered_exactly_once());
+
+            client.replay(server_addr, b"replayed").unwrap();
+            let mut buf = [0; 13];
+            server_conn.read_exact(&mut buf).await.unwrap();
+            server_conn.write_all(b"world").await.unwrap();
+            client_conn.read_exact(&mut buf[..5]).await.unwrap();
+            drop(client_conn);
+            drop(server_conn);
+            let ledgers = client.connection_ledgers(server_addr);
+            assert_eq!(ledgers.len(), 1);
+            ledgers[0].assert_delivered_exactly_once();
+            assert_eq!(ledgers[0].client_to_server.received, 5);
+
+            for _ in 0..inner::MAX_LEDGERS_PER_LINK {
+                let conn = client.connect(server_addr).await.unwrap();
+                let _ = listener.accept().await.unwrap();
+                drop(conn);
+            }
+            let ledgers = client.connection_ledgers(server_addr);
+            assert_eq!(ledgers.len(), inner::MAX_LEDGERS_PER_LINK);
+            assert_eq!(ledgers[0].client_to_server.sent, 0);
+        });
+    }
+
+    #[test]
+    /// Test that writes larger than the link MTU are delivered in multiple reads.
+    fn test_link_mtu() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let server = network.scoped(server_ip);
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            client.set_link_mtu(server_ip, Some(4));
+            let server_addr = net::SocketAddr::new(server_ip, 9092);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let mut conn = client.connect(server_addr).await.unwrap();
+            let (mut server_conn, _) = listener.accept().await.unwrap();
+
+            conn.write_all(b"hello world").await.unwrap();
+            let mut buf = [0; 64];
+            let mut received = vec![];
+            while received.len() < 11 {
+                let read = server_conn.read(&mut buf).await.unwrap();
+                assert!(read <= 4, "expected reads to be limited by the mtu");
+                received.extend_from_slice(&buf[..read]);
+            }
+            assert_eq!(&received[..], b"hello world");
+        });
+    }
+
+    #[test]
+    /// Test that captured traffic can be replayed into a later connection.
+    fn test_replay() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let server = network.scoped(server_ip);
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(server_ip, 9092);
+            let mut listener = server.bind(server_addr).await.unwrap();
+            client.capture_traffic(server_addr);
+
+            let conn = client.connect(server_addr).await.unwrap();
+            let (server_conn, _) = listener.accept().await.unwrap();
+            let mut client_transport = Framed::new(conn, LinesCodec::new());
+            let mut server_transport = Framed::new(server_conn, LinesCodec::new());
+            client_transport
+                .send(String::from("transfer 100"))
+                .await
+                .unwrap();
+            assert_eq!(
+                server_transport.next().await.unwrap().unwrap(),
+                "transfer 100"
+            );
+            drop(client_transport);
+            drop(server_transport);
+
+            let _conn = client.connect(server_addr).await.unwrap();
+            let (server_conn, _) = listener.accept().await.unwrap();
+            assert_eq!(client.connections_established(server_addr), 2);
+            let captured = client.captured_traffic(server_addr);
+            assert_eq!(captured[0], b"transfer 100\n".to_vec());
+            assert!(captured[1].is_empty());
+
+            client.replay(server_addr, &captured[0]).unwrap();
+            let mut server_transport = Framed::new(server_conn, LinesCodec::new());
+            assert_eq!(
+                server_transport.next().await.unwrap().unwrap(),
+                "transfer 100"
+            );
+        });
+    }
+
+    #[test]
+    /// Test that connections to a listener with a handshake are delayed by its cost.
+    fn test_handshake() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let server = network.scoped(server_ip);
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let tls_addr = net::SocketAddr::new(server_ip, 443);
+            let plain_addr = net::SocketAddr::new(server_ip, 80);
+            let _tls_listener = server.bind(tls_addr).await.unwrap();
+            let _plain_listener = server.bind(plain_addr).await.unwrap();
+            server.set_handshake(tls_addr, Some(Handshake::tls12()));
+
+            let start_time = handle.now();
+            client.connect(plain_addr).await.unwrap();
+            assert_eq!(handle.now(), start_time);
+            client.connect(tls_addr).await.unwrap();
+            assert_eq!(handle.now() - start_time, Handshake::tls12().cpu);
+
+            // Each round trip of the handshake takes the latency of the link in both directions.
+            let client_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let ms = time::Duration::from_millis;
+            network.set_one_way_latency(client_ip, server_ip, Some(ms(10)));
+            network.set_one_way_latency(server_ip, client_ip, Some(ms(30)));
+            let start_time = handle.now();
+            client.connect(tls_addr).await.unwrap();
+            assert_eq!(
+                handle.now() - start_time,
+                Handshake::tls12().duration(ms(40))
+            );
+
+            server.set_handshake(tls_addr, None);
+            let start_time = handle.now();
+            client.connect(tls_addr).await.unwrap();
+            assert_eq!(handle.now(), start_time);
+        });
+    }
+
+    #[test]
+    /// Test that exhausting ephemeral ports returns a typed error until a port is released.
+    fn test_port_exhaustion() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let server_addr = net::SocketAddr::new(server_ip, 9092);
+            let _listener = network.scoped(server_ip).bind(server_addr).await.unwrap();
+            let client_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let client = network.scoped(client_ip);
+            client.set_ephemeral_ports(50000..=50001);
+
+            let first = client.connect(server_addr).await.unwrap();
+            let _second = client.connect(server_addr).await.unwrap();
+            let usage = PortUsage {
+                in_use: 2,
+                time_wait: 0,
+                listening: 0,
+                capacity: 2,
+            };
+            assert_eq!(client.port_usage(), usage);
+            let err = client.connect(server_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+            let exhausted = err
+                .get_ref()
+                .and_then(|err| err.downcast_ref::<AddrExhausted>())
+                .unwrap();
+            assert_eq!(
+                exhausted,
+                &AddrExhausted {
+                    host: client_ip,
+                    usage
+                }
+            );
+
+            drop(first);
+            assert_eq!(client.port_usage().in_use, 1);
+            client.connect(server_addr).await.unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that connections are reset once idle for longer than the idle timeout, while
+    /// connections which exchange bytes more often stay open.
+    fn test_idle_timeout() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let timeout = time::Duration::from_secs(60);
+        runtime.set_idle_timeout(Some(timeout));
+        runtime.block_on(async {
+            let server_addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(server_addr).await.unwrap();
+            let mut active = handle.connect(server_addr).await.unwrap();
+            let (mut active_server, _) = listener.accept().await.unwrap();
+            let mut idle = handle.connect(server_addr).await.unwrap();
+            let (mut idle_server, _) = listener.accept().await.unwrap();
+
+            let mut buf = [0; 4];
+            for _ in 0..3 {
+                handle.delay_from(timeout / 2).await;
+                active.write_all(b"ping").await.unwrap();
+                active_server.read_exact(&mut buf).await.unwrap();
+            }
+            // a reader waiting on an idle connection observes the reset.
+            assert_eq!(
+                idle_server.read(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionReset
+            );
+            assert_eq!(
+                idle.write_all(b"ping").await.unwrap_err().kind(),
+                io::ErrorKind::BrokenPipe
+            );
+        });
+    }
+
+    #[test]
+    /// Test that hosts cannot bind the addresses of other hosts, and that loopback addresses
+    /// stay on the host which uses them.
+    fn test_host_addresses() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let a = network.scoped(a_ip);
+            let b = network.scoped(b_ip);
+            let foreign = net::SocketAddr::new(b_ip, 9092);
+            assert_eq!(
+                a.bind(foreign).await.unwrap_err().kind(),
+                io::ErrorKind::AddrNotAvailable
+            );
+            assert_eq!(
+                a.bind_udp(foreign).await.unwrap_err().kind(),
+                io::ErrorKind::AddrNotAvailable
+            );
+
+            let loopback: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut a_listener = a.bind(loopback).await.unwrap();
+            let _b_listener = b.bind(loopback).await.unwrap();
+            let conn = a.connect(loopback).await.unwrap();
+            let peer_addr = crate::TcpStream::peer_addr(&conn).unwrap();
+            assert_eq!(peer_addr, net::SocketAddr::new(a_ip, 9092));
+            let (_, peer) = a_listener.accept().await.unwrap();
+            assert_eq!(peer.ip(), a_ip);
+        });
+    }
+
+    #[test]
+    /// Test that ports of connections closed by the client are held in TIME_WAIT, exhausting the
+    /// ephemeral port range until they are released.
+    fn test_client_time_wait() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let server_addr = net::SocketAddr::new(server_ip, 9092);
+            let mut listener = network.scoped(server_ip).bind(server_addr).await.unwrap();
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            client.set_ephemeral_ports(50000..=50001);
+            let time_wait = time::Duration::from_secs(60);
+            client.set_time_wait(Some(time_wait));
+
+            // a connection closed by the server first does not hold the client port.
+            let client_conn = client.connect(server_addr).await.unwrap();
+            let (server_conn, _) = listener.accept().await.unwrap();
+            drop(server_conn);
+            handle.delay_from(time::Duration::from_millis(1)).await;
+            drop(client_conn);
+            assert_eq!(client.port_usage().time_wait, 0);
+
+            for _ in 0..2 {
+                client.connect(server_addr).await.unwrap();
+            }
+            let usage = client.port_usage();
+            assert_eq!((usage.in_use, usage.time_wait), (0, 2));
+            let err = client.connect(server_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+
+            handle.delay_from(time_wait).await;
+            assert_eq!(client.port_usage().time_wait, 0);
+            client.connect(server_addr).await.unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that datagrams are delivered between hosts and dropped across clogged links.
+    fn test_udp() {
+        use crate::UdpSocket;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let mut a = network
+                .scoped(a_ip)
+                .bind_udp(net::SocketAddr::new(a_ip, 0))
+                .await
+                .unwrap();
+            let b_addr = net::SocketAddr::new(b_ip, 53);
+            let mut b = network.scoped(b_ip).bind_udp(b_addr).await.unwrap();
+            assert_eq!(
+                network
+                    .scoped(b_ip)
+                    .bind_udp(b_addr)
+                    .await
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::AddrInUse
+            );
+
+            let mut buf = [0; 16];
+            a.send_to(b"query", b_addr).await.unwrap();
+            let (len, from) = b.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"query");
+            assert_eq!(from, a.local_addr().unwrap());
+            b.send_to(b"response", from).await.unwrap();
+            let (len, _) = a.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"response");
+
+            network
+                .clone_inner()
+                .lock()
+                .unwrap()
+                .clog_connection(fault::CloggedConnection::new(a_ip, b_ip));
+            a.send_to(b"dropped", b_addr).await.unwrap();
+            let timeout = time::Duration::from_secs(1);
+            let err = handle.timeout(b.recv_from(&mut buf), timeout).await;
+            assert!(err.is_err(), "expected clogged datagram to be dropped");
+        });
+    }
+
+    #[test]
+    /// Test that healing a partition leaves clogged links clogged, and that unclogging a link
+    /// leaves it partitioned.
+    fn test_partition_clog() {
+        use crate::UdpSocket;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let mut a = network
+                .scoped(a_ip)
+                .bind_udp(net::SocketAddr::new(a_ip, 0))
+                .await
+                .unwrap();
+            let b_addr = net::SocketAddr::new(b_ip, 53);
+            let mut b = network.scoped(b_ip).bind_udp(b_addr).await.unwrap();
+            let mut buf = [0; 16];
+            let timeout = time::Duration::from_secs(1);
+            let clog = || fault::CloggedConnection::new(a_ip, b_ip);
+
+            network
+                .clone_inner()
+                .lock()
+                .unwrap()
+                .clog_connection(clog());
+            network.partition(&[a_ip], &[b_ip]);
+            network.heal();
+            a.send_to(b"clogged", b_addr).await.unwrap();
+            let recv = handle.timeout(b.recv_from(&mut buf), timeout).await;
+            assert!(recv.is_err(), "expected clog to outlast the partition");
+
+            network.partition(&[a_ip], &[b_ip]);
+            network
+                .clone_inner()
+                .lock()
+                .unwrap()
+                .unclog_connection(clog());
+            a.send_to(b"partitioned", b_addr).await.unwrap();
+            let recv = handle.timeout(b.recv_from(&mut buf), timeout).await;
+            assert!(recv.is_err(), "expected partition to outlast the clog");
+
+            network.heal();
+            a.send_to(b"healed", b_addr).await.unwrap();
+            let (len, _) = b.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"healed");
+        });
+    }
+
+    #[test]
+    /// Test that multicast and broadcast datagrams fan out to each subscribed socket, subject to
+    /// the faults of the link to each receiver.
+    fn test_udp_multicast() {
+        use crate::UdpSocket;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let c_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 3));
+            let d_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 4));
+            let mut a = network
+                .scoped(a_ip)
+                .bind_udp(net::SocketAddr::new(a_ip, 0))
+                .await
+                .unwrap();
+            let mut receivers = vec![];
+            for ip in [b_ip, c_ip, d_ip].iter() {
+                let socket = network
+                    .scoped(*ip)
+                    .bind_udp(net::SocketAddr::new(*ip, 5000))
+                    .await
+                    .unwrap();
+                receivers.push(socket);
+            }
+            let group = net::Ipv4Addr::new(239, 0, 0, 1);
+            receivers[0].join_multicast_v4(group).unwrap();
+            receivers[1].join_multicast_v4(group).unwrap();
+            receivers[1].join_multicast_v4(group).unwrap();
+            assert_eq!(
+                a.join_multicast_v4(net::Ipv4Addr::new(10, 0, 0, 9))
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::InvalidInput
+            );
+
+            let latency = time::Duration::from_millis(100);
+            network.set_one_way_latency(a_ip, c_ip, Some(latency));
+            let start = handle.now();
+            let mut buf = [0; 16];
+            a.send_to(b"heartbeat", net::SocketAddr::new(group.into(), 5000))
+                .await
+                .unwrap();
+            let (len, from) = receivers[0].recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"heartbeat");
+            assert_eq!(from, a.local_addr().unwrap());
+            assert_eq!(handle.now(), start);
+            receivers[1].recv_from(&mut buf).await.unwrap();
+            assert_eq!(handle.now() - start, latency);
+            let timeout = time::Duration::from_secs(1);
+            let err = handle
+                .timeout(receivers[2].recv_from(&mut buf), timeout)
+                .await;
+            assert!(err.is_err(), "expected non-member not to receive datagram");
+
+            let broadcast = net::SocketAddr::new(net::Ipv4Addr::BROADCAST.into(), 5000);
+            assert_eq!(
+                a.send_to(b"hello", broadcast).await.unwrap_err().kind(),
+                io::ErrorKind::PermissionDenied
+            );
+            a.set_broadcast(true).unwrap();
+            assert!(a.broadcast().unwrap());
+            network.partition(&[a_ip], &[b_ip]);
+            a.send_to(b"hello", broadcast).await.unwrap();
+            let err = handle
+                .timeout(receivers[0].recv_from(&mut buf), timeout)
+                .await;
+            assert!(
+                err.is_err(),
+                "expected partitioned receiver to miss datagram"
+            );
+            for receiver in receivers[1..].iter_mut() {
+                let (len, _) = receiver.recv_from(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], b"hello");
+            }
+        });
+    }
+
+    #[test]
+    /// Test that Unix domain sockets connect within a host, but not across hosts.
+    fn test_unix() {
+        use crate::UnixListener;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let other = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let path = path::Path::new("/run/app.sock");
+            let mut listener = server.bind_unix(path).await.unwrap();
+            assert_eq!(
+                server.bind_unix(path).await.unwrap_err().kind(),
+                io::ErrorKind::AddrInUse
+            );
+            assert_eq!(
+                other.connect_unix(path).await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+
+            let mut client = server.connect_unix(path).await.unwrap();
+            let mut accepted = listener.accept().await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            drop(listener);
+            assert_eq!(
+                server.connect_unix(path).await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+        });
+    }
+
+    #[test]
+    /// Test that names resolve to the hosts registered under them.
+    fn test_resolver() {
+        use crate::Resolver;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let a = network.scoped(a_ip);
+            let b = network.scoped(b_ip);
+            let resolver = a.resolver();
+            assert_eq!(
+                resolver.resolve("db").await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+            a.register_name("db");
+            b.register_name("DB");
+            assert_eq!(resolver.resolve("db").await.unwrap(), vec![a_ip, b_ip]);
+            a.unregister_name("db");
+            assert_eq!(resolver.resolve("Db").await.unwrap(), vec![b_ip]);
+        });
+    }
+
+    #[test]
+    /// Test that resolvers cache answers and negative answers until their TTL expires, and serve
+    /// stale answers past their TTL when enabled.
+    fn test_resolver_cache() {
+        use crate::Resolver;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let a = network.scoped(a_ip);
+            let b = network.scoped(b_ip);
+            let secs = time::Duration::from_secs;
+            a.set_name_ttl(secs(30), secs(5));
+            let resolver = a.resolver();
+
+            // a negative answer is cached until the negative TTL expires.
+            assert_eq!(
+                resolver.resolve("db").await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+            b.register_name("db");
+            assert_eq!(
+                resolver.resolve("db").await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+            handle.delay_from(secs(5)).await;
+            assert_eq!(resolver.resolve("db").await.unwrap(), vec![b_ip]);
+
+            // the unregistered address is served until the TTL expires.
+            b.unregister_name("db");
+            a.register_name("db");
+            handle.delay_from(secs(29)).await;
+            assert_eq!(resolver.resolve("db").await.unwrap(), vec![b_ip]);
+            handle.delay_from(secs(1)).await;
+            assert_eq!(resolver.resolve("db").await.unwrap(), vec![a_ip]);
+
+            // stale answers are served past their TTL.
+            a.set_serve_stale(true);
+            a.unregister_name("db");
+            handle.delay_from(secs(60)).await;
+            assert_eq!(resolver.resolve("db").await.unwrap(), vec![a_ip]);
+            a.set_serve_stale(false);
+            assert_eq!(
+                resolver.resolve("db").await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+        });
+    }
+
+    #[test]
+    /// Test that IPv6 hosts can bind and connect, and that dual-stack listeners accept both
+    /// IPv4 and IPv6 connections, including those made before the listener was bound, and
+    /// release both addresses when closed.
+    fn test_ipv6() {
+        use crate::TcpStream;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server_v4 = net::Ipv4Addr::new(10, 0, 0, 1);
+            let server_v6: net::Ipv6Addr = "fd00::1".parse().unwrap();
+            let server = network.dual_stack(server_v4, server_v6);
+            let v6_client = network.scoped("fd00::2".parse::<net::Ipv6Addr>().unwrap());
+            let v4_client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let dual_client =
+                network.dual_stack(net::Ipv4Addr::new(10, 0, 0, 3), "fd00::3".parse().unwrap());
+
+            let v4_addr = net::SocketAddr::new(server_v4.into(), 9092);
+            let early = v4_client.connect(v4_addr).await.unwrap();
+            let unspecified = net::SocketAddr::new(net::Ipv6Addr::UNSPECIFIED.into(), 9092);
+            let mut listener = server.bind(unspecified).await.unwrap();
+            let v6_addr = net::SocketAddr::new(server_v6.into(), 9092);
+            assert_eq!(listener.local_addr().unwrap(), v6_addr);
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, early.local_addr().unwrap());
+            assert_eq!(
+                server.bind(v4_addr).await.unwrap_err().kind(),
+                io::ErrorKind::AddrInUse
+            );
+
+            let client = v6_client.connect(v6_addr).await.unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+            assert!(peer.is_ipv6());
+
+            v4_client.connect(v4_addr).await.unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer.ip(), net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2)));
+
+            // Dual-stack clients connect from the address matching the family of the server.
+            dual_client.connect(v6_addr).await.unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer.ip(), "fd00::3".parse::<net::IpAddr>().unwrap());
+
+            drop(listener);
+            let err = v4_client.connect(v4_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            let mut listener = server.bind(v4_addr).await.unwrap();
+            v4_client.connect(v4_addr).await.unwrap();
+            listener.accept().await.unwrap();
+            drop(listener);
+            server.bind(unspecified).await.unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that partitions time out new connections and stall existing ones until healed.
+    fn test_partition() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let c_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 3));
+            let server_addr = net::SocketAddr::new(a_ip, 9092);
+            let mut listener = network.scoped(a_ip).bind(server_addr).await.unwrap();
+            let mut client = network.scoped(b_ip).connect(server_addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+
+            network.partition(&[a_ip], &[b_ip, c_ip]);
+            let start = handle.now();
+            let err = network.scoped(c_ip).connect(server_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(handle.now() - start, time::Duration::from_secs(127));
+            let write = crate::spawn_with_result(&handle, async move {
+                client.write_all(b"ping").await.unwrap();
+            });
+            let mut buf = [0; 4];
+            let timeout = time::Duration::from_secs(10);
+            let read = handle.timeout(server.read_exact(&mut buf), timeout).await;
+            assert!(read.is_err(), "expected read across partition to stall");
+
+            network.heal();
+            write.await;
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            network.scoped(c_ip).connect(server_addr).await.unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that connects across a partition wait for it to be healed, and fail once the SYN
+    /// timeout elapses rather than immediately.
+    fn test_partition_connect() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(a_ip, 9092);
+            let mut listener = network.scoped(a_ip).bind(server_addr).await.unwrap();
+
+            network.partition(&[a_ip], &[b_ip]);
+            let start = handle.now();
+            let heal = async {
+                handle.delay_from(time::Duration::from_secs(3)).await;
+                network.heal();
+            };
+            let (connected, _) =
+                future::join(network.scoped(b_ip).connect(server_addr), heal).await;
+            connected.unwrap();
+            listener.accept().await.unwrap();
+            assert_eq!(handle.now() - start, time::Duration::from_secs(3));
+
+            network.set_syn_timeout(time::Duration::from_secs(5));
+            network.partition_one_way(&[a_ip], &[b_ip]);
+            let start = handle.now();
+            let err = network.scoped(b_ip).connect(server_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(handle.now() - start, time::Duration::from_secs(5));
+        });
+    }
+
+    #[test]
+    /// Test that one-way partitions and latency only affect traffic in one direction.
+    fn test_one_way_link() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(a_ip, 9092);
+            let mut listener = network.scoped(a_ip).bind(server_addr).await.unwrap();
+            let mut client = network.scoped(b_ip).connect(server_addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+
+            let latency = time::Duration::from_millis(250);
+            network.set_one_way_latency(a_ip, b_ip, Some(latency));
+            let start = handle.now();
+            client.write_all(b"ping").await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(handle.now(), start);
+            server.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(handle.now() - start, latency);
+            network.set_one_way_latency(a_ip, b_ip, None);
+
+            // A can no longer reach B, but B can still reach A.
+            network.partition_one_way(&[a_ip], &[b_ip]);
+            client.write_all(b"ping").await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+            let write = crate::spawn_with_result(&handle, async move {
+                server.write_all(b"pong").await.unwrap();
+            });
+            let timeout = time::Duration::from_secs(10);
+            let read = handle.timeout(client.read_exact(&mut buf), timeout).await;
+            assert!(read.is_err(), "expected traffic from A to B to stall");
+            let err = network.scoped(b_ip).connect(server_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+            network.heal();
+            write.await;
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        });
+    }
+
+    #[test]
+    /// Test that clogs and one-way cuts of a link are applied to existing connections
+    /// independently, so that removing one leaves the other in place.
+    fn test_one_way_cut_clog() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let a_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+            let b_ip = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_addr = net::SocketAddr::new(a_ip, 9092);
+            let mut listener = network.scoped(a_ip).bind(server_addr).await.unwrap();
+            let mut client = network.scoped(b_ip).connect(server_addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            let timeout = time::Duration::from_secs(10);
+            let clog = || fault::CloggedConnection::new(b_ip, a_ip);
+            let inner = network.clone_inner();
+
+            // unclogging the link leaves the one-way cut from A to B in place.
+            inner.lock().unwrap().clog_connection(clog());
+            network.partition_one_way(&[a_ip], &[b_ip]);
+            inner.lock().unwrap().unclog_connection(clog());
+            let write = handle.timeout(server.write_all(b"pong"), timeout).await;
+            assert!(write.is_err(), "expected traffic from A to B to stall");
+            client.write_all(b"ping").await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+
+            // healing the one-way cut leaves the clogged link clogged.
+            inner.lock().unwrap().clog_connection(clog());
+            network.heal();
+            let write = handle.timeout(server.write_all(b"pong"), timeout).await;
+            assert!(write.is_err(), "expected traffic from A to B to stall");
+            let write = handle.timeout(client.write_all(b"ping"), timeout).await;
+            assert!(write.is_err(), "expected traffic from B to A to stall");
+
+            inner.lock().unwrap().unclog_connection(clog());
+            server.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        });
+    }
 }
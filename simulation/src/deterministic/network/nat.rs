@@ -0,0 +1,253 @@
+//! Network address translation.
+//!
+//! A [`Nat`] places a group of hosts behind a single public address. Connections from a host
+//! behind the NAT to a host outside of it are assigned a port on the public address, which is
+//! the address the remote host observes. Hosts outside of the NAT cannot connect to the hosts
+//! behind it, or to its public address, and their connection attempts time out. Hosts behind
+//! the same NAT connect to each other directly.
+//!
+//! Restarting a NAT with [`NatHandle::reset`] discards its mappings, resetting the connections
+//! which pass through it. Subsequent connections are assigned new public ports, so that servers
+//! observe reconnecting clients at a new address.
+//!
+//! Only streams are translated. Datagrams pass through unmodified.
+use super::{Inner, PortUsage};
+use std::{collections, net, ops, sync};
+use tracing::trace;
+
+/// Translation state of an installed [`Nat`].
+#[derive(Debug)]
+pub(crate) struct NatState {
+    public: net::IpAddr,
+    hosts: collections::HashSet<net::IpAddr>,
+    /// Public port assigned to each private address with a live connection.
+    mappings: collections::HashMap<net::SocketAddr, u16>,
+    /// Next public port to try assigning. Ports are assigned in descending order, so that a
+    /// port is not reused until every other port in the range has been assigned.
+    next_port: Option<u16>,
+}
+
+impl NatState {
+    pub(crate) fn public(&self) -> net::IpAddr {
+        self.public
+    }
+
+    /// Returns true if `host` is behind this NAT.
+    pub(crate) fn contains(&self, host: net::IpAddr) -> bool {
+        self.hosts.contains(&host)
+    }
+
+    /// Returns the public address of `private`, assigning a port from `ports` if it does not
+    /// have one. Returns `None` if every port in the range is assigned.
+    pub(crate) fn map(
+        &mut self,
+        private: net::SocketAddr,
+        ports: ops::RangeInclusive<u16>,
+    ) -> Option<net::SocketAddr> {
+        if let Some(port) = self.mappings.get(&private) {
+            return Some(net::SocketAddr::new(self.public, *port));
+        }
+        let (low, high) = (*ports.start(), *ports.end());
+        let start = match self.next_port {
+            Some(port) if ports.contains(&port) => port,
+            _ => high,
+        };
+        let assigned: collections::HashSet<u16> = self.mappings.values().cloned().collect();
+        let port = (low..=start)
+            .rev()
+            .chain((start..=high).rev())
+            .find(|port| !assigned.contains(port))?;
+        self.next_port = if port == low { None } else { Some(port - 1) };
+        self.mappings.insert(private, port);
+        trace!("mapped {} to {}:{}", private, self.public, port);
+        Some(net::SocketAddr::new(self.public, port))
+    }
+
+    /// Returns the usage of the public ports in `ports`, each of which is in use while it is
+    /// assigned to a private address.
+    pub(crate) fn port_usage(&self, ports: &ops::RangeInclusive<u16>) -> PortUsage {
+        PortUsage {
+            in_use: self
+                .mappings
+                .values()
+                .filter(|port| ports.contains(port))
+                .count(),
+            time_wait: 0,
+            listening: 0,
+            capacity: usize::from(*ports.end() - *ports.start()) + 1,
+        }
+    }
+
+    /// Release the mappings of private addresses which no longer have a live connection.
+    pub(crate) fn retain(&mut self, live: &collections::HashSet<net::SocketAddr>) {
+        self.mappings.retain(|private, _| live.contains(private));
+    }
+
+    pub(crate) fn is_mapped(&self, private: net::SocketAddr) -> bool {
+        self.mappings.contains_key(&private)
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.mappings.clear();
+    }
+}
+
+/// Builder for a NAT, installed into the network with [`Nat::install`].
+pub struct Nat {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    state: NatState,
+}
+
+impl Nat {
+    pub(crate) fn new(inner: sync::Arc<sync::Mutex<Inner>>, public: net::IpAddr) -> Self {
+        Self {
+            inner,
+            state: NatState {
+                public,
+                hosts: collections::HashSet::new(),
+                mappings: collections::HashMap::new(),
+                next_port: None,
+            },
+        }
+    }
+
+    /// Place `host` behind this NAT.
+    pub fn host(mut self, host: net::IpAddr) -> Self {
+        self.state.hosts.insert(host);
+        self
+    }
+
+    /// Consumes this builder and begins translating connections from its hosts, replacing any
+    /// NAT previously installed with the same public address. Connections established before
+    /// the NAT was installed are not translated.
+    pub fn install(self) -> NatHandle {
+        trace!(
+            "installing NAT {} for {} hosts",
+            self.state.public,
+            self.state.hosts.len()
+        );
+        let public = self.state.public;
+        self.inner.lock().unwrap().install_nat(self.state);
+        NatHandle {
+            inner: self.inner,
+            public,
+        }
+    }
+}
+
+/// Handle to an installed [`Nat`].
+#[derive(Debug, Clone)]
+pub struct NatHandle {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    public: net::IpAddr,
+}
+
+impl NatHandle {
+    pub fn public(&self) -> net::IpAddr {
+        self.public
+    }
+
+    /// Restart the NAT, discarding its mappings. Connections which pass through it are reset.
+    pub fn reset(&self) {
+        self.inner.lock().unwrap().reset_nat(self.public);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        deterministic::{AddrExhausted, DeterministicRuntime, PortUsage},
+        Environment, TcpListener, TcpStream,
+    };
+    use std::{io, net};
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    /// Test that connections from behind a NAT are translated to its public address, that
+    /// unsolicited inbound connections are dropped, and that resetting the NAT resets its
+    /// connections and assigns new ports.
+    fn translation() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let public: net::IpAddr = "203.0.113.1".parse().unwrap();
+        let client_ip: net::IpAddr = "192.168.0.2".parse().unwrap();
+        let peer_ip: net::IpAddr = "192.168.0.3".parse().unwrap();
+        let server_addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+        let client = runtime.handle(client_ip);
+        let peer = runtime.handle(peer_ip);
+        let server = runtime.handle(server_addr.ip());
+        let nat = runtime.nat(public).host(client_ip).host(peer_ip).install();
+        runtime.block_on(async {
+            let mut listener = server.bind(server_addr).await.unwrap();
+            let private_addr = net::SocketAddr::new(client_ip, 80);
+            let mut private_listener = client.bind(private_addr).await.unwrap();
+
+            let mut client_conn = client.connect(server_addr).await.unwrap();
+            let (_server_conn, first_addr) = listener.accept().await.unwrap();
+            assert_eq!(first_addr.ip(), public);
+            assert_eq!(client_conn.local_addr().unwrap().ip(), client_ip);
+
+            // hosts behind the same NAT connect directly.
+            peer.connect(private_addr).await.unwrap();
+            let (_, addr) = private_listener.accept().await.unwrap();
+            assert_eq!(addr.ip(), peer_ip);
+
+            for dest in [private_addr, net::SocketAddr::new(public, 80)].iter() {
+                assert_eq!(
+                    server.connect(*dest).await.unwrap_err().kind(),
+                    io::ErrorKind::TimedOut
+                );
+            }
+
+            nat.reset();
+            let mut buf = [0; 4];
+            assert_eq!(
+                client_conn.read(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionReset
+            );
+            let _client_conn = client.connect(server_addr).await.unwrap();
+            let (_, second_addr) = listener.accept().await.unwrap();
+            assert_eq!(second_addr.ip(), public);
+            assert_ne!(second_addr.port(), first_addr.port());
+        });
+    }
+
+    #[test]
+    /// Test that connecting through a NAT with every public port assigned fails with a typed
+    /// error reporting the usage of the public address.
+    fn port_exhaustion() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let public: net::IpAddr = "203.0.113.1".parse().unwrap();
+        let client_ip: net::IpAddr = "192.168.0.2".parse().unwrap();
+        let server_addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+        let client = runtime.handle(client_ip);
+        let server = runtime.handle(server_addr.ip());
+        runtime
+            .handle(public)
+            .network_handle()
+            .set_ephemeral_ports(40000..=40000);
+        runtime.nat(public).host(client_ip).install();
+        runtime.block_on(async {
+            let _listener = server.bind(server_addr).await.unwrap();
+            let _first = client.connect(server_addr).await.unwrap();
+            let err = client.connect(server_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+            let exhausted = err
+                .get_ref()
+                .and_then(|err| err.downcast_ref::<AddrExhausted>())
+                .unwrap();
+            let usage = PortUsage {
+                in_use: 1,
+                time_wait: 0,
+                listening: 0,
+                capacity: 1,
+            };
+            assert_eq!(
+                exhausted,
+                &AddrExhausted {
+                    host: public,
+                    usage
+                }
+            );
+        });
+    }
+}
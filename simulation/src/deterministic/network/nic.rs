@@ -0,0 +1,127 @@
+//! Host level network interface limits.
+//!
+//! Every host in the deterministic network has a single `Nic` which caps the traffic it sends.
+//! Writes to every stream on the host, whichever end of the connection it is, reserve
+//! transmission time on the host `Nic`, so aggregate egress saturates once the configured
+//! bandwidth or packet rate is exceeded. Traffic received by the host is limited only by the
+//! `Nic` of its sender. Datagrams are counted towards the bytes sent by the host, but are not
+//! limited.
+//!
+//! Each stream additionally owns a `Nic` of its own, which limits the bandwidth of that single
+//! connection independently of other traffic through the host.
+use std::{sync, time};
+
+#[derive(Debug, Default)]
+pub(crate) struct Nic {
+    /// Maximum number of bytes which can be transmitted per second.
+    bytes_per_second: Option<u64>,
+    /// Maximum number of packets which can be transmitted per second.
+    packets_per_second: Option<u64>,
+    /// Instant at which the last reserved transmission completes.
+    busy_until: Option<time::Instant>,
+    /// Total number of bytes written by connections on this host.
+    bytes_sent: u64,
+}
+
+impl Nic {
+    pub(crate) fn new() -> sync::Arc<sync::Mutex<Nic>> {
+        sync::Arc::new(sync::Mutex::new(Nic::default()))
+    }
+
+    pub(crate) fn set_bandwidth(&mut self, bytes_per_second: Option<u64>) {
+        self.bytes_per_second = bytes_per_second;
+    }
+
+    pub(crate) fn set_packet_rate(&mut self, packets_per_second: Option<u64>) {
+        self.packets_per_second = packets_per_second;
+    }
+
+    pub(crate) fn record_sent(&mut self, len: usize) {
+        self.bytes_sent += len as u64;
+    }
+
+    pub(crate) fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Returns true if this Nic imposes no limits on transmission.
+    pub(crate) fn is_unlimited(&self) -> bool {
+        self.bytes_per_second.is_none() && self.packets_per_second.is_none()
+    }
+
+    /// Time taken to transmit a single packet of `len` bytes.
+    fn transmission_time(&self, len: usize) -> time::Duration {
+        let byte_time = self
+            .bytes_per_second
+            .map(|bps| duration_for(len as u64, bps))
+            .unwrap_or_default();
+        let packet_time = self
+            .packets_per_second
+            .map(|pps| duration_for(1, pps))
+            .unwrap_or_default();
+        std::cmp::max(byte_time, packet_time)
+    }
+
+    /// Reserve transmission time for a packet of `len` bytes, returning the instant at which
+    /// the packet has been fully transmitted. Packets are transmitted in reservation order.
+    pub(crate) fn reserve(&mut self, now: time::Instant, len: usize) -> time::Instant {
+        let start = match self.busy_until {
+            Some(busy_until) if busy_until > now => busy_until,
+            _ => now,
+        };
+        let done = start + self.transmission_time(len);
+        self.busy_until.replace(done);
+        done
+    }
+}
+
+/// Returns the time taken to process `units` at a rate of `per_second`.
+fn duration_for(units: u64, per_second: u64) -> time::Duration {
+    if per_second == 0 {
+        // A zero rate never completes, approximate this with a very long duration.
+        return time::Duration::from_secs(u64::from(u32::max_value()));
+    }
+    let nanos = u128::from(units) * 1_000_000_000 / u128::from(per_second);
+    time::Duration::from_nanos(nanos as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that reservations are serialized through the Nic.
+    fn reservations_queue() {
+        let mut nic = Nic::default();
+        nic.set_bandwidth(Some(1000));
+        let now = time::Instant::now();
+        let first = nic.reserve(now, 500);
+        let second = nic.reserve(now, 500);
+        assert_eq!(first, now + time::Duration::from_millis(500));
+        assert_eq!(second, now + time::Duration::from_secs(1));
+    }
+
+    #[test]
+    /// Test that the slowest of the byte and packet limits is used.
+    fn packet_rate_limits() {
+        let mut nic = Nic::default();
+        nic.set_bandwidth(Some(1_000_000));
+        nic.set_packet_rate(Some(10));
+        let now = time::Instant::now();
+        assert_eq!(nic.reserve(now, 1), now + time::Duration::from_millis(100));
+    }
+
+    #[test]
+    /// Test that an idle Nic does not carry over previous reservations.
+    fn idle_nic_starts_now() {
+        let mut nic = Nic::default();
+        nic.set_bandwidth(Some(1000));
+        let now = time::Instant::now();
+        nic.reserve(now, 1000);
+        let later = now + time::Duration::from_secs(5);
+        assert_eq!(
+            nic.reserve(later, 1000),
+            later + time::Duration::from_secs(1)
+        );
+    }
+}
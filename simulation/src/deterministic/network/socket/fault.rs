@@ -1,15 +1,79 @@
 //! Fault injection for AsyncRead/AsyncWrite types.
 
+use super::super::{
+    fault::{Corruption, Fragmentation, ProbeLoss},
+    ledger::Ledger,
+    nic::Nic,
+};
 use crate::TcpStream;
+use bytes::{Buf, Bytes, IntoBuf};
 use futures::{task::Waker, FutureExt, Poll};
-use std::time;
+use std::{collections, time};
 use std::{io, net, pin::Pin, sync, task::Context};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::timer::Delay;
+use tracing::trace;
+
+/// Default interval between unanswered keepalive probes.
+const DEFAULT_KEEPALIVE_INTERVAL: time::Duration = time::Duration::from_secs(75);
+
+/// Default number of unanswered keepalive probes before a connection is considered dead.
+const DEFAULT_KEEPALIVE_RETRIES: u32 = 9;
+
+/// Determines when a flush of a [`FaultyTcpStream`] completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Flushes complete once every byte written has been delivered, which is the send latency
+    /// after the most recent write. Code which relies on flush for ordering observes the
+    /// latency of the link.
+    Delivered,
+    /// Flushes complete immediately, as with an unbuffered in memory stream.
+    Relaxed,
+}
+
+impl Default for FlushMode {
+    fn default() -> Self {
+        FlushMode::Delivered
+    }
+}
+
+/// Idle timeout of a connection, shared by both of its streams. A middlebox on the path discards
+/// the connection once no bytes have been exchanged in either direction for `timeout`.
+#[derive(Debug)]
+pub(crate) struct IdleTimeout {
+    timeout: time::Duration,
+    last_activity: time::Instant,
+}
+
+impl IdleTimeout {
+    pub(crate) fn new(timeout: time::Duration, now: time::Instant) -> Self {
+        Self {
+            timeout,
+            last_activity: now,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct KeepaliveState {
+    /// Time a connection must be idle before probes are sent.
+    idle: time::Duration,
+    /// Number of consecutive probes which have gone unanswered.
+    unanswered: u32,
+    /// Time at which the next probe is sent.
+    next_probe: time::Instant,
+    /// Delays until the next probe is sent, one for reads and one for writes, so that a read
+    /// and a write pending at the same time are both woken when the connection is found dead.
+    read_probe: Delay,
+    write_probe: Delay,
+}
 
 #[derive(Debug)]
 struct FaultState {
     send_latency: time::Duration,
+    /// Latency configured for the link in the direction this side sends, used in place of
+    /// `send_latency` while set.
+    link_latency: Option<time::Duration>,
     send_delay: Delay,
     receive_latency: time::Duration,
     receive_delay: Delay,
@@ -18,6 +82,69 @@ struct FaultState {
     receive_clogged: bool,
     receive_waker: Option<Waker>,
     disconnected: bool,
+    /// Set when the connection was reset, causing reads to fail with `ConnectionReset` rather
+    /// than `BrokenPipe`.
+    reset: bool,
+    /// Set when the peer has silently dropped the connection. Writes are discarded and reads
+    /// never complete, as the peer will never send anything again.
+    black_holed: bool,
+    /// Set when the read half has been shut down, causing reads to return EOF.
+    read_shutdown: bool,
+    /// Set when the write half has been shut down, causing writes to fail with `BrokenPipe`.
+    write_shutdown: bool,
+    /// Network interface of the host which owns this stream.
+    nic: Option<sync::Arc<sync::Mutex<Nic>>>,
+    /// Transmission limits of this stream alone, applied in addition to those of the host
+    /// network interface.
+    link: Nic,
+    /// Delay until an in progress write has been transmitted by the host network interface.
+    nic_delay: Option<Delay>,
+    /// Delay until the FIN sent by a closing peer arrives.
+    fin_delay: Option<Delay>,
+    /// Time at which the peer sent its FIN, by shutting down its write half or dropping.
+    peer_fin_at: Option<time::Instant>,
+    /// Fault state of the peer, which is told when this side sends its FIN.
+    peer: Option<sync::Weak<sync::Mutex<FaultState>>>,
+    /// Maximum number of bytes delivered by a single write.
+    mtu: Option<usize>,
+    /// Shortening of reads, if enabled.
+    fragmentation: Option<Fragmentation>,
+    /// Corruption of reads, if enabled.
+    corruption: Option<Corruption>,
+    /// Idle timeout of the connection, if enabled.
+    idle_timeout: Option<sync::Arc<sync::Mutex<IdleTimeout>>>,
+    /// Delay until the connection times out if it remains idle.
+    idle_delay: Option<Delay>,
+    flush_mode: FlushMode,
+    /// Time at which every byte written so far has been delivered, if any are in flight.
+    delivered_at: Option<time::Instant>,
+    /// Delay until the bytes in flight when a flush began have been delivered.
+    flush_delay: Option<Delay>,
+    /// Time at which the stream was dropped, closing its side of the connection.
+    dropped_at: Option<time::Instant>,
+    /// Ledgers recording the bytes written to and read from this stream.
+    ledgers: Option<(
+        sync::Arc<sync::Mutex<Ledger>>,
+        sync::Arc<sync::Mutex<Ledger>>,
+    )>,
+    /// Log of all bytes written, if capture is enabled.
+    capture: Option<sync::Arc<sync::Mutex<Vec<u8>>>>,
+    /// Bytes injected into the read side, returned before any bytes sent by the peer.
+    injected: collections::VecDeque<Bytes>,
+    /// Waker for a read which is waiting on bytes from the peer.
+    read_waker: Option<Waker>,
+    keepalive: Option<KeepaliveState>,
+    keepalive_interval: time::Duration,
+    keepalive_retries: u32,
+    /// Loss of keepalive probes on a lossy link, if packet loss is installed.
+    probe_loss: Option<ProbeLoss>,
+}
+
+impl FaultState {
+    /// Returns the latency applied to bytes sent by this side.
+    fn effective_send_latency(&self) -> time::Duration {
+        self.link_latency.unwrap_or(self.send_latency)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -29,15 +156,127 @@ impl FaultyTcpStreamHandle {
     pub fn is_dropped(&self) -> bool {
         sync::Arc::strong_count(&self.inner) <= 1
     }
+    /// Returns the time at which the stream was dropped, if it has been.
+    pub(crate) fn dropped_at(&self) -> Option<time::Instant> {
+        self.inner.lock().unwrap().dropped_at
+    }
+    /// Tell `peer` when this side sends its FIN, so that the peer delivers EOF a receive latency
+    /// after the FIN was sent rather than after EOF was first observed.
+    pub(crate) fn set_peer(&self, peer: &FaultyTcpStreamHandle) {
+        self.inner.lock().unwrap().peer = Some(sync::Arc::downgrade(&peer.inner));
+    }
+    /// Returns true if this is the handle of `stream`.
+    pub(crate) fn is_handle_of<T>(&self, stream: &FaultyTcpStream<T>) -> bool {
+        sync::Arc::ptr_eq(&self.inner, &stream.fault_state)
+    }
     pub fn disconnect(&self) {
         self.inner.lock().unwrap().disconnected = true;
     }
+    /// Leave the connection half open, as if the peer silently dropped it. Subsequent writes
+    /// succeed but are never delivered, and reads wait forever without receiving EOF. Only
+    /// keepalive probes can detect the dead connection.
+    pub fn black_hole(&self) {
+        self.inner.lock().unwrap().black_holed = true;
+    }
+    /// Sever the connection, as if a reset was received. Subsequent reads fail with
+    /// `ConnectionReset` and writes fail with `BrokenPipe`, including reads and writes which are
+    /// currently waiting.
+    pub fn reset(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disconnected = true;
+        lock.reset = true;
+        let wakers = vec![
+            lock.send_waker.take(),
+            lock.receive_waker.take(),
+            lock.read_waker.take(),
+        ];
+        for waker in wakers.into_iter().flatten() {
+            waker.wake();
+        }
+    }
     pub fn set_send_latency(&self, duration: time::Duration) {
         self.inner.lock().unwrap().send_latency = duration;
     }
+    /// Delay bytes sent by this side by the latency of the link, overriding the send latency
+    /// until `None` is passed.
+    pub(crate) fn set_link_latency(&self, latency: Option<time::Duration>) {
+        self.inner.lock().unwrap().link_latency = latency;
+    }
     pub fn set_receive_latency(&self, duration: time::Duration) {
         self.inner.lock().unwrap().receive_latency = duration;
     }
+    pub fn send_latency(&self) -> time::Duration {
+        self.inner.lock().unwrap().send_latency
+    }
+    pub fn receive_latency(&self) -> time::Duration {
+        self.inner.lock().unwrap().receive_latency
+    }
+    /// Limit the number of bytes delivered by a single write. Larger writes are split into
+    /// multiple deliveries, each of which is subject to send latency.
+    pub fn set_mtu(&self, mtu: Option<usize>) {
+        self.inner.lock().unwrap().mtu = mtu;
+    }
+    /// Shorten reads to a seeded random length according to `fragmentation`.
+    pub(crate) fn set_fragmentation(&self, fragmentation: Option<Fragmentation>) {
+        self.inner.lock().unwrap().fragmentation = fragmentation;
+    }
+    /// Corrupt the bytes returned by reads according to `corruption`.
+    pub(crate) fn set_corruption(&self, corruption: Option<Corruption>) {
+        self.inner.lock().unwrap().corruption = corruption;
+    }
+    /// Lose keepalive probes according to `probe_loss`, in addition to those lost while the
+    /// connection is clogged or disconnected.
+    pub(crate) fn set_probe_loss(&self, probe_loss: Option<ProbeLoss>) {
+        self.inner.lock().unwrap().probe_loss = probe_loss;
+    }
+    /// Reset the connection once it has been idle for longer than `idle_timeout`.
+    pub(crate) fn set_idle_timeout(
+        &self,
+        idle_timeout: Option<sync::Arc<sync::Mutex<IdleTimeout>>>,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.idle_timeout = idle_timeout;
+        lock.idle_delay = None;
+    }
+    pub fn set_flush_mode(&self, mode: FlushMode) {
+        self.inner.lock().unwrap().flush_mode = mode;
+    }
+    pub fn flush_mode(&self) -> FlushMode {
+        self.inner.lock().unwrap().flush_mode
+    }
+    /// Record all bytes subsequently written to the provided log.
+    pub(crate) fn capture(&self, log: sync::Arc<sync::Mutex<Vec<u8>>>) {
+        self.inner.lock().unwrap().capture.replace(log);
+    }
+    /// Inject bytes into the read side of the stream, as if they were sent by the peer.
+    pub fn inject(&self, bytes: &[u8]) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.injected.push_back(Bytes::from(bytes));
+        if let Some(waker) = lock.read_waker.take() {
+            waker.wake()
+        }
+    }
+    /// Limit the number of bytes per second which can be written to this stream. Passing `None`
+    /// removes the limit.
+    pub fn set_bandwidth(&self, bytes_per_second: Option<u64>) {
+        self.inner
+            .lock()
+            .unwrap()
+            .link
+            .set_bandwidth(bytes_per_second);
+    }
+    /// Record bytes written to this stream in `send`, and bytes read from it in `receive`.
+    pub(crate) fn attach_ledgers(
+        &self,
+        send: sync::Arc<sync::Mutex<Ledger>>,
+        receive: sync::Arc<sync::Mutex<Ledger>>,
+    ) {
+        self.inner.lock().unwrap().ledgers.replace((send, receive));
+    }
+    /// Route writes through the provided host network interface.
+    pub(crate) fn attach_nic(&self, nic: sync::Arc<sync::Mutex<Nic>>) {
+        self.inner.lock().unwrap().nic.replace(nic);
+    }
 
     pub fn is_fully_clogged(&self) -> bool {
         let lock = self.inner.lock().unwrap();
@@ -94,6 +333,7 @@ impl<T> FaultyTcpStream<T> {
         let receive_delay = handle.delay_from(send_latency);
         let fault_state = FaultState {
             send_latency,
+            link_latency: None,
             send_delay,
             receive_latency,
             receive_delay,
@@ -102,6 +342,33 @@ impl<T> FaultyTcpStream<T> {
             receive_clogged: false,
             receive_waker: None,
             disconnected: false,
+            reset: false,
+            black_holed: false,
+            read_shutdown: false,
+            write_shutdown: false,
+            nic: None,
+            link: Nic::default(),
+            nic_delay: None,
+            fin_delay: None,
+            peer_fin_at: None,
+            peer: None,
+            mtu: None,
+            fragmentation: None,
+            corruption: None,
+            idle_timeout: None,
+            idle_delay: None,
+            flush_mode: FlushMode::default(),
+            delivered_at: None,
+            flush_delay: None,
+            dropped_at: None,
+            ledgers: None,
+            capture: None,
+            injected: collections::VecDeque::new(),
+            read_waker: None,
+            keepalive: None,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            keepalive_retries: DEFAULT_KEEPALIVE_RETRIES,
+            probe_loss: None,
         };
         let fault_state = sync::Arc::new(sync::Mutex::new(fault_state));
 
@@ -116,9 +383,140 @@ impl<T> FaultyTcpStream<T> {
         (wrapped_stream, handle)
     }
 
+    /// Enable keepalive probes once the connection has been idle for the provided duration, or
+    /// disable them with `None`. Probes are lost while the connection is clogged or disconnected,
+    /// and with the seeded probability of packet loss on the link if it is installed. Once
+    /// enough consecutive probes are lost, reads and writes fail with `TimedOut`. Panics if
+    /// `idle` is zero.
+    pub fn set_keepalive(&self, idle: Option<time::Duration>) {
+        if let Some(idle) = idle {
+            assert!(
+                idle > time::Duration::from_secs(0),
+                "illegal keepalive idle time: {:?}",
+                idle
+            );
+        }
+        let mut lock = self.fault_state.lock().unwrap();
+        let next_probe = self.handle.now() + idle.unwrap_or_default();
+        lock.keepalive = idle.map(|idle| KeepaliveState {
+            idle,
+            unanswered: 0,
+            next_probe,
+            read_probe: self.handle.delay(next_probe),
+            write_probe: self.handle.delay(next_probe),
+        });
+    }
+
+    /// Returns the idle duration after which keepalive probes are sent, if enabled.
+    pub fn keepalive(&self) -> Option<time::Duration> {
+        let lock = self.fault_state.lock().unwrap();
+        lock.keepalive.as_ref().map(|keepalive| keepalive.idle)
+    }
+
+    /// Configure the interval between unanswered keepalive probes, and the number of unanswered
+    /// probes after which the connection is considered dead. Panics if `interval` is zero.
+    pub fn set_keepalive_probes(&self, interval: time::Duration, retries: u32) {
+        assert!(
+            interval > time::Duration::from_secs(0),
+            "illegal keepalive interval: {:?}",
+            interval
+        );
+        let mut lock = self.fault_state.lock().unwrap();
+        lock.keepalive_interval = interval;
+        lock.keepalive_retries = retries;
+    }
+
+    /// Process any keepalive probes which are due, returning `Ready` with an error if the
+    /// connection has been detected as dead. Otherwise, arrange for the current task to be woken
+    /// when the next probe is due, using the delay of the read or write side as `reading`.
+    fn poll_keepalive(&self, cx: &mut Context<'_>, reading: bool) -> Poll<io::Error> {
+        let mut lock = self.fault_state.lock().unwrap();
+        let state = &mut *lock;
+        let link_down =
+            state.disconnected || state.black_holed || state.send_clogged || state.receive_clogged;
+        let interval = state.keepalive_interval;
+        let retries = state.keepalive_retries;
+        let now = self.handle.now();
+        let keepalive = match state.keepalive.as_mut() {
+            Some(keepalive) => keepalive,
+            None => return Poll::Pending,
+        };
+        while keepalive.next_probe <= now {
+            let lost = link_down || state.probe_loss.as_ref().map_or(false, ProbeLoss::is_lost);
+            if !lost {
+                keepalive.unanswered = 0;
+                keepalive.next_probe += keepalive.idle;
+            } else if keepalive.unanswered >= retries {
+                trace!("keepalive probes unanswered, connection timed out");
+                state.disconnected = true;
+                state.keepalive.take();
+                return Poll::Ready(io::ErrorKind::TimedOut.into());
+            } else {
+                keepalive.unanswered += 1;
+                keepalive.next_probe += interval;
+            }
+        }
+        let probe = if reading {
+            &mut keepalive.read_probe
+        } else {
+            &mut keepalive.write_probe
+        };
+        probe.reset(keepalive.next_probe);
+        let _ = probe.poll_unpin(cx);
+        Poll::Pending
+    }
+
+    /// Reset the connection if it has been idle for longer than its idle timeout, as if the
+    /// middlebox which discarded it answered the next segment with a reset. Otherwise, arrange
+    /// for the current task to be woken when the connection would time out.
+    fn poll_idle_timeout(&self, cx: &mut Context<'_>) {
+        let mut lock = self.fault_state.lock().unwrap();
+        let deadline = match lock.idle_timeout.as_ref() {
+            Some(idle_timeout) => {
+                let idle_timeout = idle_timeout.lock().unwrap();
+                idle_timeout.last_activity + idle_timeout.timeout
+            }
+            None => return,
+        };
+        if lock.disconnected {
+            return;
+        }
+        if self.handle.now() >= deadline {
+            trace!("connection idle for longer than its idle timeout, resetting");
+            lock.disconnected = true;
+            lock.reset = true;
+            lock.idle_delay.take();
+            return;
+        }
+        let handle = &self.handle;
+        let delay = lock
+            .idle_delay
+            .get_or_insert_with(|| handle.delay(deadline));
+        delay.reset(deadline);
+        let _ = delay.poll_unpin(cx);
+    }
+
+    /// Reset the idle timeout of the connection after bytes were exchanged.
+    fn idle_activity(&self) {
+        let lock = self.fault_state.lock().unwrap();
+        if let Some(idle_timeout) = lock.idle_timeout.as_ref() {
+            idle_timeout.lock().unwrap().last_activity = self.handle.now();
+        }
+    }
+
+    /// Reset the keepalive idle timer after bytes were exchanged.
+    fn keepalive_activity(&self) {
+        let mut lock = self.fault_state.lock().unwrap();
+        let now = self.handle.now();
+        if let Some(keepalive) = lock.keepalive.as_mut() {
+            keepalive.unanswered = 0;
+            keepalive.next_probe = now + keepalive.idle;
+        }
+    }
+
     fn poll_send_delay(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         let mut lock = self.fault_state.lock().unwrap();
-        let send_latency = lock.send_latency;
+        let send_latency = lock.effective_send_latency();
         if lock.disconnected {
             return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
         }
@@ -138,9 +536,235 @@ impl<T> FaultyTcpStream<T> {
         Poll::Ready(Ok(()))
     }
 
+    /// Reserve transmission time for `len` bytes on both this stream and the host network
+    /// interface, returning Ready once the bytes have been transmitted by both. Reservations are
+    /// held until `clear_nic_delay` is called, so that a write which is retried after returning
+    /// Pending is not charged twice.
+    fn poll_nic_delay(&self, cx: &mut Context<'_>, len: usize) -> Poll<()> {
+        let mut lock = self.fault_state.lock().unwrap();
+        if lock.nic_delay.is_none() {
+            let now = self.handle.now();
+            let link_deadline = if lock.link.is_unlimited() {
+                None
+            } else {
+                Some(lock.link.reserve(now, len))
+            };
+            let host_deadline = lock.nic.as_ref().and_then(|nic| {
+                let mut nic = nic.lock().unwrap();
+                if nic.is_unlimited() {
+                    None
+                } else {
+                    Some(nic.reserve(now, len))
+                }
+            });
+            let deadline = match link_deadline.into_iter().chain(host_deadline).max() {
+                Some(deadline) => deadline,
+                None => return Poll::Ready(()),
+            };
+            lock.nic_delay.replace(self.handle.delay(deadline));
+        }
+        if let Some(delay) = lock.nic_delay.as_mut() {
+            futures::ready!(delay.poll_unpin(cx));
+        }
+        Poll::Ready(())
+    }
+
+    /// Returns the number of bytes out of `len` which can be delivered by a single write.
+    fn write_len(&self, len: usize) -> usize {
+        match self.fault_state.lock().unwrap().mtu {
+            Some(mtu) => std::cmp::min(len, std::cmp::max(mtu, 1)),
+            None => len,
+        }
+    }
+
+    /// Returns the number of bytes out of `len` which a single read can return.
+    fn read_len(&self, len: usize) -> usize {
+        match self.fault_state.lock().unwrap().fragmentation.as_ref() {
+            Some(fragmentation) => fragmentation.read_len(len),
+            None => len,
+        }
+    }
+
+    /// Corrupt the bytes returned by a read, returning the number of bytes which remain.
+    fn corrupt(&self, read: &mut [u8]) -> usize {
+        match self.fault_state.lock().unwrap().corruption.as_ref() {
+            Some(corruption) => corruption.corrupt(read),
+            None => read.len(),
+        }
+    }
+
+    /// Attempt to read any injected bytes into `dst`. Returns the number of bytes read, or None if
+    /// no bytes were injected.
+    fn read_injected(&self, dst: &mut [u8]) -> Option<usize> {
+        let mut lock = self.fault_state.lock().unwrap();
+        let mut bytes = lock.injected.pop_front()?;
+        let to_write = std::cmp::min(dst.len(), bytes.len());
+        bytes
+            .split_to(to_write)
+            .into_buf()
+            .copy_to_slice(&mut dst[..to_write]);
+        if !bytes.is_empty() {
+            lock.injected.push_front(bytes);
+        }
+        Some(to_write)
+    }
+
+    fn register_read_waker(&self, cx: &mut Context<'_>) {
+        self.fault_state
+            .lock()
+            .unwrap()
+            .read_waker
+            .replace(cx.waker().clone());
+    }
+
+    fn record_written(&self, written: &[u8]) {
+        let lock = self.fault_state.lock().unwrap();
+        if let Some(capture) = lock.capture.as_ref() {
+            capture.lock().unwrap().extend_from_slice(written);
+        }
+        if let Some(nic) = lock.nic.as_ref() {
+            nic.lock().unwrap().record_sent(written.len());
+        }
+        if let Some((send, _)) = lock.ledgers.as_ref() {
+            send.lock().unwrap().record_sent(written);
+        }
+    }
+
+    /// Mark the bytes just written as in flight until the send latency has elapsed.
+    fn record_in_flight(&self) {
+        let mut lock = self.fault_state.lock().unwrap();
+        let delivered_at = self.handle.now() + lock.effective_send_latency();
+        if lock.delivered_at.map_or(true, |at| at < delivered_at) {
+            lock.delivered_at.replace(delivered_at);
+        }
+    }
+
+    /// Returns Ready once every byte in flight has been delivered. Delivery stalls while sends
+    /// are clogged, and fails if the connection is disconnected.
+    fn poll_delivered(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let mut lock = self.fault_state.lock().unwrap();
+        if lock.disconnected {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if lock.send_clogged {
+            lock.send_waker.replace(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let delivered_at = match lock.delivered_at {
+            Some(delivered_at) => delivered_at,
+            None => return Poll::Ready(Ok(())),
+        };
+        if lock.flush_delay.is_none() {
+            lock.flush_delay.replace(self.handle.delay(delivered_at));
+        }
+        if let Some(delay) = lock.flush_delay.as_mut() {
+            futures::ready!(delay.poll_unpin(cx));
+        }
+        lock.flush_delay.take();
+        // Bytes written while the flush was waiting extend the deadline.
+        if lock.delivered_at == Some(delivered_at) {
+            lock.delivered_at.take();
+            Poll::Ready(Ok(()))
+        } else {
+            drop(lock);
+            self.poll_delivered(cx)
+        }
+    }
+
+    fn flush_mode(&self) -> FlushMode {
+        self.fault_state.lock().unwrap().flush_mode
+    }
+
+    fn record_read(&self, read: &[u8]) {
+        let lock = self.fault_state.lock().unwrap();
+        if let Some((_, receive)) = lock.ledgers.as_ref() {
+            receive.lock().unwrap().record_received(read);
+        }
+    }
+
+    fn is_read_shutdown(&self) -> bool {
+        self.fault_state.lock().unwrap().read_shutdown
+    }
+
+    fn is_write_shutdown(&self) -> bool {
+        self.fault_state.lock().unwrap().write_shutdown
+    }
+
+    /// Mark the halves selected by `how` as shut down, waking a read waiting on a half which
+    /// has been shut down. Shared by `TcpStream::shutdown` and `AsyncWrite::poll_shutdown`.
+    fn shutdown_halves(&self, how: net::Shutdown) -> io::Result<()> {
+        let read_waker = {
+            let mut lock = self.fault_state.lock().unwrap();
+            if lock.reset {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            if lock.disconnected {
+                return Err(io::ErrorKind::NotConnected.into());
+            }
+            if how != net::Shutdown::Write {
+                lock.read_shutdown = true;
+            }
+            if how != net::Shutdown::Read {
+                lock.write_shutdown = true;
+            }
+            lock.read_waker.take()
+        };
+        if let Some(waker) = read_waker {
+            waker.wake();
+        }
+        if how != net::Shutdown::Read {
+            self.send_fin();
+        }
+        Ok(())
+    }
+
+    /// Record the time at which this side sent its FIN with the peer, if it is still open.
+    /// Only the first FIN is recorded.
+    fn send_fin(&self) {
+        let peer = match self.fault_state.lock() {
+            Ok(lock) => lock.peer.as_ref().and_then(sync::Weak::upgrade),
+            Err(_) => None,
+        };
+        if let Some(peer) = peer {
+            if let Ok(mut peer) = peer.lock() {
+                if peer.peer_fin_at.is_none() {
+                    peer.peer_fin_at = Some(self.handle.now());
+                }
+            }
+        }
+    }
+
+    fn is_black_holed(&self) -> bool {
+        self.fault_state.lock().unwrap().black_holed
+    }
+
+    fn clear_nic_delay(&self) {
+        self.fault_state.lock().unwrap().nic_delay.take();
+    }
+
+    /// Delay delivery of EOF from a closed peer by the current receive latency after the peer
+    /// sent its FIN, modeling the time taken for the FIN to arrive. Any bytes sent before the
+    /// peer closed are read first. If the time the FIN was sent is unknown, the latency is
+    /// counted from when EOF is first observed.
+    fn poll_fin_delay(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut lock = self.fault_state.lock().unwrap();
+        if lock.fin_delay.is_none() {
+            let sent_at = lock.peer_fin_at.unwrap_or_else(|| self.handle.now());
+            let deadline = sent_at + lock.receive_latency;
+            lock.fin_delay.replace(self.handle.delay(deadline));
+        }
+        if let Some(delay) = lock.fin_delay.as_mut() {
+            futures::ready!(delay.poll_unpin(cx));
+        }
+        Poll::Ready(())
+    }
+
     fn poll_receive_delay(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         let mut lock = self.fault_state.lock().unwrap();
         let receive_latency = lock.receive_latency;
+        if lock.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
         if lock.disconnected {
             return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
         }
@@ -161,6 +785,15 @@ impl<T> FaultyTcpStream<T> {
     }
 }
 
+impl<T> Drop for FaultyTcpStream<T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.fault_state.lock() {
+            state.dropped_at = Some(self.handle.now());
+        }
+        self.send_fin();
+    }
+}
+
 impl<T> AsyncRead for FaultyTcpStream<T>
 where
     T: TcpStream,
@@ -170,10 +803,50 @@ where
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(e) = self.poll_keepalive(cx, true) {
+            return Poll::Ready(Err(e));
+        }
+        self.poll_idle_timeout(cx);
+        if self.is_read_shutdown() {
+            return Poll::Ready(Ok(0));
+        }
+        {
+            let mut lock = self.fault_state.lock().unwrap();
+            // Black holed reads wait until the connection is reset, including by a crash.
+            if lock.black_holed && !lock.disconnected {
+                lock.read_waker.replace(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
         if let Err(e) = futures::ready!(self.poll_receive_delay(cx)) {
             return Poll::Ready(Err(e));
         }
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        let len = self.read_len(buf.len());
+        let buf = &mut buf[..len];
+        if let Some(read) = self.read_injected(buf) {
+            return Poll::Ready(Ok(read));
+        }
+        let result = match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => {
+                self.register_read_waker(cx);
+                return Poll::Pending;
+            }
+        };
+        match result {
+            Ok(0) if !buf.is_empty() => {
+                futures::ready!(self.poll_fin_delay(cx));
+                Poll::Ready(Ok(0))
+            }
+            Ok(read) => {
+                let read = self.corrupt(&mut buf[..read]);
+                self.record_read(&buf[..read]);
+                self.keepalive_activity();
+                self.idle_activity();
+                Poll::Ready(Ok(read))
+            }
+            result => Poll::Ready(result),
+        }
     }
 }
 
@@ -186,14 +859,40 @@ where
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
+        if let Poll::Ready(e) = self.poll_keepalive(cx, false) {
+            return Poll::Ready(Err(e));
+        }
+        self.poll_idle_timeout(cx);
+        if self.is_write_shutdown() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if self.is_black_holed() {
+            // The bytes are recorded as sent, but never reach the peer.
+            self.record_written(buf);
+            return Poll::Ready(Ok(buf.len()));
+        }
         if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
             return Poll::Ready(Err(e));
         }
-        Pin::new(&mut self.inner).poll_write(cx, buf)
+        let len = self.write_len(buf.len());
+        futures::ready!(self.poll_nic_delay(cx, len));
+        let result = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]));
+        self.clear_nic_delay();
+        if let Ok(written) = result {
+            self.record_written(&buf[..written]);
+            self.record_in_flight();
+            self.keepalive_activity();
+            self.idle_activity();
+        }
+        Poll::Ready(result)
     }
+    /// In [`FlushMode::Delivered`], flushing waits until every byte written has been delivered
+    /// to the peer. In [`FlushMode::Relaxed`], flushing completes immediately.
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
-            return Poll::Ready(Err(e));
+        if self.flush_mode() == FlushMode::Delivered {
+            if let Err(e) = futures::ready!(self.poll_delivered(cx)) {
+                return Poll::Ready(Err(e));
+            }
         }
         Pin::new(&mut self.inner).poll_flush(cx)
     }
@@ -204,6 +903,11 @@ where
         if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
             return Poll::Ready(Err(e));
         }
+        if !self.is_write_shutdown() {
+            if let Err(e) = self.shutdown_halves(net::Shutdown::Write) {
+                return Poll::Ready(Err(e));
+            }
+        }
         Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
@@ -218,6 +922,44 @@ where
     fn peer_addr(&self) -> io::Result<net::SocketAddr> {
         T::peer_addr(&self.inner)
     }
+    /// Shutting down the write half delivers EOF to the peer after the receive latency, as
+    /// with a closed stream. Reads which are waiting when the read half is shut down return
+    /// EOF.
+    fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        self.shutdown_halves(how)?;
+        if how != net::Shutdown::Read {
+            T::shutdown(&self.inner, how)?;
+        }
+        Ok(())
+    }
+    fn nodelay(&self) -> io::Result<bool> {
+        T::nodelay(&self.inner)
+    }
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        T::set_nodelay(&self.inner, nodelay)
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        T::ttl(&self.inner)
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        T::set_ttl(&self.inner, ttl)
+    }
+    /// Keepalive probes are modeled by the fault layer rather than the wrapped stream, see
+    /// [`FaultyTcpStream::set_keepalive`].
+    fn keepalive(&self) -> io::Result<Option<time::Duration>> {
+        Ok(FaultyTcpStream::keepalive(self))
+    }
+    /// Fails with `InvalidInput` for a zero idle time, as real sockets do.
+    fn set_keepalive(&self, keepalive: Option<time::Duration>) -> io::Result<()> {
+        if keepalive == Some(time::Duration::from_secs(0)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keepalive idle time must be non-zero",
+            ));
+        }
+        FaultyTcpStream::set_keepalive(self, keepalive);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -229,6 +971,7 @@ mod tests {
     use futures::{SinkExt, StreamExt};
     use std::time;
     use tokio::codec::{Framed, LinesCodec};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[test]
     /// Test that injecting delay and disconnect faults causes the socket to delay and disconnect reads.
@@ -265,6 +1008,194 @@ mod tests {
         });
     }
 
+    #[test]
+    /// Test that link latency overrides the send latency until it is removed.
+    fn link_latency() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+            let (mut client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            let ms = time::Duration::from_millis;
+            client_handle.set_send_latency(ms(100));
+            for (link_latency, expected) in [(Some(ms(250)), ms(250)), (None, ms(100))].iter() {
+                client_handle.set_link_latency(*link_latency);
+                let start_time = handle.now();
+                client_conn.write_all(b"ping").await.unwrap();
+                client_conn.flush().await.unwrap();
+                assert_eq!(handle.now() - start_time, *expected);
+            }
+        });
+    }
+
+    #[test]
+    /// Test that flushes wait for written bytes to be delivered, unless flushes are relaxed.
+    fn flush_delivery() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let latency = time::Duration::from_millis(100);
+            for mode in [FlushMode::Delivered, FlushMode::Relaxed].iter() {
+                let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+                let (mut client_conn, client_handle) =
+                    FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+                client_handle.set_send_latency(latency);
+                client_handle.set_flush_mode(*mode);
+
+                let start_time = handle.now();
+                client_conn.write_all(b"ping").await.unwrap();
+                assert_eq!(handle.now(), start_time);
+                client_conn.flush().await.unwrap();
+                let expected = match mode {
+                    FlushMode::Delivered => latency,
+                    FlushMode::Relaxed => time::Duration::from_secs(0),
+                };
+                assert_eq!(handle.now() - start_time, expected, "{:?}", mode);
+
+                // nothing is in flight, so a second flush completes immediately.
+                let flushed_at = handle.now();
+                client_conn.flush().await.unwrap();
+                assert_eq!(handle.now(), flushed_at);
+            }
+        });
+    }
+
+    #[test]
+    /// Test that socket options are recorded, and that the keepalive option enables keepalive
+    /// probes.
+    fn socket_options() {
+        let runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let server_addr = "127.0.0.1:9092".parse().unwrap();
+        let client_addr = "127.0.0.1:35255".parse().unwrap();
+        let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+        let (client_conn, _) = FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+        assert!(!client_conn.nodelay().unwrap());
+        assert_eq!(client_conn.ttl().unwrap(), 64);
+
+        client_conn.set_nodelay(true).unwrap();
+        client_conn.set_ttl(16).unwrap();
+        let idle = time::Duration::from_secs(30);
+        TcpStream::set_keepalive(&client_conn, Some(idle)).unwrap();
+        assert!(client_conn.nodelay().unwrap());
+        assert_eq!(client_conn.ttl().unwrap(), 16);
+        assert_eq!(TcpStream::keepalive(&client_conn).unwrap(), Some(idle));
+        assert_eq!(FaultyTcpStream::keepalive(&client_conn), Some(idle));
+        let zero = Some(time::Duration::from_secs(0));
+        assert_eq!(
+            TcpStream::set_keepalive(&client_conn, zero)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(TcpStream::keepalive(&client_conn).unwrap(), Some(idle));
+    }
+
+    #[test]
+    /// Test that a closed peer delivers buffered bytes, followed by EOF once the FIN arrives,
+    /// with the latency counted from when the peer closed rather than when EOF is read.
+    fn delayed_fin() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+            let (client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            let (server_conn, server_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), server_conn);
+            client_handle.set_peer(&server_handle);
+            server_handle.set_peer(&client_handle);
+
+            // write a single message, then close the connection.
+            let mut server_transport = Framed::new(server_conn, LinesCodec::new());
+            server_transport
+                .send(String::from("Hello Future!"))
+                .await
+                .unwrap();
+            let closed_at = handle.now();
+            drop(server_transport);
+
+            let mut transport = Framed::new(client_conn, LinesCodec::new());
+            let result = transport.next().await.unwrap().unwrap();
+            assert_eq!(result, String::from("Hello Future!"));
+
+            // time spent before reading EOF counts towards the FIN delay.
+            client_handle.set_receive_latency(time::Duration::from_secs(10));
+            handle.delay_from(time::Duration::from_secs(4)).await;
+            assert!(transport.next().await.is_none(), "expected EOF");
+            assert_eq!(
+                handle.now() - closed_at,
+                time::Duration::from_secs(10),
+                "expected EOF to arrive a receive latency after the peer closed"
+            );
+        });
+    }
+
+    #[test]
+    /// Test that keepalive probes detect a clogged connection after the probe schedule elapses.
+    fn keepalive_timeout() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+            let (client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            client_conn.set_keepalive(Some(time::Duration::from_secs(10)));
+            client_conn.set_keepalive_probes(time::Duration::from_secs(1), 3);
+            assert_eq!(client_conn.keepalive(), Some(time::Duration::from_secs(10)));
+
+            // an idle but healthy connection is not timed out.
+            let mut transport = Framed::new(client_conn, LinesCodec::new());
+            let start_time = handle.now();
+            let idle = handle
+                .timeout(transport.next(), time::Duration::from_secs(100))
+                .await;
+            assert!(idle.is_err(), "expected healthy connection to stay open");
+
+            client_handle.clog_receives();
+            let clogged_at = handle.now();
+            let result = transport.next().await.unwrap();
+            assert!(result.is_err(), "expected keepalive to time out");
+            assert!(handle.now() - clogged_at <= time::Duration::from_secs(13));
+            assert!(handle.now() - start_time > time::Duration::from_secs(100));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "illegal keepalive idle time")]
+    /// Test that a zero keepalive idle time, which would send probes continuously, is rejected.
+    fn zero_keepalive_idle() {
+        let runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let server_addr = "127.0.0.1:9092".parse().unwrap();
+        let client_addr = "127.0.0.1:35255".parse().unwrap();
+        let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+        let (client_conn, _) = FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+        client_conn.set_keepalive(Some(time::Duration::from_secs(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "illegal keepalive interval")]
+    /// Test that a zero keepalive probe interval is rejected.
+    fn zero_keepalive_interval() {
+        let runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let server_addr = "127.0.0.1:9092".parse().unwrap();
+        let client_addr = "127.0.0.1:35255".parse().unwrap();
+        let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+        let (client_conn, _) = FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+        client_conn.set_keepalive_probes(time::Duration::from_secs(0), 3);
+    }
+
     #[test]
     /// tests that send and receives can be clogged/unclogged
     #[allow(unused_must_use)]
@@ -360,4 +1291,27 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    /// Test that resetting a black holed connection unblocks pending reads.
+    fn black_hole_reset_unblocks() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+            let (mut client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            client_handle.black_hole();
+            let read = crate::spawn_with_result(&handle, async move {
+                let mut buf = [0; 4];
+                client_conn.read(&mut buf).await
+            });
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            client_handle.reset();
+            let err = read.await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        });
+    }
 }
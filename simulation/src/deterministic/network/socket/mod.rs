@@ -1,9 +1,10 @@
 use bytes::{Buf, Bytes, IntoBuf};
 use futures::{channel::mpsc, Future, Poll, Sink, SinkExt, Stream};
-use std::{fmt, io, net, pin::Pin, task::Context};
+use std::{fmt, io, net, pin::Pin, sync, task::Context, time};
 use tokio::io::{AsyncRead, AsyncWrite};
 pub mod fault;
-pub use fault::{FaultyTcpStream, FaultyTcpStreamHandle};
+pub(crate) use fault::IdleTimeout;
+pub use fault::{FaultyTcpStream, FaultyTcpStreamHandle, FlushMode};
 use tracing::{span, trace, Level};
 
 /// Returns a client/server socket pair, along with a SocketHandle which can be used to close
@@ -19,11 +20,34 @@ pub fn new_socket_pair(
     (client_socket, server_socket)
 }
 
+/// Default IP_TTL of new sockets, matching Linux.
+const DEFAULT_TTL: u32 = 64;
+
+/// Socket options set on a `SocketHalf`. Options are recorded so that they can be read back,
+/// but do not affect how bytes are delivered.
+#[derive(Debug)]
+struct SocketOptions {
+    nodelay: bool,
+    ttl: u32,
+    keepalive: Option<time::Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: false,
+            ttl: DEFAULT_TTL,
+            keepalive: None,
+        }
+    }
+}
+
 pub struct SocketHalf {
     tx: mpsc::Sender<Bytes>,
     rx: mpsc::Receiver<Bytes>,
     staged: Option<Bytes>,
     shutdown: bool,
+    options: sync::Mutex<SocketOptions>,
     local_addr: net::SocketAddr,
     peer_addr: net::SocketAddr,
 }
@@ -53,6 +77,7 @@ impl SocketHalf {
             rx,
             staged: None,
             shutdown: false,
+            options: sync::Mutex::default(),
             local_addr,
             peer_addr,
         }
@@ -63,6 +88,10 @@ impl SocketHalf {
     pub fn peer_addr(&self) -> net::SocketAddr {
         self.peer_addr
     }
+    /// Present the peer at `peer_addr`, as when the peer is behind a NAT.
+    pub(crate) fn set_peer_addr(&mut self, peer_addr: net::SocketAddr) {
+        self.peer_addr = peer_addr;
+    }
     pub(crate) fn connected(&self) -> bool {
         !self.tx.is_closed()
     }
@@ -108,8 +137,8 @@ impl AsyncRead for SocketHalf {
                     self.staged.replace(new_bytes)
                 }
                 None => {
-                    trace!("socket disconnected");
-                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                    trace!("peer closed, returning EOF");
+                    return Poll::Ready(Ok(0));
                 }
             };
         })
@@ -164,6 +193,36 @@ impl crate::TcpStream for SocketHalf {
     fn peer_addr(&self) -> io::Result<net::SocketAddr> {
         Ok(self.peer_addr)
     }
+    /// Shutting down the write half closes the channel to the peer, which reads any bytes
+    /// already sent followed by EOF. Shutting down the read half is left to `FaultyTcpStream`.
+    fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        if how != net::Shutdown::Read {
+            trace!("shutting down write half");
+            self.tx.clone().close_channel();
+        }
+        Ok(())
+    }
+    fn nodelay(&self) -> io::Result<bool> {
+        Ok(self.options.lock().unwrap().nodelay)
+    }
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.options.lock().unwrap().nodelay = nodelay;
+        Ok(())
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        Ok(self.options.lock().unwrap().ttl)
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.options.lock().unwrap().ttl = ttl;
+        Ok(())
+    }
+    fn keepalive(&self) -> io::Result<Option<time::Duration>> {
+        Ok(self.options.lock().unwrap().keepalive)
+    }
+    fn set_keepalive(&self, keepalive: Option<time::Duration>) -> io::Result<()> {
+        self.options.lock().unwrap().keepalive = keepalive;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -214,8 +273,8 @@ mod tests {
     }
 
     #[test]
-    /// Tests that disconnecting the server and client will cause both the server and client to fail further
-    /// reads/writes with an error.
+    /// Tests that disconnecting the server will cause the client to observe EOF on reads, and fail further
+    /// writes with an error.
     fn test_disconnect() {
         let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
         let handle = runtime.localhost_handle();
@@ -240,7 +299,7 @@ mod tests {
                     }
                     num if num == 2 => {
                         assert!(send_result.is_ok(), "expected send to succeed");
-                        assert!(transport.next().await.unwrap().is_err(), "msg num 2 should cause the server to close, resulting in an err returned by the receive")
+                        assert!(transport.next().await.is_none(), "msg num 2 should cause the server to close, resulting in EOF returned by the receive")
                     }
                     _ => {
                         assert!(send_result.is_err(), "now that the server is closed, sends should always fail");
@@ -0,0 +1,263 @@
+//! Network topologies of racks and regions.
+//!
+//! Configuring the latency of every pair of hosts individually quickly becomes unmanageable as a
+//! simulation grows. A [`TopologyBuilder`] instead places each host in a rack within a region,
+//! and assigns a [`LinkProfile`] to hosts in the same rack, hosts in different racks of the same
+//! region, and hosts in different regions, optionally overridden for particular pairs of
+//! regions. Installing the topology derives the latency and loss of every pair of hosts from
+//! their locations.
+use super::Inner;
+use crate::deterministic::DeterministicRandomHandle;
+use std::{collections, net, sync, time};
+use tracing::trace;
+
+/// Latency and loss of traffic between two hosts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkProfile {
+    /// Latency of traffic sent in each direction.
+    pub latency: time::Duration,
+    /// Probability that each datagram is dropped. Streams are unaffected.
+    pub loss: f64,
+}
+
+impl LinkProfile {
+    /// Create a profile with the provided one way latency and no loss.
+    pub fn new(latency: time::Duration) -> Self {
+        Self { latency, loss: 0.0 }
+    }
+
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.loss = probability;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Location {
+    region: String,
+    rack: String,
+}
+
+/// Builder for a [`Topology`], installed into the network with [`TopologyBuilder::install`].
+pub struct TopologyBuilder {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    random_handle: DeterministicRandomHandle,
+    hosts: Vec<(net::IpAddr, Location)>,
+    same_rack: Option<LinkProfile>,
+    same_region: Option<LinkProfile>,
+    cross_region: Option<LinkProfile>,
+    regions: collections::HashMap<(String, String), LinkProfile>,
+}
+
+impl TopologyBuilder {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+    ) -> Self {
+        Self {
+            inner,
+            random_handle,
+            hosts: vec![],
+            same_rack: None,
+            same_region: None,
+            cross_region: None,
+            regions: collections::HashMap::new(),
+        }
+    }
+
+    /// Place `host` in `rack` of `region`, replacing any previous placement. Racks are scoped
+    /// to their region.
+    pub fn host(mut self, host: net::IpAddr, region: &str, rack: &str) -> Self {
+        self.hosts.retain(|(ip, _)| *ip != host);
+        let location = Location {
+            region: region.to_string(),
+            rack: rack.to_string(),
+        };
+        self.hosts.push((host, location));
+        self
+    }
+
+    /// Profile of links between hosts in the same rack.
+    pub fn same_rack(mut self, profile: LinkProfile) -> Self {
+        self.same_rack = Some(profile);
+        self
+    }
+
+    /// Profile of links between hosts in different racks of the same region.
+    pub fn same_region(mut self, profile: LinkProfile) -> Self {
+        self.same_region = Some(profile);
+        self
+    }
+
+    /// Profile of links between hosts in different regions.
+    pub fn cross_region(mut self, profile: LinkProfile) -> Self {
+        self.cross_region = Some(profile);
+        self
+    }
+
+    /// Profile of links between hosts in regions `a` and `b`, overriding the cross region
+    /// profile.
+    pub fn regions(mut self, a: &str, b: &str, profile: LinkProfile) -> Self {
+        self.regions.insert(region_pair(a, b), profile);
+        self
+    }
+
+    fn profile(&self, a: &Location, b: &Location) -> Option<&LinkProfile> {
+        if a.region != b.region {
+            return self
+                .regions
+                .get(&region_pair(&a.region, &b.region))
+                .or_else(|| self.cross_region.as_ref());
+        }
+        if a.rack != b.rack {
+            self.same_region.as_ref()
+        } else {
+            self.same_rack.as_ref()
+        }
+    }
+
+    /// Consumes this builder and applies the profile of each pair of hosts to new and existing
+    /// traffic between them. Pairs of hosts without a profile are left unchanged. Installing a
+    /// packet loss fault injector afterwards replaces the loss applied by the topology.
+    pub fn install(self) -> Topology {
+        let mut links = collections::HashMap::new();
+        for (a, a_location) in self.hosts.iter() {
+            for (b, b_location) in self.hosts.iter() {
+                if a == b {
+                    continue;
+                }
+                if let Some(profile) = self.profile(a_location, b_location) {
+                    links.insert((*a, *b), profile.clone());
+                }
+            }
+        }
+        trace!(
+            "installing topology of {} hosts, {} links",
+            self.hosts.len(),
+            links.len()
+        );
+        let mut lock = self.inner.lock().unwrap();
+        for ((a, b), profile) in links.iter() {
+            lock.set_one_way_latency(*a, *b, Some(profile.latency));
+            if profile.loss > 0.0 {
+                lock.set_link_loss(&self.random_handle, *a, *b, profile.loss);
+            }
+        }
+        Topology {
+            hosts: self.hosts,
+            links,
+        }
+    }
+}
+
+/// Regions in a canonical order, so that profiles apply in both directions.
+fn region_pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+/// An installed topology.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    hosts: Vec<(net::IpAddr, Location)>,
+    links: collections::HashMap<(net::IpAddr, net::IpAddr), LinkProfile>,
+}
+
+impl Topology {
+    fn location(&self, host: net::IpAddr) -> Option<&Location> {
+        self.hosts
+            .iter()
+            .find(|(ip, _)| *ip == host)
+            .map(|(_, location)| location)
+    }
+
+    /// Returns the region of `host`.
+    pub fn region(&self, host: net::IpAddr) -> Option<&str> {
+        self.location(host).map(|l| l.region.as_str())
+    }
+
+    /// Returns the rack of `host`.
+    pub fn rack(&self, host: net::IpAddr) -> Option<&str> {
+        self.location(host).map(|l| l.rack.as_str())
+    }
+
+    /// Returns the hosts in `region`, in the order they were placed, which can be used to
+    /// partition a region away from the rest of the network.
+    pub fn region_hosts(&self, region: &str) -> Vec<net::IpAddr> {
+        self.hosts
+            .iter()
+            .filter(|(_, l)| l.region == region)
+            .map(|(ip, _)| *ip)
+            .collect()
+    }
+
+    /// Returns the hosts in `rack` of `region`, in the order they were placed.
+    pub fn rack_hosts(&self, region: &str, rack: &str) -> Vec<net::IpAddr> {
+        self.hosts
+            .iter()
+            .filter(|(_, l)| l.region == region && l.rack == rack)
+            .map(|(ip, _)| *ip)
+            .collect()
+    }
+
+    /// Returns the profile applied to traffic sent from `from` to `to`.
+    pub fn link(&self, from: net::IpAddr, to: net::IpAddr) -> Option<&LinkProfile> {
+        self.links.get(&(from, to))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment, UdpSocket};
+
+    #[test]
+    /// Test that the latency and loss of traffic between hosts is derived from their locations.
+    fn derived_links() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let ips: Vec<net::IpAddr> = ["10.0.0.1", "10.0.0.2", "10.0.1.1", "10.1.0.1", "10.2.0.1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let millis = time::Duration::from_millis;
+        let topology = runtime
+            .topology()
+            .host(ips[0], "east", "a")
+            .host(ips[1], "east", "a")
+            .host(ips[2], "east", "b")
+            .host(ips[3], "west", "a")
+            .host(ips[4], "north", "a")
+            .same_region(LinkProfile::new(millis(1)))
+            .cross_region(LinkProfile::new(millis(50)))
+            .regions("north", "east", LinkProfile::new(millis(80)).loss(1.0))
+            .install();
+        assert_eq!(topology.region_hosts("east"), &ips[..3]);
+        assert_eq!(topology.rack_hosts("west", "a"), vec![ips[3]]);
+        assert_eq!(topology.rack(ips[2]), Some("b"));
+        assert_eq!(topology.link(ips[0], ips[1]), None);
+        assert_eq!(topology.link(ips[4], ips[0]).unwrap().loss, 1.0);
+
+        let sockets: Vec<_> = ips.iter().map(|ip| runtime.handle(*ip)).collect();
+        runtime.block_on(async {
+            let addr = |ip| net::SocketAddr::new(ip, 53);
+            let mut receiver = sockets[0].bind_udp(addr(ips[0])).await.unwrap();
+            let mut buf = [0; 4];
+            for (i, expected) in [(1, millis(0)), (2, millis(1)), (3, millis(50))].iter() {
+                let mut sender = sockets[*i].bind_udp(addr(ips[*i])).await.unwrap();
+                let sent = handle.now();
+                sender.send_to(b"ping", addr(ips[0])).await.unwrap();
+                receiver.recv_from(&mut buf).await.unwrap();
+                assert_eq!(handle.now() - sent, *expected);
+            }
+
+            let mut lossy = sockets[4].bind_udp(addr(ips[4])).await.unwrap();
+            lossy.send_to(b"ping", addr(ips[0])).await.unwrap();
+            let recv = handle.timeout(receiver.recv_from(&mut buf), millis(200));
+            assert!(recv.await.is_err(), "expected datagram to be dropped");
+        });
+    }
+}
@@ -0,0 +1,165 @@
+//! In memory datagram sockets.
+//!
+//! Datagrams are delivered to the socket bound to their destination address after the one-way
+//! latency between the hosts. Matching UDP semantics, delivery is unreliable: datagrams sent to
+//! an address with no bound socket, or across a link which is clogged by a fault injector, are
+//! silently dropped.
+//!
+//! Datagrams sent to a multicast group or to the broadcast address fan out to every subscribed
+//! socket, and each copy is subject to the loss and latency of the link to its receiver.
+//!
+//! Crashing the host of a socket fails its pending and subsequent sends and receives with
+//! `ConnectionAborted`.
+use super::{
+    abort::{AbortSignal, Aborted},
+    Inner,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{channel::mpsc, future, FutureExt, Poll, StreamExt};
+use std::{collections, fmt, io, net, sync, task::Context, time};
+use tokio::timer::Delay;
+use tracing::trace;
+
+/// A datagram, along with the address it was sent from and the time it is delivered.
+pub(crate) type Datagram = (net::SocketAddr, Bytes, time::Instant);
+
+pub struct UdpSocket {
+    local_addr: net::SocketAddr,
+    incoming: mpsc::UnboundedReceiver<Datagram>,
+    /// Datagrams which have been sent but not yet delivered, in order of delivery.
+    pending: collections::VecDeque<Datagram>,
+    delay: Option<Delay>,
+    time_handle: crate::deterministic::DeterministicTimeHandle,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    /// Signal fired when the host of this socket crashes.
+    abort: Aborted,
+}
+
+impl fmt::Debug for UdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UdpSocket {{ local_addr: {} }}", self.local_addr)
+    }
+}
+
+impl UdpSocket {
+    pub(crate) fn new(
+        local_addr: net::SocketAddr,
+        incoming: mpsc::UnboundedReceiver<Datagram>,
+        time_handle: crate::deterministic::DeterministicTimeHandle,
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        abort: AbortSignal,
+    ) -> Self {
+        Self {
+            local_addr,
+            incoming,
+            pending: collections::VecDeque::new(),
+            delay: None,
+            time_handle,
+            inner,
+            abort: abort.aborted(),
+        }
+    }
+
+    /// Returns the next datagram which has been delivered, or `None` once the socket has been
+    /// unbound and every pending datagram delivered.
+    fn poll_delivered(&mut self, cx: &mut Context<'_>) -> Poll<Option<(net::SocketAddr, Bytes)>> {
+        let mut closed = false;
+        loop {
+            match self.incoming.poll_next_unpin(cx) {
+                Poll::Ready(Some(datagram)) => {
+                    // Datagrams sent across faster links overtake those already in flight.
+                    let index = self
+                        .pending
+                        .iter()
+                        .position(|(_, _, deliver_at)| *deliver_at > datagram.2)
+                        .unwrap_or_else(|| self.pending.len());
+                    self.pending.insert(index, datagram);
+                    self.delay.take();
+                }
+                Poll::Ready(None) => {
+                    closed = true;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+        let deliver_at = match self.pending.front() {
+            Some((_, _, deliver_at)) => *deliver_at,
+            None if closed => return Poll::Ready(None),
+            None => return Poll::Pending,
+        };
+        if deliver_at > self.time_handle.now() {
+            let time_handle = &self.time_handle;
+            let delay = self
+                .delay
+                .get_or_insert_with(|| time_handle.delay(deliver_at));
+            futures::ready!(delay.poll_unpin(cx));
+        }
+        self.delay.take();
+        Poll::Ready(
+            self.pending
+                .pop_front()
+                .map(|(from, datagram, _)| (from, datagram)),
+        )
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        // Crashing a host unbinds its sockets, and the address may since have been bound again.
+        if self.abort.is_aborted() {
+            return;
+        }
+        if let Ok(mut lock) = self.inner.lock() {
+            lock.unbind_udp(self.local_addr);
+        }
+    }
+}
+
+#[async_trait]
+impl crate::UdpSocket for UdpSocket {
+    async fn send_to(&mut self, buf: &[u8], target: net::SocketAddr) -> io::Result<usize> {
+        self.abort.check()?;
+        let mut lock = self.inner.lock().unwrap();
+        lock.send_datagram(self.local_addr, target, Bytes::from(buf))?;
+        Ok(buf.len())
+    }
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)> {
+        let delivered = future::poll_fn(|cx| {
+            if let Poll::Ready(error) = self.abort.poll_unpin(cx) {
+                return Poll::Ready(Err(error));
+            }
+            self.poll_delivered(cx).map(Ok)
+        });
+        match delivered.await? {
+            Some((from, datagram)) => {
+                // Datagrams which do not fit in the buffer are truncated.
+                let len = std::cmp::min(buf.len(), datagram.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                trace!("received {} byte datagram from {}", datagram.len(), from);
+                Ok((len, from))
+            }
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.local_addr)
+    }
+    fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_udp_broadcast(self.local_addr, broadcast);
+        Ok(())
+    }
+    fn broadcast(&self) -> io::Result<bool> {
+        Ok(self.inner.lock().unwrap().udp_broadcast(self.local_addr))
+    }
+    fn join_multicast_v4(&self, group: net::Ipv4Addr) -> io::Result<()> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.join_multicast(self.local_addr, group.into())
+    }
+    fn leave_multicast_v4(&self, group: net::Ipv4Addr) -> io::Result<()> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.leave_multicast(self.local_addr, group.into())
+    }
+}
@@ -0,0 +1,90 @@
+//! In memory Unix domain sockets.
+//!
+//! Unix domain sockets are local to a host, so each host has its own namespace of socket paths.
+//! Streams are not routed over the simulated network and are unaffected by network faults, but
+//! crashing the host fails pending and subsequent accepts with `ConnectionAborted`.
+use super::{
+    abort::{AbortSignal, Aborted},
+    Inner, SocketHalf,
+};
+use async_trait::async_trait;
+use futures::{channel::mpsc, future, FutureExt, Poll, StreamExt};
+use std::{fmt, io, net, path, sync};
+use tracing::trace;
+
+pub type UnixStream = SocketHalf;
+
+pub struct UnixListener {
+    host: net::IpAddr,
+    path: path::PathBuf,
+    incoming: mpsc::UnboundedReceiver<UnixStream>,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    /// Signal fired when the host of this listener crashes.
+    abort: Aborted,
+}
+
+impl fmt::Debug for UnixListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "UnixListener {{ host: {}, path: {} }}",
+            self.host,
+            self.path.display()
+        )
+    }
+}
+
+impl UnixListener {
+    pub(crate) fn new(
+        host: net::IpAddr,
+        path: path::PathBuf,
+        incoming: mpsc::UnboundedReceiver<UnixStream>,
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        abort: AbortSignal,
+    ) -> Self {
+        Self {
+            host,
+            path,
+            incoming,
+            inner,
+            abort: abort.aborted(),
+        }
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        // Crashing a host unbinds its listeners, and the path may since have been bound again.
+        if self.abort.is_aborted() {
+            return;
+        }
+        if let Ok(mut lock) = self.inner.lock() {
+            lock.unbind_unix(self.host, &self.path);
+        }
+    }
+}
+
+impl crate::UnixStream for SocketHalf {}
+
+#[async_trait]
+impl crate::UnixListener for UnixListener {
+    type Stream = UnixStream;
+    async fn accept(&mut self) -> io::Result<Self::Stream> {
+        let incoming = future::poll_fn(|cx| {
+            if let Poll::Ready(error) = self.abort.poll_unpin(cx) {
+                return Poll::Ready(Err(error));
+            }
+            self.incoming.poll_next_unpin(cx).map(Ok)
+        });
+        match incoming.await? {
+            Some(stream) => {
+                trace!("accepted unix connection on {}", self.path.display());
+                Ok(stream)
+            }
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+    fn local_path(&self) -> io::Result<path::PathBuf> {
+        Ok(self.path.clone())
+    }
+}
@@ -0,0 +1,147 @@
+//! Named phases of a simulation run.
+//!
+//! Simulations commonly proceed through distinct phases: setting up a cluster, injecting faults,
+//! allowing the cluster to recover, and verifying its state. A [`PhaseSchedule`] runs each phase
+//! for its configured simulated duration, handing its fault intensity to a callback which adjusts
+//! fault injectors and workloads accordingly. Phase boundaries are emitted to the trace, and the
+//! current phase can be shared with an [`InvariantChecker`] so that invariants which only hold
+//! outside of fault injection are not checked while faults are being injected.
+//!
+//! [`InvariantChecker`]:`super::InvariantChecker`
+use crate::Environment;
+use std::{sync, time};
+use tracing::trace;
+
+/// A single phase of a [`PhaseSchedule`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    pub name: String,
+    /// Simulated time spent in this phase.
+    pub duration: time::Duration,
+    /// Intensity of faults during this phase, where 0.0 disables faults and 1.0 is the
+    /// configured full intensity.
+    pub fault_intensity: f64,
+}
+
+impl Phase {
+    /// Scale a fault probability by the fault intensity of this phase.
+    pub fn scale(&self, probability: f64) -> f64 {
+        (probability * self.fault_intensity).max(0.0).min(1.0)
+    }
+}
+
+/// The phase a [`PhaseSchedule`] is currently running, shared with observers.
+#[derive(Debug, Clone, Default)]
+pub struct CurrentPhase {
+    inner: sync::Arc<sync::Mutex<Option<String>>>,
+}
+
+impl CurrentPhase {
+    /// Returns the name of the current phase, or `None` if the schedule has not started or has
+    /// completed.
+    pub fn name(&self) -> Option<String> {
+        self.inner.lock().unwrap().clone()
+    }
+
+    /// Returns true if the current phase is named `name`.
+    pub fn is(&self, name: &str) -> bool {
+        self.inner.lock().unwrap().as_ref().map(String::as_str) == Some(name)
+    }
+
+    fn set(&self, name: Option<String>) {
+        *self.inner.lock().unwrap() = name;
+    }
+}
+
+/// A sequence of phases run one after another.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseSchedule {
+    phases: Vec<Phase>,
+    current: CurrentPhase,
+}
+
+impl PhaseSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a phase named `name`, lasting `duration` with the provided fault intensity.
+    pub fn phase(mut self, name: &str, duration: time::Duration, fault_intensity: f64) -> Self {
+        self.phases.push(Phase {
+            name: name.to_string(),
+            duration,
+            fault_intensity,
+        });
+        self
+    }
+
+    pub fn phases(&self) -> &[Phase] {
+        &self.phases
+    }
+
+    /// Returns a handle to the current phase, which is updated as the schedule runs.
+    pub fn current(&self) -> CurrentPhase {
+        self.current.clone()
+    }
+
+    /// Consumes this schedule and runs each phase in order. `enter` is called at the start of
+    /// each phase, and should configure faults and workloads for the phase. Returns once the
+    /// final phase has elapsed.
+    pub async fn run<E, F>(self, env: E, mut enter: F)
+    where
+        E: Environment,
+        F: FnMut(&Phase),
+    {
+        for phase in self.phases.iter() {
+            trace!(
+                "entering phase {} for {:?} at fault intensity {}",
+                phase.name,
+                phase.duration,
+                phase.fault_intensity
+            );
+            self.current.set(Some(phase.name.clone()));
+            enter(phase);
+            env.delay_from(phase.duration).await;
+            trace!("completed phase {}", phase.name);
+        }
+        self.current.set(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that phases are entered in order, each for its configured duration.
+    fn phase_boundaries() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let schedule = PhaseSchedule::new()
+            .phase("setup", time::Duration::from_secs(1), 0.0)
+            .phase("chaos", time::Duration::from_secs(10), 1.0)
+            .phase("recovery", time::Duration::from_secs(5), 0.0);
+        let current = schedule.current();
+        runtime.block_on(async {
+            let start = handle.now();
+            let mut entered = vec![];
+            assert_eq!(current.name(), None);
+            schedule
+                .run(handle.clone(), |phase| {
+                    assert!(current.is(&phase.name));
+                    entered.push((handle.now() - start, phase.scale(0.5)));
+                })
+                .await;
+            assert_eq!(current.name(), None);
+            assert_eq!(
+                entered,
+                vec![
+                    (time::Duration::from_secs(0), 0.0),
+                    (time::Duration::from_secs(1), 0.5),
+                    (time::Duration::from_secs(11), 0.0),
+                ]
+            );
+        });
+    }
+}
@@ -0,0 +1,101 @@
+//! Wall-clock accounting of time spent polling spawned tasks.
+//!
+//! Simulated time says nothing about how long a simulation takes to execute. When profiling is
+//! enabled, every task spawned through a `DeterministicRuntimeHandle` records the real time spent
+//! polling it, aggregated by the type of the spawned future.
+use futures::{Future, Poll};
+use std::{collections, pin::Pin, sync, task::Context, time};
+
+/// Wall-clock time spent polling tasks spawned from a particular future type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskPollProfile {
+    /// Type name of the spawned future.
+    pub name: &'static str,
+    /// Number of tasks spawned from this future type.
+    pub tasks: u64,
+    /// Number of times tasks of this type were polled.
+    pub polls: u64,
+    /// Total wall-clock time spent polling tasks of this type.
+    pub total: time::Duration,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    enabled: bool,
+    tasks: collections::HashMap<&'static str, TaskPollProfile>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PollProfiler {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl PollProfiler {
+    pub(crate) fn enable(&self) {
+        self.inner.lock().unwrap().enabled = true;
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.inner.lock().unwrap().enabled
+    }
+
+    /// Wrap `future`, recording the time spent polling it under `name`.
+    pub(crate) fn wrap<F>(&self, name: &'static str, future: F) -> Profiled<F>
+    where
+        F: Future<Output = ()>,
+    {
+        self.inner
+            .lock()
+            .unwrap()
+            .tasks
+            .entry(name)
+            .or_insert_with(|| TaskPollProfile {
+                name,
+                tasks: 0,
+                polls: 0,
+                total: time::Duration::from_secs(0),
+            })
+            .tasks += 1;
+        Profiled {
+            name,
+            profiler: self.clone(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn record(&self, name: &'static str, elapsed: time::Duration) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(profile) = lock.tasks.get_mut(name) {
+            profile.polls += 1;
+            profile.total += elapsed;
+        }
+    }
+
+    /// Returns the recorded profiles, ordered from the most to the least time spent polling.
+    pub(crate) fn report(&self) -> Vec<TaskPollProfile> {
+        let lock = self.inner.lock().unwrap();
+        let mut report: Vec<TaskPollProfile> = lock.tasks.values().cloned().collect();
+        report.sort_by(|a, b| b.total.cmp(&a.total).then(a.name.cmp(b.name)));
+        report
+    }
+}
+
+pub(crate) struct Profiled<F> {
+    name: &'static str,
+    profiler: PollProfiler,
+    future: Pin<Box<F>>,
+}
+
+impl<F> Future for Profiled<F>
+where
+    F: Future<Output = ()>,
+{
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Wall-clock time is used deliberately, mock time does not advance while polling.
+        let start = time::Instant::now();
+        let result = self.future.as_mut().poll(cx);
+        self.profiler.record(self.name, start.elapsed());
+        result
+    }
+}
@@ -1,18 +1,48 @@
+use super::decision::{DecisionLog, Recordable};
 use rand::{distributions::uniform::SampleUniform, rngs, Rng};
 
-use rand_distr::{Distribution, Normal};
+use rand_distr::{Distribution, Exp, Normal, Pareto, Poisson};
 use std::{ops, sync};
 
+#[derive(Debug)]
+enum Mode {
+    Live,
+    Recording(DecisionLog),
+    Replaying(DecisionLog),
+}
+
 #[derive(Debug)]
 /// DeterministicRandom provides a deterministic RNG.
 struct Inner {
     rng: rngs::SmallRng,
+    mode: Mode,
 }
 
 impl Inner {
     fn new_with_seed(seed: u64) -> Self {
         let rng = rand::SeedableRng::seed_from_u64(seed);
-        Self { rng }
+        Self {
+            rng,
+            mode: Mode::Live,
+        }
+    }
+
+    /// Make a decision using `f`, recording it if recording is enabled. When replaying, the
+    /// decision is taken from the log instead.
+    fn decide<T, F>(&mut self, f: F) -> T
+    where
+        T: Recordable,
+        F: FnOnce(&mut rngs::SmallRng) -> T,
+    {
+        match self.mode {
+            Mode::Live => f(&mut self.rng),
+            Mode::Recording(ref mut log) => {
+                let value = f(&mut self.rng);
+                log.push(value);
+                value
+            }
+            Mode::Replaying(ref mut log) => log.pop(),
+        }
     }
 }
 
@@ -30,12 +60,64 @@ impl DeterministicRandom {
         let inner = sync::Arc::new(sync::Mutex::new(inner));
         Self { inner }
     }
+    /// Create a DeterministicRandom which replays the decisions in `log` rather than generating
+    /// them.
+    pub(crate) fn new_with_log(log: DecisionLog) -> Self {
+        let mut inner = Inner::new_with_seed(0);
+        inner.mode = Mode::Replaying(log);
+        let inner = sync::Arc::new(sync::Mutex::new(inner));
+        Self { inner }
+    }
+    /// Begin recording decisions, discarding any previously recorded decisions.
+    pub(crate) fn record(&self) {
+        self.inner.lock().unwrap().mode = Mode::Recording(DecisionLog::new());
+    }
+    /// Returns the decisions recorded so far, or the remaining decisions if replaying.
+    pub(crate) fn log(&self) -> Option<DecisionLog> {
+        match self.inner.lock().unwrap().mode {
+            Mode::Live => None,
+            Mode::Recording(ref log) | Mode::Replaying(ref log) => Some(log.clone()),
+        }
+    }
     pub fn handle(&self) -> DeterministicRandomHandle {
         let inner = sync::Arc::clone(&self.inner);
         DeterministicRandomHandle { inner }
     }
 }
 
+/// Cumulative distribution of a Zipf distribution over ranks `1..=n`, computed once so that
+/// repeated samples do not each sum `n` terms.
+#[derive(Debug, Clone)]
+pub(crate) struct Zipf {
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    pub(crate) fn new(n: u64, s: f64) -> Self {
+        assert!(n > 0, "illegal zipf params, n: {}, exponent: {}", n, s);
+        let mut cumulative = 0.0;
+        let mut cdf: Vec<f64> = (1..=n)
+            .map(|k| {
+                cumulative += (k as f64).powf(-s);
+                cumulative
+            })
+            .collect();
+        for p in cdf.iter_mut() {
+            *p /= cumulative;
+        }
+        Self { cdf }
+    }
+
+    /// Returns the rank whose cumulative probability first reaches `u`, a uniform sample from
+    /// `0.0..1.0`.
+    pub(crate) fn rank(&self, u: f64) -> u64 {
+        let index = match self.cdf.binary_search_by(|p| p.partial_cmp(&u).unwrap()) {
+            Ok(index) | Err(index) => index,
+        };
+        std::cmp::min(index, self.cdf.len() - 1) as u64 + 1
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DeterministicRandomHandle {
     inner: sync::Arc<sync::Mutex<Inner>>,
@@ -47,19 +129,125 @@ impl DeterministicRandomHandle {
             panic!("illegal normal params, mean: {}, deviation: {}", mean, dev)
         });
         let mut lock = self.inner.lock().unwrap();
-        normal.sample(&mut lock.rng)
+        lock.decide(|rng| normal.sample(rng))
+    }
+
+    /// Sample from an exponential distribution with the provided rate. Useful for generating
+    /// inter-arrival times of a Poisson process.
+    pub fn exponential(&self, rate: f64) -> f64 {
+        let exp =
+            Exp::new(rate).unwrap_or_else(|_| panic!("illegal exponential params, rate: {}", rate));
+        let mut lock = self.inner.lock().unwrap();
+        lock.decide(|rng| exp.sample(rng))
+    }
+
+    /// Sample from a Pareto distribution with the provided scale and shape. Samples are at
+    /// least `scale`, with smaller shapes producing longer tails.
+    pub fn pareto(&self, scale: f64, shape: f64) -> f64 {
+        let pareto = Pareto::new(scale, shape).unwrap_or_else(|_| {
+            panic!("illegal pareto params, scale: {}, shape: {}", scale, shape)
+        });
+        let mut lock = self.inner.lock().unwrap();
+        lock.decide(|rng| pareto.sample(rng))
+    }
+
+    /// Sample the number of events occurring in an interval from a Poisson distribution
+    /// with mean `lambda`.
+    pub fn poisson(&self, lambda: f64) -> u64 {
+        let poisson = Poisson::new(lambda)
+            .unwrap_or_else(|_| panic!("illegal poisson params, lambda: {}", lambda));
+        let mut lock = self.inner.lock().unwrap();
+        lock.decide(|rng| {
+            let sample: f64 = poisson.sample(rng);
+            sample as u64
+        })
+    }
+
+    /// Sample a rank in `1..=n` from a Zipf distribution with exponent `s`. Rank 1 is the
+    /// most popular.
+    pub fn zipf(&self, n: u64, s: f64) -> u64 {
+        Zipf::new(n, s).rank(self.gen_range(0.0..1.0))
     }
 
     pub fn should_fault(&self, probability: f64) -> bool {
         let mut lock = self.inner.lock().unwrap();
-        lock.rng.gen_bool(probability)
+        lock.decide(|rng| rng.gen_bool(probability))
     }
 
     pub fn gen_range<T>(&self, range: ops::Range<T>) -> T
     where
-        T: SampleUniform,
+        T: SampleUniform + Recordable,
     {
         let mut lock = self.inner.lock().unwrap();
-        lock.rng.gen_range(range.start, range.end)
+        lock.decide(|rng| rng.gen_range(range.start, range.end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that samples are reproducible for a given seed.
+    fn deterministic_samples() {
+        let sample = |seed| {
+            let handle = DeterministicRandom::new_with_seed(seed).handle();
+            (
+                handle.exponential(2.0),
+                handle.poisson(4.0),
+                handle.zipf(100, 1.2),
+            )
+        };
+        assert_eq!(sample(7), sample(7));
+    }
+
+    #[test]
+    /// Test that a recorded decision log replays the same samples.
+    fn replay_log() {
+        let sample = |handle: DeterministicRandomHandle| {
+            (0..100)
+                .map(|_| {
+                    (
+                        handle.exponential(2.0),
+                        handle.should_fault(0.1),
+                        handle.zipf(10, 1.1),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let random = DeterministicRandom::new_with_seed(5);
+        random.record();
+        let recorded = sample(random.handle());
+        let encoded = random.log().unwrap().encode();
+
+        let log = DecisionLog::decode(&encoded).unwrap();
+        let replayed = sample(DeterministicRandom::new_with_log(log).handle());
+        assert_eq!(recorded, replayed);
+    }
+
+    #[test]
+    /// Test that ranks are taken from the cumulative distribution, including at its bounds.
+    fn zipf_ranks() {
+        let zipf = Zipf::new(3, 1.0);
+        // probabilities are 6/11, 3/11 and 2/11.
+        assert_eq!(zipf.rank(0.0), 1);
+        assert_eq!(zipf.rank(0.5), 1);
+        assert_eq!(zipf.rank(0.6), 2);
+        assert_eq!(zipf.rank(0.9), 3);
+        assert_eq!(zipf.rank(1.0), 3);
+    }
+
+    #[test]
+    /// Test that zipf samples are within bounds and skewed towards low ranks.
+    fn zipf_skew() {
+        let handle = DeterministicRandom::new_with_seed(1).handle();
+        let samples: Vec<u64> = (0..1000).map(|_| handle.zipf(10, 1.5)).collect();
+        assert!(samples.iter().all(|rank| *rank >= 1 && *rank <= 10));
+        let first = samples.iter().filter(|rank| **rank == 1).count();
+        let last = samples.iter().filter(|rank| **rank == 10).count();
+        assert!(
+            first > last,
+            "expected rank 1 to be more popular than rank 10"
+        );
     }
 }
@@ -0,0 +1,184 @@
+//! Experimental coverage guided search over simulation parameters.
+//!
+//! Sampling seeds uniformly spends most runs re-exploring behavior which earlier runs already
+//! covered. A [`CoverageSearch`] instead keeps a corpus of parameters, such as fault schedule
+//! settings, which produced coverage no earlier run had. Each run mutates a parameter set drawn
+//! from the corpus, so exploration concentrates around configurations which reached new
+//! behavior, in the style of a coverage guided fuzzer.
+//!
+//! Simulations record coverage by calling [`Coverage::hit`] with a name for each interesting
+//! point reached, such as a fault firing or an event being handled in a particular state.
+use super::DeterministicRuntime;
+use rand::{rngs, Rng, SeedableRng};
+use std::{collections, sync};
+
+/// Coverage points reached during a single simulation run.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    inner: sync::Arc<sync::Mutex<collections::BTreeSet<String>>>,
+}
+
+impl Coverage {
+    /// Record that the point `name` was reached.
+    pub fn hit(&self, name: &str) {
+        let mut lock = self.inner.lock().unwrap();
+        if !lock.contains(name) {
+            lock.insert(name.to_string());
+        }
+    }
+
+    fn take(&self) -> collections::BTreeSet<String> {
+        std::mem::replace(&mut *self.inner.lock().unwrap(), Default::default())
+    }
+}
+
+/// A single run of a [`CoverageSearch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchRun<P> {
+    /// Seed of the runtime used for this run.
+    pub seed: u64,
+    pub params: P,
+    /// Coverage points first reached by this run.
+    pub new_coverage: Vec<String>,
+}
+
+/// The result of a [`CoverageSearch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchReport<P> {
+    runs: Vec<SearchRun<P>>,
+    coverage: collections::BTreeSet<String>,
+}
+
+impl<P> SearchReport<P> {
+    /// Returns every run, in the order they were executed.
+    pub fn runs(&self) -> &[SearchRun<P>] {
+        &self.runs
+    }
+
+    /// Returns the runs which reached new coverage, and so were added to the corpus.
+    pub fn corpus(&self) -> impl Iterator<Item = &SearchRun<P>> {
+        self.runs.iter().filter(|run| !run.new_coverage.is_empty())
+    }
+
+    /// Returns every coverage point reached across all runs.
+    pub fn coverage(&self) -> &collections::BTreeSet<String> {
+        &self.coverage
+    }
+}
+
+/// Searches for simulation parameters which reach new coverage.
+#[derive(Debug, Clone)]
+pub struct CoverageSearch<P> {
+    seed: u64,
+    runs: usize,
+    initial: P,
+}
+
+impl<P> CoverageSearch<P>
+where
+    P: Clone,
+{
+    /// Create a search starting from the `initial` parameters. The search itself is
+    /// deterministic given `seed`.
+    pub fn new(seed: u64, initial: P) -> Self {
+        Self {
+            seed,
+            runs: 100,
+            initial,
+        }
+    }
+
+    /// Number of simulation runs to perform. Defaults to 100.
+    pub fn runs(mut self, runs: usize) -> Self {
+        self.runs = runs;
+        self
+    }
+
+    /// Run the search. The first run uses the initial parameters. Each later run draws
+    /// parameters uniformly from the corpus, applies `mutate`, and runs `simulation` on a fresh
+    /// runtime with a new seed. Parameters which reach coverage not reached by any earlier run
+    /// are added to the corpus.
+    pub fn run<M, F>(&self, mut mutate: M, mut simulation: F) -> SearchReport<P>
+    where
+        M: FnMut(&P, &mut rngs::SmallRng) -> P,
+        F: FnMut(&mut DeterministicRuntime, &P, &Coverage),
+    {
+        let mut rng = rngs::SmallRng::seed_from_u64(self.seed);
+        let mut corpus = vec![self.initial.clone()];
+        let mut report = SearchReport {
+            runs: vec![],
+            coverage: collections::BTreeSet::new(),
+        };
+        let coverage = Coverage::default();
+        for i in 0..self.runs {
+            let params = if i == 0 {
+                self.initial.clone()
+            } else {
+                let parent = &corpus[rng.gen_range(0, corpus.len())];
+                mutate(parent, &mut rng)
+            };
+            let seed = rng.gen();
+            let mut runtime = DeterministicRuntime::new_with_seed(seed)
+                .expect("failed to build deterministic runtime");
+            simulation(&mut runtime, &params, &coverage);
+            let new_coverage: Vec<_> = coverage
+                .take()
+                .into_iter()
+                .filter(|point| report.coverage.insert(point.clone()))
+                .collect();
+            if i > 0 && !new_coverage.is_empty() {
+                corpus.push(params.clone());
+            }
+            report.runs.push(SearchRun {
+                seed,
+                params,
+                new_coverage,
+            });
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Environment;
+    use std::time::Duration;
+
+    /// Reaches a coverage point for each retry needed before a request succeeds, with each
+    /// attempt failing with the percentage chance given by the parameters.
+    fn retries(runtime: &mut DeterministicRuntime, failure: &u32, coverage: &Coverage) {
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            for attempt in 0..10 {
+                coverage.hit(&format!("attempt-{}", attempt));
+                if !handle.gen_bool(f64::from(*failure) / 100.0) {
+                    return;
+                }
+                handle.delay_from(Duration::from_millis(100)).await;
+            }
+        });
+    }
+
+    fn mutate(failure: &u32, rng: &mut rngs::SmallRng) -> u32 {
+        let delta: i64 = rng.gen_range(-20, 21);
+        (i64::from(*failure) + delta).max(0).min(100) as u32
+    }
+
+    #[test]
+    /// Test that the corpus contains exactly the runs which reached new coverage.
+    fn corpus_tracks_new_coverage() {
+        let search = CoverageSearch::new(7, 0).runs(50);
+        let report = search.run(mutate, retries);
+        assert_eq!(report.runs().len(), 50);
+        assert_eq!(report.runs()[0].params, 0);
+        assert_eq!(report.runs()[0].new_coverage, vec!["attempt-0".to_string()]);
+        let discovered: usize = report.corpus().map(|run| run.new_coverage.len()).sum();
+        assert_eq!(discovered, report.coverage().len());
+        assert_eq!(
+            report,
+            search.run(mutate, retries),
+            "expected search to be deterministic"
+        );
+    }
+}
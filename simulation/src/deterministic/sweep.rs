@@ -0,0 +1,603 @@
+//! Statistical assertions over simulations run across many seeds.
+//!
+//! Safety properties hold for every seed, but performance properties such as "a leader is
+//! elected within 10 simulated seconds" are only expected to hold for most runs. A [`Sweep`] runs
+//! a simulation once per seed, during which the simulation records numeric observables into
+//! [`Observations`]. The resulting [`SweepReport`] aggregates the observables across runs and
+//! supports threshold assertions, such as requiring that 99% of runs satisfy a predicate.
+//!
+//! Reports also summarize the distribution of each observable and of the simulated time each run
+//! took, so that protocol behavior such as fault or retransmission counts can be tracked from
+//! sweep to sweep rather than only whether it passed.
+//!
+//! A run which panics does not end the sweep. Its panic is recorded as a [`Failure`], and
+//! failures are grouped into classes by a fingerprint of the panic location and message, so that
+//! hundreds of failing seeds caused by a couple of bugs are reported as a couple of classes.
+use super::DeterministicRuntime;
+use std::{
+    cell::{Cell, RefCell},
+    collections, fmt, ops, panic, sync, time,
+};
+
+/// Numeric observables recorded during a single simulation run.
+///
+/// Observations can be cloned into spawned tasks, allowing observables to be sampled as the
+/// simulation progresses. Each observable keeps every recorded value in order.
+#[derive(Debug, Clone, Default)]
+pub struct Observations {
+    inner: sync::Arc<sync::Mutex<collections::BTreeMap<String, Vec<f64>>>>,
+}
+
+impl Observations {
+    /// Record a value of the observable `name`.
+    pub fn record(&self, name: &str, value: f64) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.entry(name.to_string()).or_default().push(value);
+    }
+
+    fn take(
+        &self,
+        seed: u64,
+        elapsed: time::Duration,
+        failure: Option<Failure>,
+    ) -> RunObservations {
+        let mut lock = self
+            .inner
+            .lock()
+            .unwrap_or_else(sync::PoisonError::into_inner);
+        let values = std::mem::replace(&mut *lock, Default::default());
+        RunObservations {
+            seed,
+            elapsed,
+            values,
+            failure,
+        }
+    }
+}
+
+/// The observables recorded by the run of a single seed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunObservations {
+    seed: u64,
+    elapsed: time::Duration,
+    values: collections::BTreeMap<String, Vec<f64>>,
+    failure: Option<Failure>,
+}
+
+impl RunObservations {
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns the simulated time the run took to complete.
+    pub fn elapsed(&self) -> time::Duration {
+        self.elapsed
+    }
+
+    /// Returns the panic which ended the run, if it failed.
+    pub fn failure(&self) -> Option<&Failure> {
+        self.failure.as_ref()
+    }
+
+    /// Returns every value recorded for `name`, in the order they were recorded.
+    pub fn values(&self, name: &str) -> &[f64] {
+        self.values
+            .get(name)
+            .map_or(&[][..], |values| values.as_slice())
+    }
+
+    pub fn first(&self, name: &str) -> Option<f64> {
+        self.values(name).first().cloned()
+    }
+
+    pub fn last(&self, name: &str) -> Option<f64> {
+        self.values(name).last().cloned()
+    }
+
+    pub fn max(&self, name: &str) -> Option<f64> {
+        self.values(name)
+            .iter()
+            .cloned()
+            .fold(None, |max, value| match max {
+                Some(max) if max >= value => Some(max),
+                _ => Some(value),
+            })
+    }
+}
+
+/// Runs a simulation across a range of seeds.
+#[derive(Debug, Clone)]
+pub struct Sweep {
+    seeds: ops::Range<u64>,
+}
+
+impl Sweep {
+    pub fn new(seeds: ops::Range<u64>) -> Self {
+        Self { seeds }
+    }
+
+    /// Run `simulation` once for each seed with a fresh runtime, collecting the observables
+    /// recorded by each run. A run which panics is recorded as failed, along with any
+    /// observables it recorded before panicking, and the sweep continues with the next seed.
+    pub fn run<F>(&self, mut simulation: F) -> SweepReport
+    where
+        F: FnMut(&mut DeterministicRuntime, &Observations),
+    {
+        let observations = Observations::default();
+        let runs = self
+            .seeds
+            .clone()
+            .map(|seed| {
+                let mut runtime = DeterministicRuntime::new_with_seed(seed)
+                    .expect("failed to build deterministic runtime");
+                let start = runtime.localhost_handle().now();
+                let result = {
+                    let _capture = PanicCapture::enter();
+                    panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                        simulation(&mut runtime, &observations)
+                    }))
+                };
+                let failure = result.err().map(|payload| {
+                    let location = PANIC_LOCATION.with(|location| location.borrow_mut().take());
+                    Failure::new(panic_message(&*payload), location)
+                });
+                let elapsed = runtime.localhost_handle().now() - start;
+                observations.take(seed, elapsed, failure)
+            })
+            .collect();
+        SweepReport { runs }
+    }
+}
+
+thread_local! {
+    /// Set while a sweep runs a simulation on the current thread, and holds the location of the
+    /// last panic raised by it.
+    static CAPTURING: Cell<bool> = Cell::new(false);
+    static PANIC_LOCATION: RefCell<Option<String>> = RefCell::new(None);
+}
+
+static INSTALL_HOOK: sync::Once = sync::Once::new();
+
+/// While held, panics raised on the current thread record their location rather than being
+/// printed, so that a sweep with many failing seeds does not print each of their panics.
+struct PanicCapture {
+    previous: bool,
+}
+
+impl PanicCapture {
+    fn enter() -> Self {
+        INSTALL_HOOK.call_once(|| {
+            let hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if CAPTURING.with(|capturing| capturing.get()) {
+                    let location = info
+                        .location()
+                        .map(|location| format!("{}:{}", location.file(), location.line()));
+                    PANIC_LOCATION.with(|captured| *captured.borrow_mut() = location);
+                } else {
+                    hook(info)
+                }
+            }));
+        });
+        PANIC_LOCATION.with(|location| *location.borrow_mut() = None);
+        let previous = CAPTURING.with(|capturing| capturing.replace(true));
+        PanicCapture { previous }
+    }
+}
+
+impl Drop for PanicCapture {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        CAPTURING.with(|capturing| capturing.set(previous));
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("Box<Any>")
+    }
+}
+
+/// A panic which ended the run of a seed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    message: String,
+    location: Option<String>,
+    fingerprint: String,
+}
+
+impl Failure {
+    fn new(message: String, location: Option<String>) -> Self {
+        // numbers such as times, terms and addresses vary from seed to seed, so they are
+        // replaced to group failures caused by the same bug.
+        let mut normalized = String::new();
+        for c in message.lines().next().unwrap_or_default().chars() {
+            if !c.is_ascii_digit() {
+                normalized.push(c);
+            } else if !normalized.ends_with('#') {
+                normalized.push('#');
+            }
+        }
+        let fingerprint = match location.as_ref() {
+            Some(location) => format!("{}: {}", location, normalized),
+            None => normalized,
+        };
+        Self {
+            message,
+            location,
+            fingerprint,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the source location of the panic, as `file:line`.
+    pub fn location(&self) -> Option<&str> {
+        self.location.as_ref().map(String::as_str)
+    }
+
+    /// Returns the location and first line of the message with numbers elided, which is shared
+    /// by failures caused by the same bug.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+/// Failed runs of a sweep which share a fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailureClass {
+    /// Failure of the lowest seed in the class, which is representative of the others.
+    pub representative: Failure,
+    /// Seeds which failed with this fingerprint, in seed order.
+    pub seeds: Vec<u64>,
+}
+
+/// Observables recorded across every run of a [`Sweep`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepReport {
+    runs: Vec<RunObservations>,
+}
+
+impl SweepReport {
+    /// Returns the observables of each run, in seed order.
+    pub fn runs(&self) -> &[RunObservations] {
+        &self.runs
+    }
+
+    /// Groups the failed runs by the fingerprint of their failure, in order of each class's
+    /// lowest seed.
+    pub fn failure_classes(&self) -> Vec<FailureClass> {
+        let mut classes: Vec<FailureClass> = vec![];
+        for run in self.runs.iter() {
+            let failure = match run.failure.as_ref() {
+                Some(failure) => failure,
+                None => continue,
+            };
+            match classes
+                .iter_mut()
+                .find(|class| class.representative.fingerprint == failure.fingerprint)
+            {
+                Some(class) => class.seeds.push(run.seed),
+                None => classes.push(FailureClass {
+                    representative: failure.clone(),
+                    seeds: vec![run.seed],
+                }),
+            }
+        }
+        classes
+    }
+
+    /// Panic if any run failed, reporting one representative seed for each class of failure.
+    pub fn assert_no_failures(&self) {
+        let classes = self.failure_classes();
+        if classes.is_empty() {
+            return;
+        }
+        let failed: usize = classes.iter().map(|class| class.seeds.len()).sum();
+        let mut report = format!(
+            "{} of {} runs failed, in {} classes",
+            failed,
+            self.runs.len(),
+            classes.len()
+        );
+        for class in classes.iter() {
+            report.push_str(&format!(
+                "\n{}\n  e.g. seed {}: {}\n  failed seeds: {}",
+                class.representative.fingerprint,
+                class.seeds[0],
+                class.representative.message,
+                Seeds(&class.seeds)
+            ));
+        }
+        panic!("{}", report);
+    }
+
+    /// Returns the fraction of runs which satisfy `predicate`.
+    pub fn fraction<P>(&self, predicate: P) -> f64
+    where
+        P: Fn(&RunObservations) -> bool,
+    {
+        if self.runs.is_empty() {
+            return 0.0;
+        }
+        let satisfied = self.runs.iter().filter(|run| predicate(run)).count();
+        satisfied as f64 / self.runs.len() as f64
+    }
+
+    /// Returns the `percentile` of the last value of `name` across runs which recorded it, using
+    /// the nearest rank method. `percentile` must be in `0.0..=100.0`.
+    pub fn percentile(&self, name: &str, percentile: f64) -> Option<f64> {
+        assert!(
+            percentile >= 0.0 && percentile <= 100.0,
+            "illegal percentile: {}",
+            percentile
+        );
+        let mut values: Vec<f64> = self.runs.iter().filter_map(|run| run.last(name)).collect();
+        if values.is_empty() {
+            return None;
+        }
+        sort(&mut values);
+        Some(nearest_rank(&values, percentile))
+    }
+
+    /// Summarizes the distribution of every value of `name` recorded across runs, or `None` if
+    /// no run recorded it. Observables sampled several times per run, such as the latency of
+    /// each invariant check, contribute every sample.
+    pub fn summary(&self, name: &str) -> Option<Summary> {
+        Summary::new(
+            self.runs
+                .iter()
+                .flat_map(|run| run.values(name).iter().cloned())
+                .collect(),
+        )
+    }
+
+    /// Summarizes every observable recorded across runs, keyed by name.
+    pub fn summaries(&self) -> collections::BTreeMap<String, Summary> {
+        let names: collections::BTreeSet<&String> =
+            self.runs.iter().flat_map(|run| run.values.keys()).collect();
+        names
+            .into_iter()
+            .filter_map(|name| Some((name.clone(), self.summary(name)?)))
+            .collect()
+    }
+
+    /// Summarizes the simulated time taken by each run, in seconds.
+    pub fn elapsed_summary(&self) -> Option<Summary> {
+        Summary::new(
+            self.runs
+                .iter()
+                .map(|run| run.elapsed.as_secs_f64())
+                .collect(),
+        )
+    }
+
+    /// Panic unless at least `threshold` of runs satisfy `predicate`, listing the seeds of runs
+    /// which did not.
+    pub fn assert_fraction<P>(&self, property: &str, threshold: f64, predicate: P)
+    where
+        P: Fn(&RunObservations) -> bool,
+    {
+        let fraction = self.fraction(&predicate);
+        if fraction < threshold {
+            let failed: Vec<_> = self
+                .runs
+                .iter()
+                .filter(|run| !predicate(run))
+                .map(|run| run.seed)
+                .collect();
+            panic!(
+                "{}: held for {:.2}% of runs, expected at least {:.2}%, failed seeds: {}",
+                property,
+                fraction * 100.0,
+                threshold * 100.0,
+                Seeds(&failed)
+            );
+        }
+    }
+}
+
+/// Summary statistics of the values of an observable across the runs of a sweep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    fn new(mut values: Vec<f64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        sort(&mut values);
+        Some(Self {
+            count: values.len(),
+            min: values[0],
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p50: nearest_rank(&values, 50.0),
+            p90: nearest_rank(&values, 90.0),
+            p99: nearest_rank(&values, 99.0),
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} min={} mean={} p50={} p90={} p99={} max={}",
+            self.count, self.min, self.mean, self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+fn sort(values: &mut [f64]) {
+    values.sort_by(|a, b| a.partial_cmp(b).expect("observable is NaN"));
+}
+
+/// Returns the `percentile` of sorted, non-empty `values` using the nearest rank method.
+fn nearest_rank(values: &[f64], percentile: f64) -> f64 {
+    let rank = (percentile / 100.0 * values.len() as f64).ceil() as usize;
+    values[rank.max(1) - 1]
+}
+
+struct Seeds<'a>(&'a [u64]);
+
+impl fmt::Display for Seeds<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, seed) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", seed)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Environment;
+    use std::time::Duration;
+
+    /// Simulates an election which completes after a random timeout, recording the time taken.
+    fn election(runtime: &mut DeterministicRuntime, observations: &Observations) {
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let start = handle.now();
+            let timeout = handle.gen_range(Duration::from_millis(150)..Duration::from_secs(20));
+            handle.delay_from(timeout).await;
+            let elapsed = handle.now() - start;
+            observations.record("election_time", elapsed.as_secs_f64());
+        });
+    }
+
+    #[test]
+    /// Test that observables are aggregated across seeds and thresholds are enforced.
+    fn election_sweep() {
+        let report = Sweep::new(0..50).run(election);
+        assert_eq!(report.runs().len(), 50);
+        assert_eq!(report.runs()[3].seed(), 3);
+        assert!(report.fraction(|run| run.first("election_time").is_some()) == 1.0);
+        let median = report.percentile("election_time", 50.0).unwrap();
+        let p99 = report.percentile("election_time", 99.0).unwrap();
+        assert!(median <= p99 && p99 < 20.0);
+        report.assert_fraction("elect a leader within 20s", 1.0, |run| {
+            run.max("election_time").map_or(false, |time| time < 20.0)
+        });
+        assert_eq!(
+            report,
+            Sweep::new(0..50).run(election),
+            "expected sweep to be deterministic"
+        );
+    }
+
+    #[test]
+    /// Test that observables and run durations are summarized across seeds.
+    fn summaries() {
+        let report = Sweep::new(0..10).run(|runtime, observations| {
+            let handle = runtime.localhost_handle();
+            let observations = observations.clone();
+            runtime.block_on(async move {
+                for retransmit in 1..=4 {
+                    observations.record("retransmit_delay", f64::from(retransmit));
+                }
+                handle.delay_from(Duration::from_secs(3)).await;
+                observations.record("faults", 2.0);
+            });
+        });
+        let faults = report.summary("faults").unwrap();
+        assert_eq!((faults.count, faults.min, faults.max), (10, 2.0, 2.0));
+        let delays = report.summary("retransmit_delay").unwrap();
+        assert_eq!(delays.count, 40);
+        assert_eq!(delays.mean, 2.5);
+        assert_eq!(delays.p50, 2.0);
+        assert_eq!(delays.p99, 4.0);
+        assert_eq!(report.summaries().len(), 2);
+        assert_eq!(report.summaries()["faults"], faults);
+        assert!(report.summary("elections").is_none());
+        let elapsed = report.elapsed_summary().unwrap();
+        assert_eq!((elapsed.min, elapsed.max), (3.0, 3.0));
+        assert_eq!(report.runs()[0].elapsed(), Duration::from_secs(3));
+    }
+
+    /// Simulates an election with two bugs, each of which panics for some seeds.
+    fn buggy_election(runtime: &mut DeterministicRuntime, observations: &Observations) {
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let term = handle.gen_range(0..100u64);
+            observations.record("term", term as f64);
+            match term % 3 {
+                0 => panic!("two leaders elected in term {}", term),
+                1 => assert!(term > 1000, "stale read in term {}", term),
+                _ => {}
+            }
+        });
+    }
+
+    #[test]
+    /// Test that panicking runs are grouped by fingerprint, with one class per distinct bug.
+    fn failure_classes() {
+        let report = Sweep::new(0..20).run(buggy_election);
+        assert_eq!(report.runs().len(), 20);
+        let classes = report.failure_classes();
+        assert_eq!(classes.len(), 2);
+        for class in classes.iter() {
+            let fingerprint = class.representative.fingerprint();
+            assert!(
+                fingerprint.ends_with("two leaders elected in term #")
+                    || fingerprint.ends_with("stale read in term #"),
+                "unexpected fingerprint {}",
+                fingerprint
+            );
+            assert!(class
+                .representative
+                .location()
+                .unwrap()
+                .ends_with("sweep.rs"));
+            for seed in class.seeds.iter() {
+                let run = &report.runs()[*seed as usize];
+                assert_eq!(run.failure().unwrap().fingerprint(), fingerprint);
+                assert!(run.first("term").is_some());
+            }
+        }
+        let failed = report.fraction(|run| run.failure().is_some());
+        assert!(failed > 0.0 && failed < 1.0);
+        assert_eq!(
+            report,
+            Sweep::new(0..20).run(buggy_election),
+            "expected failures to be deterministic"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "runs failed, in 1 classes")]
+    /// Test that failed runs fail the sweep, reporting each class once.
+    fn assert_no_failures() {
+        let report = Sweep::new(0..5).run(|_, _| panic!("lost write"));
+        report.assert_no_failures();
+    }
+
+    #[test]
+    #[should_panic(expected = "elect a leader within 1s")]
+    /// Test that a property which rarely holds fails the threshold assertion.
+    fn threshold_violation() {
+        let report = Sweep::new(0..20).run(election);
+        report.assert_fraction("elect a leader within 1s", 0.99, |run| {
+            run.max("election_time").map_or(false, |time| time < 1.0)
+        });
+    }
+}
@@ -0,0 +1,297 @@
+//! Tracking of parent/child relationships between spawned tasks.
+//!
+//! When task tracking is enabled, every task spawned through a `DeterministicRuntimeHandle` is
+//! assigned an id and polled within a `task` tracing span recording the id of the task which
+//! spawned it. The resulting tree can be inspected after a run to find which task spawned a
+//! misbehaving background task.
+//!
+//! Tasks can also be counted against a spawn limit on the ready queue of the executor, modeling
+//! back-pressure on task creation.
+use futures::{
+    task::{self, ArcWake, Waker},
+    Future, Poll,
+};
+use std::{
+    cell::Cell,
+    collections, fmt,
+    pin::Pin,
+    sync::{
+        self,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::Context,
+};
+use tracing::{span, trace, Level};
+
+thread_local! {
+    /// Id of the tracked task currently being polled on this thread.
+    static CURRENT_TASK: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// A task spawned while task tracking was enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskNode {
+    pub id: u64,
+    /// Type name of the spawned future.
+    pub name: &'static str,
+    /// Id of the task which spawned this task, or `None` if it was spawned from outside of a
+    /// tracked task.
+    pub parent: Option<u64>,
+    /// True if the task has run to completion.
+    pub completed: bool,
+}
+
+/// A snapshot of the tasks spawned while task tracking was enabled.
+///
+/// The `Display` implementation renders the tasks as an indented tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaskTree {
+    tasks: collections::BTreeMap<u64, TaskNode>,
+}
+
+impl TaskTree {
+    /// Returns all tracked tasks, ordered by id.
+    pub fn tasks(&self) -> impl Iterator<Item = &TaskNode> {
+        self.tasks.values()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&TaskNode> {
+        self.tasks.get(&id)
+    }
+
+    /// Returns the tasks spawned by task `parent`, or the tasks spawned from outside of any
+    /// tracked task if `parent` is `None`.
+    pub fn children(&self, parent: Option<u64>) -> impl Iterator<Item = &TaskNode> {
+        self.tasks
+            .values()
+            .filter(move |task| task.parent == parent)
+    }
+
+    fn fmt_task(&self, f: &mut fmt::Formatter<'_>, task: &TaskNode, depth: usize) -> fmt::Result {
+        let status = if task.completed {
+            "completed"
+        } else {
+            "running"
+        };
+        writeln!(
+            f,
+            "{:indent$}task {} [{}] {}",
+            "",
+            task.id,
+            status,
+            task.name,
+            indent = depth * 2
+        )?;
+        for child in self.children(Some(task.id)) {
+            self.fmt_task(f, child, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TaskTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for task in self.children(None) {
+            self.fmt_task(f, task, 0)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    enabled: bool,
+    next_id: u64,
+    tree: TaskTree,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TaskTracker {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl TaskTracker {
+    pub(crate) fn enable(&self) {
+        self.inner.lock().unwrap().enabled = true;
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.inner.lock().unwrap().enabled
+    }
+
+    /// Wrap `future`, recording it as a child of the task currently being polled.
+    pub(crate) fn wrap<F>(&self, name: &'static str, future: F) -> Tracked<F>
+    where
+        F: Future<Output = ()>,
+    {
+        let parent = CURRENT_TASK.with(|current| current.get());
+        let mut lock = self.inner.lock().unwrap();
+        let id = lock.next_id;
+        lock.next_id += 1;
+        lock.tree.tasks.insert(
+            id,
+            TaskNode {
+                id,
+                name,
+                parent,
+                completed: false,
+            },
+        );
+        trace!("task {} spawned by {:?}: {}", id, parent, name);
+        Tracked {
+            id,
+            span: span!(Level::TRACE, "task", id = id, parent = ?parent),
+            tracker: self.clone(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn complete(&self, id: u64) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(task) = lock.tree.tasks.get_mut(&id) {
+            task.completed = true;
+        }
+    }
+
+    pub(crate) fn tree(&self) -> TaskTree {
+        self.inner.lock().unwrap().tree.clone()
+    }
+}
+
+pub(crate) struct Tracked<F> {
+    id: u64,
+    span: tracing::Span,
+    tracker: TaskTracker,
+    future: Pin<Box<F>>,
+}
+
+impl<F> Future for Tracked<F>
+where
+    F: Future<Output = ()>,
+{
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let id = self.id;
+        let span = self.span.clone();
+        let _enter = span.enter();
+        let previous = CURRENT_TASK.with(|current| current.replace(Some(id)));
+        let result = self.future.as_mut().poll(cx);
+        CURRENT_TASK.with(|current| current.set(previous));
+        if result.is_ready() {
+            self.tracker.complete(id);
+        }
+        result
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimitInner {
+    limit: Option<usize>,
+    /// Number of counted tasks which are waiting to be polled.
+    ready: usize,
+}
+
+/// Counts tasks waiting in the ready queue of the executor against an optional limit. A task is
+/// ready from when it is spawned or woken until it is next polled.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SpawnLimit {
+    inner: sync::Arc<sync::Mutex<LimitInner>>,
+}
+
+impl SpawnLimit {
+    pub(crate) fn set(&self, limit: Option<usize>) {
+        self.inner.lock().unwrap().limit = limit;
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.inner.lock().unwrap().limit.is_some()
+    }
+
+    pub(crate) fn ready(&self) -> usize {
+        self.inner.lock().unwrap().ready
+    }
+
+    pub(crate) fn at_capacity(&self) -> bool {
+        let lock = self.inner.lock().unwrap();
+        lock.limit.map_or(false, |limit| lock.ready >= limit)
+    }
+
+    /// Wrap `future`, counting it as ready whenever it is waiting to be polled.
+    pub(crate) fn wrap<F>(&self, future: F) -> Counted<F>
+    where
+        F: Future<Output = ()>,
+    {
+        self.inner.lock().unwrap().ready += 1;
+        let readiness = sync::Arc::new(Readiness {
+            limit: self.clone(),
+            ready: AtomicBool::new(true),
+            waker: sync::Mutex::new(None),
+        });
+        Counted {
+            waker: task::waker(sync::Arc::clone(&readiness)),
+            readiness,
+            future: Box::pin(future),
+        }
+    }
+}
+
+/// Whether a counted task is in the ready queue, along with the waker of the executor which
+/// polls it.
+struct Readiness {
+    limit: SpawnLimit,
+    ready: AtomicBool,
+    waker: sync::Mutex<Option<Waker>>,
+}
+
+impl Readiness {
+    fn set_ready(&self, ready: bool) {
+        if self.ready.swap(ready, Ordering::SeqCst) != ready {
+            let mut lock = self.limit.inner.lock().unwrap();
+            if ready {
+                lock.ready += 1;
+            } else {
+                lock.ready -= 1;
+            }
+        }
+    }
+}
+
+impl ArcWake for Readiness {
+    fn wake_by_ref(arc_self: &sync::Arc<Self>) {
+        arc_self.set_ready(true);
+        if let Some(waker) = arc_self.waker.lock().unwrap().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+pub(crate) struct Counted<F> {
+    readiness: sync::Arc<Readiness>,
+    /// Waker passed to the wrapped future, marking the task ready before waking the executor.
+    waker: Waker,
+    future: Pin<Box<F>>,
+}
+
+impl<F> Future for Counted<F>
+where
+    F: Future<Output = ()>,
+{
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        {
+            let mut waker = self.readiness.waker.lock().unwrap();
+            if !waker.as_ref().map_or(false, |w| w.will_wake(cx.waker())) {
+                waker.replace(cx.waker().clone());
+            }
+        }
+        self.readiness.set_ready(false);
+        let waker = self.waker.clone();
+        self.future.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+}
+
+impl<F> Drop for Counted<F> {
+    fn drop(&mut self) {
+        self.readiness.set_ready(false);
+    }
+}
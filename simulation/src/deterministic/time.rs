@@ -1,6 +1,102 @@
 //! A mock source of time, allowing for determinstic control of the progress
 //! of time.
-use std::{sync, time};
+//!
+//! Because time advances instantly when all tasks are idle, a timer scheduled implausibly far in
+//! the future, usually the result of a unit mixup such as passing milliseconds where seconds were
+//! expected, results in a silent jump of the clock. Timers scheduled through a
+//! `DeterministicTimeHandle` further ahead than the audit threshold are logged at warn level and
+//! recorded so that such bugs can be reported.
+//!
+//! The wall clock advances with the mock time source from a configurable epoch, so that
+//! timestamps taken from it are identical across runs of the same simulation. The clock of each
+//! host can be offset from the mock time source and drift away from it at a fixed rate, which
+//! affects the time hosts read but never when their timers fire.
+//!
+//! Automatic advancement can be paused, after which time only moves when it is advanced
+//! explicitly, so that a future can be shown to be pending on something other than a timer.
+use crate::MonotonicInstant;
+use std::{cmp, collections, net, sync, time};
+use tracing::warn;
+
+/// Timers scheduled further ahead than this are recorded by default.
+const DEFAULT_AUDIT_THRESHOLD: time::Duration = time::Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Wall clock time at which simulations start by default, 2019-01-01T00:00:00Z.
+const DEFAULT_EPOCH: time::Duration = time::Duration::from_secs(1_546_300_800);
+
+/// A timer which was scheduled further in the future than the audit threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FarTimer {
+    /// Time at which the timer was scheduled.
+    pub scheduled_at: MonotonicInstant,
+    /// Time at which the timer fires.
+    pub deadline: MonotonicInstant,
+}
+
+impl FarTimer {
+    /// Returns how far ahead the timer was scheduled.
+    pub fn ahead(&self) -> time::Duration {
+        self.deadline - self.scheduled_at
+    }
+}
+
+/// Offset of a host clock from simulated time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skew {
+    Ahead(time::Duration),
+    Behind(time::Duration),
+}
+
+impl Skew {
+    pub(crate) fn from_nanos(nanos: i64) -> Self {
+        let magnitude = time::Duration::from_nanos(nanos.abs() as u64);
+        if nanos < 0 {
+            Skew::Behind(magnitude)
+        } else {
+            Skew::Ahead(magnitude)
+        }
+    }
+
+    pub(crate) fn as_nanos(self) -> i64 {
+        match self {
+            Skew::Ahead(skew) => skew.as_nanos() as i64,
+            Skew::Behind(skew) => -(skew.as_nanos() as i64),
+        }
+    }
+
+    /// Returns the size of the offset, regardless of direction.
+    pub fn magnitude(self) -> time::Duration {
+        match self {
+            Skew::Ahead(skew) | Skew::Behind(skew) => skew,
+        }
+    }
+}
+
+/// Offset and drift of the clock of a host from the mock time source.
+#[derive(Debug, Clone, Copy, Default)]
+struct HostClock {
+    /// Offset from the mock time source in nanoseconds, as of `since`.
+    offset: i64,
+    /// Rate at which the clock drifts ahead of the mock time source, in parts per million.
+    drift: f64,
+    /// Mock time elapsed when the offset was set.
+    since: time::Duration,
+}
+
+impl HostClock {
+    /// Returns the offset in nanoseconds once `advance` mock time has elapsed.
+    fn offset_at(&self, advance: time::Duration) -> i64 {
+        let elapsed = advance
+            .checked_sub(self.since)
+            .unwrap_or_else(|| time::Duration::from_millis(0));
+        self.offset + (elapsed.as_nanos() as f64 * self.drift / 1_000_000.0) as i64
+    }
+}
+
+/// Splits a signed offset in nanoseconds into its magnitude and whether it is ahead.
+fn split_offset(offset: i64) -> (time::Duration, bool) {
+    (time::Duration::from_nanos(offset.abs() as u64), offset >= 0)
+}
 
 #[derive(Debug)]
 struct Inner {
@@ -8,6 +104,17 @@ struct Inner {
     base: time::Instant,
     /// The amount of mock time which has elapsed.
     advance: time::Duration,
+    /// Wall clock time when no mock time has elapsed.
+    epoch: time::SystemTime,
+    /// While automatic advancement is paused, the amount of mock time which may elapse.
+    advance_limit: Option<time::Duration>,
+    /// Whether the executor is parked until a task is woken, rather than for a timeout.
+    parked_indefinitely: bool,
+    /// Clocks of hosts which are offset from the mock time source.
+    host_clocks: collections::HashMap<net::IpAddr, HostClock>,
+    /// Timers scheduled further ahead than this are recorded, `None` disables auditing.
+    audit_threshold: Option<time::Duration>,
+    far_timers: Vec<FarTimer>,
 }
 
 impl Inner {
@@ -15,6 +122,12 @@ impl Inner {
         Self {
             base: time::Instant::now(),
             advance: time::Duration::from_millis(0),
+            epoch: time::UNIX_EPOCH + DEFAULT_EPOCH,
+            advance_limit: None,
+            parked_indefinitely: false,
+            host_clocks: collections::HashMap::new(),
+            audit_threshold: Some(DEFAULT_AUDIT_THRESHOLD),
+            far_timers: vec![],
         }
     }
 
@@ -25,6 +138,48 @@ impl Inner {
     fn now(&self) -> time::Instant {
         self.base + self.advance
     }
+
+    /// Returns how much of `duration` may elapse without exceeding the advance limit.
+    fn allowed_advance(&self, duration: time::Duration) -> time::Duration {
+        match self.advance_limit {
+            Some(limit) => {
+                let remaining = limit
+                    .checked_sub(self.advance)
+                    .unwrap_or_else(|| time::Duration::from_millis(0));
+                cmp::min(duration, remaining)
+            }
+            None => duration,
+        }
+    }
+
+    /// Returns the offset of the clock of `host` in nanoseconds.
+    fn host_offset(&self, host: net::IpAddr) -> i64 {
+        self.host_clocks
+            .get(&host)
+            .map_or(0, |clock| clock.offset_at(self.advance))
+    }
+
+    fn audit(&mut self, deadline: time::Instant) {
+        let now = self.now();
+        let threshold = match self.audit_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        if deadline
+            .checked_duration_since(now)
+            .map_or(false, |ahead| ahead > threshold)
+        {
+            warn!(
+                "timer scheduled {:?} ahead exceeds audit threshold of {:?}",
+                deadline - now,
+                threshold
+            );
+            self.far_timers.push(FarTimer {
+                scheduled_at: now.into(),
+                deadline: deadline.into(),
+            });
+        }
+    }
 }
 
 /// A mock source of time, providing deterministic control of time.
@@ -81,6 +236,79 @@ impl DeterministicTimeHandle {
     pub(crate) fn now(&self) -> time::Instant {
         self.inner.lock().unwrap().now()
     }
+    /// Return the amount of mock time which has elapsed.
+    pub(crate) fn elapsed(&self) -> time::Duration {
+        self.inner.lock().unwrap().advance
+    }
+
+    /// Set the wall clock time at which the mock time source started.
+    pub(crate) fn set_epoch(&self, epoch: time::SystemTime) {
+        self.inner.lock().unwrap().epoch = epoch;
+    }
+    /// Stop advancing time automatically when every task is waiting on a timer.
+    pub(crate) fn pause(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.advance_limit = Some(lock.advance);
+    }
+    /// Resume advancing time automatically.
+    pub(crate) fn resume(&self) {
+        self.inner.lock().unwrap().advance_limit = None;
+    }
+    pub(crate) fn is_paused(&self) -> bool {
+        self.inner.lock().unwrap().advance_limit.is_some()
+    }
+    /// Allow time to advance to `deadline` while paused.
+    pub(crate) fn allow_advance_to(&self, deadline: time::Instant) {
+        let mut lock = self.inner.lock().unwrap();
+        let limit = deadline
+            .checked_duration_since(lock.base)
+            .unwrap_or_else(|| time::Duration::from_millis(0));
+        if let Some(current) = lock.advance_limit {
+            lock.advance_limit = Some(cmp::max(current, limit));
+        }
+    }
+    /// Return time now according to the clock of `host`.
+    pub(crate) fn host_now(&self, host: net::IpAddr) -> time::Instant {
+        let lock = self.inner.lock().unwrap();
+        let now = lock.now();
+        match split_offset(lock.host_offset(host)) {
+            (offset, true) => now + offset,
+            (offset, false) => now.checked_sub(offset).unwrap_or(now),
+        }
+    }
+    /// Return the wall clock time now according to the clock of `host`, the epoch plus the mock
+    /// time which has elapsed, offset by the skew of the host.
+    pub(crate) fn host_system_time(&self, host: net::IpAddr) -> time::SystemTime {
+        let lock = self.inner.lock().unwrap();
+        let now = lock.epoch + lock.advance;
+        match split_offset(lock.host_offset(host)) {
+            (offset, true) => now + offset,
+            (offset, false) => now.checked_sub(offset).unwrap_or(time::UNIX_EPOCH),
+        }
+    }
+    /// Returns the offset of the clock of `host` from the mock time source.
+    pub(crate) fn host_skew(&self, host: net::IpAddr) -> Skew {
+        Skew::from_nanos(self.inner.lock().unwrap().host_offset(host))
+    }
+    /// Set the offset of the clock of `host` from the mock time source. Any drift accumulates
+    /// from this offset.
+    pub(crate) fn set_host_skew(&self, host: net::IpAddr, skew: Skew) {
+        let mut lock = self.inner.lock().unwrap();
+        let advance = lock.advance;
+        let clock = lock.host_clocks.entry(host).or_default();
+        clock.offset = skew.as_nanos();
+        clock.since = advance;
+    }
+    /// Set the rate, in parts per million, at which the clock of `host` drifts ahead of the mock
+    /// time source, keeping the offset it has drifted to so far.
+    pub(crate) fn set_host_drift(&self, host: net::IpAddr, ppm: f64) {
+        let mut lock = self.inner.lock().unwrap();
+        let advance = lock.advance;
+        let clock = lock.host_clocks.entry(host).or_default();
+        clock.offset = clock.offset_at(advance);
+        clock.since = advance;
+        clock.drift = ppm;
+    }
 
     /// Creates an instance of `Now` from this deterministic time source.
     ///
@@ -95,15 +323,32 @@ impl DeterministicTimeHandle {
         tokio_timer::clock::Clock::new_with_now(self.clone_now())
     }
 
+    /// Set the threshold above which scheduled timers are recorded as implausibly far in the
+    /// future. Passing `None` disables auditing.
+    pub(crate) fn set_audit_threshold(&self, threshold: Option<time::Duration>) {
+        self.inner.lock().unwrap().audit_threshold = threshold;
+    }
+
+    /// Returns the timers which were scheduled further ahead than the audit threshold.
+    pub(crate) fn far_timers(&self) -> Vec<FarTimer> {
+        self.inner.lock().unwrap().far_timers.clone()
+    }
+
     pub fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay {
+        self.inner.lock().unwrap().audit(deadline);
         self.timer_handle.delay(deadline)
     }
 
     pub fn delay_from(&self, duration: time::Duration) -> tokio_timer::Delay {
-        self.timer_handle.delay(self.now() + duration)
+        self.delay(self.now() + duration)
     }
 
     pub fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio_timer::Timeout<T> {
+        {
+            let mut lock = self.inner.lock().unwrap();
+            let deadline = lock.now() + timeout;
+            lock.audit(deadline);
+        }
         self.timer_handle.timeout(value, timeout)
     }
 
@@ -116,11 +361,17 @@ impl DeterministicTimeHandle {
 struct DeterministicPark<P> {
     park: P,
     inner: sync::Arc<sync::Mutex<Inner>>,
+    /// Set when a task is woken, and cleared each time the executor parks.
+    woken: sync::Arc<sync::atomic::AtomicBool>,
 }
 
 impl<P> DeterministicPark<P> {
     fn new(park: P, inner: sync::Arc<sync::Mutex<Inner>>) -> Self {
-        Self { park, inner }
+        Self {
+            park,
+            inner,
+            woken: sync::Arc::new(sync::atomic::AtomicBool::new(false)),
+        }
     }
 }
 
@@ -128,18 +379,50 @@ impl<P> tokio_executor::park::Park for DeterministicPark<P>
 where
     P: tokio_executor::park::Park,
 {
-    type Unpark = P::Unpark;
+    type Unpark = DeterministicUnpark<P::Unpark>;
     type Error = P::Error;
     fn unpark(&self) -> Self::Unpark {
-        self.park.unpark()
+        DeterministicUnpark {
+            unpark: self.park.unpark(),
+            woken: sync::Arc::clone(&self.woken),
+        }
     }
     fn park(&mut self) -> Result<(), Self::Error> {
+        self.woken.store(false, sync::atomic::Ordering::SeqCst);
         self.park.park()
     }
     fn park_timeout(&mut self, duration: time::Duration) -> Result<(), Self::Error> {
+        // While paused, time advances no further than the limit, and parking returns without
+        // advancing once it has been reached.
+        let woken = self.woken.swap(false, sync::atomic::Ordering::SeqCst);
         let mut lock = self.inner.lock().unwrap();
-        lock.advance(duration);
-        self.park.park_timeout(time::Duration::from_millis(0))
+        let allowed = lock.allowed_advance(duration);
+        // An executor waiting for a task to be woken would spin forever, as no task is left to
+        // run and every timer lies beyond the limit.
+        let zero = time::Duration::from_millis(0);
+        if lock.parked_indefinitely && !woken && duration > zero && allowed == zero {
+            drop(lock);
+            panic!("every task is waiting on a timer while time is paused");
+        }
+        lock.advance(allowed);
+        self.park.park_timeout(zero)
+    }
+}
+
+/// Wakes the executor, recording that a task was woken.
+#[derive(Debug, Clone)]
+pub struct DeterministicUnpark<U> {
+    unpark: U,
+    woken: sync::Arc<sync::atomic::AtomicBool>,
+}
+
+impl<U> tokio_executor::park::Unpark for DeterministicUnpark<U>
+where
+    U: tokio_executor::park::Unpark,
+{
+    fn unpark(&self) {
+        self.woken.store(true, sync::atomic::Ordering::SeqCst);
+        self.unpark.unpark();
     }
 }
 
@@ -147,13 +430,16 @@ impl<P> tokio_executor::park::Park for DeterministicTime<P>
 where
     P: tokio_executor::park::Park,
 {
-    type Unpark = P::Unpark;
+    type Unpark = DeterministicUnpark<P::Unpark>;
     type Error = P::Error;
     fn unpark(&self) -> Self::Unpark {
         self.park.unpark()
     }
     fn park(&mut self) -> Result<(), Self::Error> {
-        self.park.park()
+        self.inner.lock().unwrap().parked_indefinitely = true;
+        let result = self.park.park();
+        self.inner.lock().unwrap().parked_indefinitely = false;
+        result
     }
     fn park_timeout(&mut self, duration: time::Duration) -> Result<(), Self::Error> {
         self.park.park_timeout(duration)
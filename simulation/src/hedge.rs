@@ -0,0 +1,115 @@
+//! Request hedging driven by the [`Environment`] clock.
+//!
+//! Hedging trades extra load for lower tail latency: if a request has not completed after some
+//! delay, a backup request is issued and whichever completes first is used. Whether this pays
+//! off depends on the latency distribution of the system being called, which makes it a natural
+//! fit for evaluation against the deterministic runtime with injected tail latency.
+//!
+//! [`Environment`]:`crate::Environment`
+use crate::Environment;
+use futures::{future, stream::FuturesUnordered, FutureExt, StreamExt};
+use std::{future::Future, time};
+use tracing::trace;
+
+/// The result of a hedged request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hedged<T> {
+    pub output: T,
+    /// Index of the attempt which completed first.
+    pub winner: usize,
+    /// Number of attempts which were started.
+    pub started: usize,
+}
+
+/// Start the first of `attempts`, then start each following attempt if no started attempt has
+/// completed within `delay`. Returns the output of the first attempt to complete, dropping the
+/// others. The winning attempt is recorded in the trace.
+///
+/// Panics if `attempts` is empty.
+pub async fn hedge<E, I, F>(env: &E, delay: time::Duration, attempts: I) -> Hedged<F::Output>
+where
+    E: Environment,
+    I: IntoIterator<Item = F>,
+    F: Future,
+{
+    let start = env.now();
+    let mut pending = attempts
+        .into_iter()
+        .enumerate()
+        .map(|(index, attempt)| attempt.map(move |output| (index, output)));
+    let mut running = FuturesUnordered::new();
+    running.push(pending.next().expect("hedge requires at least one attempt"));
+    let mut started = 1;
+    let (winner, output) = loop {
+        let backup = env.delay_from(delay);
+        match future::select(running.next(), backup).await {
+            future::Either::Left((winner, _)) => {
+                break winner.expect("at least one attempt is running");
+            }
+            future::Either::Right(_) => match pending.next() {
+                Some(attempt) => {
+                    trace!("starting hedged attempt {}", started);
+                    running.push(attempt);
+                    started += 1;
+                }
+                None => {
+                    let winner = running.next().await;
+                    break winner.expect("at least one attempt is running");
+                }
+            },
+        }
+    };
+    trace!(
+        "hedged attempt {} of {} won after {:?}",
+        winner,
+        started,
+        env.now() - start
+    );
+    Hedged {
+        output,
+        winner,
+        started,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that a backup attempt is only started once the delay has elapsed, and that the
+    /// first attempt to complete wins.
+    fn backup_wins() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let ms = time::Duration::from_millis;
+            let attempt = |latency| {
+                let handle = handle.clone();
+                async move {
+                    handle.delay_from(latency).await;
+                    latency
+                }
+            };
+
+            let start = handle.now();
+            let hedged = hedge(&handle, ms(20), vec![attempt(ms(100)), attempt(ms(10))]).await;
+            assert_eq!(
+                hedged,
+                Hedged {
+                    output: ms(10),
+                    winner: 1,
+                    started: 2,
+                }
+            );
+            assert_eq!(handle.now() - start, ms(30));
+
+            let start = handle.now();
+            let hedged = hedge(&handle, ms(20), vec![attempt(ms(5)), attempt(ms(1))]).await;
+            assert_eq!(hedged.winner, 0);
+            assert_eq!(hedged.started, 1);
+            assert_eq!(handle.now() - start, ms(5));
+        });
+    }
+}
@@ -0,0 +1,106 @@
+//! Recurring ticks driven by the [`Environment`] clock.
+//!
+//! Heartbeats, lease renewals and periodic flushes all wait for the next multiple of some period
+//! in a loop. Writing that loop with [`Environment::delay_from`] accumulates drift, since each
+//! delay starts when the previous iteration finishes rather than when the previous tick was due.
+//! An [`Interval`] schedules each tick a fixed period after the previous one instead, and is
+//! driven by simulated time in the deterministic environment and real timers otherwise.
+//!
+//! [`Environment`]:`crate::Environment`
+//! [`Environment::delay_from`]:`crate::Environment::delay_from`
+use crate::{Environment, MonotonicInstant};
+use futures::{FutureExt, Poll, Stream};
+use std::{pin::Pin, task::Context, time};
+use tracing::trace;
+
+/// Stream returned by [`Environment::interval`], yielding the instant each tick was due.
+///
+/// [`Environment::interval`]:`crate::Environment::interval`
+#[derive(Debug)]
+pub struct Interval<E> {
+    env: E,
+    period: time::Duration,
+    /// Instant the next tick is due.
+    deadline: MonotonicInstant,
+    delay: tokio_timer::Delay,
+}
+
+impl<E> Interval<E>
+where
+    E: Environment,
+{
+    /// Create an interval whose first tick is due at `start`, and every `period` thereafter.
+    /// Panics if `period` is zero.
+    pub fn new(env: E, start: MonotonicInstant, period: time::Duration) -> Self {
+        assert!(
+            period > time::Duration::from_millis(0),
+            "interval period must be non-zero"
+        );
+        let delay = env.delay(start);
+        Self {
+            env,
+            period,
+            deadline: start,
+            delay,
+        }
+    }
+
+    /// Returns the period between ticks.
+    pub fn period(&self) -> time::Duration {
+        self.period
+    }
+}
+
+impl<E> Stream for Interval<E>
+where
+    E: Environment,
+{
+    type Item = MonotonicInstant;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        futures::ready!(self.delay.poll_unpin(cx));
+        let tick = self.deadline;
+        let now = self.env.now();
+        let mut next = tick + self.period;
+        if next <= now {
+            // skip the ticks which were missed while the consumer was busy, rather than yielding
+            // them in a burst.
+            let missed = (now.duration_since(tick).as_nanos() / self.period.as_nanos()) as u32;
+            trace!("interval skipping {} missed ticks", missed);
+            next = tick + self.period * (missed + 1);
+        }
+        self.deadline = next;
+        let delay = self.env.delay(next);
+        self.delay = delay;
+        Poll::Ready(Some(tick))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use futures::StreamExt;
+
+    #[test]
+    /// Test that ticks are yielded every period without drifting, and that missed ticks are
+    /// skipped.
+    fn ticks() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let start = handle.now();
+            let secs = time::Duration::from_secs;
+            let mut interval = handle.interval(secs(10));
+            assert_eq!(interval.next().await, Some(start));
+            handle.delay_from(secs(3)).await;
+            assert_eq!(interval.next().await, Some(start + secs(10)));
+            assert_eq!(handle.now(), start + secs(10));
+            assert_eq!(interval.next().await, Some(start + secs(20)));
+
+            handle.delay_from(secs(25)).await;
+            assert_eq!(interval.next().await, Some(start + secs(30)));
+            assert_eq!(interval.next().await, Some(start + secs(50)));
+            assert_eq!(handle.now(), start + secs(50));
+        });
+    }
+}
@@ -157,11 +157,27 @@
 //! [Timeout]:[tokio_timer::Timeout]
 use async_trait::async_trait;
 use futures::{Future, FutureExt, Stream};
-use std::{io, net, pin::Pin, time};
-use tokio::io::{AsyncRead, AsyncWrite};
+use rand::distributions::uniform::SampleUniform;
+use std::{io, net, ops, path, pin::Pin, sync, time};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tracing::trace;
 
+mod clock;
+pub mod codec;
+pub mod components;
+pub mod connection;
 pub mod deterministic;
+pub mod hedge;
+pub mod interval;
 pub mod singlethread;
+mod state;
+pub mod sync;
+#[cfg(feature = "thread-guard")]
+pub mod thread;
+pub mod timeout;
+pub mod tls;
+pub mod workload;
+pub use clock::MonotonicInstant;
 
 #[derive(Debug)]
 pub enum Error {
@@ -174,12 +190,16 @@ pub enum Error {
     CurrentThreadRun {
         source: tokio_executor::current_thread::RunError,
     },
+    CurrentThreadTurn {
+        source: tokio_executor::current_thread::TurnError,
+    },
 }
 
 #[async_trait]
 pub trait Network {
     type TcpStream: TcpStream + Send + 'static + Unpin;
     type TcpListener: TcpListener + Send + 'static + Unpin;
+    type UdpSocket: UdpSocket + Send + 'static + Unpin;
 
     /// Binds and returns a listener which can be used to listen for new connections.
     async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
@@ -193,29 +213,115 @@ pub trait Network {
     async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
     where
         A: Into<net::SocketAddr> + Send + Sync;
+
+    /// Binds and returns a [`UdpSocket`] which can be used to send and receive datagrams.
+    ///
+    /// [`UdpSocket`]:`UdpSocket`
+    async fn bind_udp<A>(&self, addr: A) -> io::Result<Self::UdpSocket>
+    where
+        A: Into<net::SocketAddr> + Send + Sync;
 }
 
 #[async_trait]
 pub trait Environment: Unpin + Sized + Clone + Send + 'static {
     type TcpStream: TcpStream + Send + 'static + Unpin;
     type TcpListener: TcpListener + Send + 'static + Unpin;
+    type UdpSocket: UdpSocket + Send + 'static + Unpin;
+    type Resolver: Resolver + Clone + Send + Sync + 'static;
+    #[cfg(unix)]
+    type UnixStream: UnixStream + Send + 'static + Unpin;
+    #[cfg(unix)]
+    type UnixListener: UnixListener + Send + 'static + Unpin;
 
     /// Spawn a task on the runtime provided by this [`Environment`].
     fn spawn<F>(&self, future: F)
     where
         F: Future<Output = ()> + Send + 'static;
-    /// Return the time now according to the executor.
-    fn now(&self) -> time::Instant;
+    /// Spawn a task on the runtime provided by this [`Environment`], unless the runtime is
+    /// overloaded, in which case `Error::Spawn` is returned. By default this always spawns.
+    fn try_spawn<F>(&self, future: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn(future);
+        Ok(())
+    }
+    /// Spawn a task on the runtime provided by this [`Environment`] which begins executing
+    /// once `delay` has elapsed.
+    fn spawn_after<F>(&self, delay: time::Duration, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let start = self.now() + delay;
+        trace!("scheduling task to start at {:?}", start);
+        let delay = self.delay(start);
+        self.spawn(async move {
+            delay.await;
+            trace!("starting task scheduled for {:?}", start);
+            future.await
+        })
+    }
+    /// Return the time now according to the monotonic clock of the executor.
+    fn now(&self) -> MonotonicInstant;
+    /// Return the time now according to the clock of the current node. Unlike [`Environment::now`],
+    /// this clock is subject to skew in environments which model it. By default it is equal to
+    /// the monotonic clock.
+    fn node_now(&self) -> time::Instant {
+        self.now().into_std()
+    }
+    /// Return the time now according to the wall clock, for timestamps which are recorded or
+    /// exchanged with other processes. Like [`Environment::node_now`], it is subject to skew.
+    /// Deterministic environments derive it from the monotonic clock, starting from a
+    /// configurable epoch, rather than reading the system clock. By default it is read from the
+    /// system clock.
+    fn system_time(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
     /// Returns a delay future which completes after the provided instant.
-    fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay;
+    fn delay(&self, deadline: MonotonicInstant) -> tokio_timer::Delay;
     /// Returns a delay future which completes at some time from now.
     fn delay_from(&self, from_now: time::Duration) -> tokio_timer::Delay {
         let now = self.now();
         self.delay(now + from_now)
     }
+    /// Returns a stream which yields immediately and then every `period`, driven by the clock
+    /// of this [`Environment`]. Panics if `period` is zero.
+    fn interval(&self, period: time::Duration) -> interval::Interval<Self> {
+        interval::Interval::new(self.clone(), self.now(), period)
+    }
     /// Creates a timeout future which which will execute T until the timeout elapses.
     fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio_timer::Timeout<T>;
 
+    /// Samples from an exponential distribution with the provided rate, using the source of
+    /// randomness provided by this [`Environment`].
+    fn exponential(&self, rate: f64) -> f64;
+    /// Samples from a Poisson distribution with mean `lambda`, using the source of randomness
+    /// provided by this [`Environment`].
+    fn poisson(&self, lambda: f64) -> u64;
+    /// Samples a rank in `1..=n` from a Zipf distribution with exponent `s`, using the source
+    /// of randomness provided by this [`Environment`].
+    fn zipf(&self, n: u64, s: f64) -> u64;
+    /// Returns true with the provided probability, using the source of randomness provided
+    /// by this [`Environment`].
+    fn gen_bool(&self, probability: f64) -> bool;
+    /// Samples a value uniformly from the provided range, using the source of randomness provided
+    /// by this [`Environment`].
+    fn gen_range<T>(&self, range: ops::Range<T>) -> T
+    where
+        T: SampleUniform + deterministic::Recordable;
+
+    /// Returns the singleton of type `T` scoped to the runtime provided by this [`Environment`],
+    /// creating it from `T::default()` on first access. Every handle of a runtime shares the
+    /// same singletons, which are never shared with other runtimes.
+    fn state<T>(&self) -> sync::Arc<T>
+    where
+        T: Default + Send + Sync + 'static;
+
+    /// Returns a [`Resolver`] for looking up the addresses of hosts by name.
+    ///
+    /// [`Resolver`]:`Resolver`
+    fn resolver(&self) -> Self::Resolver;
+
     /// Binds and returns a listener which can be used to listen for new connections.
     async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
     where
@@ -228,11 +334,125 @@ pub trait Environment: Unpin + Sized + Clone + Send + 'static {
     async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
     where
         A: Into<net::SocketAddr> + Send + Sync;
+
+    /// Connects to the specified addr, failing with `TimedOut` if the connection has not been
+    /// established once `timeout` has elapsed. Unlike [`Environment::connect`], connecting to
+    /// an unreachable or clogged address does not wait indefinitely.
+    fn connect_timeout<A>(
+        &self,
+        addr: A,
+        timeout: time::Duration,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Self::TcpStream>> + Send>>
+    where
+        A: Into<net::SocketAddr>,
+    {
+        let env = self.clone();
+        let addr = addr.into();
+        Box::pin(async move {
+            match env.timeout(env.connect(addr), timeout).await {
+                Ok(result) => result,
+                Err(_) => {
+                    trace!("connection to {} timed out after {:?}", addr, timeout);
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connection timed out",
+                    ))
+                }
+            }
+        })
+    }
+
+    /// Connects to `addr`, given as `host:port` where `host` is either an IP address or a name
+    /// resolved with [`Environment::resolver`]. The addresses registered for a name are tried
+    /// in order, returning the error of the last address if none can be connected to.
+    fn connect_name(
+        &self,
+        addr: &str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Self::TcpStream>> + Send>> {
+        let env = self.clone();
+        let addr = addr.to_string();
+        Box::pin(async move {
+            if let Ok(addr) = addr.parse::<net::SocketAddr>() {
+                return env.connect(addr).await;
+            }
+            let (name, port) = split_host_port(&addr)?;
+            let resolver = env.resolver();
+            let mut last_error = None;
+            for ip in resolver.resolve(name).await? {
+                match env.connect(net::SocketAddr::new(ip, port)).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(error) => {
+                        trace!("failed to connect to {} at {}: {}", name, ip, error);
+                        last_error = Some(error);
+                    }
+                }
+            }
+            Err(last_error.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+        })
+    }
+
+    /// Binds and returns a [`UdpSocket`] which can be used to send and receive datagrams.
+    ///
+    /// [`UdpSocket`]:`UdpSocket`
+    async fn bind_udp<A>(&self, addr: A) -> io::Result<Self::UdpSocket>
+    where
+        A: Into<net::SocketAddr> + Send + Sync;
+
+    /// Binds and returns a listener for Unix domain socket connections to `path`.
+    #[cfg(unix)]
+    async fn bind_unix<P>(&self, path: P) -> io::Result<Self::UnixListener>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+
+    /// Connects to the Unix domain socket listener bound to `path`.
+    #[cfg(unix)]
+    async fn connect_unix<P>(&self, path: P) -> io::Result<Self::UnixStream>
+    where
+        P: AsRef<path::Path> + Send + Sync;
 }
 
 pub trait TcpStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
     fn local_addr(&self) -> io::Result<net::SocketAddr>;
     fn peer_addr(&self) -> io::Result<net::SocketAddr>;
+    /// Shut down the read half, write half or both halves of this stream. Shutting down the
+    /// write half delivers EOF to the peer once any bytes already written have been read, while
+    /// reads continue to receive bytes from the peer. Subsequent writes fail with `BrokenPipe`.
+    /// Shutting down the read half causes subsequent reads to return EOF. Fails by default, for
+    /// streams which cannot shut down a single half.
+    fn shutdown(&self, _how: net::Shutdown) -> io::Result<()> {
+        Err(unsupported("shutdown"))
+    }
+    /// Returns whether TCP_NODELAY is set, disabling Nagle's algorithm. Socket options fail by
+    /// default, for streams which do not support them.
+    fn nodelay(&self) -> io::Result<bool> {
+        Err(unsupported("TCP_NODELAY"))
+    }
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Err(unsupported("TCP_NODELAY"))
+    }
+    /// Returns the IP_TTL of packets sent by this stream.
+    fn ttl(&self) -> io::Result<u32> {
+        Err(unsupported("IP_TTL"))
+    }
+    fn set_ttl(&self, _ttl: u32) -> io::Result<()> {
+        Err(unsupported("IP_TTL"))
+    }
+    /// Returns the idle duration after which keepalive probes are sent, if SO_KEEPALIVE is set.
+    fn keepalive(&self) -> io::Result<Option<time::Duration>> {
+        Err(unsupported("SO_KEEPALIVE"))
+    }
+    fn set_keepalive(&self, _keepalive: Option<time::Duration>) -> io::Result<()> {
+        Err(unsupported("SO_KEEPALIVE"))
+    }
+    /// Split this stream into a read half and a write half, each of which can be moved into a
+    /// separate task. Shutting down the write half delivers EOF to the peer, as with
+    /// `shutdown(Shutdown::Write)`.
+    fn into_split(self) -> (ReadHalf<Self>, WriteHalf<Self>)
+    where
+        Self: Sized,
+    {
+        tokio::io::split(self)
+    }
 }
 
 #[async_trait]
@@ -245,6 +465,70 @@ pub trait TcpListener {
     fn into_stream(self) -> Pin<Box<dyn Stream<Item = Result<Self::Stream, io::Error>> + Send>>;
 }
 
+#[async_trait]
+pub trait UdpSocket: Send + 'static {
+    /// Sends a datagram to `target`, returning the number of bytes sent.
+    async fn send_to(&mut self, buf: &[u8], target: net::SocketAddr) -> io::Result<usize>;
+    /// Receives a datagram, returning the number of bytes read and the address it was sent
+    /// from. Datagrams larger than `buf` are truncated.
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)>;
+    fn local_addr(&self) -> io::Result<net::SocketAddr>;
+    /// Sets whether datagrams may be sent to the broadcast address. Sending to the broadcast
+    /// address fails with `PermissionDenied` unless enabled.
+    fn set_broadcast(&self, _broadcast: bool) -> io::Result<()> {
+        Err(unsupported("SO_BROADCAST"))
+    }
+    fn broadcast(&self) -> io::Result<bool> {
+        Err(unsupported("SO_BROADCAST"))
+    }
+    /// Subscribes to datagrams sent to the multicast group `group` on the port this socket is
+    /// bound to. Joining a group the socket is already a member of has no effect.
+    fn join_multicast_v4(&self, _group: net::Ipv4Addr) -> io::Result<()> {
+        Err(unsupported("IP_ADD_MEMBERSHIP"))
+    }
+    fn leave_multicast_v4(&self, _group: net::Ipv4Addr) -> io::Result<()> {
+        Err(unsupported("IP_DROP_MEMBERSHIP"))
+    }
+}
+
+pub trait UnixStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+#[async_trait]
+pub trait UnixListener {
+    type Stream: UnixStream + Send + 'static;
+    async fn accept(&mut self) -> io::Result<Self::Stream>;
+    /// Returns the path this listener is bound to.
+    fn local_path(&self) -> io::Result<path::PathBuf>;
+}
+
+#[async_trait]
+pub trait Resolver {
+    /// Resolves `name` to the addresses of the hosts registered under it, returning a `NotFound`
+    /// error if there are none.
+    async fn resolve(&self, name: &str) -> io::Result<Vec<net::IpAddr>>;
+}
+
+/// Returns the error reported by default implementations of optional socket operations.
+fn unsupported(operation: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("{} is not supported by this socket", operation),
+    )
+}
+
+/// Splits `addr` into the host and port of `host:port`.
+fn split_host_port(addr: &str) -> io::Result<(&str, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid address {}", addr),
+        )
+    };
+    let colon = addr.rfind(':').ok_or_else(invalid)?;
+    let port = addr[colon + 1..].parse().map_err(|_| invalid())?;
+    Ok((&addr[..colon], port))
+}
+
 pub fn spawn_with_result<F, E, U>(env: &E, future: F) -> impl Future<Output = U>
 where
     F: Future<Output = U> + Send + 'static,
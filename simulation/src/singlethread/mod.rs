@@ -1,22 +1,36 @@
-use crate::Error;
+use crate::{
+    deterministic::{DeterministicRandom, DeterministicRandomHandle, Recordable},
+    state::StateRegistry,
+    Error, MonotonicInstant,
+};
 use async_trait::async_trait;
 use futures::Future;
-use std::{io, net::SocketAddr, time};
+use rand::distributions::uniform::SampleUniform;
+use std::{io, net::SocketAddr, ops, sync::Arc, time};
 use tokio_executor::current_thread;
 use tokio_net::driver::Reactor;
 use tokio_timer::{clock::Clock, timer};
 mod net;
+pub use net::SystemResolver;
 #[derive(Debug, Clone)]
 pub struct SingleThreadedRuntimeHandle {
     executor_handle: current_thread::Handle,
     clock_handle: Clock,
     timer_handle: timer::Handle,
+    random_handle: DeterministicRandomHandle,
+    state: StateRegistry,
 }
 
 #[async_trait]
 impl crate::Environment for SingleThreadedRuntimeHandle {
     type TcpStream = tokio::net::TcpStream;
     type TcpListener = tokio::net::TcpListener;
+    type UdpSocket = tokio::net::UdpSocket;
+    type Resolver = net::SystemResolver;
+    #[cfg(unix)]
+    type UnixStream = tokio::net::UnixStream;
+    #[cfg(unix)]
+    type UnixListener = tokio::net::UnixListener;
     fn spawn<F>(&self, future: F)
     where
         F: Future<Output = ()> + Send + 'static,
@@ -25,15 +39,42 @@ impl crate::Environment for SingleThreadedRuntimeHandle {
             .spawn(future)
             .expect("failed to spawn task")
     }
-    fn now(&self) -> time::Instant {
-        self.clock_handle.now()
+    fn now(&self) -> MonotonicInstant {
+        self.clock_handle.now().into()
     }
-    fn delay(&self, deadline: time::Instant) -> tokio::timer::Delay {
-        self.timer_handle.delay(deadline)
+    fn delay(&self, deadline: MonotonicInstant) -> tokio::timer::Delay {
+        self.timer_handle.delay(deadline.into_std())
     }
     fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio::timer::Timeout<T> {
         self.timer_handle.timeout(value, timeout)
     }
+    fn exponential(&self, rate: f64) -> f64 {
+        self.random_handle.exponential(rate)
+    }
+    fn poisson(&self, lambda: f64) -> u64 {
+        self.random_handle.poisson(lambda)
+    }
+    fn zipf(&self, n: u64, s: f64) -> u64 {
+        self.random_handle.zipf(n, s)
+    }
+    fn gen_bool(&self, probability: f64) -> bool {
+        self.random_handle.should_fault(probability)
+    }
+    fn gen_range<T>(&self, range: ops::Range<T>) -> T
+    where
+        T: SampleUniform + Recordable,
+    {
+        self.random_handle.gen_range(range)
+    }
+    fn state<T>(&self) -> Arc<T>
+    where
+        T: Default + Send + Sync + 'static,
+    {
+        self.state.get()
+    }
+    fn resolver(&self) -> Self::Resolver {
+        net::SystemResolver
+    }
     async fn bind<A>(&self, addr: A) -> Result<Self::TcpListener, io::Error>
     where
         A: Into<SocketAddr> + Send + Sync,
@@ -46,6 +87,26 @@ impl crate::Environment for SingleThreadedRuntimeHandle {
     {
         tokio::net::TcpStream::connect(addr.into()).await
     }
+    async fn bind_udp<A>(&self, addr: A) -> Result<Self::UdpSocket, io::Error>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        tokio::net::UdpSocket::bind(addr.into()).await
+    }
+    #[cfg(unix)]
+    async fn bind_unix<P>(&self, path: P) -> Result<Self::UnixListener, io::Error>
+    where
+        P: AsRef<std::path::Path> + Send + Sync,
+    {
+        tokio::net::UnixListener::bind(path)
+    }
+    #[cfg(unix)]
+    async fn connect_unix<P>(&self, path: P) -> Result<Self::UnixStream, io::Error>
+    where
+        P: AsRef<std::path::Path> + Send + Sync,
+    {
+        tokio::net::UnixStream::connect(path).await
+    }
 }
 
 pub struct SingleThreadedRuntime {
@@ -53,6 +114,8 @@ pub struct SingleThreadedRuntime {
     timer_handle: tokio_timer::timer::Handle,
     clock: Clock,
     executor: current_thread::CurrentThread<timer::Timer<Reactor>>,
+    random: DeterministicRandom,
+    state: StateRegistry,
 }
 
 impl SingleThreadedRuntime {
@@ -63,11 +126,15 @@ impl SingleThreadedRuntime {
         let timer = tokio_timer::Timer::new_with_now(reactor, clock.clone());
         let timer_handle = timer.handle();
         let executor = current_thread::CurrentThread::new_with_park(timer);
+        // The single threaded runtime is not deterministic, seed the random source from entropy.
+        let random = DeterministicRandom::new_with_seed(rand::random());
         let runtime = SingleThreadedRuntime {
             reactor_handle,
             timer_handle,
             clock,
             executor,
+            random,
+            state: StateRegistry::default(),
         };
         Ok(runtime)
     }
@@ -76,10 +143,13 @@ impl SingleThreadedRuntime {
         let executor_handle = self.executor.handle();
         let clock_handle = self.clock.clone();
         let timer_handle = self.timer_handle.clone();
+        let random_handle = self.random.handle();
         SingleThreadedRuntimeHandle {
             executor_handle,
             clock_handle,
             timer_handle,
+            random_handle,
+            state: self.state.clone(),
         }
     }
     pub fn spawn<F>(&mut self, future: F) -> &mut Self
@@ -111,6 +181,7 @@ impl SingleThreadedRuntime {
             ref timer_handle,
             ref clock,
             ref mut executor,
+            ..
         } = *self;
         let _reactor = tokio_net::driver::set_default(&reactor_handle);
         let clock = clock;
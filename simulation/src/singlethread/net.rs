@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use futures::Stream;
-use std::{io, net, pin::Pin};
-use tokio::net::{TcpListener, TcpStream};
+use std::{io, net, pin::Pin, time};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
 impl crate::TcpStream for TcpStream {
     fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
@@ -10,6 +10,27 @@ impl crate::TcpStream for TcpStream {
     fn peer_addr(&self) -> Result<net::SocketAddr, io::Error> {
         self.peer_addr()
     }
+    fn shutdown(&self, how: net::Shutdown) -> Result<(), io::Error> {
+        self.shutdown(how)
+    }
+    fn nodelay(&self) -> Result<bool, io::Error> {
+        self.nodelay()
+    }
+    fn set_nodelay(&self, nodelay: bool) -> Result<(), io::Error> {
+        self.set_nodelay(nodelay)
+    }
+    fn ttl(&self) -> Result<u32, io::Error> {
+        self.ttl()
+    }
+    fn set_ttl(&self, ttl: u32) -> Result<(), io::Error> {
+        self.set_ttl(ttl)
+    }
+    fn keepalive(&self) -> Result<Option<time::Duration>, io::Error> {
+        self.keepalive()
+    }
+    fn set_keepalive(&self, keepalive: Option<time::Duration>) -> Result<(), io::Error> {
+        self.set_keepalive(keepalive)
+    }
 }
 
 #[async_trait]
@@ -31,3 +52,61 @@ impl crate::TcpListener for TcpListener {
         Box::pin(self.incoming())
     }
 }
+
+#[async_trait]
+impl crate::UdpSocket for UdpSocket {
+    async fn send_to(&mut self, buf: &[u8], target: net::SocketAddr) -> io::Result<usize> {
+        tokio::net::UdpSocket::send_to(self, buf, &target).await
+    }
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)> {
+        tokio::net::UdpSocket::recv_from(self, buf).await
+    }
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+    fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        tokio::net::UdpSocket::set_broadcast(self, broadcast)
+    }
+    fn broadcast(&self) -> io::Result<bool> {
+        tokio::net::UdpSocket::broadcast(self)
+    }
+    fn join_multicast_v4(&self, group: net::Ipv4Addr) -> io::Result<()> {
+        tokio::net::UdpSocket::join_multicast_v4(self, &group, &net::Ipv4Addr::UNSPECIFIED)
+    }
+    fn leave_multicast_v4(&self, group: net::Ipv4Addr) -> io::Result<()> {
+        tokio::net::UdpSocket::leave_multicast_v4(self, &group, &net::Ipv4Addr::UNSPECIFIED)
+    }
+}
+
+#[cfg(unix)]
+impl crate::UnixStream for tokio::net::UnixStream {}
+
+#[cfg(unix)]
+#[async_trait]
+impl crate::UnixListener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+    async fn accept(&mut self) -> io::Result<Self::Stream> {
+        let (stream, _) = tokio::net::UnixListener::accept(self).await?;
+        Ok(stream)
+    }
+    fn local_path(&self) -> io::Result<std::path::PathBuf> {
+        let addr = tokio::net::UnixListener::local_addr(self)?;
+        addr.as_pathname()
+            .map(std::path::Path::to_path_buf)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "listener is not bound to a path"))
+    }
+}
+
+/// Resolves names using the resolver of the operating system.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl crate::Resolver for SystemResolver {
+    async fn resolve(&self, name: &str) -> io::Result<Vec<net::IpAddr>> {
+        // The standard library resolver blocks, which is acceptable on the single threaded
+        // runtime used for testing against real networks.
+        let addrs = net::ToSocketAddrs::to_socket_addrs(&(name, 0))?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
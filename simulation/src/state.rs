@@ -0,0 +1,71 @@
+//! Typed registry of runtime scoped singletons.
+//!
+//! Tests often need a coordination object shared by every task in a simulation, such as an
+//! invariant checker or a set of counters. Storing these in process wide statics leaks them
+//! between runtimes, for example between the seeds of a sweep. [`Environment::state`] instead
+//! returns a singleton scoped to the runtime, created on first access.
+//!
+//! [`Environment::state`]:`crate::Environment::state`
+use std::{any, collections, fmt, sync};
+
+type Singleton = sync::Arc<dyn any::Any + Send + Sync>;
+
+/// Holds one value of each type, shared by every handle of a runtime.
+#[derive(Clone, Default)]
+pub(crate) struct StateRegistry {
+    inner: sync::Arc<sync::Mutex<collections::HashMap<any::TypeId, Singleton>>>,
+}
+
+impl fmt::Debug for StateRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.inner.lock().unwrap().len();
+        write!(f, "StateRegistry {{ len: {} }}", len)
+    }
+}
+
+impl StateRegistry {
+    /// Returns the value of type `T`, inserting the default value if there is none.
+    pub(crate) fn get<T>(&self) -> sync::Arc<T>
+    where
+        T: Default + Send + Sync + 'static,
+    {
+        let mut lock = self.inner.lock().unwrap();
+        let singleton = lock
+            .entry(any::TypeId::of::<T>())
+            .or_insert_with(|| sync::Arc::new(T::default()));
+        sync::Arc::clone(singleton)
+            .downcast()
+            .expect("state registered under the TypeId of another type")
+    }
+
+    /// Remove every value from the registry.
+    pub(crate) fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    #[test]
+    /// Test that state is shared between handles of a runtime, but not between runtimes.
+    fn runtime_scoped_state() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        let a = runtime.handle("10.0.0.1".parse().unwrap());
+        let b = runtime.handle("10.0.0.2".parse().unwrap());
+        a.state::<Counter>().0.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(b.state::<Counter>().0.load(Ordering::SeqCst), 1);
+
+        let other = DeterministicRuntime::new().unwrap().localhost_handle();
+        assert_eq!(other.state::<Counter>().0.load(Ordering::SeqCst), 0);
+
+        runtime.reset_state();
+        assert_eq!(a.state::<Counter>().0.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,7 @@
+//! Synchronization primitives driven by the [`Environment`] clock and source of randomness.
+//!
+//! [`Environment`]:`crate::Environment`
+mod rate_limiter;
+mod work_queue;
+pub use rate_limiter::RateLimiter;
+pub use work_queue::{Push, ShedPolicy, WorkQueue, WorkQueueMetrics};
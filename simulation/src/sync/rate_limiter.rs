@@ -0,0 +1,179 @@
+//! Token bucket rate limiting against the [`Environment`] clock.
+use crate::{Environment, MonotonicInstant};
+use std::{sync, time};
+use tracing::trace;
+
+#[derive(Debug)]
+struct Bucket {
+    /// Maximum number of tokens which can accumulate.
+    capacity: f64,
+    /// Number of tokens currently available.
+    tokens: f64,
+    /// Number of tokens added per second.
+    rate: f64,
+    /// Maximum fractional deviation applied to each refill.
+    jitter: f64,
+    last_refill: MonotonicInstant,
+}
+
+/// A token bucket rate limiter.
+///
+/// Tokens are refilled at a constant rate according to the [`Environment`] clock, up to a maximum
+/// burst capacity. Refills can optionally be jittered using the [`Environment`] source of
+/// randomness, so that limited clients drift apart deterministically for a given seed.
+#[derive(Debug, Clone)]
+pub struct RateLimiter<E> {
+    env: E,
+    bucket: sync::Arc<sync::Mutex<Bucket>>,
+}
+
+impl<E> RateLimiter<E>
+where
+    E: Environment,
+{
+    /// Create a rate limiter which refills `rate` tokens per second, accumulating at most `burst`
+    /// tokens. The limiter starts full.
+    pub fn new(env: E, rate: f64, burst: u32) -> Self {
+        assert!(rate > 0.0, "illegal rate limiter rate: {}", rate);
+        let bucket = Bucket {
+            capacity: f64::from(burst),
+            tokens: f64::from(burst),
+            rate,
+            jitter: 0.0,
+            last_refill: env.now(),
+        };
+        Self {
+            env,
+            bucket: sync::Arc::new(sync::Mutex::new(bucket)),
+        }
+    }
+
+    /// Scale each refill by a random factor in `1 - jitter..1 + jitter`.
+    pub fn with_jitter(self, jitter: f64) -> Self {
+        assert!(
+            jitter >= 0.0 && jitter < 1.0,
+            "illegal rate limiter jitter: {}",
+            jitter
+        );
+        self.bucket.lock().unwrap().jitter = jitter;
+        self
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = self.env.now();
+        if now <= bucket.last_refill {
+            return;
+        }
+        let elapsed = (now - bucket.last_refill).as_secs_f64();
+        let factor = if bucket.jitter > 0.0 {
+            self.env.gen_range(1.0 - bucket.jitter..1.0 + bucket.jitter)
+        } else {
+            1.0
+        };
+        bucket.tokens = f64::min(
+            bucket.capacity,
+            bucket.tokens + elapsed * bucket.rate * factor,
+        );
+        bucket.last_refill = now;
+    }
+
+    /// Returns the number of whole tokens currently available.
+    pub fn available(&self) -> u32 {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        bucket.tokens as u32
+    }
+
+    /// Attempt to acquire `tokens` without waiting, returning true if they were acquired.
+    pub fn try_acquire(&self, tokens: u32) -> bool {
+        self.poll_acquire(tokens).is_none()
+    }
+
+    /// Attempt to acquire `tokens`, returning the time to wait before enough tokens will be
+    /// available if they could not be acquired.
+    fn poll_acquire(&self, tokens: u32) -> Option<time::Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        let tokens = f64::from(tokens);
+        if bucket.tokens >= tokens {
+            bucket.tokens -= tokens;
+            None
+        } else {
+            // Round up to whole milliseconds, the resolution of the timer, so that waiting always
+            // makes progress towards refilling the deficit.
+            let deficit = tokens - bucket.tokens;
+            let millis = (deficit * 1000.0 / bucket.rate).ceil() as u64;
+            Some(time::Duration::from_millis(std::cmp::max(millis, 1)))
+        }
+    }
+
+    /// Acquire `tokens`, waiting until enough tokens are available. Panics if `tokens` exceeds
+    /// the burst capacity, since such requests could never complete.
+    pub async fn acquire(&self, tokens: u32) {
+        let capacity = self.bucket.lock().unwrap().capacity;
+        assert!(
+            f64::from(tokens) <= capacity,
+            "cannot acquire {} tokens from a rate limiter with a burst of {}",
+            tokens,
+            capacity
+        );
+        while let Some(wait) = self.poll_acquire(tokens) {
+            trace!("rate limited, waiting {:?} for {} tokens", wait, tokens);
+            self.env.delay_from(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that bursts are allowed up to capacity, after which acquisitions wait for refills.
+    fn burst_then_refill() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let limiter = RateLimiter::new(handle.clone(), 10.0, 10);
+            let start_time = handle.now();
+            for _ in 0..10 {
+                assert!(limiter.try_acquire(1));
+            }
+            assert!(!limiter.try_acquire(1));
+            limiter.acquire(1).await;
+            assert_eq!(handle.now() - start_time, time::Duration::from_millis(100));
+            limiter.acquire(5).await;
+            assert_eq!(handle.now() - start_time, time::Duration::from_millis(600));
+        });
+    }
+
+    #[test]
+    /// Test that jittered refills are reproducible for a given seed.
+    fn deterministic_jitter() {
+        let simulate = |seed| {
+            let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+            let handle = runtime.localhost_handle();
+            runtime.block_on(async {
+                let limiter = RateLimiter::new(handle.clone(), 10.0, 1).with_jitter(0.5);
+                let start_time = handle.now();
+                for _ in 0..20 {
+                    limiter.acquire(1).await;
+                }
+                handle.now() - start_time
+            })
+        };
+        assert_eq!(simulate(1), simulate(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot acquire 11 tokens")]
+    /// Test that acquiring more tokens than the burst capacity panics rather than hanging.
+    fn acquire_over_burst() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            RateLimiter::new(handle.clone(), 10.0, 10).acquire(11).await;
+        });
+    }
+}
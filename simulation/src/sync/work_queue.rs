@@ -0,0 +1,238 @@
+//! Bounded work queues which shed load when full.
+use crate::{Environment, MonotonicInstant};
+use futures::{task::Waker, Poll};
+use std::{collections, fmt, sync, time};
+use tracing::trace;
+
+/// Policy applied when an item is pushed to a full [`WorkQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedPolicy {
+    /// Evict the oldest queued item to make room for the new item.
+    DropOldest,
+    /// Drop the new item, leaving the queue unchanged.
+    DropNewest,
+    /// Return the new item to the caller so that it can be refused explicitly.
+    Reject,
+}
+
+/// Outcome of pushing an item to a [`WorkQueue`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Push<T> {
+    /// The item was queued.
+    Enqueued,
+    /// The queue was full and the contained item was dropped, either the oldest queued item or
+    /// the pushed item depending on the [`ShedPolicy`].
+    Dropped(T),
+    /// The queue was full and the pushed item was rejected.
+    Rejected(T),
+}
+
+/// Counters describing the behavior of a [`WorkQueue`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WorkQueueMetrics {
+    /// Number of items queued.
+    pub enqueued: u64,
+    /// Number of items removed from the queue by consumers.
+    pub dequeued: u64,
+    /// Number of items dropped by the `DropOldest` or `DropNewest` policies.
+    pub dropped: u64,
+    /// Number of items rejected by the `Reject` policy.
+    pub rejected: u64,
+    /// Largest number of items queued at once.
+    pub max_depth: usize,
+    /// Longest time an item spent queued before being dequeued.
+    pub max_wait: time::Duration,
+}
+
+struct Inner<T> {
+    capacity: usize,
+    policy: ShedPolicy,
+    items: collections::VecDeque<(MonotonicInstant, T)>,
+    waiters: Vec<Waker>,
+    metrics: WorkQueueMetrics,
+}
+
+/// A bounded FIFO queue modeling a server request queue under overload.
+///
+/// Once the queue reaches capacity, pushed items are shed according to the configured
+/// [`ShedPolicy`]. Time spent queued is measured using the [`Environment`] clock.
+pub struct WorkQueue<E, T> {
+    env: E,
+    inner: sync::Arc<sync::Mutex<Inner<T>>>,
+}
+
+impl<E, T> Clone for WorkQueue<E, T>
+where
+    E: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            env: self.env.clone(),
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<E, T> fmt::Debug for WorkQueue<E, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = self.inner.lock().unwrap();
+        f.debug_struct("WorkQueue")
+            .field("capacity", &lock.capacity)
+            .field("policy", &lock.policy)
+            .field("len", &lock.items.len())
+            .field("metrics", &lock.metrics)
+            .finish()
+    }
+}
+
+impl<E, T> WorkQueue<E, T>
+where
+    E: Environment,
+{
+    /// Create a queue holding at most `capacity` items, shedding load according to `policy`.
+    pub fn new(env: E, capacity: usize, policy: ShedPolicy) -> Self {
+        assert!(capacity > 0, "illegal work queue capacity: {}", capacity);
+        let inner = Inner {
+            capacity,
+            policy,
+            items: collections::VecDeque::with_capacity(capacity),
+            waiters: vec![],
+            metrics: WorkQueueMetrics::default(),
+        };
+        Self {
+            env,
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        }
+    }
+
+    /// Push an item to the back of the queue.
+    pub fn push(&self, item: T) -> Push<T> {
+        let now = self.env.now();
+        let mut lock = self.inner.lock().unwrap();
+        let mut result = Push::Enqueued;
+        if lock.items.len() >= lock.capacity {
+            match lock.policy {
+                ShedPolicy::DropOldest => {
+                    trace!("work queue full, dropping oldest item");
+                    lock.metrics.dropped += 1;
+                    let (_, oldest) = lock.items.pop_front().expect("queue is full");
+                    result = Push::Dropped(oldest);
+                }
+                ShedPolicy::DropNewest => {
+                    trace!("work queue full, dropping newest item");
+                    lock.metrics.dropped += 1;
+                    return Push::Dropped(item);
+                }
+                ShedPolicy::Reject => {
+                    trace!("work queue full, rejecting item");
+                    lock.metrics.rejected += 1;
+                    return Push::Rejected(item);
+                }
+            }
+        }
+        lock.items.push_back((now, item));
+        lock.metrics.enqueued += 1;
+        lock.metrics.max_depth = lock.metrics.max_depth.max(lock.items.len());
+        for waiter in lock.waiters.drain(..) {
+            waiter.wake();
+        }
+        result
+    }
+
+    /// Remove the item at the front of the queue, if any.
+    pub fn try_pop(&self) -> Option<T> {
+        let now = self.env.now();
+        let mut lock = self.inner.lock().unwrap();
+        let (queued_at, item) = lock.items.pop_front()?;
+        lock.metrics.dequeued += 1;
+        lock.metrics.max_wait = lock.metrics.max_wait.max(now - queued_at);
+        Some(item)
+    }
+
+    /// Remove the item at the front of the queue, waiting for one to be pushed if the queue is
+    /// empty.
+    pub async fn pop(&self) -> T {
+        futures::future::poll_fn(|cx| {
+            if let Some(item) = self.try_pop() {
+                return Poll::Ready(item);
+            }
+            self.inner.lock().unwrap().waiters.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Returns the number of queued items.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn metrics(&self) -> WorkQueueMetrics {
+        self.inner.lock().unwrap().metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that each policy sheds the expected item once the queue is full.
+    fn shed_policies() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+
+        let queue = WorkQueue::new(handle.clone(), 2, ShedPolicy::DropOldest);
+        assert_eq!(queue.push(1), Push::Enqueued);
+        assert_eq!(queue.push(2), Push::Enqueued);
+        assert_eq!(queue.push(3), Push::Dropped(1));
+        assert_eq!(queue.try_pop(), Some(2));
+
+        let queue = WorkQueue::new(handle.clone(), 1, ShedPolicy::DropNewest);
+        assert_eq!(queue.push(1), Push::Enqueued);
+        assert_eq!(queue.push(2), Push::Dropped(2));
+        assert_eq!(queue.try_pop(), Some(1));
+
+        let queue = WorkQueue::new(handle, 1, ShedPolicy::Reject);
+        assert_eq!(queue.push(1), Push::Enqueued);
+        assert_eq!(queue.push(2), Push::Rejected(2));
+        let metrics = queue.metrics();
+        assert_eq!(metrics.enqueued, 1);
+        assert_eq!(metrics.rejected, 1);
+        assert_eq!(metrics.max_depth, 1);
+    }
+
+    #[test]
+    /// Test that consumers wait for items and queueing time is recorded.
+    fn pop_waits() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let queue = WorkQueue::new(handle.clone(), 4, ShedPolicy::Reject);
+            let producer = queue.clone();
+            let producer_handle = handle.clone();
+            handle.spawn(async move {
+                producer.push("request");
+                producer_handle
+                    .delay_from(time::Duration::from_secs(1))
+                    .await;
+                producer.push("request");
+            });
+            assert_eq!(queue.pop().await, "request");
+            assert_eq!(queue.pop().await, "request");
+            assert_eq!(queue.metrics().dequeued, 2);
+            assert_eq!(queue.metrics().max_wait, time::Duration::from_secs(0));
+
+            // an item left queued records the time it waited.
+            queue.push("request");
+            handle.delay_from(time::Duration::from_secs(3)).await;
+            assert_eq!(queue.pop().await, "request");
+            assert_eq!(queue.metrics().max_wait, time::Duration::from_secs(3));
+        });
+    }
+}
@@ -0,0 +1,65 @@
+//! Checked replacements for `std::thread` functions which break the deterministic time model.
+//!
+//! Spawning OS threads or blocking with `std::thread::sleep` inside a [`DeterministicRuntime`]
+//! escapes mock time and scheduling, silently making a simulation nondeterministic. The functions
+//! in this module panic with the location of the offending call when used inside a
+//! [`DeterministicRuntime`], and otherwise defer to `std::thread`.
+//!
+//! This module is only available with the `thread-guard` feature.
+//!
+//! [`DeterministicRuntime`]:`crate::deterministic::DeterministicRuntime`
+use std::{panic, thread, time};
+
+/// Checked replacement for `std::thread::spawn`.
+#[track_caller]
+pub fn spawn<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    check("std::thread::spawn");
+    thread::spawn(f)
+}
+
+/// Checked replacement for `std::thread::sleep`.
+#[track_caller]
+pub fn sleep(duration: time::Duration) {
+    check("std::thread::sleep");
+    thread::sleep(duration)
+}
+
+#[track_caller]
+fn check(call: &str) {
+    if crate::deterministic::in_simulation() {
+        panic!(
+            "{} called at {} inside a deterministic simulation, use the Environment instead",
+            call,
+            panic::Location::caller()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    #[should_panic(expected = "inside a deterministic simulation")]
+    /// Test that sleeping inside a deterministic runtime panics.
+    fn sleep_in_simulation() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.block_on(async {
+            sleep(time::Duration::from_millis(0));
+        });
+    }
+
+    #[test]
+    /// Test that checked functions defer to std outside of a deterministic runtime.
+    fn outside_simulation() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.block_on(async {});
+        sleep(time::Duration::from_millis(0));
+        spawn(|| ()).join().unwrap();
+    }
+}
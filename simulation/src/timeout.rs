@@ -0,0 +1,226 @@
+//! Stream and Sink timeouts driven by the [`Environment`] clock.
+//!
+//! Wrapping every read from a stream or write to a sink in [`Environment::timeout`] is noisy,
+//! and easy to get wrong by accidentally applying one timeout to a whole sequence of operations.
+//! [`StreamTimeoutExt`] and [`SinkTimeoutExt`] provide the common cases as combinators.
+//!
+//! [`Environment`]:`crate::Environment`
+//! [`Environment::timeout`]:`crate::Environment::timeout`
+use crate::Environment;
+use futures::{FutureExt, Poll, Sink, Stream, StreamExt};
+use std::{future::Future, io, pin::Pin, task::Context, time};
+
+/// Extension trait adding timeouts to streams.
+pub trait StreamTimeoutExt: Stream + Unpin + Sized {
+    /// Yield a `TimedOut` error whenever no item is produced within `duration` of the stream
+    /// being polled for the next item. The stream continues after a timeout, so callers can
+    /// decide whether to give up or keep waiting.
+    fn timeout_between_items<E>(
+        self,
+        env: &E,
+        duration: time::Duration,
+    ) -> TimeoutBetweenItems<Self, E>
+    where
+        E: Environment,
+    {
+        TimeoutBetweenItems {
+            stream: self,
+            env: env.clone(),
+            duration,
+            delay: None,
+        }
+    }
+}
+
+impl<S> StreamTimeoutExt for S where S: Stream + Unpin + Sized {}
+
+/// Stream returned by [`StreamTimeoutExt::timeout_between_items`].
+#[derive(Debug)]
+pub struct TimeoutBetweenItems<S, E> {
+    stream: S,
+    env: E,
+    duration: time::Duration,
+    /// Delay until the pending item times out, started when the stream is first polled after
+    /// yielding an item.
+    delay: Option<tokio_timer::Delay>,
+}
+
+impl<S, E> TimeoutBetweenItems<S, E> {
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, E> Stream for TimeoutBetweenItems<S, E>
+where
+    S: Stream + Unpin,
+    E: Environment,
+{
+    type Item = io::Result<S::Item>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(item) = self.stream.poll_next_unpin(cx) {
+            self.delay.take();
+            return Poll::Ready(item.map(Ok));
+        }
+        let duration = self.duration;
+        let env = self.env.clone();
+        let delay = self.delay.get_or_insert_with(|| env.delay_from(duration));
+        futures::ready!(delay.poll_unpin(cx));
+        self.delay.take();
+        Poll::Ready(Some(Err(io::ErrorKind::TimedOut.into())))
+    }
+}
+
+/// Extension trait adding timeouts to sinks.
+pub trait SinkTimeoutExt<Item>: Sink<Item> + Unpin
+where
+    Self::Error: From<io::Error>,
+{
+    /// Send `item` and flush the sink, failing with a `TimedOut` error if this does not complete
+    /// within `duration`.
+    fn send_timeout<E>(
+        &mut self,
+        env: &E,
+        item: Item,
+        duration: time::Duration,
+    ) -> SendTimeout<'_, Self, Item>
+    where
+        E: Environment,
+    {
+        SendTimeout {
+            sink: self,
+            item: Some(item),
+            delay: env.delay_from(duration),
+        }
+    }
+}
+
+impl<Si, Item> SinkTimeoutExt<Item> for Si
+where
+    Si: Sink<Item> + Unpin + ?Sized,
+    Si::Error: From<io::Error>,
+{
+}
+
+/// Future returned by [`SinkTimeoutExt::send_timeout`].
+#[derive(Debug)]
+pub struct SendTimeout<'a, Si: ?Sized, Item> {
+    sink: &'a mut Si,
+    item: Option<Item>,
+    delay: tokio_timer::Delay,
+}
+
+// The item is never pinned, so the future can be moved regardless of the item type.
+impl<Si: Unpin + ?Sized, Item> Unpin for SendTimeout<'_, Si, Item> {}
+
+impl<Si, Item> SendTimeout<'_, Si, Item>
+where
+    Si: Sink<Item> + Unpin + ?Sized,
+{
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        let mut sink = Pin::new(&mut *self.sink);
+        if self.item.is_some() {
+            futures::ready!(sink.as_mut().poll_ready(cx))?;
+            let item = self.item.take().expect("item is present");
+            sink.as_mut().start_send(item)?;
+        }
+        sink.poll_flush(cx)
+    }
+}
+
+impl<Si, Item> Future for SendTimeout<'_, Si, Item>
+where
+    Si: Sink<Item> + Unpin + ?Sized,
+    Si::Error: From<io::Error>,
+{
+    type Output = Result<(), Si::Error>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(result) = self.poll_send(cx) {
+            return Poll::Ready(result);
+        }
+        futures::ready!(self.delay.poll_unpin(cx));
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        Poll::Ready(Err(timed_out.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use futures::SinkExt;
+
+    /// A sink which only accepts items while `ready` is set.
+    struct Gate {
+        ready: bool,
+        sent: Vec<u32>,
+    }
+
+    impl Sink<u32> for Gate {
+        type Error = io::Error;
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            if self.ready {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        }
+        fn start_send(mut self: Pin<&mut Self>, item: u32) -> io::Result<()> {
+            self.sent.push(item);
+            Ok(())
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    /// Test that a timeout is yielded for each gap between items longer than the duration.
+    fn stream_timeout() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let (mut tx, rx) = futures::channel::mpsc::channel(1);
+            let producer = handle.clone();
+            handle.spawn(async move {
+                for delay in [1, 10].iter() {
+                    producer.delay_from(time::Duration::from_secs(*delay)).await;
+                    tx.send(*delay).await.unwrap();
+                }
+            });
+            let mut items = rx.timeout_between_items(&handle, time::Duration::from_secs(6));
+            assert_eq!(items.next().await.unwrap().unwrap(), 1);
+            assert_eq!(
+                items.next().await.unwrap().unwrap_err().kind(),
+                io::ErrorKind::TimedOut
+            );
+            assert_eq!(items.next().await.unwrap().unwrap(), 10);
+            assert!(items.next().await.is_none());
+        });
+    }
+
+    #[test]
+    /// Test that sends which cannot complete within the duration time out.
+    fn sink_timeout() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let timeout = time::Duration::from_secs(1);
+            let mut gate = Gate {
+                ready: false,
+                sent: vec![],
+            };
+            let start = handle.now();
+            let err = gate.send_timeout(&handle, 1, timeout).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(handle.now() - start, timeout);
+
+            gate.ready = true;
+            gate.send_timeout(&handle, 2, timeout).await.unwrap();
+            assert_eq!(gate.sent, vec![2]);
+        });
+    }
+}
@@ -0,0 +1,569 @@
+//! TLS layered over [`Environment`] streams.
+//!
+//! Connections are secured through the [`TlsConnect`] and [`TlsAccept`] traits, so that an
+//! application can plug in a real TLS implementation when running on a real network, and the
+//! simulated implementation in this module when running deterministically.
+//!
+//! The simulated implementation performs a handshake in which the server presents a certificate
+//! issued by a [`TestCa`], and the client verifies that the certificate was issued by a trusted
+//! authority for the name it connected to. No encryption is performed, but the handshake
+//! exchanges real bytes over the underlying stream, so latency, disconnects and other network
+//! faults injected mid-handshake fail it as they would a real handshake. Authorities are
+//! generated from the [`Environment`] source of randomness, so certificates are deterministic for
+//! a given seed.
+//!
+//! [`Environment`]:`crate::Environment`
+use crate::{deterministic::Handshake, Environment, TcpListener};
+use async_trait::async_trait;
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    Poll, StreamExt,
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io, net,
+    pin::Pin,
+    sync,
+    task::Context,
+    time,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::trace;
+
+/// Establishes TLS sessions over client streams.
+#[async_trait]
+pub trait TlsConnect<S>: Send + Sync
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// Perform a handshake over `stream`, verifying that the server is `domain`.
+    async fn connect(&self, domain: &str, stream: S) -> io::Result<Self::Stream>;
+}
+
+/// Establishes TLS sessions over accepted server streams.
+#[async_trait]
+pub trait TlsAccept<S>: Send + Sync
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// Perform a handshake over `stream`.
+    async fn accept(&self, stream: S) -> io::Result<Self::Stream>;
+}
+
+/// Connect to `addr` using `env` and perform a TLS handshake with the server named `domain`.
+pub async fn connect<E, C>(
+    env: &E,
+    connector: &C,
+    addr: net::SocketAddr,
+    domain: &str,
+) -> io::Result<C::Stream>
+where
+    E: Environment,
+    C: TlsConnect<E::TcpStream>,
+{
+    let stream = env.connect(addr).await?;
+    connector.connect(domain, stream).await
+}
+
+/// A stream over which a handshake has completed, along with the address of its peer.
+type Accepted<S> = io::Result<(S, net::SocketAddr)>;
+
+/// A listener which performs a TLS handshake on each accepted connection. Each handshake runs in
+/// its own task, so a client which stalls mid-handshake does not hold up connections accepted
+/// after it, and streams are returned in the order their handshakes complete.
+pub struct TlsListener<E, A>
+where
+    E: Environment,
+    A: TlsAccept<<E::TcpListener as TcpListener>::Stream>,
+{
+    env: E,
+    listener: E::TcpListener,
+    acceptor: sync::Arc<A>,
+    handshake: Handshake,
+    completed_tx: mpsc::UnboundedSender<Accepted<A::Stream>>,
+    completed_rx: mpsc::UnboundedReceiver<Accepted<A::Stream>>,
+}
+
+impl<E, A> TlsListener<E, A>
+where
+    E: Environment,
+    A: TlsAccept<<E::TcpListener as TcpListener>::Stream> + 'static,
+{
+    pub fn new(env: E, listener: E::TcpListener, acceptor: A) -> Self {
+        let (completed_tx, completed_rx) = mpsc::unbounded();
+        Self {
+            env,
+            listener,
+            acceptor: sync::Arc::new(acceptor),
+            handshake: Handshake::new(0, time::Duration::from_secs(0)),
+            completed_tx,
+            completed_rx,
+        }
+    }
+
+    /// Cost of each handshake. Only its processing time is charged by the listener, as the
+    /// round trips of a handshake are made by the messages it exchanges over the connection.
+    /// Defaults to no processing time.
+    pub fn handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Returns the next connection whose handshake has completed. A failed handshake returns
+    /// its error, and the listener can continue to accept connections.
+    pub async fn accept(&mut self) -> Accepted<A::Stream> {
+        loop {
+            let next = future::select(self.completed_rx.next(), self.listener.accept());
+            let accepted = match next.await {
+                Either::Left((completed, _)) => {
+                    return completed.expect("listener holds a handshake sender");
+                }
+                Either::Right((accepted, _)) => accepted,
+            };
+            let (stream, addr) = accepted?;
+            self.spawn_handshake(stream, addr);
+        }
+    }
+
+    fn spawn_handshake(
+        &self,
+        stream: <E::TcpListener as TcpListener>::Stream,
+        addr: net::SocketAddr,
+    ) {
+        let env = self.env.clone();
+        let acceptor = sync::Arc::clone(&self.acceptor);
+        let cpu = self.handshake.cpu;
+        let completed = self.completed_tx.clone();
+        self.env.spawn(async move {
+            if cpu > time::Duration::from_secs(0) {
+                env.delay_from(cpu).await;
+            }
+            let result = acceptor.accept(stream).await;
+            if let Err(error) = &result {
+                trace!("TLS handshake with {} failed: {}", addr, error);
+            }
+            // The listener may have been dropped while the handshake was in progress.
+            let _ = completed.unbounded_send(result.map(|stream| (stream, addr)));
+        });
+    }
+
+    pub fn get_ref(&self) -> &E::TcpListener {
+        &self.listener
+    }
+}
+
+/// Bind `addr` using `env`, performing a TLS handshake with `acceptor` on each accepted
+/// connection.
+pub async fn bind<E, A>(
+    env: &E,
+    acceptor: A,
+    addr: net::SocketAddr,
+) -> io::Result<TlsListener<E, A>>
+where
+    E: Environment,
+    A: TlsAccept<<E::TcpListener as TcpListener>::Stream> + 'static,
+{
+    let listener = env.bind(addr).await?;
+    Ok(TlsListener::new(env.clone(), listener, acceptor))
+}
+
+/// A certificate authority for simulated TLS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCa {
+    name: String,
+    key: u64,
+}
+
+impl TestCa {
+    /// Generate an authority named `name`, with a key drawn from the source of randomness of
+    /// `env`.
+    pub fn generate<E: Environment>(env: &E, name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            key: env.gen_range(0..u64::max_value()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Issue a certificate for `subject`.
+    pub fn issue(&self, subject: &str) -> Certificate {
+        Certificate {
+            subject: subject.to_string(),
+            issuer: self.name.clone(),
+            signature: self.sign(subject),
+        }
+    }
+
+    fn sign(&self, subject: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.key.hash(&mut hasher);
+        subject.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns true if `certificate` was issued by this authority.
+    fn verify(&self, certificate: &Certificate) -> bool {
+        certificate.issuer == self.name && certificate.signature == self.sign(&certificate.subject)
+    }
+}
+
+/// A certificate issued by a [`TestCa`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    signature: u64,
+}
+
+/// Messages exchanged during a simulated handshake.
+#[derive(Debug, PartialEq)]
+enum Message {
+    ClientHello { domain: String },
+    Certificate(Certificate),
+    Finished,
+    Alert { reason: String },
+}
+
+const CLIENT_HELLO: u8 = 1;
+const CERTIFICATE: u8 = 2;
+const FINISHED: u8 = 3;
+const ALERT: u8 = 4;
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let (kind, fields) = match self {
+            Message::ClientHello { domain } => (CLIENT_HELLO, vec![domain.clone()]),
+            Message::Certificate(certificate) => (
+                CERTIFICATE,
+                vec![
+                    certificate.subject.clone(),
+                    certificate.issuer.clone(),
+                    certificate.signature.to_string(),
+                ],
+            ),
+            Message::Finished => (FINISHED, vec![]),
+            Message::Alert { reason } => (ALERT, vec![reason.clone()]),
+        };
+        let body = fields.join("\n");
+        let mut frame = Vec::with_capacity(3 + body.len());
+        frame.push(kind);
+        frame.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        frame.extend_from_slice(body.as_bytes());
+        frame
+    }
+
+    fn decode(kind: u8, body: &[u8]) -> io::Result<Message> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed handshake message");
+        let body = std::str::from_utf8(body).map_err(|_| invalid())?;
+        let mut fields = body.split('\n');
+        let mut field = || fields.next().map(str::to_string).ok_or_else(invalid);
+        match kind {
+            CLIENT_HELLO => Ok(Message::ClientHello { domain: field()? }),
+            CERTIFICATE => Ok(Message::Certificate(Certificate {
+                subject: field()?,
+                issuer: field()?,
+                signature: field()?.parse().map_err(|_| invalid())?,
+            })),
+            FINISHED => Ok(Message::Finished),
+            ALERT => Ok(Message::Alert { reason: field()? }),
+            _ => Err(invalid()),
+        }
+    }
+
+    async fn write<S>(&self, stream: &mut S) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        stream.write_all(&self.encode()).await?;
+        stream.flush().await
+    }
+
+    /// Read the next message from `stream`. Alerts sent by the peer are returned as errors.
+    async fn read<S>(stream: &mut S) -> io::Result<Message>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut header = [0; 3];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[1], header[2]]) as usize;
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await?;
+        match Message::decode(header[0], &body)? {
+            Message::Alert { reason } => Err(handshake_error(&format!("peer alert: {}", reason))),
+            message => Ok(message),
+        }
+    }
+}
+
+fn handshake_error(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("TLS handshake failed: {}", reason),
+    )
+}
+
+/// Fail the handshake over `stream`, notifying the peer with an alert.
+async fn fail<S>(stream: &mut S, reason: &str) -> io::Error
+where
+    S: AsyncWrite + Unpin,
+{
+    trace!("failing TLS handshake: {}", reason);
+    let alert = Message::Alert {
+        reason: reason.to_string(),
+    };
+    // The handshake has already failed, so a failure to deliver the alert is ignored.
+    let _ = alert.write(stream).await;
+    handshake_error(reason)
+}
+
+/// Client side of simulated TLS, trusting certificates issued by a set of [`TestCa`]s.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedTlsConnector {
+    trusted: Vec<TestCa>,
+}
+
+impl SimulatedTlsConnector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust certificates issued by `ca`.
+    pub fn trust(mut self, ca: TestCa) -> Self {
+        self.trusted.push(ca);
+        self
+    }
+
+    fn verify(&self, domain: &str, certificate: &Certificate) -> Result<(), &'static str> {
+        if !self.trusted.iter().any(|ca| ca.verify(certificate)) {
+            return Err("untrusted certificate");
+        }
+        if certificate.subject != domain {
+            return Err("certificate name mismatch");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> TlsConnect<S> for SimulatedTlsConnector
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = TlsStream<S>;
+    async fn connect(&self, domain: &str, mut stream: S) -> io::Result<Self::Stream> {
+        let hello = Message::ClientHello {
+            domain: domain.to_string(),
+        };
+        hello.write(&mut stream).await?;
+        let certificate = match Message::read(&mut stream).await? {
+            Message::Certificate(certificate) => certificate,
+            _ => return Err(fail(&mut stream, "expected certificate").await),
+        };
+        if let Err(reason) = self.verify(domain, &certificate) {
+            return Err(fail(&mut stream, reason).await);
+        }
+        Message::Finished.write(&mut stream).await?;
+        trace!("established TLS session with {}", certificate.subject);
+        Ok(TlsStream {
+            inner: stream,
+            peer_certificate: Some(certificate),
+        })
+    }
+}
+
+/// Server side of simulated TLS, presenting a single certificate.
+#[derive(Debug, Clone)]
+pub struct SimulatedTlsAcceptor {
+    certificate: Certificate,
+}
+
+impl SimulatedTlsAcceptor {
+    pub fn new(certificate: Certificate) -> Self {
+        Self { certificate }
+    }
+}
+
+#[async_trait]
+impl<S> TlsAccept<S> for SimulatedTlsAcceptor
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = TlsStream<S>;
+    async fn accept(&self, mut stream: S) -> io::Result<Self::Stream> {
+        match Message::read(&mut stream).await? {
+            Message::ClientHello { domain } => trace!("client hello for {}", domain),
+            _ => return Err(fail(&mut stream, "expected client hello").await),
+        }
+        Message::Certificate(self.certificate.clone())
+            .write(&mut stream)
+            .await?;
+        match Message::read(&mut stream).await? {
+            Message::Finished => Ok(TlsStream {
+                inner: stream,
+                peer_certificate: None,
+            }),
+            _ => Err(fail(&mut stream, "expected finished").await),
+        }
+    }
+}
+
+/// A stream over which a simulated TLS handshake has completed.
+#[derive(Debug)]
+pub struct TlsStream<S> {
+    inner: S,
+    peer_certificate: Option<Certificate>,
+}
+
+impl<S> TlsStream<S> {
+    /// Returns the certificate presented by the server, on the client side of the session.
+    pub fn peer_certificate(&self) -> Option<&Certificate> {
+        self.peer_certificate.as_ref()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> AsyncRead for TlsStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for TlsStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TcpStream};
+
+    #[test]
+    /// Test that handshakes succeed for trusted certificates, and fail on both sides for
+    /// untrusted certificates, mismatched names and disconnects mid-handshake.
+    fn handshake() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let ca = TestCa::generate(&handle, "test-ca");
+            let rogue = TestCa::generate(&handle, "test-ca");
+            assert_ne!(ca, rogue);
+            let addr: net::SocketAddr = "127.0.0.1:9443".parse().unwrap();
+            let acceptor = SimulatedTlsAcceptor::new(ca.issue("server"));
+            let mut listener = bind(&handle, acceptor, addr).await.unwrap();
+            let server = crate::spawn_with_result(&handle, async move {
+                let mut results = vec![];
+                for _ in 0..3 {
+                    let result = listener.accept().await.map(|_| ());
+                    results.push(result.map_err(|e| e.kind()));
+                }
+                results
+            });
+
+            let connector = SimulatedTlsConnector::new().trust(ca.clone());
+            let mut stream = connect(&handle, &connector, addr, "server").await.unwrap();
+            assert_eq!(
+                stream.peer_certificate().map(|c| c.subject.as_str()),
+                Some("server")
+            );
+            stream.write_all(b"ping").await.unwrap();
+
+            let err = connect(&handle, &connector, addr, "other")
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let untrusted = SimulatedTlsConnector::new().trust(rogue);
+            let err = connect(&handle, &untrusted, addr, "server")
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(
+                server.await,
+                vec![
+                    Ok(()),
+                    Err(io::ErrorKind::InvalidData),
+                    Err(io::ErrorKind::InvalidData)
+                ]
+            );
+
+            // a server which disconnects after the client hello fails the handshake.
+            let raw_addr: net::SocketAddr = "127.0.0.1:9444".parse().unwrap();
+            let mut raw_listener = handle.bind(raw_addr).await.unwrap();
+            handle.spawn(async move {
+                let (mut stream, _) = raw_listener.accept().await.unwrap();
+                let mut hello = [0; 3];
+                stream.read_exact(&mut hello).await.unwrap();
+            });
+            let err = connect(&handle, &connector, raw_addr, "server")
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+
+    #[test]
+    /// Test that a client which stalls mid-handshake does not hold up other clients, and that
+    /// the processing time of each handshake is charged.
+    fn concurrent_handshakes() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let ca = TestCa::generate(&handle, "test-ca");
+            let addr: net::SocketAddr = "127.0.0.1:9443".parse().unwrap();
+            let cpu = time::Duration::from_millis(5);
+            let acceptor = SimulatedTlsAcceptor::new(ca.issue("server"));
+            let mut listener = bind(&handle, acceptor, addr)
+                .await
+                .unwrap()
+                .handshake(Handshake::new(1, cpu));
+            let server = crate::spawn_with_result(&handle, async move {
+                listener.accept().await.map(|(_, addr)| addr)
+            });
+
+            let _stalled = handle.connect(addr).await.unwrap();
+            let connector = SimulatedTlsConnector::new().trust(ca);
+            let start = handle.now();
+            let stream = connect(&handle, &connector, addr, "server").await.unwrap();
+            assert_eq!(handle.now() - start, cpu);
+            assert_eq!(
+                server.await.unwrap(),
+                stream.get_ref().local_addr().unwrap()
+            );
+        });
+    }
+}
@@ -0,0 +1,356 @@
+//! Workload generators which drive simulated traffic against a system under test.
+//!
+//! Workloads are generic over [`Environment`], sampling all of their decisions from the
+//! environment source of randomness. Under the [`DeterministicRuntime`] the generated load
+//! pattern is therefore reproducible for a given seed.
+//!
+//! [`DeterministicRuntime`]:`crate::deterministic::DeterministicRuntime`
+use crate::{deterministic::Zipf, Environment, MonotonicInstant};
+use futures::Future;
+use std::{io, net, sync, time};
+
+/// Outcome of running a [`ClientPopulation`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PopulationReport {
+    /// Number of requests which completed successfully.
+    pub completed: usize,
+    /// Number of requests which were abandoned by the client before completing.
+    pub aborted: usize,
+    /// Number of requests which failed to connect or returned an error.
+    pub failed: usize,
+}
+
+impl PopulationReport {
+    fn merge(&mut self, other: PopulationReport) {
+        self.completed += other.completed;
+        self.aborted += other.aborted;
+        self.failed += other.failed;
+    }
+}
+
+/// A population of simulated clients issuing requests against a set of target addresses.
+///
+/// Clients arrive following a Poisson process, pause for an exponentially distributed think
+/// time between requests, and abandon requests with a configurable probability. Each request
+/// is sent to a target chosen from a Zipf distribution, so that a skewed population
+/// concentrates load on a few hot targets.
+#[derive(Debug, Clone)]
+pub struct ClientPopulation {
+    targets: Vec<net::SocketAddr>,
+    skew: f64,
+    clients: usize,
+    requests_per_client: usize,
+    arrival_rate: f64,
+    mean_think_time: time::Duration,
+    abort_probability: f64,
+}
+
+impl ClientPopulation {
+    /// Create a population of a single client issuing a single request against `target`.
+    pub fn new(target: net::SocketAddr) -> Self {
+        Self {
+            targets: vec![target],
+            skew: 0.0,
+            clients: 1,
+            requests_per_client: 1,
+            arrival_rate: 1.0,
+            mean_think_time: time::Duration::from_secs(1),
+            abort_probability: 0.0,
+        }
+    }
+
+    /// Issue requests against `targets` rather than the single target the population was
+    /// created with. Targets are ranked in the order provided, for the purposes of [`skew`].
+    ///
+    /// [`skew`]:`ClientPopulation::skew`
+    pub fn targets<I>(mut self, targets: I) -> Self
+    where
+        I: IntoIterator<Item = net::SocketAddr>,
+    {
+        self.targets = targets.into_iter().collect();
+        assert!(!self.targets.is_empty(), "population requires a target");
+        self
+    }
+
+    /// Exponent of the Zipf distribution targets are chosen from, where the target of rank `k`
+    /// receives requests in proportion to `1 / k^skew`. Defaults to 0.0, choosing targets
+    /// uniformly.
+    pub fn skew(mut self, skew: f64) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    /// Number of clients to spawn.
+    pub fn clients(mut self, clients: usize) -> Self {
+        self.clients = clients;
+        self
+    }
+
+    /// Number of requests each client issues before exiting.
+    pub fn requests_per_client(mut self, requests: usize) -> Self {
+        self.requests_per_client = requests;
+        self
+    }
+
+    /// Mean number of clients arriving per simulated second. Panics if the rate is not positive.
+    pub fn arrival_rate(mut self, clients_per_second: f64) -> Self {
+        assert!(
+            clients_per_second > 0.0,
+            "illegal arrival rate: {}",
+            clients_per_second
+        );
+        self.arrival_rate = clients_per_second;
+        self
+    }
+
+    /// Mean time a client waits between requests.
+    pub fn think_time(mut self, mean: time::Duration) -> Self {
+        self.mean_think_time = mean;
+        self
+    }
+
+    /// Probability that a client abandons a request. An abandoned request is cancelled after an
+    /// exponentially distributed time with the mean think time, whether or not it would have
+    /// completed by then, so the number of aborted requests depends only on the seed.
+    pub fn abort_probability(mut self, probability: f64) -> Self {
+        self.abort_probability = probability;
+        self
+    }
+
+    /// Spawn the client population, invoking `session` with a new connection for every request.
+    /// Resolves once every client has issued all of its requests.
+    pub async fn run<E, F, U>(self, env: E, session: F) -> PopulationReport
+    where
+        E: Environment,
+        F: Fn(E, E::TcpStream) -> U + Send + Sync + 'static,
+        U: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let session = sync::Arc::new(session);
+        let zipf = sync::Arc::new(Zipf::new(self.targets.len() as u64, self.skew));
+        let mut arrival = env.now();
+        let mut clients = Vec::with_capacity(self.clients);
+        for _ in 0..self.clients {
+            arrival += exponential_duration(&env, self.arrival_rate);
+            let client = run_client(
+                env.clone(),
+                self.clone(),
+                sync::Arc::clone(&session),
+                sync::Arc::clone(&zipf),
+                arrival,
+            );
+            clients.push(crate::spawn_with_result(&env, client));
+        }
+        let mut report = PopulationReport::default();
+        for client in futures::future::join_all(clients).await {
+            report.merge(client);
+        }
+        report
+    }
+}
+
+async fn run_client<E, F, U>(
+    env: E,
+    config: ClientPopulation,
+    session: sync::Arc<F>,
+    zipf: sync::Arc<Zipf>,
+    arrival: MonotonicInstant,
+) -> PopulationReport
+where
+    E: Environment,
+    F: Fn(E, E::TcpStream) -> U + Send + Sync + 'static,
+    U: Future<Output = io::Result<()>> + Send + 'static,
+{
+    env.delay(arrival).await;
+    let think_rate = rate_for(config.mean_think_time);
+    let mut report = PopulationReport::default();
+    for _ in 0..config.requests_per_client {
+        let abort = env.gen_bool(config.abort_probability);
+        match env.connect(config.choose_target(&env, &zipf)).await {
+            Ok(stream) => {
+                let request = (*session)(env.clone(), stream);
+                if abort {
+                    let abort_after = exponential_duration(&env, think_rate);
+                    let abort_at = env.now() + abort_after;
+                    // the outcome of the request is discarded, and the request is dropped at
+                    // `abort_at` if it is still running.
+                    let _ = env.timeout(request, abort_after).await;
+                    env.delay(abort_at).await;
+                    report.aborted += 1;
+                } else {
+                    match request.await {
+                        Ok(()) => report.completed += 1,
+                        Err(_) => report.failed += 1,
+                    }
+                }
+            }
+            Err(_) => report.failed += 1,
+        }
+        env.delay_from(exponential_duration(&env, think_rate)).await;
+    }
+    report
+}
+
+impl ClientPopulation {
+    /// Choose a target using the cumulative distribution of target ranks, which is computed
+    /// once per run rather than for every request.
+    fn choose_target<E: Environment>(&self, env: &E, zipf: &Zipf) -> net::SocketAddr {
+        if self.targets.len() == 1 {
+            return self.targets[0];
+        }
+        let rank = zipf.rank(env.gen_range(0.0..1.0));
+        self.targets[rank as usize - 1]
+    }
+}
+
+/// Returns the rate of events per second which results in a mean interval of `mean`.
+fn rate_for(mean: time::Duration) -> f64 {
+    1.0 / mean.as_secs_f64()
+}
+
+/// Samples an exponentially distributed duration with the provided rate of events per second.
+fn exponential_duration<E: Environment>(env: &E, rate: f64) -> time::Duration {
+    if !rate.is_finite() {
+        return time::Duration::from_secs(0);
+    }
+    time::Duration::from_secs_f64(env.exponential(rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TcpListener};
+    use futures::{SinkExt, StreamExt};
+    use tokio::codec::{Framed, LinesCodec};
+
+    async fn pong_server<E: Environment>(env: E, addr: net::SocketAddr) {
+        let mut listener = env.bind(addr).await.unwrap();
+        while let Ok((socket, _)) = listener.accept().await {
+            env.spawn(async move {
+                let mut transport = Framed::new(socket, LinesCodec::new());
+                while let Some(Ok(_)) = transport.next().await {
+                    if transport.send(String::from("pong")).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    async fn ping<E: Environment>(_: E, stream: E::TcpStream) -> io::Result<()> {
+        let mut transport = Framed::new(stream, LinesCodec::new());
+        transport
+            .send(String::from("ping"))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        match transport.next().await {
+            Some(Ok(_)) => Ok(()),
+            _ => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn simulate(seed: u64, abort_probability: f64) -> (PopulationReport, time::Duration) {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            handle.spawn(pong_server(handle.clone(), addr));
+            let start_time = handle.now();
+            let report = ClientPopulation::new(addr)
+                .clients(10)
+                .requests_per_client(3)
+                .abort_probability(abort_probability)
+                .run(handle.clone(), ping)
+                .await;
+            (report, handle.now() - start_time)
+        })
+    }
+
+    #[test]
+    /// Test that every request issued by the population is accounted for.
+    fn population_completes() {
+        let (report, _) = simulate(0, 0.0);
+        assert_eq!(
+            report,
+            PopulationReport {
+                completed: 30,
+                aborted: 0,
+                failed: 0
+            }
+        );
+    }
+
+    #[test]
+    /// Test that a skewed population concentrates requests on the highest ranked target.
+    fn skewed_targets() {
+        let mut runtime = DeterministicRuntime::new_with_seed(1).unwrap();
+        let handle = runtime.localhost_handle();
+        let targets: Vec<net::SocketAddr> = (9092..9096)
+            .map(|port| net::SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let accepted: Vec<_> = targets
+            .iter()
+            .map(|_| sync::Arc::new(sync::atomic::AtomicUsize::new(0)))
+            .collect();
+        runtime.block_on(async {
+            for (addr, accepted) in targets.iter().zip(accepted.iter()) {
+                let env = handle.clone();
+                let (addr, accepted) = (*addr, sync::Arc::clone(accepted));
+                handle.spawn(async move {
+                    let mut listener = env.bind(addr).await.unwrap();
+                    while let Ok(_) = listener.accept().await {
+                        accepted.fetch_add(1, sync::atomic::Ordering::SeqCst);
+                    }
+                });
+            }
+            let report = ClientPopulation::new(targets[0])
+                .targets(targets.clone())
+                .skew(2.0)
+                .clients(20)
+                .requests_per_client(5)
+                .run(handle.clone(), |_, _| async { Ok(()) })
+                .await;
+            assert_eq!(report.completed, 100);
+        });
+        let accepted: Vec<usize> = accepted
+            .iter()
+            .map(|accepted| accepted.load(sync::atomic::Ordering::SeqCst))
+            .collect();
+        assert_eq!(accepted.iter().sum::<usize>(), 100);
+        assert!(
+            accepted[0] > 50 && accepted[0] > accepted[3],
+            "expected the first target to be hot, accepted {:?}",
+            accepted
+        );
+    }
+
+    #[test]
+    /// Test that abandoned requests are always counted as aborted, even when they would have
+    /// completed before being cancelled.
+    fn aborts_cancel_requests() {
+        let (report, _) = simulate(0, 1.0);
+        assert_eq!(
+            report,
+            PopulationReport {
+                completed: 0,
+                aborted: 30,
+                failed: 0
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "illegal arrival rate: 0")]
+    /// Test that a population which would never arrive is rejected.
+    fn zero_arrival_rate() {
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        ClientPopulation::new(addr).arrival_rate(0.0);
+    }
+
+    #[test]
+    /// Test that the generated load pattern is reproducible for a given seed.
+    fn population_is_deterministic() {
+        assert_eq!(simulate(3, 0.5), simulate(3, 0.5));
+        let (report, _) = simulate(3, 0.5);
+        assert_eq!(report.completed + report.aborted + report.failed, 30);
+    }
+}